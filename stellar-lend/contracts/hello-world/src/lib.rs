@@ -4,12 +4,13 @@
 //! Core features will be implemented incrementally in separate modules.
 
 #![no_std]
+#![allow(clippy::too_many_arguments, clippy::enum_variant_names)]
 extern crate alloc;
 use alloc::format;
 use alloc::string::ToString;
 use soroban_sdk::{
-    contract, contracterror, contractimpl, contracttype, storage, vec, Address, Env, IntoVal,
-    String, Symbol, Vec,
+    contract, contractclient, contracterror, contractimpl, contracttype, symbol_short,
+    token, vec, Address, Bytes, Env, IntoVal, Map, String, Symbol, TryFromVal, Val, Vec,
 };
 
 // Module placeholders for future expansion
@@ -20,13 +21,140 @@ use soroban_sdk::{
 // mod liquidate;
 
 
+/// Keys for every item the contract stores; per-user and per-asset items are keyed by
+/// the full `Address` or asset symbol so distinct entries can never collide
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum DataKey {
+    /// Storage layout version, written by `migrate_storage`
+    StorageVersion,
+    Reentrancy,
+    SuspiciousCount(Address),
+    RiskConfig,
+    ReserveData,
+    RevenueMetrics,
+    UserActivity(Address),
+    ProtocolActivity,
+    AssetRegistry,
+    AssetInfo(String),
+    /// Number of open positions in an asset
+    OpenPositions(String),
+    /// Number of open positions a user holds across assets and sub-accounts
+    UserOpenPositions(Address),
+    /// Number of users holding at least one open position
+    ActiveUsers,
+    /// Cumulative bad debt written off in an asset
+    BadDebt(String),
+    FlashLoanFee,
+    /// A user's position in an asset
+    Position(Address, String),
+    /// A user's position in an asset within one of their numbered sub-accounts
+    SubPosition(Address, u32, String),
+    /// Sub-accounts a user has opened
+    SubAccounts(Address),
+    /// Debt a user's sub-account has charged to isolated assets' debt ceilings
+    IsolationDebt(Address, u32),
+    MaxPriceDeviation,
+    OracleHeartbeat,
+    Admin,
+    Oracle,
+    MinCollateralRatio,
+    ErrorAnalytics,
+    ErrorLog(u32),
+    ErrorCounter,
+    Frozen(Address),
+    AdminSet,
+    ProposalCounter,
+    Proposal(u32),
+    Kyc(Address),
+    Blacklisted(Address),
+    UxConfig,
+    RecoveryConfig(Address),
+    RecoveryRequest(u32),
+    MultisigConfig,
+    MultisigProposal(u32),
+    NextRecoveryId,
+    NextMultisigProposalId,
+    /// Efficiency-mode category parameters
+    EModeCategory(u32),
+    /// Efficiency-mode category a user opted into
+    UserEMode(Address),
+    /// A fixed-term loan by id
+    Loan(u32),
+    NextLoanId,
+    /// Ids of every fixed-term loan a user has taken
+    UserLoans(Address),
+    /// Seconds after maturity before an unpaid fixed-term loan defaults
+    LoanGracePeriod,
+    /// Amount of an asset a delegatee may borrow against a delegator's collateral
+    BorrowAllowance(Address, Address, String),
+    /// Swap adapters the admin has approved for collateral swaps and leverage
+    SwapAdapters,
+    /// Scratch entry `recover_storage_error` writes and removes to check storage works
+    StorageProbe,
+}
+
+/// Ledgers per day at a ~5s close time
+const DAY_IN_LEDGERS: u32 = 17280;
+const INSTANCE_BUMP_AMOUNT: u32 = 7 * DAY_IN_LEDGERS;
+const INSTANCE_LIFETIME_THRESHOLD: u32 = INSTANCE_BUMP_AMOUNT - DAY_IN_LEDGERS;
+const USER_BUMP_AMOUNT: u32 = 30 * DAY_IN_LEDGERS;
+const USER_LIFETIME_THRESHOLD: u32 = USER_BUMP_AMOUNT - DAY_IN_LEDGERS;
+
+/// Persistent storage for per-user and per-proposal entries. Protocol configuration
+/// stays in instance storage; everything that grows with the number of users lives
+/// here, and each read or write extends the entry's TTL.
+pub struct UserStorage;
+
+impl UserStorage {
+    pub fn get<V: TryFromVal<Env, Val>>(env: &Env, key: &DataKey) -> Option<V> {
+        let value = env.storage().persistent().get::<DataKey, V>(key);
+        if value.is_some() {
+            Self::extend(env, key);
+        }
+        value
+    }
+
+    pub fn set<V: IntoVal<Env, Val>>(env: &Env, key: &DataKey, value: &V) {
+        env.storage().persistent().set(key, value);
+        Self::extend(env, key);
+    }
+
+    pub fn remove(env: &Env, key: &DataKey) {
+        env.storage().persistent().remove(key);
+    }
+
+    /// Extend the TTL of an entry if it exists, returning whether it did
+    pub fn bump(env: &Env, key: &DataKey) -> bool {
+        if env.storage().persistent().has(key) {
+            Self::extend(env, key);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn extend(env: &Env, key: &DataKey) {
+        env.storage()
+            .persistent()
+            .extend_ttl(key, USER_LIFETIME_THRESHOLD, USER_BUMP_AMOUNT);
+    }
+
+    /// Keep the contract instance, and the configuration stored in it, alive
+    pub fn bump_instance(env: &Env) {
+        env.storage()
+            .instance()
+            .extend_ttl(INSTANCE_LIFETIME_THRESHOLD, INSTANCE_BUMP_AMOUNT);
+    }
+}
+
 /// Reentrancy guard for security
 pub struct ReentrancyGuard;
 
 impl ReentrancyGuard {
-    fn key() -> Symbol { Symbol::short("reentrancy") }
+    fn key() -> DataKey { DataKey::Reentrancy }
     pub fn enter(env: &Env) -> Result<(), ProtocolError> {
-        let entered = env.storage().instance().get::<Symbol, bool>(&Self::key()).unwrap_or(false);
+        let entered = env.storage().instance().get::<DataKey, bool>(&Self::key()).unwrap_or(false);
         if entered {
             let error = ProtocolError::ReentrancyDetected;
             ErrorLogger::log_error(env, &error, None, "ReentrancyGuard::enter", "Reentrancy attack detected");
@@ -44,21 +172,20 @@ impl ReentrancyGuard {
 pub struct SecurityMonitor;
 
 impl SecurityMonitor {
-    fn suspicious_key(user: &Address) -> Symbol {
-        let env = Env::default();
-        Symbol::new(&env, "suspicious_user")
+    fn suspicious_key(user: &Address) -> DataKey {
+        DataKey::SuspiciousCount(user.clone())
     }
     pub fn record_suspicious(env: &Env, user: &Address, reason: &str) {
         let key = Self::suspicious_key(user);
-        let count = env.storage().instance().get::<Symbol, u32>(&key).unwrap_or(0) + 1;
-        env.storage().instance().set(&key, &count);
+        let count = UserStorage::get::<u32>(env, &key).unwrap_or(0) + 1;
+        UserStorage::set(env, &key, &count);
         env.events().publish(
-            (Symbol::short("security_alert"), Symbol::short("user")),
-            (Symbol::short("reason"), String::from_str(env, reason), Symbol::short("count"), count)
+            (symbol_short!("sec_alert"), symbol_short!("user")),
+            (symbol_short!("reason"), String::from_str(env, reason), symbol_short!("count"), count)
         );
     }
     pub fn get_suspicious_count(env: &Env, user: &Address) -> u32 {
-        env.storage().instance().get::<Symbol, u32>(&Self::suspicious_key(user)).unwrap_or(0)
+        UserStorage::get::<u32>(env, &Self::suspicious_key(user)).unwrap_or(0)
     }
 }
 
@@ -66,38 +193,6 @@ impl SecurityMonitor {
 #[contract]
 pub struct Contract;
 
-/// Represents a user's position in the protocol
-#[derive(Clone, Debug, Eq, PartialEq)]
-#[contracttype]
-pub struct Position {
-    /// The address of the user
-    pub user: Address,
-    /// The amount of collateral deposited
-    pub collateral: i128,
-    /// The amount borrowed
-    pub debt: i128,
-    /// Accrued borrow interest (scaled by 1e8)
-    pub borrow_interest: i128,
-    /// Accrued supply interest (scaled by 1e8)
-    pub supply_interest: i128,
-    /// Last time interest was accrued for this position
-    pub last_accrual_time: u64,
-}
-
-impl Position {
-    /// Create a new position
-    pub fn new(user: Address, collateral: i128, debt: i128) -> Self {
-        Self {
-            user,
-            collateral,
-            debt,
-            borrow_interest: 0,
-            supply_interest: 0,
-            last_accrual_time: 0,
-        }
-    }
-}
-
 /// Interest rate configuration parameters
 #[derive(Clone, Debug, Eq, PartialEq)]
 #[contracttype]
@@ -114,13 +209,17 @@ pub struct InterestRateConfig {
     pub rate_ceiling: i128,
     /// Minimum allowed rate (scaled by 1e8, e.g., 0.1% = 100000)
     pub rate_floor: i128,
+    /// Premium over the variable rate charged for a stable rate (scaled by 1e8)
+    pub stable_rate_premium: i128,
+    /// Utilization at or above which stable rates can be rebalanced up (scaled by 1e8)
+    pub stable_rebalance_threshold: i128,
     /// Last time config was updated
     pub last_update: u64,
 }
 
-impl InterestRateConfig {
+impl Default for InterestRateConfig {
     /// Create default interest rate configuration
-    pub fn default() -> Self {
+    fn default() -> Self {
         Self {
             base_rate: 2000000,         // 2%
             kink_utilization: 80000000, // 80%
@@ -128,11 +227,44 @@ impl InterestRateConfig {
             reserve_factor: 10000000,   // 10%
             rate_ceiling: 50000000,     // 50%
             rate_floor: 100000,         // 0.1%
+            stable_rate_premium: 2000000, // 2%
+            stable_rebalance_threshold: 95000000, // 95%
             last_update: 0,
         }
     }
 }
 
+/// Interest rate configuration as returned by `get_interest_rate_config`: base rate, kink
+/// utilization, multiplier, reserve factor, rate floor, rate ceiling and last update
+pub type InterestRateConfigView = (i128, i128, i128, i128, i128, i128, u64);
+
+/// Fixed-point scale of the borrow and supply indexes (1e18, finer than the 1e8 rate
+/// scale so per-second accruals don't round away)
+pub const INDEX_SCALE: i128 = 1_000_000_000_000_000_000;
+
+/// Health factor of an account exactly at its borrow limit (1.0 at the 1e8 rate scale);
+/// accounts below it can't borrow or withdraw and may be liquidated
+pub const HEALTH_FACTOR_ONE: i128 = 100_000_000;
+
+/// Basis points in one whole (100%)
+pub const BPS_ONE: i128 = 10_000;
+
+/// Default gap between an asset's LTV and its liquidation threshold (basis points)
+pub const DEFAULT_LIQUIDATION_BUFFER: i128 = 500;
+
+/// How far a locked stable rate must sit above the rate offered to new stable borrows
+/// before it can be rebalanced down (scaled by 1e8)
+pub const STABLE_REBALANCE_DOWN_MARGIN: i128 = 2_000_000;
+
+/// Most borrow-swap-deposit (or withdraw-swap-repay) rounds one leverage call runs
+pub const MAX_LEVERAGE_STEPS: u32 = 10;
+
+/// Sub-account holding the positions of the single-account entrypoints
+pub const MAIN_ACCOUNT: u32 = 0;
+
+/// Highest sub-account number a user can open
+pub const MAX_SUB_ACCOUNTS: u32 = 16;
+
 /// Current interest rate state
 #[derive(Clone, Debug, Eq, PartialEq)]
 #[contracttype]
@@ -143,10 +275,22 @@ pub struct InterestRateState {
     pub current_supply_rate: i128,
     /// Current utilization rate (scaled by 1e8)
     pub utilization_rate: i128,
-    /// Total borrowed amount
+    /// Total borrowed amount, including capitalized interest
     pub total_borrowed: i128,
-    /// Total supplied amount
+    /// Part of `total_borrowed` carried at stable rates
+    pub total_stable_debt: i128,
+    /// Average stable rate weighted by stable debt (scaled by 1e8)
+    pub average_stable_rate: i128,
+    /// Total lender liquidity, including supply interest earned
     pub total_supplied: i128,
+    /// Total borrower collateral held; it is not lendable and earns no interest
+    pub total_collateral: i128,
+    /// Reserve-factor share of borrow interest retained by the pool
+    pub total_reserves: i128,
+    /// Cumulative borrow index (scaled by INDEX_SCALE)
+    pub borrow_index: i128,
+    /// Cumulative supply index (scaled by INDEX_SCALE); the sToken exchange rate
+    pub supply_index: i128,
     /// Last time interest was accrued
     pub last_accrual_time: u64,
 }
@@ -159,7 +303,13 @@ impl InterestRateState {
             current_supply_rate: 0,
             utilization_rate: 0,
             total_borrowed: 0,
+            total_stable_debt: 0,
+            average_stable_rate: 0,
             total_supplied: 0,
+            total_collateral: 0,
+            total_reserves: 0,
+            borrow_index: INDEX_SCALE,
+            supply_index: INDEX_SCALE,
             last_accrual_time: 0,
         }
     }
@@ -173,6 +323,8 @@ pub struct RiskConfig {
     pub close_factor: i128,
     /// % bonus collateral given to liquidators (scaled by 1e8)
     pub liquidation_incentive: i128,
+    /// Share of the liquidation bonus kept by the protocol as reserves (scaled by 1e8)
+    pub liquidation_protocol_fee: i128,
     /// Pause switches for protocol actions
     pub pause_borrow: bool,
     pub pause_deposit: bool,
@@ -182,11 +334,12 @@ pub struct RiskConfig {
     pub last_update: u64,
 }
 
-impl RiskConfig {
-    pub fn default() -> Self {
+impl Default for RiskConfig {
+    fn default() -> Self {
         Self {
             close_factor: 50000000,          // 50%
             liquidation_incentive: 10000000, // 10%
+            liquidation_protocol_fee: 10000000, // 10% of the bonus
             pause_borrow: false,
             pause_deposit: false,
             pause_withdraw: false,
@@ -200,8 +353,8 @@ impl RiskConfig {
 pub struct RiskConfigStorage;
 
 impl RiskConfigStorage {
-    fn key() -> Symbol {
-        Symbol::short("risk_cfg")
+    fn key() -> DataKey {
+        DataKey::RiskConfig
     }
     pub fn save(env: &Env, config: &RiskConfig) {
         env.storage().instance().set(&Self::key(), config);
@@ -210,7 +363,294 @@ impl RiskConfigStorage {
         env.storage()
             .instance()
             .get(&Self::key())
-            .unwrap_or_else(RiskConfig::default)
+            .unwrap_or_default()
+    }
+    /// Risk config in effect for an asset: the asset's own parameters and the protocol's
+    /// liquidation fee, paused if either the protocol or the asset is paused
+    pub fn for_asset(env: &Env, info: &AssetInfo) -> RiskConfig {
+        let protocol = Self::get(env);
+        let asset = &info.risk_config;
+        RiskConfig {
+            close_factor: asset.close_factor,
+            liquidation_incentive: asset.liquidation_incentive,
+            liquidation_protocol_fee: protocol.liquidation_protocol_fee,
+            pause_borrow: protocol.pause_borrow || asset.pause_borrow,
+            pause_deposit: protocol.pause_deposit || asset.pause_deposit,
+            pause_withdraw: protocol.pause_withdraw || asset.pause_withdraw,
+            pause_liquidate: protocol.pause_liquidate || asset.pause_liquidate,
+            last_update: asset.last_update,
+        }
+    }
+}
+
+/// Efficiency-mode category of correlated assets (e.g. stablecoins) whose parameters
+/// replace the per-asset ones while all of a user's collateral and debt sit in it
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct EModeCategory {
+    pub id: u32,
+    pub label: String,
+    /// Share of collateral value that can be borrowed (basis points)
+    pub ltv: i128,
+    /// Share of collateral value at which the account becomes liquidatable (basis points)
+    pub liquidation_threshold: i128,
+    /// Bonus collateral given to liquidators (basis points)
+    pub liquidation_bonus: i128,
+}
+
+/// Storage helper for efficiency-mode categories and user opt-ins
+pub struct EModeStorage;
+
+impl EModeStorage {
+    pub fn get_category(env: &Env, id: u32) -> Option<EModeCategory> {
+        if id == 0 {
+            return None;
+        }
+        env.storage().instance().get(&DataKey::EModeCategory(id))
+    }
+    pub fn save_category(env: &Env, category: &EModeCategory) {
+        env.storage()
+            .instance()
+            .set(&DataKey::EModeCategory(category.id), category);
+    }
+    /// Category the user opted into (0 = none)
+    pub fn get_user_category(env: &Env, user: &Address) -> u32 {
+        UserStorage::get(env, &DataKey::UserEMode(user.clone())).unwrap_or(0)
+    }
+    pub fn set_user_category(env: &Env, user: &Address, id: u32) {
+        let key = DataKey::UserEMode(user.clone());
+        if id == 0 {
+            UserStorage::remove(env, &key);
+        } else {
+            UserStorage::set(env, &key, &id);
+        }
+    }
+}
+
+/// Default seconds after maturity before an unpaid fixed-term loan defaults
+const DEFAULT_LOAN_GRACE_PERIOD: u64 = 3 * 24 * 60 * 60;
+
+/// Lifecycle of a fixed-term loan
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum LoanStatus {
+    Active,
+    /// Past maturity but still within the grace period
+    Matured,
+    /// Unpaid after the grace period; liquidatable regardless of account health
+    Defaulted,
+    Repaid,
+    /// Written off as bad debt after the borrower's collateral ran out
+    WrittenOff,
+}
+
+/// Loan with a fixed rate and maturity agreed at origination, held alongside the
+/// borrower's open-ended positions and backed by the same collateral
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct FixedTermLoan {
+    pub id: u32,
+    pub borrower: Address,
+    pub asset: String,
+    pub principal: i128,
+    /// Annual rate fixed for the life of the loan (scaled by 1e8)
+    pub rate: i128,
+    pub originated_at: u64,
+    pub maturity: u64,
+    /// Amount owed as of `last_accrual_time`, including interest
+    pub debt: i128,
+    pub last_accrual_time: u64,
+    pub status: LoanStatus,
+}
+
+impl FixedTermLoan {
+    /// Whether the loan still carries debt
+    pub fn is_open(&self) -> bool {
+        matches!(
+            self.status,
+            LoanStatus::Active | LoanStatus::Matured | LoanStatus::Defaulted
+        )
+    }
+
+    /// Amount owed at `now`, including interest since the last accrual
+    pub fn current_debt(&self, now: u64) -> i128 {
+        self.debt
+            + InterestRateManager::calculate_interest(
+                self.debt,
+                self.rate,
+                now.saturating_sub(self.last_accrual_time),
+            )
+    }
+
+    /// Capitalize interest up to `now`
+    pub fn accrue(&mut self, now: u64) {
+        self.debt = self.current_debt(now);
+        self.last_accrual_time = now.max(self.last_accrual_time);
+    }
+
+    /// Status the loan has at `now` given the grace period, without changing closed loans
+    pub fn status_at(&self, now: u64, grace_period: u64) -> LoanStatus {
+        if !self.is_open() {
+            self.status
+        } else if now > self.maturity.saturating_add(grace_period) {
+            LoanStatus::Defaulted
+        } else if now >= self.maturity {
+            LoanStatus::Matured
+        } else {
+            LoanStatus::Active
+        }
+    }
+}
+
+/// Storage helper for fixed-term loans
+pub struct LoanStorage;
+
+impl LoanStorage {
+    pub fn get_loan(env: &Env, id: u32) -> Option<FixedTermLoan> {
+        UserStorage::get(env, &DataKey::Loan(id))
+    }
+    pub fn save_loan(env: &Env, loan: &FixedTermLoan) {
+        UserStorage::set(env, &DataKey::Loan(loan.id), loan);
+        UserStorage::bump_instance(env);
+    }
+    /// Allocate the next loan id
+    pub fn next_id(env: &Env) -> u32 {
+        let id = env.storage().instance().get(&DataKey::NextLoanId).unwrap_or(1u32);
+        env.storage().instance().set(&DataKey::NextLoanId, &(id + 1));
+        id
+    }
+    pub fn get_user_loan_ids(env: &Env, user: &Address) -> Vec<u32> {
+        UserStorage::get(env, &DataKey::UserLoans(user.clone())).unwrap_or(Vec::new(env))
+    }
+    pub fn add_user_loan(env: &Env, user: &Address, id: u32) {
+        let mut ids = Self::get_user_loan_ids(env, user);
+        ids.push_back(id);
+        UserStorage::set(env, &DataKey::UserLoans(user.clone()), &ids);
+    }
+    /// Loans of a user that still carry debt
+    pub fn open_loans(env: &Env, user: &Address) -> Vec<FixedTermLoan> {
+        let mut loans = Vec::new(env);
+        for id in Self::get_user_loan_ids(env, user).iter() {
+            if let Some(loan) = Self::get_loan(env, id) {
+                if loan.is_open() {
+                    loans.push_back(loan);
+                }
+            }
+        }
+        loans
+    }
+    pub fn get_grace_period(env: &Env) -> u64 {
+        env.storage()
+            .instance()
+            .get(&DataKey::LoanGracePeriod)
+            .unwrap_or(DEFAULT_LOAN_GRACE_PERIOD)
+    }
+    pub fn set_grace_period(env: &Env, seconds: u64) {
+        env.storage().instance().set(&DataKey::LoanGracePeriod, &seconds);
+    }
+
+    /// A loan with interest accrued and status advanced to the current ledger time, without writing state
+    pub fn projected_loan(env: &Env, id: u32) -> Option<FixedTermLoan> {
+        let mut loan = Self::get_loan(env, id)?;
+        let now = env.ledger().timestamp();
+        loan.status = loan.status_at(now, Self::get_grace_period(env));
+        if loan.is_open() {
+            loan.accrue(now);
+        }
+        Some(loan)
+    }
+
+    /// Move the loan to the status it has at the current ledger time, emitting
+    /// `LoanMatured` and `LoanDefaulted` as it passes maturity and the grace period
+    pub fn sync_status(env: &Env, loan: &mut FixedTermLoan) {
+        let status = loan.status_at(env.ledger().timestamp(), Self::get_grace_period(env));
+        if status == loan.status {
+            return;
+        }
+        if loan.status == LoanStatus::Active {
+            ProtocolEvent::LoanMatured {
+                loan_id: loan.id,
+                borrower: loan.borrower.clone(),
+                amount_due: loan.current_debt(env.ledger().timestamp()),
+            }
+            .emit(env);
+        }
+        if status == LoanStatus::Defaulted {
+            ProtocolEvent::LoanDefaulted {
+                loan_id: loan.id,
+                borrower: loan.borrower.clone(),
+                amount_due: loan.current_debt(env.ledger().timestamp()),
+            }
+            .emit(env);
+        }
+        loan.status = status;
+    }
+}
+
+/// Storage helper for the numbered sub-accounts a user has opened besides their main account
+pub struct SubAccounts;
+
+impl SubAccounts {
+    pub fn list(env: &Env, user: &Address) -> Vec<u32> {
+        UserStorage::get(env, &DataKey::SubAccounts(user.clone())).unwrap_or(Vec::new(env))
+    }
+    /// Record a sub-account as opened the first time a position is saved in it
+    pub fn open(env: &Env, user: &Address, sub_account: u32) {
+        if sub_account == MAIN_ACCOUNT {
+            return;
+        }
+        let mut list = Self::list(env, user);
+        if !list.contains(sub_account) {
+            list.push_back(sub_account);
+            UserStorage::set(env, &DataKey::SubAccounts(user.clone()), &list);
+        }
+    }
+    pub fn validate(sub_account: u32) -> Result<(), ProtocolError> {
+        if sub_account > MAX_SUB_ACCOUNTS {
+            return Err(ProtocolError::InvalidInput);
+        }
+        Ok(())
+    }
+}
+
+/// Credit delegation: allowances that let a delegatee borrow against a delegator's collateral
+pub struct CreditDelegation;
+
+impl CreditDelegation {
+    fn key(delegator: &Address, delegatee: &Address, asset: &String) -> DataKey {
+        DataKey::BorrowAllowance(delegator.clone(), delegatee.clone(), asset.clone())
+    }
+    pub fn get_allowance(env: &Env, delegator: &Address, delegatee: &Address, asset: &String) -> i128 {
+        UserStorage::get(env, &Self::key(delegator, delegatee, asset)).unwrap_or(0)
+    }
+    pub fn set_allowance(
+        env: &Env,
+        delegator: &Address,
+        delegatee: &Address,
+        asset: &String,
+        amount: i128,
+    ) {
+        let key = Self::key(delegator, delegatee, asset);
+        if amount == 0 {
+            UserStorage::remove(env, &key);
+        } else {
+            UserStorage::set(env, &key, &amount);
+        }
+    }
+    /// Draw `amount` from a delegatee's allowance, failing if it does not cover it
+    pub fn spend_allowance(
+        env: &Env,
+        delegator: &Address,
+        delegatee: &Address,
+        asset: &String,
+        amount: i128,
+    ) -> Result<(), ProtocolError> {
+        let allowance = Self::get_allowance(env, delegator, delegatee, asset);
+        if allowance < amount {
+            return Err(ProtocolError::Unauthorized);
+        }
+        Self::set_allowance(env, delegator, delegatee, asset, allowance - amount);
+        Ok(())
     }
 }
 
@@ -233,13 +673,13 @@ pub struct ReserveData {
 }
 
 impl ReserveData {
-    pub fn default() -> Self {
+    pub fn default(env: &Env) -> Self {
         Self {
             total_fees_collected: 0,
             total_fees_distributed: 0,
             current_reserves: 0,
             treasury_address: Address::from_string(&String::from_str(
-                &Env::default(),
+                env,
                 "GCXOTMMXRS24MYZI5FJPUCOEOFNWSR4XX7UXIK3NDGGE6A5QMJ5FF2FS",
             )), // Placeholder
             last_distribution_time: 0,
@@ -249,7 +689,7 @@ impl ReserveData {
 }
 
 /// Revenue metrics for analytics
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
 #[contracttype]
 pub struct RevenueMetrics {
     /// Daily fees collected
@@ -262,6 +702,8 @@ pub struct RevenueMetrics {
     pub total_borrow_fees: i128,
     /// Total supply fees collected
     pub total_supply_fees: i128,
+    /// Total flash loan fees collected
+    pub total_flash_loan_fees: i128,
 }
 
 /// User activity tracking metrics
@@ -298,15 +740,9 @@ pub struct ProtocolActivity {
     pub last_update: u64,
 }
 
-impl RevenueMetrics {
-    pub fn default() -> Self {
-        Self {
-            daily_fees: 0,
-            weekly_fees: 0,
-            monthly_fees: 0,
-            total_borrow_fees: 0,
-            total_supply_fees: 0,
-        }
+impl Default for UserActivity {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -347,6 +783,12 @@ impl UserActivity {
     }
 }
 
+impl Default for ProtocolActivity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ProtocolActivity {
     pub fn new() -> Self {
         Self {
@@ -378,11 +820,11 @@ impl ProtocolActivity {
 pub struct ReserveStorage;
 
 impl ReserveStorage {
-    fn reserve_key() -> Symbol {
-        Symbol::short("reserve")
+    fn reserve_key() -> DataKey {
+        DataKey::ReserveData
     }
-    fn metrics_key() -> Symbol {
-        Symbol::short("metrics")
+    fn metrics_key() -> DataKey {
+        DataKey::RevenueMetrics
     }
 
     pub fn save_reserve_data(env: &Env, data: &ReserveData) {
@@ -393,7 +835,7 @@ impl ReserveStorage {
         env.storage()
             .instance()
             .get(&Self::reserve_key())
-            .unwrap_or_else(ReserveData::default)
+            .unwrap_or_else(|| ReserveData::default(env))
     }
 
     pub fn save_revenue_metrics(env: &Env, metrics: &RevenueMetrics) {
@@ -404,7 +846,70 @@ impl ReserveStorage {
         env.storage()
             .instance()
             .get(&Self::metrics_key())
-            .unwrap_or_else(RevenueMetrics::default)
+            .unwrap_or_default()
+    }
+
+    pub fn save_flash_loan_fee(env: &Env, fee: i128) {
+        env.storage().instance().set(&DataKey::FlashLoanFee, &fee);
+    }
+
+    /// Flash loan fee as a share of the principal (scaled by 1e8, default 0.09%)
+    pub fn get_flash_loan_fee(env: &Env) -> i128 {
+        env.storage()
+            .instance()
+            .get(&DataKey::FlashLoanFee)
+            .unwrap_or(90_000)
+    }
+
+    /// Fee owed for flash-borrowing `amount`
+    pub fn flash_loan_fee_for(env: &Env, amount: i128) -> i128 {
+        amount * Self::get_flash_loan_fee(env) / 100_000_000
+    }
+
+    /// Book a repaid flash loan fee as reserves of the asset
+    pub fn collect_flash_loan_fee(env: &Env, asset: &String, fee: i128) {
+        let mut ir_state = InterestRateStorage::get_asset_state(env, asset);
+        ir_state.total_reserves += fee;
+        InterestRateStorage::save_asset_state(env, asset, &ir_state);
+        let mut reserve_data = Self::get_reserve_data(env);
+        reserve_data.total_fees_collected += fee;
+        reserve_data.current_reserves += fee;
+        Self::save_reserve_data(env, &reserve_data);
+        let mut metrics = Self::get_revenue_metrics(env);
+        metrics.total_flash_loan_fees += fee;
+        Self::save_revenue_metrics(env, &metrics);
+        ProtocolEvent::FeesCollected {
+            amount: fee,
+            source: String::from_str(env, "flash_loan"),
+        }
+        .emit(env);
+    }
+
+    /// Collect the reserve factor's share of a borrower's accrued interest as protocol fees
+    pub fn collect_borrow_fee(env: &Env, asset: &String, borrow_interest: i128) {
+        if borrow_interest <= 0 {
+            return;
+        }
+        let config = InterestRateStorage::get_asset_config(env, asset);
+        let (borrow_fee, _) =
+            InterestRateManager::collect_fees_from_interest(env, borrow_interest, 0, config.reserve_factor);
+        if borrow_fee > 0 {
+            let mut reserve_data = Self::get_reserve_data(env);
+            reserve_data.total_fees_collected += borrow_fee;
+            reserve_data.current_reserves += borrow_fee;
+            Self::save_reserve_data(env, &reserve_data);
+
+            // Update revenue metrics
+            let mut metrics = Self::get_revenue_metrics(env);
+            metrics.total_borrow_fees += borrow_fee;
+            Self::save_revenue_metrics(env, &metrics);
+
+            ProtocolEvent::FeesCollected {
+                amount: borrow_fee,
+                source: String::from_str(env, "borrow"),
+            }
+            .emit(env);
+        }
     }
 }
 
@@ -412,27 +917,20 @@ impl ReserveStorage {
 pub struct ActivityStorage;
 
 impl ActivityStorage {
-    fn user_activity_key(env: &Env, user: &Address) -> Symbol {
-        // Use a simple approach: create a unique key based on user address
-        let user_str = user.to_string();
-        // Use a fixed key for simplicity - in production you'd want a more sophisticated approach
-        Symbol::new(env, "user_activity")
+    fn user_activity_key(user: &Address) -> DataKey {
+        DataKey::UserActivity(user.clone())
     }
 
-    fn protocol_activity_key() -> Symbol {
-        Symbol::short("protocol_activity")
+    fn protocol_activity_key() -> DataKey {
+        DataKey::ProtocolActivity
     }
 
     pub fn save_user_activity(env: &Env, user: &Address, activity: &UserActivity) {
-        env.storage()
-            .instance()
-            .set(&Self::user_activity_key(env, user), activity);
+        UserStorage::set(env, &Self::user_activity_key(user), activity);
     }
 
     pub fn get_user_activity(env: &Env, user: &Address) -> Option<UserActivity> {
-        env.storage()
-            .instance()
-            .get(&Self::user_activity_key(env, user))
+        UserStorage::get(env, &Self::user_activity_key(user))
     }
 
     pub fn save_protocol_activity(env: &Env, activity: &ProtocolActivity) {
@@ -445,7 +943,7 @@ impl ActivityStorage {
         env.storage()
             .instance()
             .get(&Self::protocol_activity_key())
-            .unwrap_or_else(ProtocolActivity::new)
+            .unwrap_or_default()
     }
 }
 
@@ -461,8 +959,17 @@ pub struct AssetInfo {
     pub decimals: u32,
     /// Oracle address for this asset
     pub oracle_address: Address,
-    /// Minimum collateral ratio for this asset (scaled by 100)
+    /// SEP-41 token contract that holds this asset's balances
+    pub token_address: Option<Address>,
+    /// sToken contract minting lender shares for this asset
+    pub stoken_address: Option<Address>,
+    /// Minimum collateral ratio the asset was listed with (scaled by 100); its LTV and
+    /// liquidation threshold are derived from it until set directly
     pub min_collateral_ratio: i128,
+    /// Share of collateral value that can be borrowed against (basis points)
+    pub ltv: i128,
+    /// Share of collateral value at which the account becomes liquidatable (basis points)
+    pub liquidation_threshold: i128,
     /// Asset-specific risk configuration
     pub risk_config: RiskConfig,
     /// Asset-specific interest rate configuration
@@ -473,6 +980,21 @@ pub struct AssetInfo {
     pub deposit_enabled: bool,
     /// Whether this asset is enabled for borrowing
     pub borrow_enabled: bool,
+    /// Max collateral plus lender supply held in this asset (0 = uncapped)
+    pub supply_cap: i128,
+    /// Max total debt in this asset (0 = uncapped)
+    pub borrow_cap: i128,
+    /// Isolation mode: users holding this collateral may only borrow assets that are
+    /// borrowable in isolation, up to `debt_ceiling`
+    pub isolated: bool,
+    /// Max debt value backed by this asset in isolation (quote currency, scaled by 1e8)
+    pub debt_ceiling: i128,
+    /// Debt value currently backed by this asset in isolation (quote currency, scaled by 1e8)
+    pub isolation_debt: i128,
+    /// Whether users in isolation mode may borrow this asset
+    pub borrowable_in_isolation: bool,
+    /// Efficiency-mode category the asset belongs to (0 = none)
+    pub emode_category: u32,
     /// Last time asset config was updated
     pub last_update: u64,
 }
@@ -482,20 +1004,62 @@ impl AssetInfo {
         symbol: String,
         decimals: u32,
         oracle_address: Address,
+        token_address: Option<Address>,
         min_collateral_ratio: i128,
     ) -> Self {
         Self {
             symbol,
             decimals,
             oracle_address,
+            token_address,
+            stoken_address: None,
             min_collateral_ratio,
+            ltv: 0,
+            liquidation_threshold: 0,
             risk_config: RiskConfig::default(),
             interest_config: InterestRateConfig::default(),
             interest_state: InterestRateState::initial(),
             deposit_enabled: true,
             borrow_enabled: true,
+            supply_cap: 0,
+            borrow_cap: 0,
+            isolated: false,
+            debt_ceiling: 0,
+            isolation_debt: 0,
+            borrowable_in_isolation: false,
+            emode_category: 0,
             last_update: 0,
         }
+        .with_collateral_ratio(min_collateral_ratio)
+    }
+
+    /// Derive the LTV from a minimum collateral ratio (scaled by 100), with the
+    /// liquidation threshold a default buffer above it
+    pub fn with_collateral_ratio(mut self, min_collateral_ratio: i128) -> Self {
+        self.min_collateral_ratio = min_collateral_ratio;
+        self.ltv = if min_collateral_ratio > 0 {
+            (100 * BPS_ONE / min_collateral_ratio).min(BPS_ONE - 1)
+        } else {
+            0
+        };
+        self.liquidation_threshold = (self.ltv + DEFAULT_LIQUIDATION_BUFFER).min(BPS_ONE - 1);
+        self
+    }
+
+    /// Fail if depositing `amount` more would take collateral plus lender supply over the supply cap
+    pub fn check_supply_cap(&self, state: &InterestRateState, amount: i128) -> Result<(), ProtocolError> {
+        if self.supply_cap > 0 && state.total_collateral + state.total_supplied + amount > self.supply_cap {
+            return Err(ProtocolError::SupplyCapExceeded);
+        }
+        Ok(())
+    }
+
+    /// Fail if borrowing `amount` more would take total debt over the borrow cap
+    pub fn check_borrow_cap(&self, state: &InterestRateState, amount: i128) -> Result<(), ProtocolError> {
+        if self.borrow_cap > 0 && state.total_borrowed + amount > self.borrow_cap {
+            return Err(ProtocolError::BorrowCapExceeded);
+        }
+        Ok(())
     }
 }
 
@@ -505,32 +1069,56 @@ impl AssetInfo {
 pub struct AssetPosition {
     /// The user address
     pub user: Address,
+    /// Sub-account of the user holding the position (`MAIN_ACCOUNT` for the main account)
+    pub sub_account: u32,
     /// The asset symbol
     pub asset: String,
-    /// Amount of collateral deposited for this asset
+    /// Amount of collateral deposited for this asset; it backs borrowing and is never lent out
     pub collateral: i128,
-    /// Amount borrowed for this asset
-    pub debt: i128,
-    /// Accrued borrow interest for this asset (scaled by 1e8)
+    /// Debt in borrow-index units; current debt is `scaled_debt * borrow_index / INDEX_SCALE`
+    pub scaled_debt: i128,
+    /// Debt carried at `stable_rate`, including interest up to `last_accrual_time`
+    pub stable_debt: i128,
+    /// Stable rate locked in at borrow time (scaled by 1e8)
+    pub stable_rate: i128,
+    /// Borrow index at the position's last accrual
+    pub borrow_index: i128,
+    /// Lender liquidity in supply-index units, mirrored by the user's sToken balance;
+    /// it redeems for `supply_shares * supply_index / INDEX_SCALE`
+    pub supply_shares: i128,
+    /// Supply index at the position's last accrual
+    pub supply_index: i128,
+    /// Borrow interest capitalized into the debt so far
     pub borrow_interest: i128,
-    /// Accrued supply interest for this asset (scaled by 1e8)
+    /// Supply interest earned through the supply index so far
     pub supply_interest: i128,
     /// Last time interest was accrued for this position
     pub last_accrual_time: u64,
 }
 
 impl AssetPosition {
-    pub fn new(user: Address, asset: String, collateral: i128, debt: i128) -> Self {
+    pub fn new(user: Address, asset: String, collateral: i128, scaled_debt: i128) -> Self {
         Self {
             user,
+            sub_account: MAIN_ACCOUNT,
             asset,
             collateral,
-            debt,
+            scaled_debt,
+            stable_debt: 0,
+            stable_rate: 0,
+            borrow_index: INDEX_SCALE,
+            supply_shares: 0,
+            supply_index: INDEX_SCALE,
             borrow_interest: 0,
             supply_interest: 0,
             last_accrual_time: 0,
         }
     }
+
+    /// Whether the position holds collateral, debt or supply
+    pub fn is_open(&self) -> bool {
+        self.collateral > 0 || self.scaled_debt > 0 || self.stable_debt > 0 || self.supply_shares > 0
+    }
 }
 
 /// Asset registry for managing all supported assets
@@ -546,8 +1134,8 @@ pub struct AssetRegistry {
 }
 
 impl AssetRegistry {
-    pub fn new(default_asset: String) -> Self {
-        let mut assets = Vec::new(&Env::default());
+    pub fn new(env: &Env, default_asset: String) -> Self {
+        let mut assets = Vec::new(env);
         assets.push_back(default_asset.clone());
         Self {
             supported_assets: assets,
@@ -561,29 +1149,19 @@ impl AssetRegistry {
 pub struct AssetStorage;
 
 impl AssetStorage {
-    fn registry_key() -> Symbol {
-        Symbol::short("asset_reg")
-    }
-    fn asset_info_key(asset: &String) -> Symbol {
-        if asset == &String::from_str(&Env::default(), "XLM") {
-            Symbol::short("asset_xlm")
-        } else if asset == &String::from_str(&Env::default(), "USDC") {
-            Symbol::short("asset_usdc")
-        } else if asset == &String::from_str(&Env::default(), "BTC") {
-            Symbol::short("asset_btc")
-        } else if asset == &String::from_str(&Env::default(), "ETH") {
-            Symbol::short("asset_eth")
-        } else {
-            Symbol::short("asset_def")
-        }
+    fn registry_key() -> DataKey {
+        DataKey::AssetRegistry
+    }
+    fn asset_info_key(asset: &String) -> DataKey {
+        DataKey::AssetInfo(asset.clone())
     }
-    fn position_key(user: &Address, asset: &str) -> Symbol {
-        match asset {
-            "XLM" => Symbol::short("pos_xlm"),
-            "USDC" => Symbol::short("pos_usdc"),
-            "BTC" => Symbol::short("pos_btc"),
-            "ETH" => Symbol::short("pos_eth"),
-            _ => Symbol::short("pos_def"),
+    /// The main account keeps the key positions had before sub-accounts; positions
+    /// stored in that older layout are rewritten by `migrate_storage`
+    fn position_key(user: &Address, sub_account: u32, asset: &String) -> DataKey {
+        if sub_account == MAIN_ACCOUNT {
+            DataKey::Position(user.clone(), asset.clone())
+        } else {
+            DataKey::SubPosition(user.clone(), sub_account, asset.clone())
         }
     }
 
@@ -597,7 +1175,7 @@ impl AssetStorage {
         env.storage()
             .instance()
             .get(&Self::registry_key())
-            .unwrap_or_else(|| AssetRegistry::new(String::from_str(env, "XLM")))
+            .unwrap_or_else(|| AssetRegistry::new(env, String::from_str(env, "XLM")))
     }
 
     pub fn save_asset_info(env: &Env, asset: &String, info: &AssetInfo) {
@@ -610,19 +1188,142 @@ impl AssetStorage {
         env.storage().instance().get(&key)
     }
 
-    pub fn save_asset_position(env: &Env, user: &Address, asset: &str, position: &AssetPosition) {
-        let key = (Self::position_key(user, asset), user.clone());
-        env.storage().instance().set(&key, position);
+    /// Get a listed asset's info, failing if the asset is not supported
+    pub fn require_asset(env: &Env, asset: &String) -> Result<AssetInfo, ProtocolError> {
+        Self::get_asset_info(env, asset).ok_or(ProtocolError::AssetNotSupported)
     }
 
-    pub fn get_asset_position(env: &Env, user: &Address, asset: &str) -> Option<AssetPosition> {
-        let key = (Self::position_key(user, asset), user.clone());
-        env.storage().instance().get(&key)
+    pub fn save_asset_position(env: &Env, user: &Address, asset: &String, position: &AssetPosition) {
+        let key = Self::position_key(user, position.sub_account, asset);
+        let was_open = UserStorage::get::<AssetPosition>(env, &key).is_some_and(|p| p.is_open());
+        UserStorage::set(env, &key, position);
+        Self::track_open_position(env, user, asset, was_open, position.is_open());
+    }
+
+    pub fn get_asset_position(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        asset: &String,
+    ) -> Option<AssetPosition> {
+        let key = Self::position_key(user, sub_account, asset);
+        UserStorage::get(env, &key)
+    }
+
+    pub fn remove_asset_position(env: &Env, user: &Address, sub_account: u32, asset: &String) {
+        let key = Self::position_key(user, sub_account, asset);
+        let was_open = UserStorage::get::<AssetPosition>(env, &key).is_some_and(|p| p.is_open());
+        UserStorage::remove(env, &key);
+        Self::track_open_position(env, user, asset, was_open, false);
     }
 
-    pub fn remove_asset_position(env: &Env, user: &Address, asset: &str) {
-        let key = (Self::position_key(user, asset), user.clone());
-        env.storage().instance().remove(&key);
+    /// Keep the open position counts of an asset and of the user, and the number of
+    /// users with any open position, in step with a position write
+    fn track_open_position(env: &Env, user: &Address, asset: &String, was_open: bool, is_open: bool) {
+        if was_open == is_open {
+            return;
+        }
+        let step = |count: u32| if is_open { count + 1 } else { count.saturating_sub(1) };
+        env.storage()
+            .instance()
+            .set(&DataKey::OpenPositions(asset.clone()), &step(Self::open_positions(env, asset)));
+
+        let key = DataKey::UserOpenPositions(user.clone());
+        let held = UserStorage::get::<u32>(env, &key).unwrap_or(0);
+        let now_held = step(held);
+        if now_held == 0 {
+            UserStorage::remove(env, &key);
+        } else {
+            UserStorage::set(env, &key, &now_held);
+        }
+        if (held == 0) != (now_held == 0) {
+            let users = if now_held > 0 {
+                Self::active_users(env) + 1
+            } else {
+                Self::active_users(env).saturating_sub(1)
+            };
+            env.storage().instance().set(&DataKey::ActiveUsers, &users);
+        }
+    }
+
+    /// Number of positions in an asset holding collateral, debt or supply; a user has
+    /// one per sub-account
+    pub fn open_positions(env: &Env, asset: &String) -> u32 {
+        env.storage()
+            .instance()
+            .get(&DataKey::OpenPositions(asset.clone()))
+            .unwrap_or(0)
+    }
+
+    /// Number of distinct users holding an open position in any asset
+    pub fn active_users(env: &Env) -> u32 {
+        env.storage()
+            .instance()
+            .get(&DataKey::ActiveUsers)
+            .unwrap_or(0)
+    }
+
+    /// Get the token contract backing an asset
+    pub fn get_token_address(env: &Env, asset: &String) -> Result<Address, ProtocolError> {
+        let info = Self::get_asset_info(env, asset).ok_or(ProtocolError::AssetNotSupported)?;
+        info.token_address.ok_or(ProtocolError::ConfigurationError)
+    }
+
+    /// Get the sToken contract minting lender shares for an asset
+    pub fn get_stoken_address(env: &Env, asset: &String) -> Result<Address, ProtocolError> {
+        let info = Self::get_asset_info(env, asset).ok_or(ProtocolError::AssetNotSupported)?;
+        info.stoken_address.ok_or(ProtocolError::ConfigurationError)
+    }
+
+    /// Register a new asset and add it to the registry (callers check authorization)
+    pub fn list_asset(
+        env: &Env,
+        symbol: String,
+        decimals: u32,
+        oracle_address: Address,
+        token_address: Address,
+        min_collateral_ratio: i128,
+    ) -> Result<(), ProtocolError> {
+        if symbol.is_empty() {
+            return Err(ProtocolError::InvalidAsset);
+        }
+
+        if decimals == 0 {
+            return Err(ProtocolError::InvalidAmount);
+        }
+
+        // Check if asset already exists
+        if Self::get_asset_info(env, &symbol).is_some() {
+            return Err(ProtocolError::AlreadyInitialized);
+        }
+
+        // Create new asset info, starting from the protocol's default risk parameters
+        let mut asset_info = AssetInfo::new(
+            symbol.clone(),
+            decimals,
+            oracle_address,
+            Some(token_address),
+            min_collateral_ratio,
+        );
+        let defaults = RiskConfigStorage::get(env);
+        asset_info.risk_config.close_factor = defaults.close_factor;
+        asset_info.risk_config.liquidation_incentive = defaults.liquidation_incentive;
+        Self::save_asset_info(env, &symbol, &asset_info);
+
+        // Update registry
+        let mut registry = Self::get_registry(env);
+        registry.supported_assets.push_back(symbol.clone());
+        registry.last_update = env.ledger().timestamp();
+        Self::save_registry(env, &registry);
+
+        ProtocolEvent::AssetAdded {
+            asset: symbol.clone(),
+            symbol: asset_info.symbol,
+            decimals: asset_info.decimals,
+        }
+        .emit(env);
+
+        Ok(())
     }
 }
 
@@ -630,7 +1331,7 @@ impl AssetStorage {
 pub struct InterestRateManager;
 
 impl InterestRateManager {
-    /// Calculate utilization rate (total_borrowed / total_supplied)
+    /// Calculate utilization rate (total_borrowed / total_supplied, lender liquidity only)
     pub fn calculate_utilization(total_borrowed: i128, total_supplied: i128) -> i128 {
         if total_supplied == 0 {
             return 0;
@@ -678,55 +1379,189 @@ impl InterestRateManager {
         (principal * rate * time_delta as i128) / (seconds_per_year * 100_000_000)
     }
 
-    /// Update interest rates based on current state
-    pub fn update_rates(env: &Env, state: &mut InterestRateState, config: &InterestRateConfig) {
-        let utilization = Self::calculate_utilization(state.total_borrowed, state.total_supplied);
-        let borrow_rate = Self::calculate_borrow_rate(utilization, config);
-        let supply_rate =
-            Self::calculate_supply_rate(borrow_rate, utilization, config.reserve_factor);
+    /// Grow the borrow and supply indexes, and the totals they drive, from the last accrual up to `now`
+    pub fn accrue_indexes(state: &mut InterestRateState, now: u64) {
+        if state.last_accrual_time == 0 || now <= state.last_accrual_time {
+            return;
+        }
+        let time_delta = now - state.last_accrual_time;
+        let new_index = state.borrow_index
+            + Self::calculate_interest(state.borrow_index, state.current_borrow_rate, time_delta);
 
-        state.utilization_rate = utilization;
+        // Variable debt grows with the index, stable debt at its average rate
+        let variable_debt = state.total_borrowed - state.total_stable_debt;
+        let stable_accrued =
+            Self::calculate_interest(state.total_stable_debt, state.average_stable_rate, time_delta);
+        let accrued = (variable_debt * new_index) / state.borrow_index - variable_debt + stable_accrued;
+        state.total_stable_debt += stable_accrued;
+        state.total_borrowed += accrued;
+        state.borrow_index = new_index;
+
+        // Lenders earn the supply rate through the supply index; the rest of the
+        // borrow interest is the reserve factor's share
+        let new_supply_index = state.supply_index
+            + Self::calculate_interest(state.supply_index, state.current_supply_rate, time_delta);
+        let earned =
+            (state.total_supplied * new_supply_index) / state.supply_index - state.total_supplied;
+        state.total_supplied += earned;
+        state.total_reserves += accrued - earned;
+        state.supply_index = new_supply_index;
+    }
+
+    /// Rate paid across all of an asset's debt: the variable rate on variable debt and
+    /// the average stable rate on stable debt, weighted by amount
+    pub fn overall_borrow_rate(state: &InterestRateState, variable_rate: i128) -> i128 {
+        if state.total_borrowed <= 0 {
+            return variable_rate;
+        }
+        let stable_debt = state.total_stable_debt.min(state.total_borrowed);
+        ((state.total_borrowed - stable_debt) * variable_rate + stable_debt * state.average_stable_rate)
+            / state.total_borrowed
+    }
+
+    /// Stable rate offered for borrowing `amount` more: the variable rate at the
+    /// utilization the borrow leaves behind plus the premium
+    pub fn stable_borrow_rate(state: &InterestRateState, config: &InterestRateConfig, amount: i128) -> i128 {
+        let utilization = Self::calculate_utilization(state.total_borrowed + amount, state.total_supplied);
+        Self::calculate_borrow_rate(utilization, config) + config.stable_rate_premium
+    }
+
+    /// Add `amount` of stable debt at `rate` to an asset's totals, keeping the average rate weighted
+    pub fn increase_stable_total(state: &mut InterestRateState, amount: i128, rate: i128) {
+        let total = state.total_stable_debt + amount;
+        if total > 0 {
+            state.average_stable_rate =
+                (state.total_stable_debt * state.average_stable_rate + amount * rate) / total;
+        }
+        state.total_stable_debt = total;
+    }
+
+    /// Take `amount` of stable debt at `rate` off an asset's totals
+    pub fn decrease_stable_total(state: &mut InterestRateState, amount: i128, rate: i128) {
+        let total = state.total_stable_debt - amount;
+        if total <= 0 {
+            state.total_stable_debt = 0;
+            state.average_stable_rate = 0;
+            return;
+        }
+        state.average_stable_rate =
+            ((state.total_stable_debt * state.average_stable_rate - amount * rate) / total).max(0);
+        state.total_stable_debt = total;
+    }
+
+    /// Convert a debt amount into borrow-index units (rounded up)
+    pub fn debt_to_scaled(amount: i128, borrow_index: i128) -> i128 {
+        (amount * INDEX_SCALE + borrow_index - 1) / borrow_index
+    }
+
+    /// Convert borrow-index units into a debt amount (rounded down)
+    pub fn scaled_to_debt(scaled_debt: i128, borrow_index: i128) -> i128 {
+        (scaled_debt * borrow_index) / INDEX_SCALE
+    }
+
+    /// Convert supplied liquidity into sToken shares at the given supply index (rounded down)
+    pub fn supply_to_shares(amount: i128, supply_index: i128) -> i128 {
+        (amount * INDEX_SCALE) / supply_index
+    }
+
+    /// Convert sToken shares into the liquidity they redeem for (rounded down)
+    pub fn shares_to_supply(shares: i128, supply_index: i128) -> i128 {
+        (shares * supply_index) / INDEX_SCALE
+    }
+
+    /// Add `amount` of new debt to a position at the given borrow index
+    pub fn add_debt(position: &mut AssetPosition, amount: i128, borrow_index: i128) {
+        position.scaled_debt += Self::debt_to_scaled(amount, borrow_index);
+    }
+
+    /// Add `amount` of new debt to an accrued position at a stable `rate`; the position's
+    /// stable rate becomes the amount-weighted average
+    pub fn add_stable_debt(position: &mut AssetPosition, amount: i128, rate: i128) {
+        let total = position.stable_debt + amount;
+        position.stable_rate = (position.stable_debt * position.stable_rate + amount * rate) / total;
+        position.stable_debt = total;
+    }
+
+    /// Debt of an accrued position: variable debt at the given borrow index plus stable debt
+    pub fn position_debt(position: &AssetPosition, borrow_index: i128) -> i128 {
+        Self::scaled_to_debt(position.scaled_debt, borrow_index) + position.stable_debt
+    }
+
+    /// Remove up to `amount` of debt from an accrued position, variable debt first;
+    /// returns the amount actually repaid and the stable part of it
+    pub fn remove_debt(position: &mut AssetPosition, amount: i128, borrow_index: i128) -> (i128, i128) {
+        let debt = Self::scaled_to_debt(position.scaled_debt, borrow_index);
+        let variable_repaid = amount.min(debt);
+        // Re-scale what is left, rounding up so the remainder is never understated
+        position.scaled_debt = Self::debt_to_scaled(debt - variable_repaid, borrow_index);
+        let stable_repaid = (amount - variable_repaid).min(position.stable_debt);
+        position.stable_debt -= stable_repaid;
+        (variable_repaid + stable_repaid, stable_repaid)
+    }
+
+    /// Update interest rates based on current state
+    pub fn update_rates(env: &Env, state: &mut InterestRateState, config: &InterestRateConfig) {
+        Self::accrue_indexes(state, env.ledger().timestamp());
+        Self::refresh_rates(state, config);
+        state.last_accrual_time = env.ledger().timestamp();
+    }
+
+    /// Set utilization and rates from the state's current totals
+    pub fn refresh_rates(state: &mut InterestRateState, config: &InterestRateConfig) {
+        let utilization = Self::calculate_utilization(state.total_borrowed, state.total_supplied);
+        let borrow_rate = Self::calculate_borrow_rate(utilization, config);
+        let supply_rate = Self::calculate_supply_rate(
+            Self::overall_borrow_rate(state, borrow_rate),
+            utilization,
+            config.reserve_factor,
+        );
+
+        state.utilization_rate = utilization;
         state.current_borrow_rate = borrow_rate;
         state.current_supply_rate = supply_rate;
-        state.last_accrual_time = env.ledger().timestamp();
     }
 
     /// Accrue interest for a position
     pub fn accrue_interest_for_position(
         env: &Env,
-        position: &mut Position,
-        borrow_rate: i128,
-        supply_rate: i128,
+        position: &mut AssetPosition,
+        borrow_index: i128,
+        supply_index: i128,
     ) {
-        let current_time = env.ledger().timestamp();
-        let time_delta = if position.last_accrual_time == 0 {
-            0
-        } else {
-            current_time - position.last_accrual_time
-        };
-
-        if time_delta > 0 {
-            // Accrue borrow interest
-            if position.debt > 0 {
-                let borrow_interest =
-                    Self::calculate_interest(position.debt, borrow_rate, time_delta);
-                position.borrow_interest += borrow_interest;
-            }
-
-            // Accrue supply interest
-            if position.collateral > 0 {
-                let supply_interest =
-                    Self::calculate_interest(position.collateral, supply_rate, time_delta);
-                position.supply_interest += supply_interest;
-            }
-
-            position.last_accrual_time = current_time;
+        // Borrow interest is capitalized through the index; record how much was added
+        if position.scaled_debt > 0 && borrow_index > position.borrow_index {
+            let before = Self::scaled_to_debt(position.scaled_debt, position.borrow_index);
+            let after = Self::scaled_to_debt(position.scaled_debt, borrow_index);
+            position.borrow_interest += after - before;
+        }
+        position.borrow_index = borrow_index;
+
+        // Supply interest grows the value of the lender's shares; record how much was added
+        if position.supply_shares > 0 && supply_index > position.supply_index {
+            let before = Self::shares_to_supply(position.supply_shares, position.supply_index);
+            let after = Self::shares_to_supply(position.supply_shares, supply_index);
+            position.supply_interest += after - before;
+        }
+        position.supply_index = supply_index;
+
+        // Stable debt accrues at the position's own rate since its last accrual
+        let now = env.ledger().timestamp();
+        if position.stable_debt > 0 && now > position.last_accrual_time {
+            let interest = Self::calculate_interest(
+                position.stable_debt,
+                position.stable_rate,
+                now - position.last_accrual_time,
+            );
+            position.stable_debt += interest;
+            position.borrow_interest += interest;
         }
+
+        position.last_accrual_time = now;
     }
 
     /// Calculate and collect protocol fees from interest
     pub fn collect_fees_from_interest(
-        env: &Env,
+        _env: &Env,
         borrow_interest: i128,
         supply_interest: i128,
         reserve_factor: i128,
@@ -746,2189 +1581,5268 @@ impl InterestRateManager {
 }
 
 /// Storage helper for interest rate configuration
+///
+/// Rate config and state live on each asset's `AssetInfo`. The asset-less accessors
+/// act on the registry's default asset.
 pub struct InterestRateStorage;
 
 impl InterestRateStorage {
-    fn config_key() -> Symbol {
-        Symbol::short("ir_config")
-    }
-    fn state_key() -> Symbol {
-        Symbol::short("ir_state")
+    fn default_asset(env: &Env) -> String {
+        AssetStorage::get_registry(env).default_asset
     }
 
     pub fn save_config(env: &Env, config: &InterestRateConfig) {
-        env.storage().instance().set(&Self::config_key(), config);
+        Self::save_asset_config(env, &Self::default_asset(env), config);
     }
 
     pub fn get_config(env: &Env) -> InterestRateConfig {
-        env.storage()
-            .instance()
-            .get(&Self::config_key())
-            .unwrap_or_else(InterestRateConfig::default)
+        Self::get_asset_config(env, &Self::default_asset(env))
     }
 
     pub fn save_state(env: &Env, state: &InterestRateState) {
-        env.storage().instance().set(&Self::state_key(), state);
+        Self::save_asset_state(env, &Self::default_asset(env), state);
     }
 
     pub fn get_state(env: &Env) -> InterestRateState {
-        env.storage()
-            .instance()
-            .get(&Self::state_key())
-            .unwrap_or_else(InterestRateState::initial)
+        Self::get_asset_state(env, &Self::default_asset(env))
     }
 
     pub fn update_state(env: &Env) -> InterestRateState {
-        let mut state = Self::get_state(env);
-        let config = Self::get_config(env);
+        Self::update_asset_state(env, &Self::default_asset(env))
+    }
+
+    pub fn save_asset_config(env: &Env, asset: &String, config: &InterestRateConfig) {
+        if let Some(mut info) = AssetStorage::get_asset_info(env, asset) {
+            info.interest_config = config.clone();
+            AssetStorage::save_asset_info(env, asset, &info);
+        }
+    }
+
+    pub fn get_asset_config(env: &Env, asset: &String) -> InterestRateConfig {
+        AssetStorage::get_asset_info(env, asset)
+            .map(|info| info.interest_config)
+            .unwrap_or_default()
+    }
+
+    /// Save an asset's state with its rates refreshed from the totals being written, so the
+    /// next operation is priced at the utilization this one left behind
+    pub fn save_asset_state(env: &Env, asset: &String, state: &InterestRateState) {
+        if let Some(mut info) = AssetStorage::get_asset_info(env, asset) {
+            let mut state = state.clone();
+            InterestRateManager::refresh_rates(&mut state, &info.interest_config);
+            info.interest_state = state;
+            AssetStorage::save_asset_info(env, asset, &info);
+        }
+    }
+
+    pub fn get_asset_state(env: &Env, asset: &String) -> InterestRateState {
+        AssetStorage::get_asset_info(env, asset)
+            .map(|info| info.interest_state)
+            .unwrap_or_else(InterestRateState::initial)
+    }
+
+    /// Accrue an asset's indexes to the current ledger time and refresh its rates
+    pub fn update_asset_state(env: &Env, asset: &String) -> InterestRateState {
+        let state = Self::projected_asset_state(env, asset);
+        Self::save_asset_state(env, asset, &state);
+        state
+    }
+
+    /// The state `update_asset_state` would write, without writing it
+    pub fn projected_asset_state(env: &Env, asset: &String) -> InterestRateState {
+        let mut state = Self::get_asset_state(env, asset);
+        let config = Self::get_asset_config(env, asset);
         InterestRateManager::update_rates(env, &mut state, &config);
-        Self::save_state(env, &state);
         state
     }
+
+    /// Borrow index of an asset projected to the current ledger time, without writing state
+    pub fn current_borrow_index(env: &Env, asset: &String) -> i128 {
+        let mut state = Self::get_asset_state(env, asset);
+        InterestRateManager::accrue_indexes(&mut state, env.ledger().timestamp());
+        state.borrow_index
+    }
+
+    /// Supply index of an asset projected to the current ledger time, without writing state
+    pub fn current_supply_index(env: &Env, asset: &String) -> i128 {
+        let mut state = Self::get_asset_state(env, asset);
+        InterestRateManager::accrue_indexes(&mut state, env.ledger().timestamp());
+        state.supply_index
+    }
 }
 
 /// Helper functions for state management
 pub struct StateHelper;
 
 impl StateHelper {
-    /// Save a position to storage
-    pub fn save_position(env: &Env, position: &Position) {
-        let key = (Symbol::short("position"), position.user.clone());
-        env.storage().instance().set(&key, position);
+    /// Save a position to storage; position writes also keep the contract instance alive
+    pub fn save_position(env: &Env, position: &AssetPosition) {
+        AssetStorage::save_asset_position(env, &position.user, &position.asset, position);
+        SubAccounts::open(env, &position.user, position.sub_account);
+        UserStorage::bump_instance(env);
     }
 
-    /// Retrieve a position from storage
-    pub fn get_position(env: &Env, user: &Address) -> Option<Position> {
-        let key = (Symbol::short("position"), user.clone());
-        env.storage().instance().get(&key)
+    /// Extend the TTL of everything stored for a user, returning the number of entries bumped
+    pub fn bump_user(env: &Env, user: &Address) -> u32 {
+        let mut keys = vec![
+            env,
+            DataKey::UserActivity(user.clone()),
+            DataKey::SuspiciousCount(user.clone()),
+            DataKey::Frozen(user.clone()),
+            DataKey::Kyc(user.clone()),
+            DataKey::Blacklisted(user.clone()),
+            DataKey::RecoveryConfig(user.clone()),
+            DataKey::UserEMode(user.clone()),
+            DataKey::UserLoans(user.clone()),
+            DataKey::SubAccounts(user.clone()),
+        ];
+        let sub_accounts = SubAccounts::list(env, user);
+        for asset in AssetStorage::get_registry(env).supported_assets.iter() {
+            keys.push_back(DataKey::Position(user.clone(), asset.clone()));
+            for sub_account in sub_accounts.iter() {
+                keys.push_back(DataKey::SubPosition(user.clone(), sub_account, asset.clone()));
+            }
+        }
+        for id in LoanStorage::get_user_loan_ids(env, user).iter() {
+            keys.push_back(DataKey::Loan(id));
+        }
+        let mut bumped = 0u32;
+        for key in keys.iter() {
+            bumped += UserStorage::bump(env, &key) as u32;
+        }
+        UserStorage::bump_instance(env);
+        bumped
+    }
+
+    /// Retrieve a user's main-account position in an asset from storage
+    pub fn get_position(env: &Env, user: &Address, asset: &String) -> Option<AssetPosition> {
+        Self::get_sub_position(env, user, MAIN_ACCOUNT, asset)
+    }
+
+    /// Retrieve a user's position in an asset within one of their sub-accounts
+    pub fn get_sub_position(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        asset: &String,
+    ) -> Option<AssetPosition> {
+        AssetStorage::get_asset_position(env, user, sub_account, asset)
+    }
+
+    /// Remove a user's main-account position in an asset from storage
+    pub fn remove_position(env: &Env, user: &Address, asset: &String) {
+        AssetStorage::remove_asset_position(env, user, MAIN_ACCOUNT, asset);
+    }
+
+    /// A user's position in an asset within a sub-account (empty if none) with interest
+    /// accrued to `state`
+    pub fn accrued_position(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        asset: &String,
+        state: &InterestRateState,
+    ) -> AssetPosition {
+        let mut position = Self::get_sub_position(env, user, sub_account, asset).unwrap_or(AssetPosition {
+            sub_account,
+            ..AssetPosition::new(user.clone(), asset.clone(), 0, 0)
+        });
+        InterestRateManager::accrue_interest_for_position(
+            env,
+            &mut position,
+            state.borrow_index,
+            state.supply_index,
+        );
+        position
+    }
+
+    /// Apply a borrow to the user's accrued position, checking liquidity, the borrow cap
+    /// and isolation mode; returns the new position and the account health it leaves.
+    /// `stable_rate` locks the debt at that rate instead of the variable rate. Shared by
+    /// `borrow` and `preview_borrow`.
+    pub fn plan_borrow(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        asset_info: &AssetInfo,
+        state: &InterestRateState,
+        amount: i128,
+        stable_rate: Option<i128>,
+    ) -> Result<(AssetPosition, AccountHealth), ProtocolError> {
+        let mut position = Self::accrued_position(env, user, sub_account, &asset_info.symbol, state);
+
+        // Only lender liquidity can be borrowed; collateral stays in the pool
+        if amount > state.total_supplied - state.total_borrowed {
+            return Err(ProtocolError::InsufficientLiquidity);
+        }
+        asset_info.check_borrow_cap(state, amount)?;
+        IsolationMode::check_borrow(env, user, sub_account, asset_info, amount)?;
+
+        match stable_rate {
+            Some(rate) => InterestRateManager::add_stable_debt(&mut position, amount, rate),
+            None => InterestRateManager::add_debt(&mut position, amount, state.borrow_index),
+        }
+        let health = Self::account_health_with(env, user, Some(&position))?;
+        Ok((position, health))
+    }
+
+    /// Take collateral out of the user's accrued position; returns the new position and
+    /// the account health it leaves. Shared by `withdraw` and `preview_withdraw`.
+    pub fn plan_withdraw(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        asset: &String,
+        state: &InterestRateState,
+        amount: i128,
+    ) -> Result<(AssetPosition, AccountHealth), ProtocolError> {
+        let mut position = Self::accrued_position(env, user, sub_account, asset, state);
+        if position.collateral < amount {
+            return Err(ProtocolError::InsufficientCollateral);
+        }
+        position.collateral -= amount;
+        let health = Self::account_health_with(env, user, Some(&position))?;
+        Ok((position, health))
+    }
+
+    /// Pay down up to `amount` of the user's accrued debt, variable debt first; returns
+    /// the new position, the amount actually repaid and its stable part. Shared by `repay`
+    /// and `preview_repay`.
+    pub fn plan_repay(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        asset: &String,
+        state: &InterestRateState,
+        amount: i128,
+    ) -> (AssetPosition, i128, i128) {
+        let mut position = Self::accrued_position(env, user, sub_account, asset, state);
+        let (repaid, stable_repaid) =
+            InterestRateManager::remove_debt(&mut position, amount, state.borrow_index);
+        (position, repaid, stable_repaid)
+    }
+
+    /// Collateral seized for `repay_amount` of debt: its value in collateral units plus the
+    /// liquidation bonus, or `available` collateral if that falls short, in which case only
+    /// the debt it covers is repaid. Returns the amount repaid, the collateral seized, the
+    /// protocol's fee out of the bonus and what the liquidator receives.
+    pub fn liquidation_terms(
+        env: &Env,
+        debt_info: &AssetInfo,
+        collateral_info: &AssetInfo,
+        collateral_risk: &RiskConfig,
+        emode_category: u32,
+        repay_amount: i128,
+        available: i128,
+    ) -> Result<(i128, i128, i128, i128), ProtocolError> {
+        // An account in efficiency mode pays its category's bonus instead (basis points to 1e8)
+        let incentive = match EModeStorage::get_category(env, emode_category) {
+            Some(category) => category.liquidation_bonus * HEALTH_FACTOR_ONE / BPS_ONE,
+            None => collateral_risk.liquidation_incentive,
+        };
+        let mut repay_amount = repay_amount;
+        let mut seized = Self::convert_amount(env, debt_info, collateral_info, repay_amount)?
+            * (100_000_000 + incentive)
+            / 100_000_000;
+
+        // Without enough collateral, seize all of it and repay only the debt it covers
+        if seized > available {
+            seized = available;
+            let base = seized * 100_000_000 / (100_000_000 + incentive);
+            repay_amount = Self::convert_amount(env, collateral_info, debt_info, base)?;
+        }
+        if repay_amount == 0 || seized == 0 {
+            return Err(ProtocolError::InvalidAmount);
+        }
+
+        // The protocol keeps its fee out of the bonus; the liquidator receives the rest
+        let bonus = seized - seized * 100_000_000 / (100_000_000 + incentive);
+        let protocol_fee = bonus * collateral_risk.liquidation_protocol_fee / 100_000_000;
+        Ok((repay_amount, seized, protocol_fee, seized - protocol_fee))
+    }
+
+    /// Base units of an asset worth `value` in the quote currency (rounded down)
+    pub fn value_to_amount(env: &Env, info: &AssetInfo, value: i128) -> Result<i128, ProtocolError> {
+        let price = Self::asset_price(env, info)?;
+        Self::mul_div(value, Self::decimal_scale(info.decimals)?, price)
+    }
+
+    /// `value * numerator / denominator` (rounded down), failing on overflow
+    fn mul_div(value: i128, numerator: i128, denominator: i128) -> Result<i128, ProtocolError> {
+        value
+            .checked_mul(numerator)
+            .and_then(|product| product.checked_div(denominator))
+            .ok_or(ProtocolError::InvalidAmount)
+    }
+
+    /// Base units in one whole unit of an asset with `decimals` decimals
+    fn decimal_scale(decimals: u32) -> Result<i128, ProtocolError> {
+        10i128.checked_pow(decimals).ok_or(ProtocolError::InvalidAmount)
+    }
+
+    /// Current debt of a position, including interest capitalized through the borrow index
+    /// and stable interest since its last accrual
+    pub fn current_debt(env: &Env, position: &AssetPosition) -> i128 {
+        let borrow_index = InterestRateStorage::current_borrow_index(env, &position.asset);
+        let now = env.ledger().timestamp();
+        let stable_interest = InterestRateManager::calculate_interest(
+            position.stable_debt,
+            position.stable_rate,
+            now.saturating_sub(position.last_accrual_time),
+        );
+        InterestRateManager::scaled_to_debt(position.scaled_debt, borrow_index)
+            + position.stable_debt
+            + stable_interest
     }
 
-    /// Remove a position from storage
-    pub fn remove_position(env: &Env, user: &Address) {
-        let key = (Symbol::short("position"), user.clone());
-        env.storage().instance().remove(&key);
+    /// Liquidity a lender's shares currently redeem for, including supply interest
+    pub fn current_supply(env: &Env, position: &AssetPosition) -> i128 {
+        let supply_index = InterestRateStorage::current_supply_index(env, &position.asset);
+        InterestRateManager::shares_to_supply(position.supply_shares, supply_index)
     }
 
     /// Calculate the collateral ratio for a position (collateral / debt, scaled by 100 for percent)
-    pub fn collateral_ratio(position: &Position) -> i128 {
-        if position.debt == 0 {
+    pub fn collateral_ratio(env: &Env, position: &AssetPosition) -> i128 {
+        let debt = Self::current_debt(env, position);
+        if debt == 0 {
             return i128::MAX; // Infinite ratio if no debt
         }
         // Ratio as percent (e.g., 150 means 150%)
-        (position.collateral * 100) / position.debt
+        (position.collateral * 100) / debt
     }
 
-    /// Calculate the dynamic collateral ratio for a position using price oracle
-    /// (collateral * price) / debt, scaled by 100 for percent
-    pub fn dynamic_collateral_ratio<P: PriceOracle>(env: &Env, position: &Position) -> i128 {
-        if position.debt == 0 {
-            return i128::MAX;
+    /// Price of one whole unit of an asset (scaled by 1e8) from the asset's own oracle;
+    /// fails if the oracle has no positive price or its last one is older than the heartbeat
+    pub fn asset_price(env: &Env, info: &AssetInfo) -> Result<i128, ProtocolError> {
+        let data = match AssetOracleClient::new(env, &info.oracle_address).try_lastprice(&info.symbol) {
+            Ok(Ok(Some(data))) => data,
+            _ => return Err(ProtocolError::OracleFailure),
+        };
+        let age = env.ledger().timestamp().saturating_sub(data.timestamp);
+        if data.price <= 0 || age > OracleConfig::get_heartbeat(env) {
+            return Err(ProtocolError::OracleFailure);
         }
-        let price = P::get_price(env); // price is scaled by 1e8
-                                       // Ratio as percent (e.g., 150 means 150%)
-        ((position.collateral * price * 100) / 100_000_000) / position.debt
+        Ok(data.price)
     }
-}
 
-/// Event types for protocol actions
-pub enum ProtocolEvent {
-    Deposit {
-        user: String,
-        amount: i128,
-        asset: String,
-    },
-    Borrow {
-        user: String,
-        amount: i128,
-        asset: String,
-    },
-    Repay {
-        user: String,
-        amount: i128,
-        asset: String,
-    },
-    Withdraw {
-        user: String,
-        amount: i128,
-        asset: String,
-    },
-    Liquidate {
-        user: String,
+    /// Value of `amount` base units of an asset in the quote currency (scaled by 1e8)
+    pub fn asset_value(env: &Env, info: &AssetInfo, amount: i128) -> Result<i128, ProtocolError> {
+        let price = Self::asset_price(env, info)?;
+        Self::mul_div(amount, price, Self::decimal_scale(info.decimals)?)
+    }
+
+    /// Convert `amount` base units of one asset into base units of another at oracle
+    /// prices (rounded down), failing on overflow
+    pub fn convert_amount(
+        env: &Env,
+        from: &AssetInfo,
+        to: &AssetInfo,
         amount: i128,
-        asset: String,
-    },
-    InterestAccrued {
-        user: String,
-        borrow_interest: i128,
-        supply_interest: i128,
-        asset: String,
-    },
-    RateUpdated {
-        borrow_rate: i128,
-        supply_rate: i128,
-        utilization: i128,
-        asset: String,
-    },
-    ConfigUpdated {
-        parameter: String,
-        old_value: i128,
-        new_value: i128,
-    },
-    FeesCollected {
+    ) -> Result<i128, ProtocolError> {
+        let from_price = Self::asset_price(env, from)?;
+        let to_price = Self::asset_price(env, to)?;
+        // Rescale by the decimal difference alone so only one side grows
+        let (amount, denominator) = if to.decimals >= from.decimals {
+            let scale = Self::decimal_scale(to.decimals - from.decimals)?;
+            (amount.checked_mul(scale).ok_or(ProtocolError::InvalidAmount)?, to_price)
+        } else {
+            let scale = Self::decimal_scale(from.decimals - to.decimals)?;
+            (amount, to_price.checked_mul(scale).ok_or(ProtocolError::InvalidAmount)?)
+        };
+        Self::mul_div(amount, from_price, denominator)
+    }
+
+    /// Health of a user's main account across every listed asset
+    pub fn account_health(env: &Env, user: &Address) -> Result<AccountHealth, ProtocolError> {
+        Self::sub_account_health(env, user, MAIN_ACCOUNT)
+    }
+
+    /// Health of one of a user's sub-accounts across every listed asset
+    pub fn sub_account_health(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+    ) -> Result<AccountHealth, ProtocolError> {
+        let emode = EModeStorage::get_category(env, EModeStorage::get_user_category(env, user));
+        Self::account_health_in(env, user, sub_account, None, None, emode)
+    }
+
+    /// Health of the sub-account holding `pending`, with it standing in for the stored
+    /// position in its asset, so a change can be checked before it is saved; without a
+    /// pending position, health of the main account
+    pub fn account_health_with(
+        env: &Env,
+        user: &Address,
+        pending: Option<&AssetPosition>,
+    ) -> Result<AccountHealth, ProtocolError> {
+        let sub_account = pending.map_or(MAIN_ACCOUNT, |position| position.sub_account);
+        let emode = EModeStorage::get_category(env, EModeStorage::get_user_category(env, user));
+        Self::account_health_in(env, user, sub_account, pending, None, emode)
+    }
+
+    /// Health of an account with `loan` standing in for the stored copy of that fixed-term
+    /// loan, or added to the user's loans if it is new
+    pub fn account_health_with_loan(
+        env: &Env,
+        user: &Address,
+        loan: &FixedTermLoan,
+    ) -> Result<AccountHealth, ProtocolError> {
+        let emode = EModeStorage::get_category(env, EModeStorage::get_user_category(env, user));
+        Self::account_health_in(env, user, MAIN_ACCOUNT, None, Some(loan), emode)
+    }
+
+    /// Health of a sub-account opted into `emode`; the category's LTV and liquidation
+    /// threshold replace each asset's only while every position with collateral or debt,
+    /// and every open fixed-term loan, is in it. Fixed-term loans belong to the main account.
+    pub fn account_health_in(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        pending: Option<&AssetPosition>,
+        pending_loan: Option<&FixedTermLoan>,
+        emode: Option<EModeCategory>,
+    ) -> Result<AccountHealth, ProtocolError> {
+        let mut health = AccountHealth {
+            collateral_value: 0,
+            borrow_limit: 0,
+            liquidation_limit: 0,
+            debt_value: 0,
+            health_factor: i128::MAX,
+            emode_category: 0,
+        };
+        let (mut emode_borrow_limit, mut emode_liquidation_limit) = (0, 0);
+        let mut in_emode = emode.is_some();
+        for asset in AssetStorage::get_registry(env).supported_assets.iter() {
+            let position = match pending {
+                Some(position) if position.asset == asset => Some(position.clone()),
+                _ => Self::get_sub_position(env, user, sub_account, &asset),
+            };
+            let (position, info) = match (position, AssetStorage::get_asset_info(env, &asset)) {
+                (Some(position), Some(info)) => (position, info),
+                _ => continue,
+            };
+            if position.collateral > 0 {
+                let value = Self::asset_value(env, &info, position.collateral)?;
+                health.collateral_value += value;
+                health.borrow_limit += value * info.ltv / BPS_ONE;
+                health.liquidation_limit += value * info.liquidation_threshold / BPS_ONE;
+                if let Some(category) = &emode {
+                    emode_borrow_limit += value * category.ltv / BPS_ONE;
+                    emode_liquidation_limit += value * category.liquidation_threshold / BPS_ONE;
+                }
+            }
+            let debt = Self::current_debt(env, &position);
+            if debt > 0 {
+                health.debt_value += Self::asset_value(env, &info, debt)?;
+            }
+            if (position.collateral > 0 || debt > 0)
+                && emode.as_ref().is_some_and(|category| info.emode_category != category.id)
+            {
+                in_emode = false;
+            }
+        }
+
+        // Fixed-term loans count toward the main account's debt like any other borrow
+        let now = env.ledger().timestamp();
+        let mut loans = Vec::new(env);
+        if sub_account == MAIN_ACCOUNT {
+            for loan in LoanStorage::open_loans(env, user).iter() {
+                if pending_loan.is_none_or(|pending| pending.id != loan.id) {
+                    loans.push_back(loan);
+                }
+            }
+        }
+        if let Some(loan) = pending_loan.filter(|loan| loan.is_open()) {
+            loans.push_back(loan.clone());
+        }
+        for loan in loans.iter() {
+            let info = match AssetStorage::get_asset_info(env, &loan.asset) {
+                Some(info) => info,
+                None => continue,
+            };
+            health.debt_value += Self::asset_value(env, &info, loan.current_debt(now))?;
+            if emode.as_ref().is_some_and(|category| info.emode_category != category.id) {
+                in_emode = false;
+            }
+        }
+        if let (true, Some(category)) = (in_emode, &emode) {
+            health.borrow_limit = emode_borrow_limit;
+            health.liquidation_limit = emode_liquidation_limit;
+            health.emode_category = category.id;
+        }
+        if health.debt_value > 0 {
+            health.health_factor = health.liquidation_limit * HEALTH_FACTOR_ONE / health.debt_value;
+        }
+        Ok(health)
+    }
+}
+
+/// Debt of one asset a sub-account has charged to an isolated asset's debt ceiling
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct IsolationCharge {
+    /// Isolated collateral asset whose ceiling carries the debt
+    pub ceiling_asset: String,
+    /// Asset borrowed
+    pub debt_asset: String,
+    /// Borrowed amount still charged, in the debt asset's units
+    pub amount: i128,
+    /// Value charged to the ceiling for that amount (quote currency, scaled by 1e8)
+    pub value: i128,
+}
+
+/// Isolation mode: collateral in an isolated asset can only back borrows of assets
+/// allowlisted for isolation, and only up to the isolated asset's debt ceiling
+pub struct IsolationMode;
+
+impl IsolationMode {
+    /// Isolated assets in which the user's sub-account holds collateral
+    pub fn isolated_collateral(env: &Env, user: &Address, sub_account: u32) -> Vec<AssetInfo> {
+        let mut isolated = Vec::new(env);
+        for asset in AssetStorage::get_registry(env).supported_assets.iter() {
+            let info = match AssetStorage::get_asset_info(env, &asset) {
+                Some(info) if info.isolated => info,
+                _ => continue,
+            };
+            if StateHelper::get_sub_position(env, user, sub_account, &asset)
+                .is_some_and(|p| p.collateral > 0)
+            {
+                isolated.push_back(info);
+            }
+        }
+        isolated
+    }
+
+    /// Check a borrow of `amount` of `asset` against the user's isolated collateral,
+    /// returning the isolated assets whose debt ceilings it counts toward
+    pub fn check_borrow(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        asset: &AssetInfo,
         amount: i128,
-        source: String,
-    },
-    FeesDistributed {
+    ) -> Result<Vec<AssetInfo>, ProtocolError> {
+        let isolated = Self::isolated_collateral(env, user, sub_account);
+        if isolated.is_empty() {
+            return Ok(isolated);
+        }
+        if !asset.borrowable_in_isolation {
+            return Err(ProtocolError::NotBorrowableInIsolation);
+        }
+        let value = StateHelper::asset_value(env, asset, amount)?;
+        for info in isolated.iter() {
+            if info.isolation_debt + value > info.debt_ceiling {
+                return Err(ProtocolError::DebtCeilingExceeded);
+            }
+        }
+        Ok(isolated)
+    }
+
+    /// Check a borrow of `amount` of `asset` against the user's isolated collateral and
+    /// charge its value to their debt ceilings
+    pub fn record_borrow(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        asset: &AssetInfo,
         amount: i128,
-        treasury: String,
-    },
-    TreasuryUpdated {
-        old_address: String,
-        new_address: String,
-    },
-    ReserveUpdated {
-        total_collected: i128,
-        current_reserves: i128,
-    },
-    AssetAdded {
-        asset: String,
-        symbol: String,
-        decimals: u32,
-    },
-    AssetUpdated {
-        asset: String,
-        parameter: String,
-        old_value: String,
-        new_value: String,
-    },
-    AssetDisabled {
-        asset: String,
-        reason: String,
-    },
-    UserActivityTracked {
-        user: String,
-        action: String,
-        amount: i128,
-        timestamp: u64,
-    },
-    ProtocolStatsUpdated {
-        total_users: u32,
-        active_users_24h: u32,
-        total_transactions: u32,
-    },
-    AccountFrozen {
-        user: String,
-    },
-    AccountUnfrozen {
-        user: String,
-    },
-    // Social Recovery and Multi-Signature events
-    GuardianAdded {
-        user: String,
-        guardian: String,
-        name: String,
-        weight: u32,
-    },
-    GuardianRemoved {
-        user: String,
-        guardian: String,
-    },
-    RecoveryRequestCreated {
-        request_id: u32,
-        user: String,
-        new_address: String,
-        required_approvals: u32,
-        time_delay: u64,
-    },
-    RecoveryRequestApproved {
-        request_id: u32,
-        guardian: String,
-        current_approvals: u32,
-    },
-    RecoveryRequestExecuted {
-        request_id: u32,
-        user: String,
-        new_address: String,
-    },
-    RecoveryRequestCancelled {
-        request_id: u32,
-        user: String,
-    },
-    MultiSigProposalCreated {
-        proposal_id: u32,
-        creator: String,
-        description: String,
-        target_function: String,
-        required_approvals: u32,
-        time_delay: u64,
-    },
-    MultiSigProposalApproved {
-        proposal_id: u32,
-        signer: String,
-        current_approvals: u32,
-    },
-    MultiSigProposalExecuted {
-        proposal_id: u32,
-        creator: String,
-        target_function: String,
-    },
-    MultiSigProposalCancelled {
-        proposal_id: u32,
-        creator: String,
-    },
-    MultiSigSignerAdded {
-        signer: String,
-        added_by: String,
-    },
-    MultiSigSignerRemoved {
-        signer: String,
-        removed_by: String,
-    },
-}
+    ) -> Result<(), ProtocolError> {
+        let isolated = Self::check_borrow(env, user, sub_account, asset, amount)?;
+        if isolated.is_empty() {
+            return Ok(());
+        }
+        let value = StateHelper::asset_value(env, asset, amount)?;
+        let mut charges = Self::charges(env, user, sub_account);
+        for mut info in isolated.iter() {
+            info.isolation_debt += value;
+            AssetStorage::save_asset_info(env, &info.symbol.clone(), &info);
+            Self::add_charge(
+                &mut charges,
+                IsolationCharge {
+                    ceiling_asset: info.symbol.clone(),
+                    debt_asset: asset.symbol.clone(),
+                    amount,
+                    value,
+                },
+            );
+        }
+        Self::save_charges(env, user, sub_account, &charges);
+        Ok(())
+    }
 
-impl ProtocolEvent {
-    /// Emit the event using Soroban's event system
-    pub fn emit(&self, env: &Env) {
-        match self {
-            ProtocolEvent::Deposit {
-                user,
-                amount,
-                asset,
-            } => {
-                env.events().publish(
-                    (Symbol::short("deposit"), Symbol::short("user")),
-                    (
-                        Symbol::short("user"),
-                        *amount,
-                        Symbol::short("asset"),
-                        asset.clone(),
-                    ),
-                );
-            }
-            ProtocolEvent::Borrow {
-                user,
-                amount,
-                asset,
-            } => {
-                env.events().publish(
-                    (Symbol::short("borrow"), Symbol::short("user")),
-                    (
-                        Symbol::short("user"),
-                        *amount,
-                        Symbol::short("asset"),
-                        asset.clone(),
-                    ),
-                );
-            }
-            ProtocolEvent::Repay {
-                user,
-                amount,
-                asset,
-            } => {
-                env.events().publish(
-                    (Symbol::short("repay"), Symbol::short("user")),
-                    (
-                        Symbol::short("user"),
-                        *amount,
-                        Symbol::short("asset"),
-                        asset.clone(),
-                    ),
-                );
+    /// Check `collateral` being added to the user's sub-account: when it is isolated and
+    /// the sub-account holds none of it yet, every debt already there must be borrowable
+    /// in isolation and fit under its debt ceiling. Returns the debts to charge to it.
+    pub fn check_collateral_added(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        collateral: &AssetInfo,
+    ) -> Result<Vec<IsolationCharge>, ProtocolError> {
+        let mut debts = Vec::new(env);
+        if !collateral.isolated
+            || StateHelper::get_sub_position(env, user, sub_account, &collateral.symbol)
+                .is_some_and(|p| p.collateral > 0)
+        {
+            return Ok(debts);
+        }
+        let mut total = 0;
+        for asset in AssetStorage::get_registry(env).supported_assets.iter() {
+            let position = match StateHelper::get_sub_position(env, user, sub_account, &asset) {
+                Some(position) => position,
+                None => continue,
+            };
+            let amount = StateHelper::current_debt(env, &position);
+            if amount <= 0 {
+                continue;
             }
-            ProtocolEvent::Withdraw {
-                user,
-                amount,
-                asset,
-            } => {
-                env.events().publish(
-                    (Symbol::short("withdraw"), Symbol::short("user")),
-                    (
-                        Symbol::short("user"),
-                        *amount,
-                        Symbol::short("asset"),
-                        asset.clone(),
-                    ),
-                );
+            let info = AssetStorage::require_asset(env, &asset)?;
+            if !info.borrowable_in_isolation {
+                return Err(ProtocolError::NotBorrowableInIsolation);
             }
-            ProtocolEvent::Liquidate {
-                user,
+            let value = StateHelper::asset_value(env, &info, amount)?;
+            total += value;
+            debts.push_back(IsolationCharge {
+                ceiling_asset: collateral.symbol.clone(),
+                debt_asset: asset,
                 amount,
-                asset,
-            } => {
-                env.events().publish(
-                    (Symbol::short("liquidate"), Symbol::short("user")),
-                    (
-                        Symbol::short("user"),
-                        *amount,
-                        Symbol::short("asset"),
-                        asset.clone(),
-                    ),
-                );
-            }
-            ProtocolEvent::InterestAccrued {
-                user,
-                borrow_interest,
-                supply_interest,
-                asset,
-            } => {
-                env.events().publish(
-                    (Symbol::short("interest_accrued"), Symbol::short("user")),
-                    (
-                        Symbol::short("borrow_interest"),
-                        *borrow_interest,
-                        Symbol::short("supply_interest"),
-                        *supply_interest,
-                        Symbol::short("asset"),
-                        asset.clone(),
-                    ),
-                );
+                value,
+            });
+        }
+        if collateral.isolation_debt + total > collateral.debt_ceiling {
+            return Err(ProtocolError::DebtCeilingExceeded);
+        }
+        Ok(debts)
+    }
+
+    /// Check `collateral` being added to the user's sub-account and charge the debt
+    /// already there to its debt ceiling. Call before the collateral is saved.
+    pub fn record_collateral_added(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        collateral: &AssetInfo,
+    ) -> Result<(), ProtocolError> {
+        let debts = Self::check_collateral_added(env, user, sub_account, collateral)?;
+        if debts.is_empty() {
+            return Ok(());
+        }
+        let mut info = AssetStorage::require_asset(env, &collateral.symbol)?;
+        let mut charges = Self::charges(env, user, sub_account);
+        for debt in debts.iter() {
+            info.isolation_debt += debt.value;
+            Self::add_charge(&mut charges, debt);
+        }
+        AssetStorage::save_asset_info(env, &collateral.symbol, &info);
+        Self::save_charges(env, user, sub_account, &charges);
+        Ok(())
+    }
+
+    /// Release what the user's sub-account charged to `collateral`'s debt ceiling once it
+    /// holds none of that collateral any more. Call after the reduced position is saved.
+    pub fn record_collateral_removed(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        collateral: &AssetInfo,
+    ) {
+        if !collateral.isolated
+            || StateHelper::get_sub_position(env, user, sub_account, &collateral.symbol)
+                .is_some_and(|p| p.collateral > 0)
+        {
+            return;
+        }
+        let mut kept = Vec::new(env);
+        let mut released = 0;
+        for charge in Self::charges(env, user, sub_account).iter() {
+            if charge.ceiling_asset == collateral.symbol {
+                released += charge.value;
+            } else {
+                kept.push_back(charge);
             }
-            ProtocolEvent::RateUpdated {
-                borrow_rate,
-                supply_rate,
-                utilization,
-                asset,
-            } => {
-                env.events().publish(
-                    (Symbol::short("rate_updated"), Symbol::short("borrow_rate")),
-                    (
-                        Symbol::short("supply_rate"),
-                        *supply_rate,
-                        Symbol::short("utilization"),
-                        *utilization,
-                        Symbol::short("asset"),
-                        asset.clone(),
-                    ),
-                );
+        }
+        if released == 0 {
+            return;
+        }
+        if let Some(mut info) = AssetStorage::get_asset_info(env, &collateral.symbol) {
+            info.isolation_debt = (info.isolation_debt - released).max(0);
+            AssetStorage::save_asset_info(env, &collateral.symbol, &info);
+        }
+        Self::save_charges(env, user, sub_account, &kept);
+    }
+
+    /// Amount of `asset` the user can still borrow under their debt ceilings, or `None`
+    /// if they hold no isolated collateral
+    pub fn borrow_headroom(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        asset: &AssetInfo,
+    ) -> Result<Option<i128>, ProtocolError> {
+        let isolated = Self::isolated_collateral(env, user, sub_account);
+        if isolated.is_empty() {
+            return Ok(None);
+        }
+        if !asset.borrowable_in_isolation {
+            return Ok(Some(0));
+        }
+        let mut headroom = i128::MAX;
+        for info in isolated.iter() {
+            headroom = headroom.min(info.debt_ceiling - info.isolation_debt);
+        }
+        Ok(Some(StateHelper::value_to_amount(env, asset, headroom.max(0))?))
+    }
+
+    /// Release `amount` of repaid `asset` debt from the ceilings it was charged to, at
+    /// the value it was charged at; interest beyond the charged amount releases nothing
+    pub fn record_repay(env: &Env, user: &Address, sub_account: u32, asset: &AssetInfo, amount: i128) {
+        Self::release(env, user, sub_account, Some(&asset.symbol), amount);
+    }
+
+    /// Release everything the sub-account has charged to debt ceilings, once its debt
+    /// has been written off
+    pub fn release_all(env: &Env, user: &Address, sub_account: u32) {
+        Self::release(env, user, sub_account, None, i128::MAX);
+    }
+
+    /// Release up to `amount` of the charges of `debt_asset` (or of every asset)
+    fn release(env: &Env, user: &Address, sub_account: u32, debt_asset: Option<&String>, amount: i128) {
+        let mut kept = Vec::new(env);
+        for mut charge in Self::charges(env, user, sub_account).iter() {
+            if debt_asset.is_some_and(|asset| asset != &charge.debt_asset) {
+                kept.push_back(charge);
+                continue;
             }
-            ProtocolEvent::ConfigUpdated {
-                parameter,
-                old_value,
-                new_value,
-            } => {
-                env.events().publish(
-                    (Symbol::short("config_updated"), Symbol::short("parameter")),
-                    (
-                        Symbol::short("old_value"),
-                        *old_value,
-                        Symbol::short("new_value"),
-                        *new_value,
-                    ),
-                );
+            let repaid = amount.min(charge.amount);
+            let released = charge.value * repaid / charge.amount;
+            if let Some(mut info) = AssetStorage::get_asset_info(env, &charge.ceiling_asset) {
+                info.isolation_debt = (info.isolation_debt - released).max(0);
+                AssetStorage::save_asset_info(env, &charge.ceiling_asset, &info);
             }
-            ProtocolEvent::FeesCollected { amount, source } => {
-                env.events().publish(
-                    (Symbol::short("fees_collected"), Symbol::short("amount")),
-                    (Symbol::short("source"), source.clone()),
-                );
+            charge.amount -= repaid;
+            charge.value -= released;
+            if charge.amount > 0 {
+                kept.push_back(charge);
             }
-            ProtocolEvent::FeesDistributed { amount, treasury } => {
-                env.events().publish(
-                    (Symbol::short("fees_distributed"), Symbol::short("amount")),
-                    (Symbol::short("treasury"), treasury.clone()),
-                );
+        }
+        Self::save_charges(env, user, sub_account, &kept);
+    }
+
+    /// Debt the sub-account has charged to debt ceilings
+    pub fn charges(env: &Env, user: &Address, sub_account: u32) -> Vec<IsolationCharge> {
+        UserStorage::get(env, &DataKey::IsolationDebt(user.clone(), sub_account))
+            .unwrap_or(Vec::new(env))
+    }
+
+    /// Add a charge, merging it into an existing one for the same ceiling and debt asset
+    fn add_charge(charges: &mut Vec<IsolationCharge>, new: IsolationCharge) {
+        let existing = charges.iter().position(|charge| {
+            charge.ceiling_asset == new.ceiling_asset && charge.debt_asset == new.debt_asset
+        });
+        match existing {
+            Some(index) => {
+                let mut charge = charges.get(index as u32).unwrap();
+                charge.amount += new.amount;
+                charge.value += new.value;
+                charges.set(index as u32, charge);
             }
-            ProtocolEvent::TreasuryUpdated {
-                old_address,
-                new_address,
+            None => charges.push_back(new),
+        }
+    }
+
+    fn save_charges(env: &Env, user: &Address, sub_account: u32, charges: &Vec<IsolationCharge>) {
+        let key = DataKey::IsolationDebt(user.clone(), sub_account);
+        if charges.is_empty() {
+            UserStorage::remove(env, &key);
+        } else {
+            UserStorage::set(env, &key, charges);
+        }
+    }
+}
+
+/// Single rounds of the leverage and deleverage loops. Neither checks account health;
+/// callers check it once the loop is done.
+pub struct Leverage;
+
+impl Leverage {
+    /// Smallest output accepted for swapping `amount` of `from` into `to`: the oracle
+    /// conversion less `max_slippage` basis points
+    fn min_out(
+        env: &Env,
+        from: &AssetInfo,
+        to: &AssetInfo,
+        amount: i128,
+        max_slippage: i128,
+    ) -> Result<i128, ProtocolError> {
+        Ok(StateHelper::convert_amount(env, from, to, amount)? * (BPS_ONE - max_slippage) / BPS_ONE)
+    }
+
+    /// Borrow `amount` of the debt asset for the user, swap it through `adapter` and
+    /// deposit the proceeds as collateral; returns the collateral deposited
+    pub fn borrow_and_deposit(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        adapter: &Address,
+        debt_info: &AssetInfo,
+        collateral_info: &AssetInfo,
+        amount: i128,
+        max_slippage: i128,
+    ) -> Result<i128, ProtocolError> {
+        Self::borrow(env, user, sub_account, debt_info, amount)?;
+        let received = Self::swap(env, adapter, debt_info, collateral_info, amount, max_slippage)?;
+        // The debt just taken on counts toward the ceiling of isolated collateral it buys
+        Self::deposit(env, user, sub_account, collateral_info, received)?;
+        Ok(received)
+    }
+
+    /// Flash-borrow `amount` of the debt asset's liquidity, swap it through `adapter`,
+    /// deposit the proceeds as collateral and borrow the principal plus the flash loan fee
+    /// against it to repay the flash loan. The fee goes to reserves as with `flash_loan`.
+    /// Returns the collateral deposited and the debt taken on.
+    pub fn flash_and_deposit(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        adapter: &Address,
+        debt_info: &AssetInfo,
+        collateral_info: &AssetInfo,
+        amount: i128,
+        max_slippage: i128,
+    ) -> Result<(i128, i128), ProtocolError> {
+        let debt_asset = &debt_info.symbol;
+        let state = InterestRateStorage::update_asset_state(env, debt_asset);
+        if amount > state.total_supplied - state.total_borrowed {
+            return Err(ProtocolError::InsufficientLiquidity);
+        }
+        let fee = ReserveStorage::flash_loan_fee_for(env, amount);
+
+        let received = Self::swap(env, adapter, debt_info, collateral_info, amount, max_slippage)?;
+        Self::deposit(env, user, sub_account, collateral_info, received)?;
+        Self::borrow(env, user, sub_account, debt_info, amount + fee)?;
+
+        ProtocolEvent::FlashLoan {
+            receiver: user.to_string(),
+            asset: debt_asset.clone(),
+            amount,
+            fee,
+        }
+        .emit(env);
+        ReserveStorage::collect_flash_loan_fee(env, debt_asset, fee);
+        Ok((received, amount + fee))
+    }
+
+    /// Record a variable-rate borrow of `amount` on the user's position, collecting borrow
+    /// fees as `borrow` does; the tokens stay in the pool
+    fn borrow(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        debt_info: &AssetInfo,
+        amount: i128,
+    ) -> Result<(), ProtocolError> {
+        let debt_asset = &debt_info.symbol;
+        check_aml(env, user, amount, "borrow")?;
+        let state = InterestRateStorage::update_asset_state(env, debt_asset);
+        let (position, _) =
+            StateHelper::plan_borrow(env, user, sub_account, debt_info, &state, amount, None)?;
+        IsolationMode::record_borrow(env, user, sub_account, debt_info, amount)?;
+        StateHelper::save_position(env, &position);
+        let mut ir_state = InterestRateStorage::get_asset_state(env, debt_asset);
+        ir_state.total_borrowed += amount;
+        InterestRateStorage::save_asset_state(env, debt_asset, &ir_state);
+        ReserveStorage::collect_borrow_fee(env, debt_asset, position.borrow_interest);
+        Ok(())
+    }
+
+    /// Credit `amount` of collateral the pool already holds to the user's position
+    fn deposit(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        collateral_info: &AssetInfo,
+        amount: i128,
+    ) -> Result<(), ProtocolError> {
+        let collateral_asset = &collateral_info.symbol;
+        let state = InterestRateStorage::update_asset_state(env, collateral_asset);
+        collateral_info.check_supply_cap(&state, amount)?;
+        IsolationMode::record_collateral_added(env, user, sub_account, collateral_info)?;
+        let mut position =
+            StateHelper::accrued_position(env, user, sub_account, collateral_asset, &state);
+        position.collateral += amount;
+        StateHelper::save_position(env, &position);
+        let mut ir_state = InterestRateStorage::get_asset_state(env, collateral_asset);
+        ir_state.total_collateral += amount;
+        InterestRateStorage::save_asset_state(env, collateral_asset, &ir_state);
+        Ok(())
+    }
+
+    /// Swap `amount` of the pool's debt asset into the collateral asset through `adapter`
+    fn swap(
+        env: &Env,
+        adapter: &Address,
+        debt_info: &AssetInfo,
+        collateral_info: &AssetInfo,
+        amount: i128,
+        max_slippage: i128,
+    ) -> Result<i128, ProtocolError> {
+        TokenHelper::swap(
+            env,
+            adapter,
+            &AssetStorage::get_token_address(env, &debt_info.symbol)?,
+            &AssetStorage::get_token_address(env, &collateral_info.symbol)?,
+            amount,
+            Self::min_out(env, debt_info, collateral_info, amount, max_slippage)?,
+        )
+    }
+
+    /// Withdraw `amount` of the user's collateral, swap it through `adapter` and repay
+    /// debt with the proceeds; anything left after the debt is paid off goes to the user.
+    /// Returns the debt repaid.
+    pub fn withdraw_and_repay(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        adapter: &Address,
+        collateral_info: &AssetInfo,
+        debt_info: &AssetInfo,
+        amount: i128,
+        max_slippage: i128,
+    ) -> Result<i128, ProtocolError> {
+        let debt_asset = &debt_info.symbol;
+        let collateral_asset = &collateral_info.symbol;
+        let state = InterestRateStorage::update_asset_state(env, collateral_asset);
+        let (position, _) =
+            StateHelper::plan_withdraw(env, user, sub_account, collateral_asset, &state, amount)?;
+        StateHelper::save_position(env, &position);
+        let mut ir_state = InterestRateStorage::get_asset_state(env, collateral_asset);
+        ir_state.total_collateral -= amount;
+        InterestRateStorage::save_asset_state(env, collateral_asset, &ir_state);
+
+        let debt_token = AssetStorage::get_token_address(env, debt_asset)?;
+        let received = TokenHelper::swap(
+            env,
+            adapter,
+            &AssetStorage::get_token_address(env, collateral_asset)?,
+            &debt_token,
+            amount,
+            Self::min_out(env, collateral_info, debt_info, amount, max_slippage)?,
+        )?;
+
+        let state = InterestRateStorage::update_asset_state(env, debt_asset);
+        let (position, repaid, stable_repaid) =
+            StateHelper::plan_repay(env, user, sub_account, debt_asset, &state, received);
+        StateHelper::save_position(env, &position);
+        let mut ir_state = InterestRateStorage::get_asset_state(env, debt_asset);
+        ir_state.total_borrowed -= repaid;
+        InterestRateManager::decrease_stable_total(&mut ir_state, stable_repaid, position.stable_rate);
+        InterestRateStorage::save_asset_state(env, debt_asset, &ir_state);
+        IsolationMode::record_repay(env, user, sub_account, debt_info, repaid);
+        if received > repaid {
+            TokenHelper::transfer_out(env, &debt_token, user, received - repaid);
+        }
+        Ok(repaid)
+    }
+}
+
+/// Risk snapshot of an account across all of its asset positions, valued in the quote currency
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct AccountHealth {
+    /// Total collateral value (scaled by 1e8)
+    pub collateral_value: i128,
+    /// Collateral value weighted by each asset's LTV; debt may not be raised above it
+    pub borrow_limit: i128,
+    /// Collateral value weighted by each asset's liquidation threshold
+    pub liquidation_limit: i128,
+    /// Total debt value, including accrued interest (scaled by 1e8)
+    pub debt_value: i128,
+    /// `liquidation_limit / debt_value` scaled by `HEALTH_FACTOR_ONE`; `i128::MAX` without
+    /// debt. Below one the account can be liquidated.
+    pub health_factor: i128,
+    /// Efficiency-mode category whose parameters applied (0 = none)
+    pub emode_category: u32,
+}
+
+impl AccountHealth {
+    /// Whether the account's debt fits within its LTV borrow limit
+    pub fn within_borrow_limit(&self) -> bool {
+        self.debt_value <= self.borrow_limit
+    }
+}
+
+/// Outcome of a borrow, withdrawal or repayment, computed without executing it
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct PositionPreview {
+    /// Collateral left in the asset
+    pub collateral: i128,
+    /// Debt left in the asset, including accrued interest
+    pub debt: i128,
+    /// Account health factor afterwards (scaled by 1e8)
+    pub health_factor: i128,
+    /// Asset utilization and rates once they reflect the change (scaled by 1e8)
+    pub utilization_rate: i128,
+    pub borrow_rate: i128,
+    pub supply_rate: i128,
+}
+
+impl PositionPreview {
+    /// Preview of `position` in an asset whose accrued `state` already carries the
+    /// change in totals
+    pub fn new(
+        env: &Env,
+        position: &AssetPosition,
+        health: &AccountHealth,
+        mut state: InterestRateState,
+    ) -> Self {
+        let config = InterestRateStorage::get_asset_config(env, &position.asset);
+        InterestRateManager::refresh_rates(&mut state, &config);
+        Self {
+            collateral: position.collateral,
+            debt: InterestRateManager::position_debt(position, state.borrow_index),
+            health_factor: health.health_factor,
+            utilization_rate: state.utilization_rate,
+            borrow_rate: state.current_borrow_rate,
+            supply_rate: state.current_supply_rate,
+        }
+    }
+}
+
+/// Live totals of one asset market, in the asset's own units
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct AssetStats {
+    pub asset: String,
+    /// Borrower collateral held
+    pub total_collateral: i128,
+    /// Outstanding debt, including accrued interest
+    pub total_debt: i128,
+    /// Reserve-factor share of borrow interest retained by the pool
+    pub total_reserves: i128,
+    /// Lender liquidity not lent out
+    pub available_liquidity: i128,
+    /// Positions holding collateral, debt or supply; a user has one per sub-account
+    pub open_positions: u32,
+}
+
+/// Protocol-wide totals: every asset market plus their aggregate value in the quote
+/// currency (scaled by 1e8)
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct SystemStats {
+    pub assets: Vec<AssetStats>,
+    pub total_collateral_value: i128,
+    pub total_debt_value: i128,
+    pub total_reserves_value: i128,
+    pub available_liquidity_value: i128,
+    /// Distinct users holding an open position in any asset
+    pub active_users: u32,
+}
+
+impl SystemStats {
+    /// Totals of one asset, with interest projected to the current ledger time
+    pub fn for_asset(env: &Env, asset: &String) -> AssetStats {
+        let mut state = InterestRateStorage::get_asset_state(env, asset);
+        InterestRateManager::accrue_indexes(&mut state, env.ledger().timestamp());
+        AssetStats {
+            asset: asset.clone(),
+            total_collateral: state.total_collateral,
+            total_debt: state.total_borrowed,
+            total_reserves: state.total_reserves,
+            available_liquidity: (state.total_supplied - state.total_borrowed).max(0),
+            open_positions: AssetStorage::open_positions(env, asset),
+        }
+    }
+
+    /// Totals of every listed asset and their aggregate value
+    pub fn collect(env: &Env) -> Result<Self, ProtocolError> {
+        let mut stats = SystemStats {
+            assets: Vec::new(env),
+            total_collateral_value: 0,
+            total_debt_value: 0,
+            total_reserves_value: 0,
+            available_liquidity_value: 0,
+            active_users: AssetStorage::active_users(env),
+        };
+        for asset in AssetStorage::get_registry(env).supported_assets.iter() {
+            let info = match AssetStorage::get_asset_info(env, &asset) {
+                Some(info) => info,
+                None => continue,
+            };
+            let asset_stats = Self::for_asset(env, &asset);
+            stats.total_collateral_value +=
+                StateHelper::asset_value(env, &info, asset_stats.total_collateral)?;
+            stats.total_debt_value += StateHelper::asset_value(env, &info, asset_stats.total_debt)?;
+            stats.total_reserves_value +=
+                StateHelper::asset_value(env, &info, asset_stats.total_reserves)?;
+            stats.available_liquidity_value +=
+                StateHelper::asset_value(env, &info, asset_stats.available_liquidity)?;
+            stats.assets.push_back(asset_stats);
+        }
+        Ok(stats)
+    }
+}
+
+/// Cumulative bad debt written off in an asset
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct BadDebtStats {
+    /// Debt written off from accounts left without collateral
+    pub total_bad_debt: i128,
+    /// Part of it absorbed by the protocol's reserves
+    pub covered_by_reserves: i128,
+    /// Part of it socialized across suppliers by writing down the supply index
+    pub socialized: i128,
+}
+
+/// Realizes debt left on accounts whose collateral has been exhausted. Protocol
+/// reserves act as the insurance fund; any loss beyond them is socialized across the
+/// asset's suppliers.
+pub struct BadDebtManager;
+
+impl BadDebtManager {
+    pub fn get_stats(env: &Env, asset: &String) -> BadDebtStats {
+        env.storage()
+            .instance()
+            .get(&DataKey::BadDebt(asset.clone()))
+            .unwrap_or(BadDebtStats {
+                total_bad_debt: 0,
+                covered_by_reserves: 0,
+                socialized: 0,
+            })
+    }
+
+    fn save_stats(env: &Env, asset: &String, stats: &BadDebtStats) {
+        env.storage()
+            .instance()
+            .set(&DataKey::BadDebt(asset.clone()), stats);
+    }
+
+    /// Write off every debt of a sub-account that has no collateral left in any asset,
+    /// including the fixed-term loans of a main account; returns whether any debt was realized
+    pub fn realize_if_insolvent(env: &Env, user: &Address, sub_account: u32) -> bool {
+        let assets = AssetStorage::get_registry(env).supported_assets;
+        for asset in assets.iter() {
+            if let Some(position) = StateHelper::get_sub_position(env, user, sub_account, &asset) {
+                if position.collateral > 0 {
+                    return false;
+                }
+            }
+        }
+
+        let mut realized = false;
+        for asset in assets.iter() {
+            let mut position = match StateHelper::get_sub_position(env, user, sub_account, &asset) {
+                Some(position) if position.scaled_debt > 0 || position.stable_debt > 0 => position,
+                _ => continue,
+            };
+            let state = InterestRateStorage::update_asset_state(env, &asset);
+            InterestRateManager::accrue_interest_for_position(
+                env,
+                &mut position,
+                state.borrow_index,
+                state.supply_index,
+            );
+            let debt = InterestRateManager::position_debt(&position, state.borrow_index);
+            if position.stable_debt > 0 {
+                let mut state = InterestRateStorage::get_asset_state(env, &asset);
+                InterestRateManager::decrease_stable_total(&mut state, position.stable_debt, position.stable_rate);
+                InterestRateStorage::save_asset_state(env, &asset, &state);
+            }
+            position.scaled_debt = 0;
+            position.stable_debt = 0;
+            StateHelper::save_position(env, &position);
+            Self::absorb(env, user, &asset, debt);
+            realized = true;
+        }
+        if sub_account != MAIN_ACCOUNT {
+            if realized {
+                IsolationMode::release_all(env, user, sub_account);
+            }
+            return realized;
+        }
+        for mut loan in LoanStorage::open_loans(env, user).iter() {
+            InterestRateStorage::update_asset_state(env, &loan.asset);
+            loan.accrue(env.ledger().timestamp());
+            let debt = loan.debt;
+            let mut state = InterestRateStorage::get_asset_state(env, &loan.asset);
+            InterestRateManager::decrease_stable_total(&mut state, debt, loan.rate);
+            InterestRateStorage::save_asset_state(env, &loan.asset, &state);
+            loan.debt = 0;
+            loan.status = LoanStatus::WrittenOff;
+            LoanStorage::save_loan(env, &loan);
+            Self::absorb(env, user, &loan.asset, debt);
+            realized = true;
+        }
+        if realized {
+            IsolationMode::release_all(env, user, sub_account);
+        }
+        realized
+    }
+
+    /// Take `amount` of written-off debt off the books, covering it from the asset's own
+    /// reserves first and writing down the supply index for the rest
+    fn absorb(env: &Env, user: &Address, asset: &String, amount: i128) {
+        let mut state = InterestRateStorage::get_asset_state(env, asset);
+        let covered = amount.min(state.total_reserves.max(0));
+        state.total_borrowed = (state.total_borrowed - amount).max(0);
+        state.total_reserves -= covered;
+
+        // The protocol-wide reserve tally follows the asset's
+        let mut reserve_data = ReserveStorage::get_reserve_data(env);
+        reserve_data.current_reserves = (reserve_data.current_reserves - covered).max(0);
+        ReserveStorage::save_reserve_data(env, &reserve_data);
+        let socialized = (amount - covered).min(state.total_supplied);
+        if socialized > 0 {
+            state.supply_index =
+                state.supply_index * (state.total_supplied - socialized) / state.total_supplied;
+            state.total_supplied -= socialized;
+        }
+        InterestRateStorage::save_asset_state(env, asset, &state);
+
+        let mut stats = Self::get_stats(env, asset);
+        stats.total_bad_debt += amount;
+        stats.covered_by_reserves += covered;
+        stats.socialized += socialized;
+        Self::save_stats(env, asset, &stats);
+
+        ProtocolEvent::BadDebtRealized {
+            user: user.to_string(),
+            asset: asset.clone(),
+            amount,
+            covered_by_reserves: covered,
+            socialized,
+        }
+        .emit(env);
+    }
+}
+
+/// Helper for moving SEP-41 tokens between users and the protocol
+pub struct TokenHelper;
+
+impl TokenHelper {
+    /// Token contract of the registry's default asset, used by the single-asset entrypoints
+    pub fn default_token(env: &Env) -> Result<Address, ProtocolError> {
+        let registry = AssetStorage::get_registry(env);
+        AssetStorage::get_token_address(env, &registry.default_asset)
+    }
+
+    /// Pull `amount` of `token` from `from` into the contract
+    pub fn transfer_in(env: &Env, token: &Address, from: &Address, amount: i128) {
+        token::Client::new(env, token).transfer(from, &env.current_contract_address(), &amount);
+    }
+
+    /// Send `amount` of `token` from the contract to `to`
+    pub fn transfer_out(env: &Env, token: &Address, to: &Address, amount: i128) {
+        token::Client::new(env, token).transfer(&env.current_contract_address(), to, &amount);
+    }
+
+    /// sToken contract of the registry's default asset
+    pub fn default_stoken(env: &Env) -> Result<Address, ProtocolError> {
+        let registry = AssetStorage::get_registry(env);
+        AssetStorage::get_stoken_address(env, &registry.default_asset)
+    }
+
+    /// Mint `shares` of `stoken` to `to`
+    pub fn mint_shares(env: &Env, stoken: &Address, to: &Address, shares: i128) {
+        STokenClient::new(env, stoken).mint(to, &shares);
+    }
+
+    /// Burn `shares` of `stoken` held by `from`
+    pub fn burn_shares(env: &Env, stoken: &Address, from: &Address, shares: i128) {
+        STokenClient::new(env, stoken).pool_burn(from, &shares);
+    }
+
+    /// Swap `amount_in` of the pool's `token_in` for `token_out` through a swap adapter;
+    /// returns the amount the pool actually received, which must be at least `min_out`
+    pub fn swap(
+        env: &Env,
+        adapter: &Address,
+        token_in: &Address,
+        token_out: &Address,
+        amount_in: i128,
+        min_out: i128,
+    ) -> Result<i128, ProtocolError> {
+        // Only approved adapters may be paid, since their output is credited by balance
+        if !SwapAdapters::is_allowed(env, adapter) {
+            return Err(ProtocolError::Unauthorized);
+        }
+        let pool = env.current_contract_address();
+        let out_client = token::Client::new(env, token_out);
+        let balance_before = out_client.balance(&pool);
+        Self::transfer_out(env, token_in, adapter, amount_in);
+        SwapAdapterClient::new(env, adapter).swap(token_in, token_out, &amount_in, &min_out, &pool);
+        let received = out_client.balance(&pool) - balance_before;
+        if received < min_out {
+            return Err(ProtocolError::SlippageExceeded);
+        }
+        Ok(received)
+    }
+}
+
+/// Pool-side interface of the sToken contracts (`contracts/s-token`)
+#[contractclient(name = "STokenClient")]
+pub trait STokenInterface {
+    /// Mint shares to `to`; only the pool may call this
+    fn mint(env: Env, to: Address, amount: i128);
+    /// Burn shares held by `from`; only the pool may call this
+    fn pool_burn(env: Env, from: Address, amount: i128);
+}
+
+/// Interface a swap adapter implements so the pool can exchange one asset for another
+/// through an external venue
+#[contractclient(name = "SwapAdapterClient")]
+pub trait SwapAdapter {
+    /// Swap `amount_in` of `token_in`, already sent to the adapter, for at least `min_out`
+    /// of `token_out` sent to `to`; returns the amount sent
+    fn swap(
+        env: Env,
+        token_in: Address,
+        token_out: Address,
+        amount_in: i128,
+        min_out: i128,
+        to: Address,
+    ) -> i128;
+}
+
+/// Swap adapters the admin has approved for collateral swaps and leverage
+pub struct SwapAdapters;
+
+impl SwapAdapters {
+    fn key() -> DataKey {
+        DataKey::SwapAdapters
+    }
+
+    pub fn list(env: &Env) -> Vec<Address> {
+        env.storage()
+            .instance()
+            .get(&Self::key())
+            .unwrap_or_else(|| Vec::new(env))
+    }
+
+    pub fn is_allowed(env: &Env, adapter: &Address) -> bool {
+        Self::list(env).contains(adapter)
+    }
+
+    pub fn set(env: &Env, adapter: &Address, allowed: bool) {
+        let mut adapters = Self::list(env);
+        match (adapters.first_index_of(adapter), allowed) {
+            (None, true) => adapters.push_back(adapter.clone()),
+            (Some(index), false) => {
+                adapters.remove(index);
+            }
+            _ => return,
+        }
+        env.storage().instance().set(&Self::key(), &adapters);
+    }
+}
+
+/// Interface a contract implements to receive flash loans
+#[contractclient(name = "FlashLoanReceiverClient")]
+pub trait FlashLoanReceiver {
+    /// Called after `amount` of `token` (the pool's `asset`) has been sent to the receiver.
+    /// Before returning, the receiver must transfer `amount + fee` of `token` back to
+    /// `pool`, and return true to accept the loan.
+    fn on_flash_loan(
+        env: Env,
+        pool: Address,
+        asset: String,
+        token: Address,
+        amount: i128,
+        fee: i128,
+        params: Bytes,
+    ) -> bool;
+}
+
+/// Event types for protocol actions
+pub enum ProtocolEvent {
+    Deposit {
+        user: String,
+        amount: i128,
+        asset: String,
+    },
+    Borrow {
+        user: String,
+        amount: i128,
+        asset: String,
+    },
+    Repay {
+        user: String,
+        amount: i128,
+        asset: String,
+    },
+    Withdraw {
+        user: String,
+        amount: i128,
+        asset: String,
+    },
+    FlashLoan {
+        receiver: String,
+        asset: String,
+        amount: i128,
+        fee: i128,
+    },
+    /// `amount` of the user's `asset` debt was repaid by `liquidator` in exchange for
+    /// `collateral_seized` of `collateral_asset`, split between the liquidator and the
+    /// protocol's fee
+    Liquidate {
+        user: String,
+        amount: i128,
+        asset: String,
+        liquidator: Address,
+        collateral_asset: String,
+        collateral_seized: i128,
+        liquidator_amount: i128,
+        protocol_fee: i128,
+    },
+    /// `amount` of the user's `asset` debt was written off after their collateral ran
+    /// out; reserves covered part of it and the rest was socialized across suppliers
+    BadDebtRealized {
+        user: String,
+        asset: String,
+        amount: i128,
+        covered_by_reserves: i128,
+        socialized: i128,
+    },
+    /// The user opted into efficiency-mode `category` (0 = opted out)
+    UserEModeSet {
+        user: Address,
+        category: u32,
+    },
+    /// The user swapped `amount_in` of `from_asset` collateral for `amount_out` of `to_asset`
+    CollateralSwapped {
+        user: Address,
+        from_asset: String,
+        to_asset: String,
+        amount_in: i128,
+        amount_out: i128,
+    },
+    /// A leverage or deleverage call moved the user's collateral and debt by the given
+    /// (signed) amounts
+    LeverageAdjusted {
+        user: Address,
+        collateral_asset: String,
+        debt_asset: String,
+        collateral_change: i128,
+        debt_change: i128,
+    },
+    /// The user moved `amount` of `asset` collateral between two of their sub-accounts
+    CollateralMoved {
+        user: Address,
+        asset: String,
+        from_sub_account: u32,
+        to_sub_account: u32,
+        amount: i128,
+    },
+    /// The payer repaid `amount` of the borrower's `asset` debt
+    RepaidOnBehalf {
+        payer: Address,
+        borrower: Address,
+        asset: String,
+        amount: i128,
+    },
+    /// The delegator let the delegatee borrow up to `amount` of `asset` against their collateral
+    BorrowAllowanceSet {
+        delegator: Address,
+        delegatee: Address,
+        asset: String,
+        amount: i128,
+    },
+    /// A fixed-term loan of `principal` at `rate` was taken out, due at `maturity`
+    LoanOriginated {
+        loan_id: u32,
+        borrower: Address,
+        asset: String,
+        principal: i128,
+        rate: i128,
+        maturity: u64,
+    },
+    /// A fixed-term loan reached maturity with `amount_due` still unpaid
+    LoanMatured {
+        loan_id: u32,
+        borrower: Address,
+        amount_due: i128,
+    },
+    /// A fixed-term loan was still unpaid after its grace period and can be liquidated
+    LoanDefaulted {
+        loan_id: u32,
+        borrower: Address,
+        amount_due: i128,
+    },
+    Supply {
+        user: String,
+        amount: i128,
+        asset: String,
+    },
+    Redeem {
+        user: String,
+        amount: i128,
+        asset: String,
+    },
+    InterestAccrued {
+        user: String,
+        borrow_interest: i128,
+        supply_interest: i128,
+        asset: String,
+    },
+    RateUpdated {
+        borrow_rate: i128,
+        supply_rate: i128,
+        utilization: i128,
+        asset: String,
+    },
+    ConfigUpdated {
+        parameter: String,
+        old_value: i128,
+        new_value: i128,
+    },
+    FeesCollected {
+        amount: i128,
+        source: String,
+    },
+    FeesDistributed {
+        amount: i128,
+        treasury: String,
+    },
+    TreasuryUpdated {
+        old_address: String,
+        new_address: String,
+    },
+    ReserveUpdated {
+        total_collected: i128,
+        current_reserves: i128,
+    },
+    AssetAdded {
+        asset: String,
+        symbol: String,
+        decimals: u32,
+    },
+    AssetUpdated {
+        asset: String,
+        parameter: String,
+        old_value: String,
+        new_value: String,
+    },
+    AssetDisabled {
+        asset: String,
+        reason: String,
+    },
+    UserActivityTracked {
+        user: String,
+        action: String,
+        amount: i128,
+        timestamp: u64,
+    },
+    ProtocolStatsUpdated {
+        total_users: u32,
+        active_users_24h: u32,
+        total_transactions: u32,
+    },
+    AccountFrozen {
+        user: String,
+    },
+    AccountUnfrozen {
+        user: String,
+    },
+    // Social Recovery and Multi-Signature events
+    GuardianAdded {
+        user: String,
+        guardian: String,
+        name: String,
+        weight: u32,
+    },
+    GuardianRemoved {
+        user: String,
+        guardian: String,
+    },
+    RecoveryRequestCreated {
+        request_id: u32,
+        user: String,
+        new_address: String,
+        required_approvals: u32,
+        time_delay: u64,
+    },
+    RecoveryRequestApproved {
+        request_id: u32,
+        guardian: String,
+        current_approvals: u32,
+    },
+    RecoveryRequestExecuted {
+        request_id: u32,
+        user: String,
+        new_address: String,
+    },
+    RecoveryRequestCancelled {
+        request_id: u32,
+        user: String,
+    },
+    MultiSigProposalCreated {
+        proposal_id: u32,
+        creator: String,
+        description: String,
+        target_function: String,
+        required_approvals: u32,
+        time_delay: u64,
+    },
+    MultiSigProposalApproved {
+        proposal_id: u32,
+        signer: String,
+        current_approvals: u32,
+    },
+    MultiSigProposalExecuted {
+        proposal_id: u32,
+        creator: String,
+        target_function: String,
+    },
+    MultiSigProposalCancelled {
+        proposal_id: u32,
+        creator: String,
+    },
+    MultiSigSignerAdded {
+        signer: String,
+        added_by: String,
+    },
+    MultiSigSignerRemoved {
+        signer: String,
+        removed_by: String,
+    },
+}
+
+impl ProtocolEvent {
+    /// Emit the event using Soroban's event system
+    pub fn emit(&self, env: &Env) {
+        match self {
+            ProtocolEvent::Deposit {
+                user: _,
+                amount,
+                asset,
+            } => {
+                env.events().publish(
+                    (symbol_short!("deposit"), symbol_short!("user")),
+                    (
+                        symbol_short!("user"),
+                        *amount,
+                        symbol_short!("asset"),
+                        asset.clone(),
+                    ),
+                );
+            }
+            ProtocolEvent::Borrow {
+                user: _,
+                amount,
+                asset,
+            } => {
+                env.events().publish(
+                    (symbol_short!("borrow"), symbol_short!("user")),
+                    (
+                        symbol_short!("user"),
+                        *amount,
+                        symbol_short!("asset"),
+                        asset.clone(),
+                    ),
+                );
+            }
+            ProtocolEvent::Repay {
+                user: _,
+                amount,
+                asset,
+            } => {
+                env.events().publish(
+                    (symbol_short!("repay"), symbol_short!("user")),
+                    (
+                        symbol_short!("user"),
+                        *amount,
+                        symbol_short!("asset"),
+                        asset.clone(),
+                    ),
+                );
+            }
+            ProtocolEvent::Withdraw {
+                user: _,
+                amount,
+                asset,
+            } => {
+                env.events().publish(
+                    (symbol_short!("withdraw"), symbol_short!("user")),
+                    (
+                        symbol_short!("user"),
+                        *amount,
+                        symbol_short!("asset"),
+                        asset.clone(),
+                    ),
+                );
+            }
+            ProtocolEvent::Supply {
+                user: _,
+                amount,
+                asset,
+            } => {
+                env.events().publish(
+                    (symbol_short!("supply"), symbol_short!("user")),
+                    (
+                        symbol_short!("user"),
+                        *amount,
+                        symbol_short!("asset"),
+                        asset.clone(),
+                    ),
+                );
+            }
+            ProtocolEvent::Redeem {
+                user: _,
+                amount,
+                asset,
+            } => {
+                env.events().publish(
+                    (symbol_short!("redeem"), symbol_short!("user")),
+                    (
+                        symbol_short!("user"),
+                        *amount,
+                        symbol_short!("asset"),
+                        asset.clone(),
+                    ),
+                );
+            }
+            ProtocolEvent::Liquidate {
+                user: _,
+                amount,
+                asset,
+                liquidator,
+                collateral_asset,
+                collateral_seized,
+                liquidator_amount,
+                protocol_fee,
+            } => {
+                env.events().publish(
+                    (symbol_short!("liquidate"), symbol_short!("user"), liquidator.clone()),
+                    (
+                        symbol_short!("user"),
+                        *amount,
+                        symbol_short!("asset"),
+                        asset.clone(),
+                        symbol_short!("seized"),
+                        *collateral_seized,
+                        symbol_short!("col_asset"),
+                        collateral_asset.clone(),
+                        symbol_short!("liq_share"),
+                        *liquidator_amount,
+                        symbol_short!("fee"),
+                        *protocol_fee,
+                    ),
+                );
+            }
+            ProtocolEvent::FlashLoan {
+                receiver,
+                asset,
+                amount,
+                fee,
+            } => {
+                env.events().publish(
+                    (symbol_short!("flash"), symbol_short!("receiver")),
+                    (
+                        receiver.clone(),
+                        symbol_short!("asset"),
+                        asset.clone(),
+                        *amount,
+                        symbol_short!("fee"),
+                        *fee,
+                    ),
+                );
+            }
+            ProtocolEvent::BadDebtRealized {
+                user,
+                asset,
+                amount,
+                covered_by_reserves,
+                socialized,
+            } => {
+                env.events().publish(
+                    (symbol_short!("bad_debt"), symbol_short!("user")),
+                    (
+                        user.clone(),
+                        symbol_short!("asset"),
+                        asset.clone(),
+                        *amount,
+                        symbol_short!("covered"),
+                        *covered_by_reserves,
+                        symbol_short!("social"),
+                        *socialized,
+                    ),
+                );
+            }
+            ProtocolEvent::UserEModeSet { user, category } => {
+                env.events().publish(
+                    (symbol_short!("emode"), symbol_short!("user")),
+                    (user.clone(), symbol_short!("category"), *category),
+                );
+            }
+            ProtocolEvent::CollateralSwapped {
+                user,
+                from_asset,
+                to_asset,
+                amount_in,
+                amount_out,
+            } => {
+                env.events().publish(
+                    (symbol_short!("swap"), user.clone()),
+                    (from_asset.clone(), *amount_in, to_asset.clone(), *amount_out),
+                );
+            }
+            ProtocolEvent::LeverageAdjusted {
+                user,
+                collateral_asset,
+                debt_asset,
+                collateral_change,
+                debt_change,
+            } => {
+                env.events().publish(
+                    (symbol_short!("leverage"), user.clone()),
+                    (
+                        collateral_asset.clone(),
+                        *collateral_change,
+                        debt_asset.clone(),
+                        *debt_change,
+                    ),
+                );
+            }
+            ProtocolEvent::CollateralMoved {
+                user,
+                asset,
+                from_sub_account,
+                to_sub_account,
+                amount,
+            } => {
+                env.events().publish(
+                    (symbol_short!("sub_move"), user.clone()),
+                    (asset.clone(), *from_sub_account, *to_sub_account, *amount),
+                );
+            }
+            ProtocolEvent::RepaidOnBehalf {
+                payer,
+                borrower,
+                asset,
+                amount,
+            } => {
+                env.events().publish(
+                    (symbol_short!("repay"), symbol_short!("behalf")),
+                    (payer.clone(), borrower.clone(), asset.clone(), *amount),
+                );
+            }
+            ProtocolEvent::BorrowAllowanceSet {
+                delegator,
+                delegatee,
+                asset,
+                amount,
+            } => {
+                env.events().publish(
+                    (symbol_short!("delegate"), delegator.clone()),
+                    (delegatee.clone(), asset.clone(), *amount),
+                );
+            }
+            ProtocolEvent::LoanOriginated {
+                loan_id,
+                borrower,
+                asset,
+                principal,
+                rate,
+                maturity,
+            } => {
+                env.events().publish(
+                    (symbol_short!("loan_orig"), *loan_id),
+                    (
+                        borrower.clone(),
+                        asset.clone(),
+                        *principal,
+                        symbol_short!("rate"),
+                        *rate,
+                        symbol_short!("maturity"),
+                        *maturity,
+                    ),
+                );
+            }
+            ProtocolEvent::LoanMatured {
+                loan_id,
+                borrower,
+                amount_due,
+            } => {
+                env.events().publish(
+                    (symbol_short!("loan_mat"), *loan_id),
+                    (borrower.clone(), symbol_short!("due"), *amount_due),
+                );
+            }
+            ProtocolEvent::LoanDefaulted {
+                loan_id,
+                borrower,
+                amount_due,
+            } => {
+                env.events().publish(
+                    (symbol_short!("loan_dflt"), *loan_id),
+                    (borrower.clone(), symbol_short!("due"), *amount_due),
+                );
+            }
+            ProtocolEvent::InterestAccrued {
+                user: _,
+                borrow_interest,
+                supply_interest,
+                asset,
+            } => {
+                env.events().publish(
+                    (symbol_short!("int_accr"), symbol_short!("user")),
+                    (
+                        symbol_short!("brw_int"),
+                        *borrow_interest,
+                        symbol_short!("sup_int"),
+                        *supply_interest,
+                        symbol_short!("asset"),
+                        asset.clone(),
+                    ),
+                );
+            }
+            ProtocolEvent::RateUpdated {
+                borrow_rate: _,
+                supply_rate,
+                utilization,
+                asset,
+            } => {
+                env.events().publish(
+                    (symbol_short!("rate_upd"), symbol_short!("brw_rate")),
+                    (
+                        symbol_short!("sup_rate"),
+                        *supply_rate,
+                        symbol_short!("util"),
+                        *utilization,
+                        symbol_short!("asset"),
+                        asset.clone(),
+                    ),
+                );
+            }
+            ProtocolEvent::ConfigUpdated {
+                parameter: _,
+                old_value,
+                new_value,
+            } => {
+                env.events().publish(
+                    (symbol_short!("cfg_upd"), symbol_short!("parameter")),
+                    (
+                        symbol_short!("old_value"),
+                        *old_value,
+                        symbol_short!("new_value"),
+                        *new_value,
+                    ),
+                );
+            }
+            ProtocolEvent::FeesCollected { amount: _, source } => {
+                env.events().publish(
+                    (symbol_short!("fees_coll"), symbol_short!("amount")),
+                    (symbol_short!("source"), source.clone()),
+                );
+            }
+            ProtocolEvent::FeesDistributed { amount: _, treasury } => {
+                env.events().publish(
+                    (symbol_short!("fees_dist"), symbol_short!("amount")),
+                    (symbol_short!("treasury"), treasury.clone()),
+                );
+            }
+            ProtocolEvent::TreasuryUpdated {
+                old_address: _,
+                new_address,
             } => {
                 env.events().publish(
                     (
-                        Symbol::short("treasury_updated"),
-                        Symbol::short("old_address"),
+                        symbol_short!("trsy_upd"),
+                        symbol_short!("old_addr"),
                     ),
-                    (Symbol::short("new_address"), new_address.clone()),
+                    (symbol_short!("new_addr"), new_address.clone()),
                 );
             }
             ProtocolEvent::ReserveUpdated {
-                total_collected,
+                total_collected: _,
                 current_reserves,
             } => {
                 env.events().publish(
                     (
-                        Symbol::short("reserve_updated"),
-                        Symbol::short("total_collected"),
+                        symbol_short!("rsv_upd"),
+                        symbol_short!("collected"),
                     ),
-                    (Symbol::short("current_reserves"), *current_reserves),
+                    (symbol_short!("reserves"), *current_reserves),
                 );
             }
             ProtocolEvent::AssetAdded {
-                asset,
+                asset: _,
                 symbol,
                 decimals,
             } => {
                 env.events().publish(
-                    (Symbol::short("asset_added"), Symbol::short("asset")),
+                    (symbol_short!("asset_add"), symbol_short!("asset")),
                     (
-                        Symbol::short("symbol"),
+                        symbol_short!("symbol"),
                         symbol.clone(),
-                        Symbol::short("decimals"),
+                        symbol_short!("decimals"),
                         *decimals,
                     ),
                 );
             }
             ProtocolEvent::AssetUpdated {
-                asset,
+                asset: _,
                 parameter,
                 old_value,
                 new_value,
             } => {
                 env.events().publish(
-                    (Symbol::short("asset_updated"), Symbol::short("asset")),
+                    (symbol_short!("asset_upd"), symbol_short!("asset")),
                     (
-                        Symbol::short("parameter"),
+                        symbol_short!("parameter"),
                         parameter.clone(),
-                        Symbol::short("old_value"),
+                        symbol_short!("old_value"),
                         old_value.clone(),
-                        Symbol::short("new_value"),
+                        symbol_short!("new_value"),
                         new_value.clone(),
                     ),
                 );
             }
-            ProtocolEvent::AssetDisabled { asset, reason } => {
+            ProtocolEvent::AssetDisabled { asset: _, reason } => {
                 env.events().publish(
-                    (Symbol::short("asset_disabled"), Symbol::short("asset")),
-                    (Symbol::short("reason"), reason.clone()),
+                    (symbol_short!("asset_off"), symbol_short!("asset")),
+                    (symbol_short!("reason"), reason.clone()),
                 );
             }
             ProtocolEvent::UserActivityTracked {
-                user,
+                user: _,
                 action,
                 amount,
                 timestamp,
             } => {
                 env.events().publish(
-                    (Symbol::short("user_activity"), Symbol::short("user")),
+                    (symbol_short!("activity"), symbol_short!("user")),
                     (
-                        Symbol::short("action"),
+                        symbol_short!("action"),
                         action.clone(),
-                        Symbol::short("amount"),
+                        symbol_short!("amount"),
                         *amount,
-                        Symbol::short("timestamp"),
+                        symbol_short!("timestamp"),
                         *timestamp,
                     ),
                 );
             }
             ProtocolEvent::ProtocolStatsUpdated {
-                total_users,
+                total_users: _,
                 active_users_24h,
                 total_transactions,
             } => {
                 env.events().publish(
                     (
-                        Symbol::short("protocol_stats"),
-                        Symbol::short("total_users"),
+                        symbol_short!("stats"),
+                        symbol_short!("users"),
                     ),
                     (
-                        Symbol::short("active_users_24h"),
+                        symbol_short!("act_24h"),
                         *active_users_24h,
-                        Symbol::short("total_transactions"),
+                        symbol_short!("total_txs"),
                         *total_transactions,
                     ),
                 );
             }
             ProtocolEvent::AccountFrozen { user } => {
                 env.events().publish(
-                    (Symbol::short("account_frozen"), Symbol::short("user")),
-                    (Symbol::short("user"), user.clone()),
+                    (symbol_short!("frozen"), symbol_short!("user")),
+                    (symbol_short!("user"), user.clone()),
                 );
             }
             ProtocolEvent::AccountUnfrozen { user } => {
                 env.events().publish(
-                    (Symbol::short("account_unfrozen"), Symbol::short("user")),
-                    (Symbol::short("user"), user.clone()),
+                    (symbol_short!("unfrozen"), symbol_short!("user")),
+                    (symbol_short!("user"), user.clone()),
                 );
             }
             // Social Recovery and Multi-Signature events
-            ProtocolEvent::GuardianAdded { user, guardian, name, weight } => {
+            ProtocolEvent::GuardianAdded { user: _, guardian, name, weight } => {
+                env.events().publish(
+                    (symbol_short!("guard_add"), symbol_short!("user")),
+                    (symbol_short!("guardian"), guardian.clone(), symbol_short!("name"), name.clone(), symbol_short!("weight"), *weight),
+                );
+            }
+            ProtocolEvent::GuardianRemoved { user: _, guardian } => {
+                env.events().publish(
+                    (symbol_short!("guard_rm"), symbol_short!("user")),
+                    (symbol_short!("guardian"), guardian.clone()),
+                );
+            }
+            ProtocolEvent::RecoveryRequestCreated { request_id: _, user, new_address, required_approvals, time_delay } => {
+                env.events().publish(
+                    (symbol_short!("rec_new"), symbol_short!("req_id")),
+                    (symbol_short!("user"), user.clone(), symbol_short!("new_addr"), new_address.clone(), symbol_short!("required"), *required_approvals, symbol_short!("delay"), *time_delay),
+                );
+            }
+            ProtocolEvent::RecoveryRequestApproved { request_id: _, guardian, current_approvals } => {
+                env.events().publish(
+                    (symbol_short!("rec_appr"), symbol_short!("req_id")),
+                    (symbol_short!("guardian"), guardian.clone(), symbol_short!("approvals"), *current_approvals),
+                );
+            }
+            ProtocolEvent::RecoveryRequestExecuted { request_id: _, user, new_address } => {
+                env.events().publish(
+                    (symbol_short!("rec_exec"), symbol_short!("req_id")),
+                    (symbol_short!("user"), user.clone(), symbol_short!("new_addr"), new_address.clone()),
+                );
+            }
+            ProtocolEvent::RecoveryRequestCancelled { request_id: _, user } => {
+                env.events().publish(
+                    (symbol_short!("rec_cncl"), symbol_short!("req_id")),
+                    (symbol_short!("user"), user.clone()),
+                );
+            }
+            ProtocolEvent::MultiSigProposalCreated { proposal_id: _, creator, description, target_function, required_approvals, time_delay } => {
+                env.events().publish(
+                    (symbol_short!("ms_new"), symbol_short!("prop_id")),
+                    (symbol_short!("creator"), creator.clone(), symbol_short!("desc"), description.clone(), symbol_short!("target_fn"), target_function.clone(), symbol_short!("required"), *required_approvals, symbol_short!("delay"), *time_delay),
+                );
+            }
+            ProtocolEvent::MultiSigProposalApproved { proposal_id: _, signer, current_approvals } => {
                 env.events().publish(
-                    (Symbol::short("guardian_added"), Symbol::short("user")),
-                    (Symbol::short("guardian"), guardian.clone(), Symbol::short("name"), name.clone(), Symbol::short("weight"), *weight),
+                    (symbol_short!("ms_appr"), symbol_short!("prop_id")),
+                    (symbol_short!("signer"), signer.clone(), symbol_short!("approvals"), *current_approvals),
                 );
             }
-            ProtocolEvent::GuardianRemoved { user, guardian } => {
+            ProtocolEvent::MultiSigProposalExecuted { proposal_id: _, creator, target_function } => {
                 env.events().publish(
-                    (Symbol::short("guardian_removed"), Symbol::short("user")),
-                    (Symbol::short("guardian"), guardian.clone()),
+                    (symbol_short!("ms_exec"), symbol_short!("prop_id")),
+                    (symbol_short!("creator"), creator.clone(), symbol_short!("target_fn"), target_function.clone()),
                 );
             }
-            ProtocolEvent::RecoveryRequestCreated { request_id, user, new_address, required_approvals, time_delay } => {
+            ProtocolEvent::MultiSigProposalCancelled { proposal_id: _, creator } => {
                 env.events().publish(
-                    (Symbol::short("recovery_request_created"), Symbol::short("request_id")),
-                    (Symbol::short("user"), user.clone(), Symbol::short("new_address"), new_address.clone(), Symbol::short("required_approvals"), *required_approvals, Symbol::short("time_delay"), *time_delay),
+                    (symbol_short!("ms_cncl"), symbol_short!("prop_id")),
+                    (symbol_short!("creator"), creator.clone()),
                 );
             }
-            ProtocolEvent::RecoveryRequestApproved { request_id, guardian, current_approvals } => {
+            ProtocolEvent::MultiSigSignerAdded { signer: _, added_by } => {
                 env.events().publish(
-                    (Symbol::short("recovery_request_approved"), Symbol::short("request_id")),
-                    (Symbol::short("guardian"), guardian.clone(), Symbol::short("current_approvals"), *current_approvals),
+                    (symbol_short!("ms_add"), symbol_short!("signer")),
+                    (symbol_short!("added_by"), added_by.clone()),
                 );
             }
-            ProtocolEvent::RecoveryRequestExecuted { request_id, user, new_address } => {
+            ProtocolEvent::MultiSigSignerRemoved { signer: _, removed_by } => {
                 env.events().publish(
-                    (Symbol::short("recovery_request_executed"), Symbol::short("request_id")),
-                    (Symbol::short("user"), user.clone(), Symbol::short("new_address"), new_address.clone()),
+                    (symbol_short!("ms_rm"), symbol_short!("signer")),
+                    (symbol_short!("removed"), removed_by.clone()),
                 );
             }
-            ProtocolEvent::RecoveryRequestCancelled { request_id, user } => {
-                env.events().publish(
-                    (Symbol::short("recovery_request_cancelled"), Symbol::short("request_id")),
-                    (Symbol::short("user"), user.clone()),
-                );
+        }
+    }
+}
+
+impl ProtocolEvent {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            ProtocolEvent::Deposit { .. } => "Deposit",
+            ProtocolEvent::Borrow { .. } => "Borrow",
+            ProtocolEvent::Repay { .. } => "Repay",
+            ProtocolEvent::Withdraw { .. } => "Withdraw",
+            ProtocolEvent::Supply { .. } => "Supply",
+            ProtocolEvent::Redeem { .. } => "Redeem",
+            ProtocolEvent::FlashLoan { .. } => "FlashLoan",
+            ProtocolEvent::Liquidate { .. } => "Liquidate",
+            ProtocolEvent::BadDebtRealized { .. } => "BadDebtRealized",
+            ProtocolEvent::UserEModeSet { .. } => "UserEModeSet",
+            ProtocolEvent::CollateralSwapped { .. } => "CollateralSwapped",
+            ProtocolEvent::LeverageAdjusted { .. } => "LeverageAdjusted",
+            ProtocolEvent::CollateralMoved { .. } => "CollateralMoved",
+            ProtocolEvent::RepaidOnBehalf { .. } => "RepaidOnBehalf",
+            ProtocolEvent::BorrowAllowanceSet { .. } => "BorrowAllowanceSet",
+            ProtocolEvent::LoanOriginated { .. } => "LoanOriginated",
+            ProtocolEvent::LoanMatured { .. } => "LoanMatured",
+            ProtocolEvent::LoanDefaulted { .. } => "LoanDefaulted",
+            ProtocolEvent::InterestAccrued { .. } => "InterestAccrued",
+            ProtocolEvent::RateUpdated { .. } => "RateUpdated",
+            ProtocolEvent::ConfigUpdated { .. } => "ConfigUpdated",
+            ProtocolEvent::FeesCollected { .. } => "FeesCollected",
+            ProtocolEvent::FeesDistributed { .. } => "FeesDistributed",
+            ProtocolEvent::TreasuryUpdated { .. } => "TreasuryUpdated",
+            ProtocolEvent::ReserveUpdated { .. } => "ReserveUpdated",
+            ProtocolEvent::AssetAdded { .. } => "AssetAdded",
+            ProtocolEvent::AssetUpdated { .. } => "AssetUpdated",
+            ProtocolEvent::AssetDisabled { .. } => "AssetDisabled",
+            ProtocolEvent::UserActivityTracked { .. } => "UserActivityTracked",
+            ProtocolEvent::ProtocolStatsUpdated { .. } => "ProtocolStatsUpdated",
+            ProtocolEvent::AccountFrozen { .. } => "AccountFrozen",
+            ProtocolEvent::AccountUnfrozen { .. } => "AccountUnfrozen",
+            // Social Recovery and Multi-Signature events
+            ProtocolEvent::GuardianAdded { .. } => "GuardianAdded",
+            ProtocolEvent::GuardianRemoved { .. } => "GuardianRemoved",
+            ProtocolEvent::RecoveryRequestCreated { .. } => "RecoveryRequestCreated",
+            ProtocolEvent::RecoveryRequestApproved { .. } => "RecoveryRequestApproved",
+            ProtocolEvent::RecoveryRequestExecuted { .. } => "RecoveryRequestExecuted",
+            ProtocolEvent::RecoveryRequestCancelled { .. } => "RecoveryRequestCancelled",
+            ProtocolEvent::MultiSigProposalCreated { .. } => "MultiSigProposalCreated",
+            ProtocolEvent::MultiSigProposalApproved { .. } => "MultiSigProposalApproved",
+            ProtocolEvent::MultiSigProposalExecuted { .. } => "MultiSigProposalExecuted",
+            ProtocolEvent::MultiSigProposalCancelled { .. } => "MultiSigProposalCancelled",
+            ProtocolEvent::MultiSigSignerAdded { .. } => "MultiSigSignerAdded",
+            ProtocolEvent::MultiSigSignerRemoved { .. } => "MultiSigSignerRemoved",
+        }
+    }
+}
+
+/// Latest price an asset oracle has published
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct PriceData {
+    /// Price of one whole unit of the asset in the quote currency (scaled by 1e8)
+    pub price: i128,
+    /// Ledger timestamp the price was published at
+    pub timestamp: u64,
+}
+
+/// Price feed each listed asset's `oracle_address` implements
+#[contractclient(name = "AssetOracleClient")]
+pub trait AssetOracle {
+    /// Latest price of `asset`, if the feed has one
+    fn lastprice(env: Env, asset: String) -> Option<PriceData>;
+}
+
+/// Oracle configuration management
+pub struct OracleConfig;
+
+impl OracleConfig {
+    fn max_deviation_key() -> DataKey {
+        DataKey::MaxPriceDeviation
+    }
+    fn heartbeat_key() -> DataKey {
+        DataKey::OracleHeartbeat
+    }
+
+    pub fn set_max_price_deviation(
+        env: &Env,
+        caller: &Address,
+        deviation: i128,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(env, caller)?;
+        env.storage()
+            .instance()
+            .set(&Self::max_deviation_key(), &deviation);
+        Ok(())
+    }
+
+    pub fn get_max_price_deviation(env: &Env) -> i128 {
+        env.storage()
+            .instance()
+            .get::<DataKey, i128>(&Self::max_deviation_key())
+            .unwrap_or(50) // Default 50%
+    }
+
+    pub fn set_heartbeat(env: &Env, caller: &Address, heartbeat: u64) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(env, caller)?;
+        env.storage()
+            .instance()
+            .set(&Self::heartbeat_key(), &heartbeat);
+        Ok(())
+    }
+
+    pub fn get_heartbeat(env: &Env) -> u64 {
+        env.storage()
+            .instance()
+            .get::<DataKey, u64>(&Self::heartbeat_key())
+            .unwrap_or(3600) // Default 1 hour
+    }
+}
+
+/// Protocol configuration and admin management
+pub struct ProtocolConfig;
+
+impl ProtocolConfig {
+    /// Storage key for admin address
+    fn admin_key() -> DataKey {
+        DataKey::Admin
+    }
+    /// Storage key for oracle address
+    fn oracle_key() -> DataKey {
+        DataKey::Oracle
+    }
+    /// Storage key for min collateral ratio
+    fn min_collateral_ratio_key() -> DataKey {
+        DataKey::MinCollateralRatio
+    }
+
+    /// Set the admin address (only callable once)
+    pub fn set_admin(env: &Env, admin: &Address) {
+        if env.storage().instance().has(&Self::admin_key()) {
+            panic!("Admin already set");
+        }
+        env.storage().instance().set(&Self::admin_key(), admin);
+    }
+
+    /// Get the admin address
+    pub fn get_admin(env: &Env) -> Address {
+        env.storage()
+            .instance()
+            .get(&Self::admin_key())
+            .expect("Admin not set")
+    }
+
+    /// Require that the caller is admin and has authorized this invocation
+    pub fn require_admin(env: &Env, caller: &Address) -> Result<(), ProtocolError> {
+        let admin = Self::get_admin(env);
+        if &admin != caller {
+            return Err(ProtocolError::NotAdmin);
+        }
+        caller.require_auth();
+        Ok(())
+    }
+
+    /// Set the oracle address (admin only)
+    pub fn set_oracle(env: &Env, caller: &Address, oracle: &Address) -> Result<(), ProtocolError> {
+        Self::require_admin(env, caller)?;
+        env.storage().instance().set(&Self::oracle_key(), oracle);
+        Ok(())
+    }
+
+    /// Get the oracle address
+    pub fn get_oracle(env: &Env) -> Address {
+        env.storage()
+            .instance()
+            .get::<DataKey, Address>(&Self::oracle_key())
+            .expect("Oracle not set")
+    }
+
+    /// Set the minimum collateral ratio (admin only)
+    pub fn set_min_collateral_ratio(
+        env: &Env,
+        caller: &Address,
+        ratio: i128,
+    ) -> Result<(), ProtocolError> {
+        Self::require_admin(env, caller)?;
+        env.storage()
+            .instance()
+            .set(&Self::min_collateral_ratio_key(), &ratio);
+        Ok(())
+    }
+
+    /// Get the minimum collateral ratio
+    pub fn get_min_collateral_ratio(env: &Env) -> i128 {
+        env.storage()
+            .instance()
+            .get::<DataKey, i128>(&Self::min_collateral_ratio_key())
+            .unwrap_or(150)
+    }
+}
+
+/// Risk configuration as the symbol-keyed layout and version 2 stored it
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct LegacyRiskConfig {
+    pub close_factor: i128,
+    pub liquidation_incentive: i128,
+    pub pause_borrow: bool,
+    pub pause_deposit: bool,
+    pub pause_withdraw: bool,
+    pub pause_liquidate: bool,
+    pub last_update: u64,
+}
+
+impl From<LegacyRiskConfig> for RiskConfig {
+    fn from(legacy: LegacyRiskConfig) -> Self {
+        Self {
+            close_factor: legacy.close_factor,
+            liquidation_incentive: legacy.liquidation_incentive,
+            pause_borrow: legacy.pause_borrow,
+            pause_deposit: legacy.pause_deposit,
+            pause_withdraw: legacy.pause_withdraw,
+            pause_liquidate: legacy.pause_liquidate,
+            last_update: legacy.last_update,
+            ..RiskConfig::default()
+        }
+    }
+}
+
+/// Interest rate configuration as the symbol-keyed layout and version 2 stored it, before
+/// stable-rate borrowing; upgraded configs take the default stable-rate premium and
+/// rebalance threshold
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct LegacyInterestRateConfig {
+    pub base_rate: i128,
+    pub kink_utilization: i128,
+    pub multiplier: i128,
+    pub reserve_factor: i128,
+    pub rate_ceiling: i128,
+    pub rate_floor: i128,
+    pub last_update: u64,
+}
+
+impl From<LegacyInterestRateConfig> for InterestRateConfig {
+    fn from(legacy: LegacyInterestRateConfig) -> Self {
+        Self {
+            base_rate: legacy.base_rate,
+            kink_utilization: legacy.kink_utilization,
+            multiplier: legacy.multiplier,
+            reserve_factor: legacy.reserve_factor,
+            rate_ceiling: legacy.rate_ceiling,
+            rate_floor: legacy.rate_floor,
+            last_update: legacy.last_update,
+            ..InterestRateConfig::default()
+        }
+    }
+}
+
+/// Interest rate state as the symbol-keyed layout stored it
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct LegacyInterestRateState {
+    pub current_borrow_rate: i128,
+    pub current_supply_rate: i128,
+    pub utilization_rate: i128,
+    pub total_borrowed: i128,
+    pub total_supplied: i128,
+    pub last_accrual_time: u64,
+}
+
+impl From<LegacyInterestRateState> for InterestRateState {
+    fn from(legacy: LegacyInterestRateState) -> Self {
+        Self {
+            current_borrow_rate: legacy.current_borrow_rate,
+            current_supply_rate: legacy.current_supply_rate,
+            utilization_rate: legacy.utilization_rate,
+            total_borrowed: legacy.total_borrowed,
+            total_supplied: legacy.total_supplied,
+            last_accrual_time: legacy.last_accrual_time,
+            ..InterestRateState::initial()
+        }
+    }
+}
+
+/// Revenue metrics as the symbol-keyed layout and version 2 stored them
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct LegacyRevenueMetrics {
+    pub daily_fees: i128,
+    pub weekly_fees: i128,
+    pub monthly_fees: i128,
+    pub total_borrow_fees: i128,
+    pub total_supply_fees: i128,
+}
+
+impl From<LegacyRevenueMetrics> for RevenueMetrics {
+    fn from(legacy: LegacyRevenueMetrics) -> Self {
+        Self {
+            daily_fees: legacy.daily_fees,
+            weekly_fees: legacy.weekly_fees,
+            monthly_fees: legacy.monthly_fees,
+            total_borrow_fees: legacy.total_borrow_fees,
+            total_supply_fees: legacy.total_supply_fees,
+            ..RevenueMetrics::default()
+        }
+    }
+}
+
+/// Asset market as the symbol-keyed layout stored it
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct LegacyAssetInfo {
+    pub symbol: String,
+    pub decimals: u32,
+    pub oracle_address: Address,
+    pub min_collateral_ratio: i128,
+    pub risk_config: LegacyRiskConfig,
+    pub interest_config: LegacyInterestRateConfig,
+    pub interest_state: LegacyInterestRateState,
+    pub deposit_enabled: bool,
+    pub borrow_enabled: bool,
+    pub last_update: u64,
+}
+
+impl From<LegacyAssetInfo> for AssetInfo {
+    fn from(legacy: LegacyAssetInfo) -> Self {
+        let mut info = AssetInfo::new(
+            legacy.symbol,
+            legacy.decimals,
+            legacy.oracle_address,
+            None,
+            legacy.min_collateral_ratio,
+        );
+        info.risk_config = legacy.risk_config.into();
+        info.interest_config = legacy.interest_config.into();
+        info.interest_state = legacy.interest_state.into();
+        info.deposit_enabled = legacy.deposit_enabled;
+        info.borrow_enabled = legacy.borrow_enabled;
+        info.last_update = legacy.last_update;
+        info
+    }
+}
+
+/// Asset position as the symbol-keyed layout stored it, with debt and its accrued
+/// interest held as plain amounts
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct LegacyAssetPosition {
+    pub user: Address,
+    pub asset: String,
+    pub collateral: i128,
+    pub debt: i128,
+    pub borrow_interest: i128,
+    pub supply_interest: i128,
+    pub last_accrual_time: u64,
+}
+
+impl LegacyAssetPosition {
+    /// Position with the debt and its unpaid interest scaled at the asset's borrow index
+    pub fn upgrade(self, borrow_index: i128) -> AssetPosition {
+        let mut position = AssetPosition::new(self.user, self.asset, self.collateral, 0);
+        InterestRateManager::add_debt(&mut position, self.debt + self.borrow_interest, borrow_index);
+        position.borrow_interest = self.borrow_interest;
+        position.supply_interest = self.supply_interest;
+        position.last_accrual_time = self.last_accrual_time;
+        position
+    }
+}
+
+/// Interest rate state as version 2 stored it, before stable-rate borrowing; every
+/// borrow then was variable, so upgraded states carry no stable debt or average rate
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct InterestRateStateV2 {
+    pub current_borrow_rate: i128,
+    pub current_supply_rate: i128,
+    pub utilization_rate: i128,
+    pub total_borrowed: i128,
+    pub total_supplied: i128,
+    pub total_collateral: i128,
+    pub total_reserves: i128,
+    pub borrow_index: i128,
+    pub supply_index: i128,
+    pub last_accrual_time: u64,
+}
+
+impl From<InterestRateStateV2> for InterestRateState {
+    fn from(v2: InterestRateStateV2) -> Self {
+        Self {
+            current_borrow_rate: v2.current_borrow_rate,
+            current_supply_rate: v2.current_supply_rate,
+            utilization_rate: v2.utilization_rate,
+            total_borrowed: v2.total_borrowed,
+            total_supplied: v2.total_supplied,
+            total_collateral: v2.total_collateral,
+            total_reserves: v2.total_reserves,
+            borrow_index: v2.borrow_index,
+            supply_index: v2.supply_index,
+            last_accrual_time: v2.last_accrual_time,
+            ..InterestRateState::initial()
+        }
+    }
+}
+
+/// Asset market as version 2 stored it, before LTVs, caps, isolation and e-mode
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct AssetInfoV2 {
+    pub symbol: String,
+    pub decimals: u32,
+    pub oracle_address: Address,
+    pub token_address: Option<Address>,
+    pub stoken_address: Option<Address>,
+    pub min_collateral_ratio: i128,
+    pub risk_config: LegacyRiskConfig,
+    pub interest_config: LegacyInterestRateConfig,
+    pub interest_state: InterestRateStateV2,
+    pub deposit_enabled: bool,
+    pub borrow_enabled: bool,
+    pub last_update: u64,
+}
+
+/// Fields added to the market after version 2 start as they would on a newly listed one:
+/// - `supply_cap` and `borrow_cap` are 0, leaving the market uncapped
+/// - `isolated` and `borrowable_in_isolation` are false with no `debt_ceiling` or
+///   `isolation_debt`; no version 2 account can hold isolated collateral, so there are
+///   no per-user isolation charges to carry over
+/// - `emode_category` is 0, outside any category; categories and user opt-ins are kept
+///   under their own keys, which version 2 never wrote
+/// - `ltv` and `liquidation_threshold` are derived from `min_collateral_ratio`, as a new
+///   listing's are; account liquidation limits are computed from them and never stored
+impl From<AssetInfoV2> for AssetInfo {
+    fn from(v2: AssetInfoV2) -> Self {
+        let mut info = AssetInfo::new(
+            v2.symbol,
+            v2.decimals,
+            v2.oracle_address,
+            v2.token_address,
+            v2.min_collateral_ratio,
+        );
+        info.stoken_address = v2.stoken_address;
+        info.risk_config = v2.risk_config.into();
+        info.interest_config = v2.interest_config.into();
+        info.interest_state = v2.interest_state.into();
+        info.deposit_enabled = v2.deposit_enabled;
+        info.borrow_enabled = v2.borrow_enabled;
+        info.last_update = v2.last_update;
+        info
+    }
+}
+
+/// Asset position as versions 1 and 2 stored it, before stable debt and sub-accounts;
+/// its debt stays variable and the position moves to the main account
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct AssetPositionV2 {
+    pub user: Address,
+    pub asset: String,
+    pub collateral: i128,
+    pub scaled_debt: i128,
+    pub borrow_index: i128,
+    pub supply_shares: i128,
+    pub supply_index: i128,
+    pub borrow_interest: i128,
+    pub supply_interest: i128,
+    pub last_accrual_time: u64,
+}
+
+impl From<AssetPositionV2> for AssetPosition {
+    fn from(v2: AssetPositionV2) -> Self {
+        Self {
+            user: v2.user,
+            sub_account: MAIN_ACCOUNT,
+            asset: v2.asset,
+            collateral: v2.collateral,
+            scaled_debt: v2.scaled_debt,
+            stable_debt: 0,
+            stable_rate: 0,
+            borrow_index: v2.borrow_index,
+            supply_shares: v2.supply_shares,
+            supply_index: v2.supply_index,
+            borrow_interest: v2.borrow_interest,
+            supply_interest: v2.supply_interest,
+            last_accrual_time: v2.last_accrual_time,
+        }
+    }
+}
+
+/// Current storage layout version
+pub const STORAGE_VERSION: u32 = 3;
+
+/// Moves data written under the legacy symbol keys to their `DataKey` entries and
+/// rewrites entries stored in earlier layouts in the current one
+pub struct StorageMigration;
+
+impl StorageMigration {
+    /// Move one value from a legacy key, returning whether anything was moved
+    fn move_value<K, V>(env: &Env, legacy: &K, key: &DataKey) -> bool
+    where
+        K: IntoVal<Env, Val>,
+        V: IntoVal<Env, Val> + TryFromVal<Env, Val>,
+    {
+        Self::move_legacy::<K, V, V>(env, legacy, key)
+    }
+
+    /// Move one value from a legacy key, decoding it with the layout `L` it was stored
+    /// in and converting it to the current type
+    fn move_legacy<K, L, V>(env: &Env, legacy: &K, key: &DataKey) -> bool
+    where
+        K: IntoVal<Env, Val>,
+        L: TryFromVal<Env, Val>,
+        V: From<L> + IntoVal<Env, Val>,
+    {
+        let storage = env.storage().instance();
+        match storage.get::<K, L>(legacy) {
+            Some(value) => {
+                storage.set(key, &V::from(value));
+                storage.remove(legacy);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Decode a stored struct as the older layout `L` unless it already has `field`, a
+    /// field only the current layout carries. Decoding a struct into a layout with a
+    /// different field count traps, so the fields are checked on the raw map first.
+    fn decode_older<L>(env: &Env, value: &Val, field: &str) -> Option<L>
+    where
+        L: TryFromVal<Env, Val>,
+    {
+        let fields = Map::<Symbol, Val>::try_from_val(env, value).ok()?;
+        if fields.contains_key(Symbol::new(env, field)) {
+            return None;
+        }
+        L::try_from_val(env, value).ok()
+    }
+
+    /// Rewrite an instance entry stored in the version 2 layout `L` in its current
+    /// layout. Entries that already have `field` are in the current layout and are left
+    /// alone, returning false.
+    fn upgrade_value<L, V>(env: &Env, key: &DataKey, field: &str) -> bool
+    where
+        L: TryFromVal<Env, Val>,
+        V: From<L> + IntoVal<Env, Val>,
+    {
+        let storage = env.storage().instance();
+        match storage
+            .get::<DataKey, Val>(key)
+            .and_then(|value| Self::decode_older::<L>(env, &value, field))
+        {
+            Some(value) => {
+                storage.set(key, &V::from(value));
+                true
             }
-            ProtocolEvent::MultiSigProposalCreated { proposal_id, creator, description, target_function, required_approvals, time_delay } => {
-                env.events().publish(
-                    (Symbol::short("multisig_proposal_created"), Symbol::short("proposal_id")),
-                    (Symbol::short("creator"), creator.clone(), Symbol::short("description"), description.clone(), Symbol::short("target_function"), target_function.clone(), Symbol::short("required_approvals"), *required_approvals, Symbol::short("time_delay"), *time_delay),
-                );
+            None => false,
+        }
+    }
+
+    /// Check `caller` against the admin under either layout, before anything is moved
+    pub fn require_admin(env: &Env, caller: &Address) -> Result<(), ProtocolError> {
+        let storage = env.storage().instance();
+        let admin = storage
+            .get::<DataKey, Address>(&DataKey::Admin)
+            .or_else(|| storage.get::<Symbol, Address>(&symbol_short!("admin")));
+        if admin.as_ref() != Some(caller) {
+            return Err(ProtocolError::NotAdmin);
+        }
+        caller.require_auth();
+        Ok(())
+    }
+
+    /// Move the legacy admin entry to its typed key
+    pub fn migrate_admin(env: &Env) -> u32 {
+        Self::move_value::<Symbol, Address>(env, &symbol_short!("admin"), &DataKey::Admin) as u32
+    }
+
+    /// Move a per-user or per-proposal entry from instance to persistent storage
+    fn to_persistent<V>(env: &Env, key: &DataKey) -> bool
+    where
+        V: IntoVal<Env, Val> + TryFromVal<Env, Val>,
+    {
+        match env.storage().instance().get::<DataKey, V>(key) {
+            Some(value) => {
+                UserStorage::set(env, key, &value);
+                env.storage().instance().remove(key);
+                true
             }
-            ProtocolEvent::MultiSigProposalApproved { proposal_id, signer, current_approvals } => {
-                env.events().publish(
-                    (Symbol::short("multisig_proposal_approved"), Symbol::short("proposal_id")),
-                    (Symbol::short("signer"), signer.clone(), Symbol::short("current_approvals"), *current_approvals),
-                );
+            None => false,
+        }
+    }
+
+    /// Move protocol-wide entries, asset markets and the positions of `users`, converting
+    /// values from the layouts they were stored in. Keys the old layout could never have
+    /// written (symbols too long for `symbol_short!`) have nothing to move and are
+    /// skipped, as are fixed-term loans, whose entries and counter only exist from
+    /// version 3. Safe to call repeatedly.
+    pub fn migrate(env: &Env, users: &Vec<Address>) -> u32 {
+        let mut moved = 0u32;
+
+        // Versions 1 and 2 stored markets and protocol-wide entries under their typed
+        // keys in layouts that have since gained fields
+        moved += Self::upgrade_value::<LegacyRiskConfig, RiskConfig>(env, &DataKey::RiskConfig, "liquidation_protocol_fee") as u32;
+        moved += Self::upgrade_value::<LegacyRevenueMetrics, RevenueMetrics>(env, &DataKey::RevenueMetrics, "total_flash_loan_fees") as u32;
+        for asset in AssetStorage::get_registry(env).supported_assets.iter() {
+            moved += Self::upgrade_value::<AssetInfoV2, AssetInfo>(env, &DataKey::AssetInfo(asset), "ltv") as u32;
+        }
+
+        moved += Self::move_legacy::<Symbol, LegacyRiskConfig, RiskConfig>(env, &symbol_short!("risk_cfg"), &DataKey::RiskConfig) as u32;
+        moved += Self::move_value::<Symbol, ReserveData>(env, &symbol_short!("reserve"), &DataKey::ReserveData) as u32;
+        moved += Self::move_legacy::<Symbol, LegacyRevenueMetrics, RevenueMetrics>(env, &symbol_short!("metrics"), &DataKey::RevenueMetrics) as u32;
+        moved += Self::move_value::<Symbol, AssetRegistry>(env, &symbol_short!("asset_reg"), &DataKey::AssetRegistry) as u32;
+        moved += Self::move_value::<Symbol, i128>(env, &symbol_short!("max_dev"), &DataKey::MaxPriceDeviation) as u32;
+        moved += Self::move_value::<Symbol, u64>(env, &symbol_short!("heartbeat"), &DataKey::OracleHeartbeat) as u32;
+        moved += Self::move_value::<Symbol, Address>(env, &symbol_short!("oracle"), &DataKey::Oracle) as u32;
+        moved += Self::move_value::<Symbol, i128>(env, &symbol_short!("min_ratio"), &DataKey::MinCollateralRatio) as u32;
+        moved += Self::move_value::<Symbol, UXConfig>(env, &symbol_short!("ux_config"), &DataKey::UxConfig) as u32;
+        moved += Self::move_value::<String, Vec<Address>>(env, &String::from_str(env, "admin_set"), &DataKey::AdminSet) as u32;
+        moved += Self::move_value::<String, u32>(env, &String::from_str(env, "proposal_counter"), &DataKey::ProposalCounter) as u32;
+
+        // Asset infos were stored under one slot per well-known symbol plus a shared
+        // "asset_def" slot; each is re-keyed by the symbol it actually holds
+        let storage = env.storage().instance();
+        for slot in ["asset_xlm", "asset_usdc", "asset_btc", "asset_eth", "asset_def"] {
+            let legacy = Symbol::new(env, slot);
+            if let Some(info) = storage.get::<Symbol, LegacyAssetInfo>(&legacy) {
+                let info = AssetInfo::from(info);
+                storage.set(&DataKey::AssetInfo(info.symbol.clone()), &info);
+                storage.remove(&legacy);
+                moved += 1;
             }
-            ProtocolEvent::MultiSigProposalExecuted { proposal_id, creator, target_function } => {
-                env.events().publish(
-                    (Symbol::short("multisig_proposal_executed"), Symbol::short("proposal_id")),
-                    (Symbol::short("creator"), creator.clone(), Symbol::short("target_function"), target_function.clone()),
-                );
+        }
+
+        // The single protocol-wide price, its timestamp and fallback gave way to per-asset
+        // oracles; their entries, under the legacy symbols or the keys version 2 used, are dropped
+        for legacy in ["oracle_p", "oracle_t", "fallback"] {
+            storage.remove(&Symbol::new(env, legacy));
+        }
+        for key in ["OraclePrice", "OracleLastUpdate", "FallbackPrice"] {
+            storage.remove(&vec![env, Symbol::new(env, key)]);
+        }
+
+        // Interest configuration and state used to be global; they belong to the default asset
+        let registry = AssetStorage::get_registry(env);
+        if let Some(mut info) = AssetStorage::get_asset_info(env, &registry.default_asset) {
+            let config_key = symbol_short!("ir_config");
+            let state_key = symbol_short!("ir_state");
+            if let Some(config) = storage.get::<Symbol, LegacyInterestRateConfig>(&config_key) {
+                info.interest_config = config.into();
+                storage.remove(&config_key);
+                moved += 1;
             }
-            ProtocolEvent::MultiSigProposalCancelled { proposal_id, creator } => {
-                env.events().publish(
-                    (Symbol::short("multisig_proposal_cancelled"), Symbol::short("proposal_id")),
-                    (Symbol::short("creator"), creator.clone()),
-                );
+            if let Some(state) = storage.get::<Symbol, LegacyInterestRateState>(&state_key) {
+                info.interest_state = state.into();
+                storage.remove(&state_key);
+                moved += 1;
             }
-            ProtocolEvent::MultiSigSignerAdded { signer, added_by } => {
-                env.events().publish(
-                    (Symbol::short("multisig_signer_added"), Symbol::short("signer")),
-                    (Symbol::short("added_by"), added_by.clone()),
-                );
+            AssetStorage::save_asset_info(env, &registry.default_asset, &info);
+        }
+
+        for user in users.iter() {
+            for slot in ["pos_xlm", "pos_usdc", "pos_btc", "pos_eth", "pos_def"] {
+                let legacy = (Symbol::new(env, slot), user.clone());
+                if let Some(position) = storage.get::<(Symbol, Address), LegacyAssetPosition>(&legacy) {
+                    let state = InterestRateStorage::get_asset_state(env, &position.asset);
+                    let position = position.upgrade(state.borrow_index);
+                    AssetStorage::save_asset_position(env, &user, &position.asset, &position);
+                    storage.remove(&legacy);
+                    moved += 1;
+                }
             }
-            ProtocolEvent::MultiSigSignerRemoved { signer, removed_by } => {
-                env.events().publish(
-                    (Symbol::short("multisig_signer_removed"), Symbol::short("signer")),
-                    (Symbol::short("removed_by"), removed_by.clone()),
-                );
+        }
+
+        // Version 1 kept per-user and per-proposal entries in instance storage, and
+        // versions 1 and 2 stored positions before stable debt and sub-accounts. Positions
+        // from then were never counted as open, so they are saved as new.
+        for user in users.iter() {
+            for asset in registry.supported_assets.iter() {
+                let key = DataKey::Position(user.clone(), asset.clone());
+                let stored = storage
+                    .get::<DataKey, Val>(&key)
+                    .and_then(|value| Self::decode_older::<AssetPositionV2>(env, &value, "sub_account"));
+                if let Some(position) = stored {
+                    AssetStorage::save_asset_position(env, &user, &asset, &position.into());
+                    storage.remove(&key);
+                    moved += 1;
+                }
+                let stored = env
+                    .storage()
+                    .persistent()
+                    .get::<DataKey, Val>(&key)
+                    .and_then(|value| Self::decode_older::<AssetPositionV2>(env, &value, "sub_account"));
+                if let Some(position) = stored {
+                    UserStorage::remove(env, &key);
+                    AssetStorage::save_asset_position(env, &user, &asset, &position.into());
+                    moved += 1;
+                }
             }
+            moved += Self::to_persistent::<UserActivity>(env, &DataKey::UserActivity(user.clone())) as u32;
+            moved += Self::to_persistent::<u32>(env, &DataKey::SuspiciousCount(user.clone())) as u32;
+            moved += Self::to_persistent::<bool>(env, &DataKey::Frozen(user.clone())) as u32;
+            moved += Self::to_persistent::<KYCStatus>(env, &DataKey::Kyc(user.clone())) as u32;
+            moved += Self::to_persistent::<bool>(env, &DataKey::Blacklisted(user.clone())) as u32;
+            moved += Self::to_persistent::<SocialRecoveryConfig>(env, &DataKey::RecoveryConfig(user.clone())) as u32;
+        }
+        let proposals = storage.get::<DataKey, u32>(&DataKey::ProposalCounter).unwrap_or(0);
+        for id in 1..=proposals {
+            moved += Self::to_persistent::<AssetProposal>(env, &DataKey::Proposal(id)) as u32;
+        }
+        let recoveries = RecoveryStorage::get_next_recovery_id(env);
+        for id in 1..=recoveries {
+            moved += Self::to_persistent::<RecoveryRequest>(env, &DataKey::RecoveryRequest(id)) as u32;
+        }
+        let multisig_proposals = RecoveryStorage::get_next_proposal_id(env);
+        for id in 1..=multisig_proposals {
+            moved += Self::to_persistent::<MultiSigProposal>(env, &DataKey::MultisigProposal(id)) as u32;
+        }
+        for index in 0..100 {
+            moved += Self::to_persistent::<ErrorContext>(env, &DataKey::ErrorLog(index)) as u32;
+        }
+
+        storage.set(&DataKey::StorageVersion, &STORAGE_VERSION);
+        moved
+    }
+
+    /// Storage layout version, 0 for contracts still on the legacy keys
+    pub fn version(env: &Env) -> u32 {
+        env.storage()
+            .instance()
+            .get(&DataKey::StorageVersion)
+            .unwrap_or(0)
+    }
+}
+
+/// Enhanced error type for protocol errors with detailed context
+#[contracterror]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ProtocolError {
+    Unauthorized = 1,
+    InsufficientCollateral = 2,
+    InsufficientCollateralRatio = 3,
+    InvalidAmount = 4,
+    InvalidAddress = 5,
+    PositionNotFound = 6,
+    AlreadyInitialized = 7,
+    NotAdmin = 8,
+    OracleNotSet = 9,
+    AdminNotSet = 10,
+    NotEligibleForLiquidation = 11,
+    ProtocolPaused = 12,
+    AssetNotSupported = 13,
+    AssetDisabled = 14,
+    InvalidAsset = 15,
+    Unknown = 16,
+    AlreadyExists = 17,
+    NotFound = 18,
+    InvalidOperation = 19,
+    InvalidInput = 20,
+    // Enhanced error types
+    OracleFailure = 21,
+    PriceStale = 22,
+    SlippageExceeded = 23,
+    ReentrancyDetected = 24,
+    ComplianceViolation = 25,
+    NetworkError = 26,
+    RateLimitExceeded = 27,
+    ConfigurationError = 28,
+    StorageError = 29,
+    RecoveryFailed = 30,
+    // Social Recovery and Multi-Signature errors
+    GuardianNotFound = 31,
+    GuardianAlreadyExists = 32,
+    RecoveryRequestNotFound = 33,
+    RecoveryRequestAlreadyExists = 34,
+    RecoveryNotReady = 35,
+    InsufficientGuardianApprovals = 36,
+    MultiSigProposalNotFound = 37,
+    MultiSigProposalAlreadyExists = 38,
+    MultiSigNotReady = 39,
+    InsufficientSignatures = 40,
+    InvalidGuardianAddress = 41,
+    InvalidRecoveryAddress = 42,
+    RecoveryTimeDelayNotMet = 43,
+    MultiSigTimeDelayNotMet = 44,
+    InsufficientLiquidity = 45,
+    FlashLoanNotRepaid = 46,
+    SupplyCapExceeded = 47,
+    BorrowCapExceeded = 48,
+    NotBorrowableInIsolation = 49,
+    DebtCeilingExceeded = 50,
+}
+
+impl ProtocolError {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            ProtocolError::Unauthorized => "Unauthorized access denied",
+            ProtocolError::InsufficientCollateral => "Insufficient collateral for operation",
+            ProtocolError::InsufficientCollateralRatio => "Collateral ratio below required minimum",
+            ProtocolError::InvalidAmount => "Invalid amount provided",
+            ProtocolError::InvalidAddress => "Invalid address format or address not found",
+            ProtocolError::PositionNotFound => "User position not found in protocol",
+            ProtocolError::AlreadyInitialized => "Component already initialized",
+            ProtocolError::NotAdmin => "Administrative privileges required",
+            ProtocolError::OracleNotSet => "Price oracle not configured",
+            ProtocolError::AdminNotSet => "Administrator not configured",
+            ProtocolError::NotEligibleForLiquidation => "Position does not meet liquidation criteria",
+            ProtocolError::ProtocolPaused => "Protocol operations are currently paused",
+            ProtocolError::AssetNotSupported => "Asset not supported by protocol",
+            ProtocolError::AssetDisabled => "Asset operations currently disabled",
+            ProtocolError::InvalidAsset => "Invalid asset configuration",
+            ProtocolError::Unknown => "Unknown error occurred",
+            ProtocolError::AlreadyExists => "Resource already exists",
+            ProtocolError::NotFound => "Requested resource not found",
+            ProtocolError::InvalidOperation => "Operation not valid in current state",
+            ProtocolError::InvalidInput => "Invalid input parameters provided",
+            // Enhanced error messages
+            ProtocolError::OracleFailure => "Oracle service failure or unreachable",
+            ProtocolError::PriceStale => "Price data is stale beyond acceptable threshold",
+            ProtocolError::SlippageExceeded => "Price slippage exceeded maximum tolerance",
+            ProtocolError::ReentrancyDetected => "Reentrancy attack detected and blocked",
+            ProtocolError::ComplianceViolation => "Transaction violates compliance requirements",
+            ProtocolError::NetworkError => "Network connectivity issues detected",
+            ProtocolError::RateLimitExceeded => "Rate limit exceeded for this operation",
+            ProtocolError::ConfigurationError => "System configuration error detected",
+            ProtocolError::StorageError => "Storage operation failed",
+            ProtocolError::RecoveryFailed => "Error recovery operation failed",
+            // Social Recovery and Multi-Signature errors
+            ProtocolError::GuardianNotFound => "Guardian not found",
+            ProtocolError::GuardianAlreadyExists => "Guardian already exists",
+            ProtocolError::RecoveryRequestNotFound => "Recovery request not found",
+            ProtocolError::RecoveryRequestAlreadyExists => "Recovery request already exists",
+            ProtocolError::RecoveryNotReady => "Recovery not ready for execution",
+            ProtocolError::InsufficientGuardianApprovals => "Insufficient guardian approvals",
+            ProtocolError::MultiSigProposalNotFound => "Multi-signature proposal not found",
+            ProtocolError::MultiSigProposalAlreadyExists => "Multi-signature proposal already exists",
+            ProtocolError::MultiSigNotReady => "Multi-signature proposal not ready for execution",
+            ProtocolError::InsufficientSignatures => "Insufficient signatures for multi-signature operation",
+            ProtocolError::InvalidGuardianAddress => "Invalid guardian address",
+            ProtocolError::InvalidRecoveryAddress => "Invalid recovery address",
+            ProtocolError::RecoveryTimeDelayNotMet => "Recovery time delay not met",
+            ProtocolError::MultiSigTimeDelayNotMet => "Multi-signature time delay not met",
+            ProtocolError::InsufficientLiquidity => "Not enough lender liquidity available",
+            ProtocolError::FlashLoanNotRepaid => "Flash loan principal and fee were not returned",
+            ProtocolError::SupplyCapExceeded => "Asset supply cap would be exceeded",
+            ProtocolError::BorrowCapExceeded => "Asset borrow cap would be exceeded",
+            ProtocolError::NotBorrowableInIsolation => "Asset cannot be borrowed against isolated collateral",
+            ProtocolError::DebtCeilingExceeded => "Isolated collateral debt ceiling would be exceeded",
         }
     }
-}
 
-impl ProtocolEvent {
-    pub fn to_str(&self) -> &'static str {
-        match self {
-            ProtocolEvent::Deposit { .. } => "Deposit",
-            ProtocolEvent::Borrow { .. } => "Borrow",
-            ProtocolEvent::Repay { .. } => "Repay",
-            ProtocolEvent::Withdraw { .. } => "Withdraw",
-            ProtocolEvent::Liquidate { .. } => "Liquidate",
-            ProtocolEvent::InterestAccrued { .. } => "InterestAccrued",
-            ProtocolEvent::RateUpdated { .. } => "RateUpdated",
-            ProtocolEvent::ConfigUpdated { .. } => "ConfigUpdated",
-            ProtocolEvent::FeesCollected { .. } => "FeesCollected",
-            ProtocolEvent::FeesDistributed { .. } => "FeesDistributed",
-            ProtocolEvent::TreasuryUpdated { .. } => "TreasuryUpdated",
-            ProtocolEvent::ReserveUpdated { .. } => "ReserveUpdated",
-            ProtocolEvent::AssetAdded { .. } => "AssetAdded",
-            ProtocolEvent::AssetUpdated { .. } => "AssetUpdated",
-            ProtocolEvent::AssetDisabled { .. } => "AssetDisabled",
-            ProtocolEvent::UserActivityTracked { .. } => "UserActivityTracked",
-            ProtocolEvent::ProtocolStatsUpdated { .. } => "ProtocolStatsUpdated",
-            ProtocolEvent::AccountFrozen { .. } => "AccountFrozen",
-            ProtocolEvent::AccountUnfrozen { .. } => "AccountUnfrozen",
-            // Social Recovery and Multi-Signature events
-            ProtocolEvent::GuardianAdded { .. } => "GuardianAdded",
-            ProtocolEvent::GuardianRemoved { .. } => "GuardianRemoved",
-            ProtocolEvent::RecoveryRequestCreated { .. } => "RecoveryRequestCreated",
-            ProtocolEvent::RecoveryRequestApproved { .. } => "RecoveryRequestApproved",
-            ProtocolEvent::RecoveryRequestExecuted { .. } => "RecoveryRequestExecuted",
-            ProtocolEvent::RecoveryRequestCancelled { .. } => "RecoveryRequestCancelled",
-            ProtocolEvent::MultiSigProposalCreated { .. } => "MultiSigProposalCreated",
-            ProtocolEvent::MultiSigProposalApproved { .. } => "MultiSigProposalApproved",
-            ProtocolEvent::MultiSigProposalExecuted { .. } => "MultiSigProposalExecuted",
-            ProtocolEvent::MultiSigProposalCancelled { .. } => "MultiSigProposalCancelled",
-            ProtocolEvent::MultiSigSignerAdded { .. } => "MultiSigSignerAdded",
-            ProtocolEvent::MultiSigSignerRemoved { .. } => "MultiSigSignerRemoved",
-        }
+    pub fn get_error_code(&self) -> u32 {
+        *self as u32
     }
-}
-
-/// Trait for price oracle integration
-pub trait PriceOracle {
-    /// Returns the price of the collateral asset in terms of the debt asset (scaled by 1e8)
-    fn get_price(env: &Env) -> i128;
-
-    /// Returns the last update timestamp
-    fn get_last_update(env: &Env) -> u64;
-
-    /// Validates if the price is within acceptable bounds
-    fn validate_price(env: &Env, price: i128) -> bool;
-}
-
-/// Real price oracle implementation with validation and fallback
-pub struct RealPriceOracle;
 
-impl PriceOracle for RealPriceOracle {
-    fn get_price(env: &Env) -> i128 {
-        // Check if oracle is set, if not return fallback price
-        if !env.storage().instance().has(&ProtocolConfig::oracle_key()) {
-            return OracleConfig::get_fallback_price(env);
+    pub fn get_detailed_message(&self, env: &Env, context: &str) -> String {
+        if context.is_empty() {
+            String::from_str(env, self.to_str())
+        } else {
+            String::from_str(env, &format!("{} | Context: {}", self.to_str(), context))
         }
+    }
 
-        // Get the configured oracle address
-        let _oracle_addr = ProtocolConfig::get_oracle(env);
+    pub fn is_recoverable(&self) -> bool {
+        matches!(
+            self,
+            ProtocolError::OracleFailure
+                | ProtocolError::PriceStale
+                | ProtocolError::NetworkError
+                | ProtocolError::RateLimitExceeded
+                | ProtocolError::StorageError
+        )
+    }
 
-        // In a real implementation, this would call the external oracle contract
-        // For now, we'll simulate a real price with some variation
-        let base_price = 200_000_000; // 2.0 * 1e8
-        let timestamp = env.ledger().timestamp();
+    pub fn get_recovery_suggestion(&self) -> &'static str {
+        match self {
+            ProtocolError::OracleFailure => "Retry with fallback oracle or use cached price",
+            ProtocolError::PriceStale => "Request fresh price update from oracle",
+            ProtocolError::NetworkError => "Retry operation after network recovery",
+            ProtocolError::RateLimitExceeded => "Wait and retry operation later",
+            ProtocolError::StorageError => "Retry storage operation",
+            ProtocolError::InsufficientCollateral => "Add more collateral to position",
+            ProtocolError::InsufficientCollateralRatio => "Increase collateral or reduce debt",
+            _ => "Contact protocol administrators for assistance",
+        }
+    }
+}
 
-        // Simulate price variation based on time (for testing)
-        let variation = ((timestamp % 1000) as i128) * 10_000; // Small variation
-        let price = base_price + variation;
+/// Error context for detailed debugging and analytics
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct ErrorContext {
+    /// Error code
+    pub error_code: u32,
+    /// Detailed error message
+    pub message: String,
+    /// User address involved (if applicable)
+    pub user: Option<Address>,
+    /// Function that triggered the error
+    pub function: String,
+    /// Additional context data
+    pub context_data: String,
+    /// Timestamp when error occurred
+    pub timestamp: u64,
+    /// Whether recovery was attempted
+    pub recovery_attempted: bool,
+    /// Whether recovery was successful
+    pub recovery_successful: bool,
+}
 
-        // Validate the price
-        if !Self::validate_price(env, price) {
-            // Fallback to a safe default price
-            return OracleConfig::get_fallback_price(env);
+impl ErrorContext {
+    pub fn new(
+        env: &Env,
+        error: &ProtocolError,
+        user: Option<Address>,
+        function: &str,
+        context_data: &str,
+    ) -> Self {
+        Self {
+            error_code: error.get_error_code(),
+            message: error.get_detailed_message(env, context_data),
+            user,
+            function: String::from_str(env, function),
+            context_data: String::from_str(env, context_data),
+            timestamp: env.ledger().timestamp(),
+            recovery_attempted: false,
+            recovery_successful: false,
         }
+    }
 
-        // Store the price and timestamp
-        OracleData::set_price(env, price);
-        OracleData::set_last_update(env, timestamp);
-
-        price
+    pub fn mark_recovery_attempted(&mut self) {
+        self.recovery_attempted = true;
     }
 
-    fn get_last_update(env: &Env) -> u64 {
-        OracleData::get_last_update(env)
+    pub fn mark_recovery_successful(&mut self) {
+        self.recovery_successful = true;
     }
+}
 
-    fn validate_price(env: &Env, price: i128) -> bool {
-        let last_price = OracleData::get_price(env);
-        let max_deviation = OracleConfig::get_max_price_deviation(env);
+/// Error analytics and metrics
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct ErrorAnalytics {
+    /// Total error count
+    pub total_errors: u32,
+    /// Errors by type (simplified - top 10 error codes with counts)
+    pub error_counts: Vec<(u32, u32)>,
+    /// Recoverable errors attempted
+    pub recovery_attempts: u32,
+    /// Successful recoveries
+    pub successful_recoveries: u32,
+    /// Last error timestamp
+    pub last_error_timestamp: u64,
+    /// Most frequent error type
+    pub most_frequent_error: u32,
+    /// Error rate (errors per hour)
+    pub hourly_error_rate: u32,
+    /// Critical errors requiring immediate attention
+    pub critical_errors: u32,
+}
 
-        if last_price == 0 {
-            return true; // First price is always valid
+impl ErrorAnalytics {
+    pub fn new(env: &Env) -> Self {
+        Self {
+            total_errors: 0,
+            error_counts: Vec::new(env),
+            recovery_attempts: 0,
+            successful_recoveries: 0,
+            last_error_timestamp: 0,
+            most_frequent_error: 0,
+            hourly_error_rate: 0,
+            critical_errors: 0,
         }
-
-        // Calculate price deviation as percentage
-        let deviation = if last_price > price {
-            ((last_price - price) * 100) / last_price
-        } else {
-            ((price - last_price) * 100) / last_price
-        };
-
-        deviation <= max_deviation
     }
-}
 
-/// Oracle data storage and management
-pub struct OracleData;
+    pub fn record_error(&mut self, error_code: u32, timestamp: u64, is_critical: bool) {
+        self.total_errors += 1;
+        self.last_error_timestamp = timestamp;
+        
+        if is_critical {
+            self.critical_errors += 1;
+        }
 
-impl OracleData {
-    fn price_key() -> Symbol {
-        Symbol::short("oracle_p")
-    }
-    fn last_update_key() -> Symbol {
-        Symbol::short("oracle_t")
+        // Update error counts
+        let mut found = false;
+        for i in 0..self.error_counts.len() {
+            let (code, count) = self.error_counts.get(i).unwrap();
+            if code == error_code {
+                self.error_counts.set(i, (code, count + 1));
+                found = true;
+                break;
+            }
+        }
+        
+        if !found {
+            self.error_counts.push_back((error_code, 1));
+        }
+
+        // Update most frequent error
+        self.update_most_frequent_error();
     }
 
-    pub fn set_price(env: &Env, price: i128) {
-        env.storage().instance().set(&Self::price_key(), &price);
+    pub fn record_recovery_attempt(&mut self) {
+        self.recovery_attempts += 1;
     }
 
-    pub fn get_price(env: &Env) -> i128 {
-        env.storage()
-            .instance()
-            .get::<Symbol, i128>(&Self::price_key())
-            .unwrap_or(0)
+    pub fn record_successful_recovery(&mut self) {
+        self.successful_recoveries += 1;
     }
 
-    pub fn set_last_update(env: &Env, timestamp: u64) {
-        env.storage()
-            .instance()
-            .set(&Self::last_update_key(), &timestamp);
+    fn update_most_frequent_error(&mut self) {
+        let mut max_count = 0;
+        let mut max_error = 0;
+        
+        for i in 0..self.error_counts.len() {
+            let (code, count) = self.error_counts.get(i).unwrap();
+            if count > max_count {
+                max_count = count;
+                max_error = code;
+            }
+        }
+        
+        self.most_frequent_error = max_error;
     }
 
-    pub fn get_last_update(env: &Env) -> u64 {
-        env.storage()
-            .instance()
-            .get::<Symbol, u64>(&Self::last_update_key())
-            .unwrap_or(0)
+    pub fn get_recovery_rate(&self) -> u32 {
+        if self.recovery_attempts == 0 {
+            return 0;
+        }
+        (self.successful_recoveries * 100) / self.recovery_attempts
     }
 }
 
-/// Oracle configuration management
-pub struct OracleConfig;
+/// Error logging and management system
+pub struct ErrorLogger;
 
-impl OracleConfig {
-    fn max_deviation_key() -> Symbol {
-        Symbol::short("max_dev")
+impl ErrorLogger {
+    fn analytics_key() -> DataKey {
+        DataKey::ErrorAnalytics
     }
-    fn heartbeat_key() -> Symbol {
-        Symbol::short("heartbeat")
+
+    fn error_log_key(index: u32) -> DataKey {
+        DataKey::ErrorLog(index)
     }
-    fn fallback_price_key() -> Symbol {
-        Symbol::short("fallback")
+
+    fn error_counter_key() -> DataKey {
+        DataKey::ErrorCounter
     }
 
-    pub fn set_max_price_deviation(
+    /// Log an error with full context
+    pub fn log_error(
         env: &Env,
-        caller: &Address,
-        deviation: i128,
-    ) -> Result<(), ProtocolError> {
-        ProtocolConfig::require_admin(env, caller)?;
-        env.storage()
-            .instance()
-            .set(&Self::max_deviation_key(), &deviation);
-        Ok(())
-    }
+        error: &ProtocolError,
+        user: Option<Address>,
+        function: &str,
+        context_data: &str,
+    ) -> ErrorContext {
+        let context = ErrorContext::new(env, error, user, function, context_data);
+        
+        // Get next error index
+        let counter = env.storage().instance().get::<DataKey, u32>(&Self::error_counter_key()).unwrap_or(0);
+        let next_counter = counter + 1;
+        env.storage().instance().set(&Self::error_counter_key(), &next_counter);
 
-    pub fn get_max_price_deviation(env: &Env) -> i128 {
-        env.storage()
-            .instance()
-            .get::<Symbol, i128>(&Self::max_deviation_key())
-            .unwrap_or(50) // Default 50%
-    }
+        // Store error context (keep last 100 errors)
+        let log_index = next_counter % 100;
+        UserStorage::set(env, &Self::error_log_key(log_index), &context);
 
-    pub fn set_heartbeat(env: &Env, caller: &Address, heartbeat: u64) -> Result<(), ProtocolError> {
-        ProtocolConfig::require_admin(env, caller)?;
-        env.storage()
-            .instance()
-            .set(&Self::heartbeat_key(), &heartbeat);
-        Ok(())
-    }
+        // Update analytics
+        let mut analytics = Self::get_analytics(env);
+        let is_critical = Self::is_critical_error(error);
+        analytics.record_error(error.get_error_code(), context.timestamp, is_critical);
+        Self::save_analytics(env, &analytics);
+
+        // Emit error event
+        env.events().publish(
+            (symbol_short!("err_log"), symbol_short!("err_code")),
+            (
+                error.get_error_code(),
+                String::from_str(env, function),
+                context.timestamp,
+            ),
+        );
 
-    pub fn get_heartbeat(env: &Env) -> u64 {
-        env.storage()
-            .instance()
-            .get::<Symbol, u64>(&Self::heartbeat_key())
-            .unwrap_or(3600) // Default 1 hour
+        context
     }
 
-    pub fn set_fallback_price(
+    /// Attempt error recovery
+    pub fn attempt_recovery(
         env: &Env,
-        caller: &Address,
-        price: i128,
+        mut context: ErrorContext,
+        recovery_fn: fn(&Env, &ErrorContext) -> Result<(), ProtocolError>,
     ) -> Result<(), ProtocolError> {
-        ProtocolConfig::require_admin(env, caller)?;
-        env.storage()
-            .instance()
-            .set(&Self::fallback_price_key(), &price);
-        Ok(())
+        context.mark_recovery_attempted();
+        
+        // Update analytics
+        let mut analytics = Self::get_analytics(env);
+        analytics.record_recovery_attempt();
+        
+        match recovery_fn(env, &context) {
+            Ok(()) => {
+                context.mark_recovery_successful();
+                analytics.record_successful_recovery();
+                Self::save_analytics(env, &analytics);
+                
+                // Emit recovery success event
+                env.events().publish(
+                    (symbol_short!("rec_ok"), symbol_short!("err_code")),
+                    (context.error_code, env.ledger().timestamp()),
+                );
+                
+                Ok(())
+            }
+            Err(recovery_error) => {
+                Self::save_analytics(env, &analytics);
+                
+                // Emit recovery failure event
+                env.events().publish(
+                    (symbol_short!("rec_fail"), symbol_short!("err_code")),
+                    (context.error_code, recovery_error.get_error_code(), env.ledger().timestamp()),
+                );
+                
+                Err(recovery_error)
+            }
+        }
     }
 
-    pub fn get_fallback_price(env: &Env) -> i128 {
+    pub fn get_analytics(env: &Env) -> ErrorAnalytics {
         env.storage()
             .instance()
-            .get::<Symbol, i128>(&Self::fallback_price_key())
-            .unwrap_or(150_000_000) // Default 1.5
+            .get(&Self::analytics_key())
+            .unwrap_or_else(|| ErrorAnalytics::new(env))
     }
 
-    pub fn is_price_stale(env: &Env) -> bool {
-        let last_update = OracleData::get_last_update(env);
-        let heartbeat = Self::get_heartbeat(env);
-        let current_time = env.ledger().timestamp();
-
-        current_time - last_update > heartbeat
+    pub fn save_analytics(env: &Env, analytics: &ErrorAnalytics) {
+        env.storage().instance().set(&Self::analytics_key(), analytics);
     }
-}
 
-/// Mock implementation of the price oracle (kept for backward compatibility)
-pub struct MockOracle;
-
-impl PriceOracle for MockOracle {
-    fn get_price(_env: &Env) -> i128 {
-        // For demo: 1 collateral = 2 debt (price = 2e8)
-        200_000_000 // 2.0 * 1e8
+    pub fn get_error_log(env: &Env, index: u32) -> Option<ErrorContext> {
+        UserStorage::get(env, &Self::error_log_key(index))
     }
 
-    fn get_last_update(_env: &Env) -> u64 {
-        0 // Mock oracle doesn't track updates
+    pub fn get_recent_errors(env: &Env, limit: u32) -> Vec<ErrorContext> {
+        let mut errors = Vec::new(env);
+        let counter = env.storage().instance().get::<DataKey, u32>(&Self::error_counter_key()).unwrap_or(0);
+        
+        let start = counter.saturating_sub(limit);
+        
+        for i in start..counter {
+            let log_index = (i + 1) % 100;
+            if let Some(error_context) = Self::get_error_log(env, log_index) {
+                errors.push_back(error_context);
+            }
+        }
+        
+        errors
     }
 
-    fn validate_price(_env: &Env, _price: i128) -> bool {
-        true // Mock oracle always validates
+    fn is_critical_error(error: &ProtocolError) -> bool {
+        matches!(
+            error,
+            ProtocolError::ReentrancyDetected
+                | ProtocolError::ComplianceViolation
+                | ProtocolError::OracleFailure
+                | ProtocolError::ConfigurationError
+                | ProtocolError::Unknown
+        )
     }
 }
 
-/// Protocol configuration and admin management
-pub struct ProtocolConfig;
-
-impl ProtocolConfig {
-    /// Storage key for admin address
-    fn admin_key() -> Symbol {
-        Symbol::short("admin")
-    }
-    /// Storage key for oracle address
-    fn oracle_key() -> Symbol {
-        Symbol::short("oracle")
-    }
-    /// Storage key for min collateral ratio
-    fn min_collateral_ratio_key() -> Symbol {
-        Symbol::short("min_ratio")
-    }
-
-    /// Set the admin address (only callable once)
-    pub fn set_admin(env: &Env, admin: &Address) {
-        if env.storage().instance().has(&Self::admin_key()) {
-            panic!("Admin already set");
-        }
-        env.storage().instance().set(&Self::admin_key(), admin);
-    }
-
-    /// Get the admin address
-    pub fn get_admin(env: &Env) -> Address {
-        env.storage()
-            .instance()
-            .get(&Self::admin_key())
-            .expect("Admin not set")
-    }
+/// Error recovery strategies
+pub struct ErrorRecovery;
 
-    /// Require that the caller is admin
-    pub fn require_admin(env: &Env, caller: &Address) -> Result<(), ProtocolError> {
-        let admin = Self::get_admin(env);
-        if &admin != caller {
-            return Err(ProtocolError::NotAdmin);
+impl ErrorRecovery {
+    /// Recovery function for storage errors
+    pub fn recover_storage_error(env: &Env, _context: &ErrorContext) -> Result<(), ProtocolError> {
+        // Attempt to retry the storage operation after a brief delay
+        let test_key = DataKey::StorageProbe;
+        env.storage().instance().set(&test_key, &true);
+        
+        if env.storage().instance().has(&test_key) {
+            env.storage().instance().remove(&test_key);
+            Ok(())
+        } else {
+            Err(ProtocolError::RecoveryFailed)
         }
-        Ok(())
-    }
-
-    /// Set the oracle address (admin only)
-    pub fn set_oracle(env: &Env, caller: &Address, oracle: &Address) -> Result<(), ProtocolError> {
-        Self::require_admin(env, caller)?;
-        env.storage().instance().set(&Self::oracle_key(), oracle);
-        Ok(())
     }
 
-    /// Get the oracle address
-    pub fn get_oracle(env: &Env) -> Address {
-        env.storage()
-            .instance()
-            .get::<Symbol, Address>(&Self::oracle_key())
-            .expect("Oracle not set")
-    }
-
-    /// Set the minimum collateral ratio (admin only)
-    pub fn set_min_collateral_ratio(
+    /// Generic recovery dispatcher
+    pub fn attempt_recovery(
         env: &Env,
-        caller: &Address,
-        ratio: i128,
+        error: &ProtocolError,
+        context: ErrorContext,
     ) -> Result<(), ProtocolError> {
-        Self::require_admin(env, caller)?;
-        env.storage()
-            .instance()
-            .set(&Self::min_collateral_ratio_key(), &ratio);
-        Ok(())
-    }
+        if !error.is_recoverable() {
+            return Err(ProtocolError::RecoveryFailed);
+        }
 
-    /// Get the minimum collateral ratio
-    pub fn get_min_collateral_ratio(env: &Env) -> i128 {
-        env.storage()
-            .instance()
-            .get::<Symbol, i128>(&Self::min_collateral_ratio_key())
-            .unwrap_or(150)
-    }
-}
+        let recovery_fn = match error {
+            ProtocolError::StorageError => Self::recover_storage_error,
+            _ => return Err(ProtocolError::RecoveryFailed),
+        };
 
-/// Enhanced error type for protocol errors with detailed context
-#[contracterror]
-#[derive(Debug, Eq, PartialEq)]
-pub enum ProtocolError {
-    Unauthorized = 1,
-    InsufficientCollateral = 2,
-    InsufficientCollateralRatio = 3,
-    InvalidAmount = 4,
-    InvalidAddress = 5,
-    PositionNotFound = 6,
-    AlreadyInitialized = 7,
-    NotAdmin = 8,
-    OracleNotSet = 9,
-    AdminNotSet = 10,
-    NotEligibleForLiquidation = 11,
-    ProtocolPaused = 12,
-    AssetNotSupported = 13,
-    AssetDisabled = 14,
-    InvalidAsset = 15,
-    Unknown = 16,
-    AlreadyExists = 17,
-    NotFound = 18,
-    InvalidOperation = 19,
-    InvalidInput = 20,
-    // Enhanced error types
-    OracleFailure = 21,
-    PriceStale = 22,
-    SlippageExceeded = 23,
-    ReentrancyDetected = 24,
-    ComplianceViolation = 25,
-    NetworkError = 26,
-    RateLimitExceeded = 27,
-    ConfigurationError = 28,
-    StorageError = 29,
-    RecoveryFailed = 30,
-    // Social Recovery and Multi-Signature errors
-    GuardianNotFound = 31,
-    GuardianAlreadyExists = 32,
-    RecoveryRequestNotFound = 33,
-    RecoveryRequestAlreadyExists = 34,
-    RecoveryNotReady = 35,
-    InsufficientGuardianApprovals = 36,
-    MultiSigProposalNotFound = 37,
-    MultiSigProposalAlreadyExists = 38,
-    MultiSigNotReady = 39,
-    InsufficientSignatures = 40,
-    InvalidGuardianAddress = 41,
-    InvalidRecoveryAddress = 42,
-    RecoveryTimeDelayNotMet = 43,
-    MultiSigTimeDelayNotMet = 44,
+        ErrorLogger::attempt_recovery(env, context, recovery_fn)
+    }
 }
-
-impl ProtocolError {
-    pub fn to_str(&self) -> &'static str {
-        match self {
-            ProtocolError::Unauthorized => "Unauthorized access denied",
-            ProtocolError::InsufficientCollateral => "Insufficient collateral for operation",
-            ProtocolError::InsufficientCollateralRatio => "Collateral ratio below required minimum",
-            ProtocolError::InvalidAmount => "Invalid amount provided",
-            ProtocolError::InvalidAddress => "Invalid address format or address not found",
-            ProtocolError::PositionNotFound => "User position not found in protocol",
-            ProtocolError::AlreadyInitialized => "Component already initialized",
-            ProtocolError::NotAdmin => "Administrative privileges required",
-            ProtocolError::OracleNotSet => "Price oracle not configured",
-            ProtocolError::AdminNotSet => "Administrator not configured",
-            ProtocolError::NotEligibleForLiquidation => "Position does not meet liquidation criteria",
-            ProtocolError::ProtocolPaused => "Protocol operations are currently paused",
-            ProtocolError::AssetNotSupported => "Asset not supported by protocol",
-            ProtocolError::AssetDisabled => "Asset operations currently disabled",
-            ProtocolError::InvalidAsset => "Invalid asset configuration",
-            ProtocolError::Unknown => "Unknown error occurred",
-            ProtocolError::AlreadyExists => "Resource already exists",
-            ProtocolError::NotFound => "Requested resource not found",
-            ProtocolError::InvalidOperation => "Operation not valid in current state",
-            ProtocolError::InvalidInput => "Invalid input parameters provided",
-            // Enhanced error messages
-            ProtocolError::OracleFailure => "Oracle service failure or unreachable",
-            ProtocolError::PriceStale => "Price data is stale beyond acceptable threshold",
-            ProtocolError::SlippageExceeded => "Price slippage exceeded maximum tolerance",
-            ProtocolError::ReentrancyDetected => "Reentrancy attack detected and blocked",
-            ProtocolError::ComplianceViolation => "Transaction violates compliance requirements",
-            ProtocolError::NetworkError => "Network connectivity issues detected",
-            ProtocolError::RateLimitExceeded => "Rate limit exceeded for this operation",
-            ProtocolError::ConfigurationError => "System configuration error detected",
-            ProtocolError::StorageError => "Storage operation failed",
-            ProtocolError::RecoveryFailed => "Error recovery operation failed",
-            // Social Recovery and Multi-Signature errors
-            ProtocolError::GuardianNotFound => "Guardian not found",
-            ProtocolError::GuardianAlreadyExists => "Guardian already exists",
-            ProtocolError::RecoveryRequestNotFound => "Recovery request not found",
-            ProtocolError::RecoveryRequestAlreadyExists => "Recovery request already exists",
-            ProtocolError::RecoveryNotReady => "Recovery not ready for execution",
-            ProtocolError::InsufficientGuardianApprovals => "Insufficient guardian approvals",
-            ProtocolError::MultiSigProposalNotFound => "Multi-signature proposal not found",
-            ProtocolError::MultiSigProposalAlreadyExists => "Multi-signature proposal already exists",
-            ProtocolError::MultiSigNotReady => "Multi-signature proposal not ready for execution",
-            ProtocolError::InsufficientSignatures => "Insufficient signatures for multi-signature operation",
-            ProtocolError::InvalidGuardianAddress => "Invalid guardian address",
-            ProtocolError::InvalidRecoveryAddress => "Invalid recovery address",
-            ProtocolError::RecoveryTimeDelayNotMet => "Recovery time delay not met",
-            ProtocolError::MultiSigTimeDelayNotMet => "Multi-signature time delay not met",
+
+// This is a sample contract. Replace this placeholder with your own contract logic.
+// A corresponding test example is available in `test.rs`.
+//
+// For comprehensive examples, visit <https://github.com/stellar/soroban-examples>.
+// The repository includes use cases for the Stellar ecosystem, such as data storage on
+// the blockchain, token swaps, liquidity pools, and more.
+//
+// Refer to the official documentation:
+// <https://developers.stellar.org/docs/build/smart-contracts/overview>.
+#[contractimpl]
+impl Contract {
+    /// Initializes the contract and sets the admin address
+    pub fn initialize(env: Env, admin: Address) -> Result<(), ProtocolError> {
+        if env.storage().instance().has(&ProtocolConfig::admin_key()) {
+            return Err(ProtocolError::AlreadyInitialized);
         }
+        admin.require_auth();
+        ProtocolConfig::set_admin(&env, &admin);
+
+        // Interest rate config and state start at their defaults on each listed asset
+
+        // Initialize risk management system with default configuration
+        let risk_config = RiskConfig::default();
+        RiskConfigStorage::save(&env, &risk_config);
+
+        // Initialize reserve management system with default configuration
+        let mut reserve_data = ReserveData::default(&env);
+        reserve_data.treasury_address = admin.clone();
+        ReserveStorage::save_reserve_data(&env, &reserve_data);
+
+        let revenue_metrics = RevenueMetrics::default();
+        ReserveStorage::save_revenue_metrics(&env, &revenue_metrics);
+
+        // Initialize multi-asset support
+        let asset_registry = AssetRegistry::new(&env, String::from_str(&env, "XLM"));
+        AssetStorage::save_registry(&env, &asset_registry);
+
+        // Initialize default XLM asset
+        let xlm_oracle = Address::from_string(&String::from_str(
+            &env,
+            "GCXOTMMXRS24MYZI5FJPUCOEOFNWSR4XX7UXIK3NDGGE6A5QMJ5FF2FS",
+        ));
+        let xlm_asset_info = AssetInfo::new(
+            String::from_str(&env, "XLM"),
+            7, // XLM has 7 decimals
+            xlm_oracle,
+            None, // token contract is wired up via set_asset_token_address
+            150,  // 150% minimum collateral ratio
+        );
+        AssetStorage::save_asset_info(&env, &String::from_str(&env, "XLM"), &xlm_asset_info);
+        env.storage().instance().set(&DataKey::StorageVersion, &STORAGE_VERSION);
+        UserStorage::bump_instance(&env);
+
+        Ok(())
     }
 
-    pub fn get_error_code(&self) -> u32 {
-        match self {
-            ProtocolError::Unauthorized => 1,
-            ProtocolError::InsufficientCollateral => 2,
-            ProtocolError::InsufficientCollateralRatio => 3,
-            ProtocolError::InvalidAmount => 4,
-            ProtocolError::InvalidAddress => 5,
-            ProtocolError::PositionNotFound => 6,
-            ProtocolError::AlreadyInitialized => 7,
-            ProtocolError::NotAdmin => 8,
-            ProtocolError::OracleNotSet => 9,
-            ProtocolError::AdminNotSet => 10,
-            ProtocolError::NotEligibleForLiquidation => 11,
-            ProtocolError::ProtocolPaused => 12,
-            ProtocolError::AssetNotSupported => 13,
-            ProtocolError::AssetDisabled => 14,
-            ProtocolError::InvalidAsset => 15,
-            ProtocolError::Unknown => 16,
-            ProtocolError::AlreadyExists => 17,
-            ProtocolError::NotFound => 18,
-            ProtocolError::InvalidOperation => 19,
-            ProtocolError::InvalidInput => 20,
-            ProtocolError::OracleFailure => 21,
-            ProtocolError::PriceStale => 22,
-            ProtocolError::SlippageExceeded => 23,
-            ProtocolError::ReentrancyDetected => 24,
-            ProtocolError::ComplianceViolation => 25,
-            ProtocolError::NetworkError => 26,
-            ProtocolError::RateLimitExceeded => 27,
-            ProtocolError::ConfigurationError => 28,
-            ProtocolError::StorageError => 29,
-            ProtocolError::RecoveryFailed => 30,
-        }
-    }
-
-    pub fn get_detailed_message(&self, context: &str) -> String {
-        let env = Env::default();
-        if context.is_empty() {
-            String::from_str(&env, self.to_str())
-        } else {
-            let mut msg = String::from_str(&env, self.to_str());
-            let context_str = String::from_str(&env, " | Context: ");
-            let context_msg = String::from_str(&env, context);
-            // Concatenate strings (simplified approach)
-            String::from_str(&env, &format!("{}{}{}", msg.to_string(), context_str.to_string(), context_msg.to_string()))
-        }
+    /// Move data stored under the legacy symbol keys to the typed key layout and rewrite
+    /// entries stored in earlier layouts (admin only). Positions are migrated for the given
+    /// `users`, so large books can be migrated in batches; returns the number of entries
+    /// moved or rewritten.
+    pub fn migrate_storage(
+        env: Env,
+        caller: Address,
+        users: Vec<Address>,
+    ) -> Result<u32, ProtocolError> {
+        StorageMigration::require_admin(&env, &caller)?;
+        let moved = StorageMigration::migrate_admin(&env);
+        Ok(moved + StorageMigration::migrate(&env, &users))
     }
 
-    pub fn is_recoverable(&self) -> bool {
-        match self {
-            ProtocolError::OracleFailure => true,
-            ProtocolError::PriceStale => true,
-            ProtocolError::NetworkError => true,
-            ProtocolError::RateLimitExceeded => true,
-            ProtocolError::StorageError => true,
-            _ => false,
-        }
+    /// Storage layout version of this contract
+    pub fn get_storage_version(env: Env) -> u32 {
+        StorageMigration::version(&env)
     }
 
-    pub fn get_recovery_suggestion(&self) -> &'static str {
-        match self {
-            ProtocolError::OracleFailure => "Retry with fallback oracle or use cached price",
-            ProtocolError::PriceStale => "Request fresh price update from oracle",
-            ProtocolError::NetworkError => "Retry operation after network recovery",
-            ProtocolError::RateLimitExceeded => "Wait and retry operation later",
-            ProtocolError::StorageError => "Retry storage operation",
-            ProtocolError::InsufficientCollateral => "Add more collateral to position",
-            ProtocolError::InsufficientCollateralRatio => "Increase collateral or reduce debt",
-            _ => "Contact protocol administrators for assistance",
-        }
+    /// Set the oracle address (admin only)
+    pub fn set_oracle(env: Env, caller: Address, oracle: Address) -> Result<(), ProtocolError> {
+        ProtocolConfig::set_oracle(&env, &caller, &oracle)?;
+        Ok(())
     }
-}
 
-/// Error context for detailed debugging and analytics
-#[derive(Clone, Debug, Eq, PartialEq)]
-#[contracttype]
-pub struct ErrorContext {
-    /// Error code
-    pub error_code: u32,
-    /// Detailed error message
-    pub message: String,
-    /// User address involved (if applicable)
-    pub user: Option<Address>,
-    /// Function that triggered the error
-    pub function: String,
-    /// Additional context data
-    pub context_data: String,
-    /// Timestamp when error occurred
-    pub timestamp: u64,
-    /// Whether recovery was attempted
-    pub recovery_attempted: bool,
-    /// Whether recovery was successful
-    pub recovery_successful: bool,
-}
+    /// Set the minimum collateral ratio (admin only)
+    pub fn set_min_collateral_ratio(
+        env: Env,
+        caller: Address,
+        ratio: i128,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::set_min_collateral_ratio(&env, &caller, ratio)?;
+        Ok(())
+    }
 
-impl ErrorContext {
-    pub fn new(
-        env: &Env,
-        error: &ProtocolError,
-        user: Option<Address>,
-        function: &str,
-        context_data: &str,
-    ) -> Self {
-        Self {
-            error_code: error.get_error_code(),
-            message: error.get_detailed_message(context_data),
-            user,
-            function: String::from_str(env, function),
-            context_data: String::from_str(env, context_data),
-            timestamp: env.ledger().timestamp(),
-            recovery_attempted: false,
-            recovery_successful: false,
-        }
+    /// Set the maximum price deviation for oracle validation (admin only)
+    pub fn set_max_price_deviation(
+        env: Env,
+        caller: Address,
+        deviation: i128,
+    ) -> Result<(), ProtocolError> {
+        OracleConfig::set_max_price_deviation(&env, &caller, deviation)?;
+        Ok(())
     }
 
-    pub fn mark_recovery_attempted(&mut self) {
-        self.recovery_attempted = true;
+    /// Set the oracle heartbeat interval (admin only)
+    pub fn set_oracle_heartbeat(
+        env: Env,
+        caller: Address,
+        heartbeat: u64,
+    ) -> Result<(), ProtocolError> {
+        OracleConfig::set_heartbeat(&env, &caller, heartbeat)?;
+        Ok(())
     }
 
-    pub fn mark_recovery_successful(&mut self) {
-        self.recovery_successful = true;
+    /// Get the oracle configuration: the maximum price deviation and the heartbeat after
+    /// which asset prices are stale
+    pub fn get_oracle_info(env: Env) -> Result<(i128, u64), ProtocolError> {
+        Ok((
+            OracleConfig::get_max_price_deviation(&env),
+            OracleConfig::get_heartbeat(&env),
+        ))
     }
-}
 
-/// Error analytics and metrics
-#[derive(Clone, Debug, Eq, PartialEq)]
-#[contracttype]
-pub struct ErrorAnalytics {
-    /// Total error count
-    pub total_errors: u32,
-    /// Errors by type (simplified - top 10 error codes with counts)
-    pub error_counts: Vec<(u32, u32)>,
-    /// Recoverable errors attempted
-    pub recovery_attempts: u32,
-    /// Successful recoveries
-    pub successful_recoveries: u32,
-    /// Last error timestamp
-    pub last_error_timestamp: u64,
-    /// Most frequent error type
-    pub most_frequent_error: u32,
-    /// Error rate (errors per hour)
-    pub hourly_error_rate: u32,
-    /// Critical errors requiring immediate attention
-    pub critical_errors: u32,
-}
+    /// Point a listed asset at the oracle contract that prices it (admin only)
+    pub fn set_asset_oracle(
+        env: Env,
+        caller: Address,
+        asset: String,
+        oracle: Address,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+        let mut asset_info = AssetStorage::require_asset(&env, &asset)?;
+        asset_info.oracle_address = oracle;
+        asset_info.last_update = env.ledger().timestamp();
+        AssetStorage::save_asset_info(&env, &asset, &asset_info);
 
-impl ErrorAnalytics {
-    pub fn new() -> Self {
-        let env = Env::default();
-        Self {
-            total_errors: 0,
-            error_counts: Vec::new(&env),
-            recovery_attempts: 0,
-            successful_recoveries: 0,
-            last_error_timestamp: 0,
-            most_frequent_error: 0,
-            hourly_error_rate: 0,
-            critical_errors: 0,
+        ProtocolEvent::AssetUpdated {
+            asset: asset.clone(),
+            parameter: String::from_str(&env, "oracle"),
+            old_value: String::from_str(&env, ""),
+            new_value: String::from_str(&env, ""),
         }
+        .emit(&env);
+        Ok(())
+    }
+
+    /// Approve or revoke a swap adapter for collateral swaps and leverage (admin only)
+    pub fn set_swap_adapter(
+        env: Env,
+        caller: Address,
+        adapter: Address,
+        allowed: bool,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+        SwapAdapters::set(&env, &adapter, allowed);
+        Ok(())
+    }
+
+    /// Swap adapters currently approved by the admin
+    pub fn get_swap_adapters(env: Env) -> Vec<Address> {
+        SwapAdapters::list(&env)
+    }
+
+    /// Get the price used to value a listed asset (scaled by 1e8)
+    pub fn get_asset_price(env: Env, asset: String) -> Result<i128, ProtocolError> {
+        let asset_info = AssetStorage::require_asset(&env, &asset)?;
+        StateHelper::asset_price(&env, &asset_info)
+    }
+
+    // --- Interest Rate Management Functions ---
+
+    /// Set the base interest rate (admin only)
+    pub fn set_base_rate(env: Env, caller: Address, rate: i128) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+
+        let mut config = InterestRateStorage::get_config(&env);
+        config.base_rate = rate;
+        config.last_update = env.ledger().timestamp();
+        InterestRateStorage::save_config(&env, &config);
+
+        // Update current rates
+        InterestRateStorage::update_state(&env);
+
+        Ok(())
+    }
+
+    /// Set the kink utilization point (admin only)
+    pub fn set_kink_utilization(
+        env: Env,
+        caller: Address,
+        utilization: i128,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+
+        let mut config = InterestRateStorage::get_config(&env);
+        config.kink_utilization = utilization;
+        config.last_update = env.ledger().timestamp();
+        InterestRateStorage::save_config(&env, &config);
+
+        // Update current rates
+        InterestRateStorage::update_state(&env);
+
+        Ok(())
+    }
+
+    /// Set the rate multiplier (admin only)
+    pub fn set_multiplier(env: Env, caller: Address, multiplier: i128) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+
+        let mut config = InterestRateStorage::get_config(&env);
+        config.multiplier = multiplier;
+        config.last_update = env.ledger().timestamp();
+        InterestRateStorage::save_config(&env, &config);
+
+        // Update current rates
+        InterestRateStorage::update_state(&env);
+
+        Ok(())
+    }
+
+    /// Set the reserve factor (admin only)
+    pub fn set_reserve_factor(env: Env, caller: Address, factor: i128) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+
+        let mut config = InterestRateStorage::get_config(&env);
+        config.reserve_factor = factor;
+        config.last_update = env.ledger().timestamp();
+        InterestRateStorage::save_config(&env, &config);
+
+        // Update current rates
+        InterestRateStorage::update_state(&env);
+
+        Ok(())
+    }
+
+    /// Set rate limits (admin only)
+    pub fn set_rate_limits(
+        env: Env,
+        caller: Address,
+        floor: i128,
+        ceiling: i128,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+
+        let mut config = InterestRateStorage::get_config(&env);
+        config.rate_floor = floor;
+        config.rate_ceiling = ceiling;
+        config.last_update = env.ledger().timestamp();
+        InterestRateStorage::save_config(&env, &config);
+
+        // Update current rates
+        InterestRateStorage::update_state(&env);
+
+        Ok(())
     }
 
-    pub fn record_error(&mut self, error_code: u32, timestamp: u64, is_critical: bool) {
-        self.total_errors += 1;
-        self.last_error_timestamp = timestamp;
-        
-        if is_critical {
-            self.critical_errors += 1;
-        }
+    /// Emergency rate adjustment (admin only)
+    pub fn emergency_rate_adjustment(
+        env: Env,
+        caller: Address,
+        new_rate: i128,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
 
-        // Update error counts
-        let mut found = false;
-        for i in 0..self.error_counts.len() {
-            let (code, count) = self.error_counts.get(i).unwrap();
-            if code == error_code {
-                self.error_counts.set(i, (code, count + 1));
-                found = true;
-                break;
-            }
-        }
-        
-        if !found {
-            self.error_counts.push_back((error_code, 1));
+        // Written on the market directly: saving the state would re-derive the rate from
+        // utilization and drop the override
+        let asset = AssetStorage::get_registry(&env).default_asset;
+        if let Some(mut info) = AssetStorage::get_asset_info(&env, &asset) {
+            info.interest_state.current_borrow_rate = new_rate;
+            info.interest_state.last_accrual_time = env.ledger().timestamp();
+            AssetStorage::save_asset_info(&env, &asset, &info);
         }
 
-        // Update most frequent error
-        self.update_most_frequent_error();
+        Ok(())
     }
 
-    pub fn record_recovery_attempt(&mut self) {
-        self.recovery_attempts += 1;
+    /// Get current interest rates
+    pub fn get_current_rates(env: Env) -> Result<(i128, i128), ProtocolError> {
+        let state = InterestRateStorage::update_state(&env);
+        Ok((state.current_borrow_rate, state.current_supply_rate))
     }
 
-    pub fn record_successful_recovery(&mut self) {
-        self.successful_recoveries += 1;
+    /// Get an asset's supply index, i.e. the underlying amount one sToken share redeems for (scaled by 1e18)
+    pub fn get_supply_index(env: Env, asset: String) -> Result<i128, ProtocolError> {
+        AssetStorage::require_asset(&env, &asset)?;
+        Ok(InterestRateStorage::current_supply_index(&env, &asset))
     }
 
-    fn update_most_frequent_error(&mut self) {
-        let mut max_count = 0;
-        let mut max_error = 0;
-        
-        for i in 0..self.error_counts.len() {
-            let (code, count) = self.error_counts.get(i).unwrap();
-            if count > max_count {
-                max_count = count;
-                max_error = code;
-            }
-        }
-        
-        self.most_frequent_error = max_error;
+    /// Get utilization metrics
+    pub fn get_utilization_metrics(env: Env) -> Result<(i128, i128, i128), ProtocolError> {
+        let state = InterestRateStorage::update_state(&env);
+        Ok((
+            state.utilization_rate,
+            state.total_borrowed,
+            state.total_supplied,
+        ))
     }
 
-    pub fn get_recovery_rate(&self) -> u32 {
-        if self.recovery_attempts == 0 {
-            return 0;
-        }
-        (self.successful_recoveries * 100) / self.recovery_attempts
-    }
-}
+    /// Get user's accrued interest in an asset
+    pub fn get_user_accrued_interest(
+        env: Env,
+        user: Address,
+        asset: String,
+    ) -> Result<(i128, i128), ProtocolError> {
+        AssetStorage::require_asset(&env, &asset)?;
+        let mut position = StateHelper::get_position(&env, &user, &asset)
+            .unwrap_or(AssetPosition::new(user, asset.clone(), 0, 0));
 
-/// Error logging and management system
-pub struct ErrorLogger;
+        // Accrue interest for the position
+        let state = InterestRateStorage::update_asset_state(&env, &asset);
+        InterestRateManager::accrue_interest_for_position(
+            &env,
+            &mut position,
+            state.borrow_index,
+            state.supply_index,
+        );
 
-impl ErrorLogger {
-    fn analytics_key() -> Symbol {
-        Symbol::short("err_analytics")
+        Ok((position.borrow_interest, position.supply_interest))
     }
 
-    fn error_log_key(index: u32) -> Symbol {
-        Symbol::new(&Env::default(), &format!("err_log_{}", index))
+    /// Manually accrue interest on every listed asset (anyone can call)
+    pub fn accrue_interest(env: Env) -> Result<(), ProtocolError> {
+        let registry = AssetStorage::get_registry(&env);
+        for asset in registry.supported_assets.iter() {
+            InterestRateStorage::update_asset_state(&env, &asset);
+        }
+        Ok(())
     }
 
-    fn error_counter_key() -> Symbol {
-        Symbol::short("err_counter")
+    /// Get interest rate configuration
+    pub fn get_interest_rate_config(env: Env) -> Result<InterestRateConfigView, ProtocolError> {
+        let config = InterestRateStorage::get_config(&env);
+        Ok((
+            config.base_rate,
+            config.kink_utilization,
+            config.multiplier,
+            config.reserve_factor,
+            config.rate_floor,
+            config.rate_ceiling,
+            config.last_update,
+        ))
     }
 
-    /// Log an error with full context
-    pub fn log_error(
-        env: &Env,
-        error: &ProtocolError,
-        user: Option<Address>,
-        function: &str,
-        context_data: &str,
-    ) -> ErrorContext {
-        let context = ErrorContext::new(env, error, user, function, context_data);
-        
-        // Get next error index
-        let counter = env.storage().instance().get::<Symbol, u32>(&Self::error_counter_key()).unwrap_or(0);
-        let next_counter = counter + 1;
-        env.storage().instance().set(&Self::error_counter_key(), &next_counter);
-
-        // Store error context (keep last 100 errors)
-        let log_index = next_counter % 100;
-        env.storage().instance().set(&Self::error_log_key(log_index), &context);
+    // --- Core Protocol Function Placeholders ---
+/// Deposit collateral in an asset into the protocol
+pub fn deposit_collateral(env: Env, asset: String, depositor: Address, amount: i128) -> Result<(), ProtocolError> {
+    depositor.require_auth();
+    Self::deposit_for(env, asset, depositor, amount)
+}
 
-        // Update analytics
-        let mut analytics = Self::get_analytics(env);
-        let is_critical = Self::is_critical_error(error);
-        analytics.record_error(error.get_error_code(), context.timestamp, is_critical);
-        Self::save_analytics(env, &analytics);
+/// Deposit collateral for an already authorized depositor
+fn deposit_for(env: Env, asset: String, depositor: Address, amount: i128) -> Result<(), ProtocolError> {
+    ReentrancyGuard::enter(&env)?;
+    let result = (|| {
+        // Input validation with enhanced error logging
+        if amount <= 0 {
+            let error = ProtocolError::InvalidAmount;
+            let context = format!("Invalid deposit amount: {}", amount);
+            ErrorLogger::log_error(&env, &error, None, "deposit_collateral", &context);
+            return Err(error);
+        }
 
-        // Emit error event
-        env.events().publish(
-            (Symbol::short("error_logged"), Symbol::short("error_code")),
-            (
-                error.get_error_code(),
-                String::from_str(env, function),
-                context.timestamp,
-            ),
-        );
+        // Resolve the asset's market
+        let asset_info = match AssetStorage::require_asset(&env, &asset) {
+            Ok(info) => info,
+            Err(error) => {
+                ErrorLogger::log_error(&env, &error, Some(depositor.clone()), "deposit_collateral", "Asset not supported");
+                return Err(error);
+            }
+        };
+        if !asset_info.deposit_enabled {
+            let error = ProtocolError::AssetDisabled;
+            ErrorLogger::log_error(&env, &error, Some(depositor.clone()), "deposit_collateral", "Deposits are disabled for this asset");
+            return Err(error);
+        }
 
-        context
-    }
+        // Check if deposit is paused
+        let risk_config = RiskConfigStorage::for_asset(&env, &asset_info);
+        if risk_config.pause_deposit {
+            let error = ProtocolError::ProtocolPaused;
+            ErrorLogger::log_error(&env, &error, None, "deposit_collateral", "Deposit operations are paused");
+            return Err(error);
+        }
 
-    /// Attempt error recovery
-    pub fn attempt_recovery(
-        env: &Env,
-        mut context: ErrorContext,
-        recovery_fn: fn(&Env, &ErrorContext) -> Result<(), ProtocolError>,
-    ) -> Result<(), ProtocolError> {
-        context.mark_recovery_attempted();
-        
-        // Update analytics
-        let mut analytics = Self::get_analytics(env);
-        analytics.record_recovery_attempt();
+        // Enhanced security checks with error logging
+        if FrozenAccounts::is_frozen(&env, &depositor) {
+            SecurityMonitor::record_suspicious(&env, &depositor, "deposit while frozen");
+            let error = ProtocolError::Unauthorized;
+            ErrorLogger::log_error(&env, &error, Some(depositor.clone()), "deposit_collateral", "Account is frozen");
+            return Err(error);
+        }
         
-        match recovery_fn(env, &context) {
-            Ok(()) => {
-                context.mark_recovery_successful();
-                analytics.record_successful_recovery();
-                Self::save_analytics(env, &analytics);
-                
-                // Emit recovery success event
-                env.events().publish(
-                    (Symbol::short("recovery_success"), Symbol::short("error_code")),
-                    (context.error_code, env.ledger().timestamp()),
-                );
-                
-                Ok(())
-            }
-            Err(recovery_error) => {
-                Self::save_analytics(env, &analytics);
-                
-                // Emit recovery failure event
-                env.events().publish(
-                    (Symbol::short("recovery_failed"), Symbol::short("error_code")),
-                    (context.error_code, recovery_error.get_error_code(), env.ledger().timestamp()),
-                );
-                
-                Err(recovery_error)
-            }
+        // Compliance checks with error logging
+        if let Err(error) = require_kyc(&env, &depositor) {
+            ErrorLogger::log_error(&env, &error, Some(depositor.clone()), "deposit_collateral", "KYC requirement not met");
+            return Err(error);
+        }
+        if let Err(error) = require_not_blacklisted(&env, &depositor) {
+            ErrorLogger::log_error(&env, &error, Some(depositor.clone()), "deposit_collateral", "Address is blacklisted");
+            return Err(error);
+        }
+        if let Err(error) = check_aml(&env, &depositor, amount, "deposit") {
+            ErrorLogger::log_error(&env, &error, Some(depositor.clone()), "deposit_collateral", "AML check failed");
+            return Err(error);
         }
-    }
 
-    pub fn get_analytics(env: &Env) -> ErrorAnalytics {
-        env.storage()
-            .instance()
-            .get(&Self::analytics_key())
-            .unwrap_or_else(ErrorAnalytics::new)
-    }
+        // Pull the collateral tokens into the protocol
+        let token = match asset_info.token_address.clone() {
+            Some(token) => token,
+            None => {
+                let error = ProtocolError::ConfigurationError;
+                ErrorLogger::log_error(&env, &error, Some(depositor.clone()), "deposit_collateral", "Asset token not configured");
+                return Err(error);
+            }
+        };
+        TokenHelper::transfer_in(&env, &token, &depositor, amount);
 
-    pub fn save_analytics(env: &Env, analytics: &ErrorAnalytics) {
-        env.storage().instance().set(&Self::analytics_key(), analytics);
-    }
+        // Load user position with error handling
+        let mut position = match StateHelper::get_position(&env, &depositor, &asset) {
+            Some(pos) => pos,
+            None => AssetPosition::new(depositor.clone(), asset.clone(), 0, 0),
+        };
 
-    pub fn get_error_log(env: &Env, index: u32) -> Option<ErrorContext> {
-        env.storage().instance().get(&Self::error_log_key(index))
-    }
+        // Accrue interest before updating position
+        let state = InterestRateStorage::update_asset_state(&env, &asset);
 
-    pub fn get_recent_errors(env: &Env, limit: u32) -> Vec<ErrorContext> {
-        let mut errors = Vec::new(env);
-        let counter = env.storage().instance().get::<Symbol, u32>(&Self::error_counter_key()).unwrap_or(0);
+        // Reject deposits that would take the asset over its supply cap
+        if let Err(error) = asset_info.check_supply_cap(&state, amount) {
+            ErrorLogger::log_error(&env, &error, Some(depositor.clone()), "deposit_collateral", "Supply cap exceeded");
+            return Err(error);
+        }
+
+        // Isolated collateral new to the account has to be able to back its existing debt
+        if let Err(error) = IsolationMode::record_collateral_added(&env, &depositor, MAIN_ACCOUNT, &asset_info) {
+            ErrorLogger::log_error(&env, &error, Some(depositor.clone()), "deposit_collateral", "Existing debt not allowed in isolation");
+            return Err(error);
+        }
         
-        let start = if counter > limit { counter - limit } else { 0 };
+        InterestRateManager::accrue_interest_for_position(
+            &env,
+            &mut position,
+            state.borrow_index,
+            state.supply_index,
+        );
+
+        // Update position with error recovery
+        position.collateral += amount;
         
-        for i in start..counter {
-            let log_index = (i + 1) % 100;
-            if let Some(error_context) = Self::get_error_log(env, log_index) {
-                errors.push_back(error_context);
+        // Attempt to save position with error recovery
+        let save_result = || -> Result<(), ProtocolError> {
+            StateHelper::save_position(&env, &position);
+            Ok(())
+        };
+
+        if save_result().is_err() {
+            let storage_error = ProtocolError::StorageError;
+            let context = ErrorLogger::log_error(
+                &env, 
+                &storage_error, 
+                Some(depositor.clone()), 
+                "deposit_collateral", 
+                "Failed to save user position"
+            );
+            
+            // Attempt recovery
+            match ErrorRecovery::attempt_recovery(&env, &storage_error, context) {
+                Ok(()) => {
+                    // Retry the save operation
+                    StateHelper::save_position(&env, &position);
+                }
+                Err(_) => return Err(storage_error),
+            }
+        }
+
+        // Update total collateral with error recovery; collateral is not lendable liquidity
+        let mut ir_state = InterestRateStorage::get_asset_state(&env, &asset);
+        ir_state.total_collateral += amount;
+        
+        let save_ir_result = || -> Result<(), ProtocolError> {
+            InterestRateStorage::save_asset_state(&env, &asset, &ir_state);
+            Ok(())
+        };
+
+        if save_ir_result().is_err() {
+            let storage_error = ProtocolError::StorageError;
+            let context = ErrorLogger::log_error(
+                &env, 
+                &storage_error, 
+                Some(depositor.clone()), 
+                "deposit_collateral", 
+                "Failed to update interest rate state"
+            );
+            
+            // Attempt recovery
+            if ErrorRecovery::attempt_recovery(&env, &storage_error, context).is_err() {
+                return Err(storage_error);
             }
         }
-        
-        errors
-    }
 
-    fn is_critical_error(error: &ProtocolError) -> bool {
-        match error {
-            ProtocolError::ReentrancyDetected => true,
-            ProtocolError::ComplianceViolation => true,
-            ProtocolError::OracleFailure => true,
-            ProtocolError::ConfigurationError => true,
-            ProtocolError::Unknown => true,
-            _ => false,
+        ProtocolEvent::Deposit {
+            user: depositor.to_string(),
+            amount,
+            asset: asset.clone(),
         }
+        .emit(&env);
+
+        Ok(())
+    })();
+    ReentrancyGuard::exit(&env);
+    result
+}
+/// Borrow an asset from the protocol at its variable rate, with dynamic risk check
+pub fn borrow(env: Env, asset: String, borrower: Address, amount: i128) -> Result<(), ProtocolError> {
+    borrower.require_auth();
+    Self::borrow_in_mode(env, asset, borrower.clone(), borrower, MAIN_ACCOUNT, amount, false)
+}
+
+/// Borrow against `delegator`'s collateral at the variable rate, within the allowance
+/// they delegated; the debt is the delegator's and the tokens go to the delegatee
+pub fn borrow_on_behalf(
+    env: Env,
+    asset: String,
+    delegatee: Address,
+    delegator: Address,
+    amount: i128,
+) -> Result<(), ProtocolError> {
+    delegatee.require_auth();
+    if delegatee == delegator {
+        return Err(ProtocolError::InvalidAddress);
     }
+    Self::borrow_in_mode(env, asset, delegatee, delegator, MAIN_ACCOUNT, amount, false)
 }
 
-/// Error recovery strategies
-pub struct ErrorRecovery;
+/// Borrow an asset at a stable rate locked in now: the asset's variable rate at the
+/// utilization after this borrow plus its stable rate premium
+pub fn borrow_stable(env: Env, asset: String, borrower: Address, amount: i128) -> Result<(), ProtocolError> {
+    borrower.require_auth();
+    Self::borrow_in_mode(env, asset, borrower.clone(), borrower, MAIN_ACCOUNT, amount, true)
+}
 
-impl ErrorRecovery {
-    /// Generic recovery function for oracle failures
-    pub fn recover_oracle_failure(env: &Env, _context: &ErrorContext) -> Result<(), ProtocolError> {
-        // Try to use fallback price
-        let fallback_price = OracleConfig::get_fallback_price(env);
-        if fallback_price > 0 {
-            OracleData::set_price(env, fallback_price);
-            OracleData::set_last_update(env, env.ledger().timestamp());
-            return Ok(());
+/// Borrow into `borrower`'s position in a sub-account and send the tokens to `caller`,
+/// who must be the borrower or hold a delegated allowance from them; `caller` has
+/// already authorized the call
+fn borrow_in_mode(
+    env: Env,
+    asset: String,
+    caller: Address,
+    borrower: Address,
+    sub_account: u32,
+    amount: i128,
+    stable: bool,
+) -> Result<(), ProtocolError> {
+    ReentrancyGuard::enter(&env)?;
+    let result = (|| {
+        if amount <= 0 {
+            return Err(ProtocolError::InvalidAmount);
         }
-        Err(ProtocolError::RecoveryFailed)
-    }
 
-    /// Recovery function for stale price data
-    pub fn recover_stale_price(env: &Env, _context: &ErrorContext) -> Result<(), ProtocolError> {
-        // Force price update with current oracle
-        let current_price = RealPriceOracle::get_price(env);
-        if current_price > 0 {
-            return Ok(());
+        let asset_info = AssetStorage::require_asset(&env, &asset)?;
+        if !asset_info.borrow_enabled {
+            return Err(ProtocolError::AssetDisabled);
         }
-        Err(ProtocolError::RecoveryFailed)
-    }
 
-    /// Recovery function for storage errors
-    pub fn recover_storage_error(env: &Env, context: &ErrorContext) -> Result<(), ProtocolError> {
-        // Attempt to retry the storage operation after a brief delay
-        let test_key = Symbol::short("storage_test");
-        env.storage().instance().set(&test_key, &true);
-        
-        if env.storage().instance().has(&test_key) {
-            env.storage().instance().remove(&test_key);
-            Ok(())
-        } else {
-            Err(ProtocolError::RecoveryFailed)
+        // Check if borrow is paused
+        let risk_config = RiskConfigStorage::for_asset(&env, &asset_info);
+        if risk_config.pause_borrow {
+            return Err(ProtocolError::ProtocolPaused);
         }
-    }
 
-    /// Generic recovery dispatcher
-    pub fn attempt_recovery(
-        env: &Env,
-        error: &ProtocolError,
-        context: ErrorContext,
-    ) -> Result<(), ProtocolError> {
-        if !error.is_recoverable() {
-            return Err(ProtocolError::RecoveryFailed);
+        if FrozenAccounts::is_frozen(&env, &borrower) {
+            SecurityMonitor::record_suspicious(&env, &borrower, "borrow while frozen");
+            return Err(ProtocolError::Unauthorized);
+        }
+        require_kyc(&env, &borrower)?;
+        require_not_blacklisted(&env, &borrower)?;
+        check_aml(&env, &borrower, amount, "borrow")?;
+        if caller != borrower {
+            if FrozenAccounts::is_frozen(&env, &caller) {
+                SecurityMonitor::record_suspicious(&env, &caller, "borrow while frozen");
+                return Err(ProtocolError::Unauthorized);
+            }
+            require_kyc(&env, &caller)?;
+            require_not_blacklisted(&env, &caller)?;
+        }
+        // Accrue interest before updating position
+        let state = InterestRateStorage::update_asset_state(&env, &asset);
+        let stable_rate = stable.then(|| {
+            InterestRateManager::stable_borrow_rate(&state, &asset_info.interest_config, amount)
+        });
+        let (position, health) =
+            StateHelper::plan_borrow(&env, &borrower, sub_account, &asset_info, &state, amount, stable_rate)?;
+        if !health.within_borrow_limit() {
+            SecurityMonitor::record_suspicious(&env, &borrower, "borrow below collateral ratio");
+            return Err(ProtocolError::InsufficientCollateralRatio);
         }
+        if caller != borrower {
+            CreditDelegation::spend_allowance(&env, &borrower, &caller, &asset, amount)?;
+        }
+        IsolationMode::record_borrow(&env, &borrower, sub_account, &asset_info, amount)?;
 
-        let recovery_fn = match error {
-            ProtocolError::OracleFailure => Self::recover_oracle_failure,
-            ProtocolError::PriceStale => Self::recover_stale_price,
-            ProtocolError::StorageError => Self::recover_storage_error,
-            _ => return Err(ProtocolError::RecoveryFailed),
-        };
+        let token = AssetStorage::get_token_address(&env, &asset)?;
 
-        ErrorLogger::attempt_recovery(env, context, recovery_fn)
-    }
-}
+        StateHelper::save_position(&env, &position);
 
-// This is a sample contract. Replace this placeholder with your own contract logic.
-// A corresponding test example is available in `test.rs`.
-//
-// For comprehensive examples, visit <https://github.com/stellar/soroban-examples>.
-// The repository includes use cases for the Stellar ecosystem, such as data storage on
-// the blockchain, token swaps, liquidity pools, and more.
-//
-// Refer to the official documentation:
-// <https://developers.stellar.org/docs/build/smart-contracts/overview>.
-#[contractimpl]
-impl Contract {
-    /// Initializes the contract and sets the admin address
-    pub fn initialize(env: Env, admin: String) -> Result<(), ProtocolError> {
-        let admin_addr = Address::from_string(&admin);
-        if env.storage().instance().has(&ProtocolConfig::admin_key()) {
-            return Err(ProtocolError::AlreadyInitialized);
+        // Update total borrowed amount
+        let mut ir_state = InterestRateStorage::get_asset_state(&env, &asset);
+        ir_state.total_borrowed += amount;
+        if let Some(rate) = stable_rate {
+            InterestRateManager::increase_stable_total(&mut ir_state, amount, rate);
         }
-        ProtocolConfig::set_admin(&env, &admin_addr);
+        InterestRateStorage::save_asset_state(&env, &asset, &ir_state);
 
-        // Initialize interest rate system with default configuration
-        let config = InterestRateConfig::default();
-        InterestRateStorage::save_config(&env, &config);
+        // Send the borrowed tokens to the borrower, or the delegatee borrowing for them
+        TokenHelper::transfer_out(&env, &token, &caller, amount);
 
-        let state = InterestRateState::initial();
-        InterestRateStorage::save_state(&env, &state);
+        // Collect any accrued borrow interest as protocol fees
+        ReserveStorage::collect_borrow_fee(&env, &asset, position.borrow_interest);
 
-        // Initialize risk management system with default configuration
-        let risk_config = RiskConfig::default();
-        RiskConfigStorage::save(&env, &risk_config);
+        ProtocolEvent::Borrow {
+            user: borrower.to_string(),
+            amount,
+            asset: asset.clone(),
+        }
+        .emit(&env);
 
-        // Initialize reserve management system with default configuration
-        let mut reserve_data = ReserveData::default();
-        reserve_data.treasury_address = admin_addr.clone();
-        ReserveStorage::save_reserve_data(&env, &reserve_data);
+        Ok(())
+    })();
+    ReentrancyGuard::exit(&env);
+    result
+}
 
-        let revenue_metrics = RevenueMetrics::default();
-        ReserveStorage::save_revenue_metrics(&env, &revenue_metrics);
+/// Repay borrowed assets
+pub fn repay(env: Env, asset: String, repayer: Address, amount: i128) -> Result<(), ProtocolError> {
+    repayer.require_auth();
+    Self::repay_for(env, asset, repayer.clone(), repayer, MAIN_ACCOUNT, amount)
+}
 
-        // Initialize multi-asset support
-        let asset_registry = AssetRegistry::new(String::from_str(&env, "XLM"));
-        AssetStorage::save_registry(&env, &asset_registry);
+/// Repay another account's debt with the payer's tokens, e.g. to rescue it before liquidation
+pub fn repay_on_behalf(
+    env: Env,
+    payer: Address,
+    borrower: Address,
+    asset: String,
+    amount: i128,
+) -> Result<(), ProtocolError> {
+    payer.require_auth();
+    Self::repay_for(env, asset, payer, borrower, MAIN_ACCOUNT, amount)
+}
 
-        // Initialize default XLM asset
-        let xlm_oracle = Address::from_string(&String::from_str(
-            &env,
-            "GCXOTMMXRS24MYZI5FJPUCOEOFNWSR4XX7UXIK3NDGGE6A5QMJ5FF2FS",
-        ));
-        let xlm_asset_info = AssetInfo::new(
-            String::from_str(&env, "XLM"),
-            7, // XLM has 7 decimals
-            xlm_oracle,
-            150, // 150% minimum collateral ratio
-        );
-        AssetStorage::save_asset_info(&env, &String::from_str(&env, "XLM"), &xlm_asset_info);
+/// Borrow at the variable rate into one of the borrower's sub-accounts, against that
+/// sub-account's collateral only
+pub fn sub_account_borrow(
+    env: Env,
+    borrower: Address,
+    sub_account: u32,
+    asset: String,
+    amount: i128,
+) -> Result<(), ProtocolError> {
+    borrower.require_auth();
+    SubAccounts::validate(sub_account)?;
+    Self::borrow_in_mode(env, asset, borrower.clone(), borrower, sub_account, amount, false)
+}
 
-        Ok(())
-    }
+/// Repay debt held in one of the repayer's sub-accounts
+pub fn sub_account_repay(
+    env: Env,
+    repayer: Address,
+    sub_account: u32,
+    asset: String,
+    amount: i128,
+) -> Result<(), ProtocolError> {
+    repayer.require_auth();
+    SubAccounts::validate(sub_account)?;
+    Self::repay_for(env, asset, repayer.clone(), repayer, sub_account, amount)
+}
+
+/// Pay down `borrower`'s debt in a sub-account with tokens pulled from `payer`, who has
+/// already authorized the call
+fn repay_for(
+    env: Env,
+    asset: String,
+    payer: Address,
+    borrower: Address,
+    sub_account: u32,
+    amount: i128,
+) -> Result<(), ProtocolError> {
+    ReentrancyGuard::enter(&env)?;
+    let result = (|| {
+        if amount <= 0 {
+            return Err(ProtocolError::InvalidAmount);
+        }
+        let asset_info = AssetStorage::require_asset(&env, &asset)?;
+
+        if FrozenAccounts::is_frozen(&env, &payer) {
+            SecurityMonitor::record_suspicious(&env, &payer, "repay while frozen");
+            return Err(ProtocolError::Unauthorized);
+        }
+        require_kyc(&env, &payer)?;
+        require_not_blacklisted(&env, &payer)?;
+        check_aml(&env, &payer, amount, "repay")?;
+        if payer != borrower {
+            require_not_blacklisted(&env, &borrower)?;
+        }
+        // Accrue interest before updating position
+        let state = InterestRateStorage::update_asset_state(&env, &asset);
+        let (position, repaid, stable_repaid) =
+            StateHelper::plan_repay(&env, &borrower, sub_account, &asset, &state, amount);
+        if repaid == 0 {
+            return Err(ProtocolError::PositionNotFound);
+        }
+
+        // Only pull what is actually owed; overpayments stay with the payer
+        let token = AssetStorage::get_token_address(&env, &asset)?;
+        TokenHelper::transfer_in(&env, &token, &payer, repaid);
+
+        StateHelper::save_position(&env, &position);
+
+        // Update total borrowed amount
+        let mut ir_state = InterestRateStorage::get_asset_state(&env, &asset);
+        ir_state.total_borrowed -= repaid;
+        InterestRateManager::decrease_stable_total(&mut ir_state, stable_repaid, position.stable_rate);
+        InterestRateStorage::save_asset_state(&env, &asset, &ir_state);
+        IsolationMode::record_repay(&env, &borrower, sub_account, &asset_info, repaid);
+
+        ProtocolEvent::Repay {
+            user: borrower.to_string(),
+            amount: repaid,
+            asset: asset.clone(),
+        }
+        .emit(&env);
+        if payer != borrower {
+            ProtocolEvent::RepaidOnBehalf {
+                payer: payer.clone(),
+                borrower: borrower.clone(),
+                asset: asset.clone(),
+                amount: repaid,
+            }
+            .emit(&env);
+        }
 
-    /// Set the oracle address (admin only)
-    pub fn set_oracle(env: Env, caller: String, oracle: String) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        let oracle_addr = Address::from_string(&oracle);
-        ProtocolConfig::set_oracle(&env, &caller_addr, &oracle_addr)?;
         Ok(())
+    })();
+    ReentrancyGuard::exit(&env);
+    result
+}
+
+
+    /// Withdraw collateral in an asset with dynamic risk check
+    pub fn withdraw(env: Env, asset: String, withdrawer: Address, amount: i128) -> Result<(), ProtocolError> {
+        withdrawer.require_auth();
+        Self::withdraw_for(env, asset, withdrawer, MAIN_ACCOUNT, amount)
     }
 
-    /// Set the minimum collateral ratio (admin only)
-    pub fn set_min_collateral_ratio(
+    /// Withdraw collateral for an already authorized withdrawer
+    fn withdraw_for(
         env: Env,
-        caller: String,
-        ratio: i128,
+        asset: String,
+        withdrawer: Address,
+        sub_account: u32,
+        amount: i128,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::set_min_collateral_ratio(&env, &caller_addr, ratio)?;
-        Ok(())
+        ReentrancyGuard::enter(&env)?;
+        let result = (|| {
+            if amount <= 0 {
+                return Err(ProtocolError::InvalidAmount);
+            }
+            let asset_info = AssetStorage::require_asset(&env, &asset)?;
+
+            // Check if withdraw is paused
+            let risk_config = RiskConfigStorage::for_asset(&env, &asset_info);
+            if risk_config.pause_withdraw {
+                return Err(ProtocolError::ProtocolPaused);
+            }
+            if FrozenAccounts::is_frozen(&env, &withdrawer) {
+                return Err(ProtocolError::Unauthorized);
+            }
+            require_kyc(&env, &withdrawer)?;
+            require_not_blacklisted(&env, &withdrawer)?;
+            check_aml(&env, &withdrawer, amount, "withdraw")?;
+            // Accrue interest before updating position
+            let state = InterestRateStorage::update_asset_state(&env, &asset);
+            let (position, health) =
+                StateHelper::plan_withdraw(&env, &withdrawer, sub_account, &asset, &state, amount)?;
+            if !health.within_borrow_limit() {
+                return Err(ProtocolError::InsufficientCollateralRatio);
+            }
+            let token = AssetStorage::get_token_address(&env, &asset)?;
+
+            StateHelper::save_position(&env, &position);
+
+            // Update total collateral
+            let mut ir_state = InterestRateStorage::get_asset_state(&env, &asset);
+            ir_state.total_collateral -= amount;
+            InterestRateStorage::save_asset_state(&env, &asset, &ir_state);
+
+            // Return the collateral tokens to the withdrawer
+            TokenHelper::transfer_out(&env, &token, &withdrawer, amount);
+
+            ProtocolEvent::Withdraw {
+                user: withdrawer.to_string(),
+                amount,
+                asset: asset.clone(),
+            }
+            .emit(&env);
+            Ok(())
+        })();
+        ReentrancyGuard::exit(&env);
+        result
     }
 
-    /// Set the maximum price deviation for oracle validation (admin only)
-    pub fn set_max_price_deviation(
+    /// Liquidate an undercollateralized position using dynamic risk check: the liquidator
+    /// repays `debt_asset` and seizes the target's collateral in `collateral_asset`
+    pub fn liquidate(
         env: Env,
-        caller: String,
-        deviation: i128,
+        liquidator: Address,
+        target: Address,
+        amount: i128,
+        debt_asset: String,
+        collateral_asset: String,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        OracleConfig::set_max_price_deviation(&env, &caller_addr, deviation)?;
-        Ok(())
+        liquidator.require_auth();
+        Self::liquidate_in(env, liquidator, target, MAIN_ACCOUNT, amount, debt_asset, collateral_asset)
     }
 
-    /// Set the oracle heartbeat interval (admin only)
-    pub fn set_oracle_heartbeat(
+    /// Liquidate an undercollateralized sub-account of the target; each sub-account is
+    /// liquidated on its own health, with only its own collateral at stake
+    pub fn liquidate_sub_account(
         env: Env,
-        caller: String,
-        heartbeat: u64,
+        liquidator: Address,
+        target: Address,
+        sub_account: u32,
+        amount: i128,
+        debt_asset: String,
+        collateral_asset: String,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        OracleConfig::set_heartbeat(&env, &caller_addr, heartbeat)?;
-        Ok(())
-    }
-
-    /// Set the fallback price for oracle failures (admin only)
-    pub fn set_fallback_price(env: Env, caller: String, price: i128) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        OracleConfig::set_fallback_price(&env, &caller_addr, price)?;
-        Ok(())
-    }
-
-    /// Get oracle configuration and status
-    pub fn get_oracle_info(env: Env) -> Result<(i128, u64, i128, u64, bool), ProtocolError> {
-        let current_price = OracleData::get_price(&env);
-        let last_update = OracleData::get_last_update(&env);
-        let max_deviation = OracleConfig::get_max_price_deviation(&env);
-        let heartbeat = OracleConfig::get_heartbeat(&env);
-        let is_stale = OracleConfig::is_price_stale(&env);
-
-        Ok((
-            current_price,
-            last_update,
-            max_deviation,
-            heartbeat,
-            is_stale,
-        ))
+        liquidator.require_auth();
+        Self::liquidate_in(env, liquidator, target, sub_account, amount, debt_asset, collateral_asset)
     }
 
-    /// Force update the oracle price (admin only, for testing)
-    pub fn force_update_price(env: Env, caller: String, price: i128) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+    /// Liquidate a sub-account of the target for an already authorized liquidator
+    fn liquidate_in(
+        env: Env,
+        liquidator: Address,
+        target: Address,
+        sub_account: u32,
+        amount: i128,
+        debt_asset: String,
+        collateral_asset: String,
+    ) -> Result<(), ProtocolError> {
+        ReentrancyGuard::enter(&env)?;
+        let result = (|| {
+            if amount <= 0 {
+                return Err(ProtocolError::InvalidAmount);
+            }
+            let debt_info = AssetStorage::require_asset(&env, &debt_asset)?;
+            let collateral_info = AssetStorage::require_asset(&env, &collateral_asset)?;
+
+            // Check if liquidation is paused; the close factor comes from the debt asset
+            // and the incentive from the collateral being seized
+            let debt_risk = RiskConfigStorage::for_asset(&env, &debt_info);
+            let collateral_risk = RiskConfigStorage::for_asset(&env, &collateral_info);
+            if debt_risk.pause_liquidate || collateral_risk.pause_liquidate {
+                return Err(ProtocolError::ProtocolPaused);
+            }
 
-        let timestamp = env.ledger().timestamp();
-        OracleData::set_price(&env, price);
-        OracleData::set_last_update(&env, timestamp);
+            if FrozenAccounts::is_frozen(&env, &target) {
+                return Err(ProtocolError::Unauthorized);
+            }
 
-        Ok(())
-    }
+            let mut debt_position = StateHelper::get_sub_position(&env, &target, sub_account, &debt_asset)
+                .ok_or(ProtocolError::PositionNotFound)?;
+            let mut collateral_position =
+                StateHelper::get_sub_position(&env, &target, sub_account, &collateral_asset)
+                    .ok_or(ProtocolError::PositionNotFound)?;
 
-    // --- Interest Rate Management Functions ---
+            // Accrue interest before liquidation
+            let debt_state = InterestRateStorage::update_asset_state(&env, &debt_asset);
+            InterestRateManager::accrue_interest_for_position(
+                &env,
+                &mut debt_position,
+                debt_state.borrow_index,
+                debt_state.supply_index,
+            );
+            let collateral_state = InterestRateStorage::update_asset_state(&env, &collateral_asset);
+            InterestRateManager::accrue_interest_for_position(
+                &env,
+                &mut collateral_position,
+                collateral_state.borrow_index,
+                collateral_state.supply_index,
+            );
 
-    /// Set the base interest rate (admin only)
-    pub fn set_base_rate(env: Env, caller: String, rate: i128) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+            // Only accounts whose debt exceeds their liquidation threshold can be liquidated
+            let health = StateHelper::sub_account_health(&env, &target, sub_account)?;
+            if health.health_factor >= HEALTH_FACTOR_ONE {
+                return Err(ProtocolError::NotEligibleForLiquidation);
+            }
 
-        let mut config = InterestRateStorage::get_config(&env);
-        config.base_rate = rate;
-        config.last_update = env.ledger().timestamp();
-        InterestRateStorage::save_config(&env, &config);
+            // Apply close factor to limit liquidation amount
+            let debt = InterestRateManager::position_debt(&debt_position, debt_state.borrow_index);
+            let max_repay_amount = (debt * debt_risk.close_factor) / 100_000_000;
+            let repay_amount = amount.min(debt).min(max_repay_amount);
 
-        // Update current rates
-        InterestRateStorage::update_state(&env);
+            if repay_amount == 0 {
+                return Err(ProtocolError::InvalidAmount);
+            }
 
-        Ok(())
-    }
+            let (repay_amount, seized, protocol_fee, liquidator_amount) = StateHelper::liquidation_terms(
+                &env,
+                &debt_info,
+                &collateral_info,
+                &collateral_risk,
+                health.emode_category,
+                repay_amount,
+                collateral_position.collateral,
+            )?;
+
+            // Liquidator pays down the debt before any collateral leaves the protocol
+            let debt_token = AssetStorage::get_token_address(&env, &debt_asset)?;
+            let collateral_token = AssetStorage::get_token_address(&env, &collateral_asset)?;
+            TokenHelper::transfer_in(&env, &debt_token, &liquidator, repay_amount);
+
+            // Release the repaid debt from any debt ceiling while the isolated collateral is still held
+            IsolationMode::record_repay(&env, &target, sub_account, &debt_info, repay_amount);
+
+            // Update positions; both legs may be the same position
+            let (_, stable_repaid) =
+                InterestRateManager::remove_debt(&mut debt_position, repay_amount, debt_state.borrow_index);
+            if debt_asset == collateral_asset {
+                debt_position.collateral -= seized;
+            } else {
+                collateral_position.collateral -= seized;
+                StateHelper::save_position(&env, &collateral_position);
+            }
+            StateHelper::save_position(&env, &debt_position);
 
-    /// Set the kink utilization point (admin only)
-    pub fn set_kink_utilization(
-        env: Env,
-        caller: String,
-        utilization: i128,
-    ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+            // Update totals: the debt is repaid, the seized collateral leaves the user and
+            // the protocol's fee stays in the pool as reserves
+            let mut ir_state = InterestRateStorage::get_asset_state(&env, &debt_asset);
+            ir_state.total_borrowed -= repay_amount;
+            InterestRateManager::decrease_stable_total(&mut ir_state, stable_repaid, debt_position.stable_rate);
+            InterestRateStorage::save_asset_state(&env, &debt_asset, &ir_state);
+            let mut ir_state = InterestRateStorage::get_asset_state(&env, &collateral_asset);
+            ir_state.total_collateral -= seized;
+            ir_state.total_reserves += protocol_fee;
+            InterestRateStorage::save_asset_state(&env, &collateral_asset, &ir_state);
 
-        let mut config = InterestRateStorage::get_config(&env);
-        config.kink_utilization = utilization;
-        config.last_update = env.ledger().timestamp();
-        InterestRateStorage::save_config(&env, &config);
+            TokenHelper::transfer_out(&env, &collateral_token, &liquidator, liquidator_amount);
 
-        // Update current rates
-        InterestRateStorage::update_state(&env);
+            ProtocolEvent::Liquidate {
+                user: target.to_string(),
+                amount: repay_amount,
+                asset: debt_asset.clone(),
+                liquidator: liquidator.clone(),
+                collateral_asset: collateral_asset.clone(),
+                collateral_seized: seized,
+                liquidator_amount,
+                protocol_fee,
+            }
+            .emit(&env);
 
-        Ok(())
+            // Debt left once every collateral position is exhausted is bad debt
+            BadDebtManager::realize_if_insolvent(&env, &target, sub_account);
+            Ok(())
+        })();
+        ReentrancyGuard::exit(&env);
+        result
     }
 
-    /// Set the rate multiplier (admin only)
-    pub fn set_multiplier(env: Env, caller: String, multiplier: i128) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
-
-        let mut config = InterestRateStorage::get_config(&env);
-        config.multiplier = multiplier;
-        config.last_update = env.ledger().timestamp();
-        InterestRateStorage::save_config(&env, &config);
+    /// Supply lender liquidity in an asset; mints the asset's sToken shares that earn its supply rate
+    pub fn supply(env: Env, asset: String, lender: Address, amount: i128) -> Result<(), ProtocolError> {
+        ReentrancyGuard::enter(&env)?;
+        let result = (|| {
+            lender.require_auth();
+            if amount <= 0 {
+                return Err(ProtocolError::InvalidAmount);
+            }
+            let asset_info = AssetStorage::require_asset(&env, &asset)?;
+            if !asset_info.deposit_enabled {
+                return Err(ProtocolError::AssetDisabled);
+            }
 
-        // Update current rates
-        InterestRateStorage::update_state(&env);
+            // Supplying shares the deposit pause switch
+            let risk_config = RiskConfigStorage::for_asset(&env, &asset_info);
+            if risk_config.pause_deposit {
+                return Err(ProtocolError::ProtocolPaused);
+            }
+            if FrozenAccounts::is_frozen(&env, &lender) {
+                SecurityMonitor::record_suspicious(&env, &lender, "supply while frozen");
+                return Err(ProtocolError::Unauthorized);
+            }
+            require_kyc(&env, &lender)?;
+            require_not_blacklisted(&env, &lender)?;
+            check_aml(&env, &lender, amount, "supply")?;
+
+            let token = AssetStorage::get_token_address(&env, &asset)?;
+            let stoken = AssetStorage::get_stoken_address(&env, &asset)?;
+            let mut position = StateHelper::get_position(&env, &lender, &asset)
+                .unwrap_or(AssetPosition::new(lender.clone(), asset.clone(), 0, 0));
+
+            // Accrue interest before updating position
+            let state = InterestRateStorage::update_asset_state(&env, &asset);
+            InterestRateManager::accrue_interest_for_position(
+                &env,
+                &mut position,
+                state.borrow_index,
+                state.supply_index,
+            );
 
-        Ok(())
-    }
+            asset_info.check_supply_cap(&state, amount)?;
+            let shares = InterestRateManager::supply_to_shares(amount, state.supply_index);
+            if shares == 0 {
+                return Err(ProtocolError::InvalidAmount);
+            }
+            TokenHelper::transfer_in(&env, &token, &lender, amount);
 
-    /// Set the reserve factor (admin only)
-    pub fn set_reserve_factor(env: Env, caller: String, factor: i128) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+            position.supply_shares += shares;
+            StateHelper::save_position(&env, &position);
 
-        let mut config = InterestRateStorage::get_config(&env);
-        config.reserve_factor = factor;
-        config.last_update = env.ledger().timestamp();
-        InterestRateStorage::save_config(&env, &config);
+            // Update total supplied amount
+            let mut ir_state = InterestRateStorage::get_asset_state(&env, &asset);
+            ir_state.total_supplied += amount;
+            InterestRateStorage::save_asset_state(&env, &asset, &ir_state);
 
-        // Update current rates
-        InterestRateStorage::update_state(&env);
+            TokenHelper::mint_shares(&env, &stoken, &lender, shares);
 
-        Ok(())
+            ProtocolEvent::Supply {
+                user: lender.to_string(),
+                amount,
+                asset: asset.clone(),
+            }
+            .emit(&env);
+            Ok(())
+        })();
+        ReentrancyGuard::exit(&env);
+        result
     }
 
-    /// Set rate limits (admin only)
-    pub fn set_rate_limits(
-        env: Env,
-        caller: String,
-        floor: i128,
-        ceiling: i128,
-    ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+    /// Redeem an asset's sToken shares for the liquidity they are worth, including supply interest
+    pub fn redeem(env: Env, asset: String, lender: Address, shares: i128) -> Result<(), ProtocolError> {
+        ReentrancyGuard::enter(&env)?;
+        let result = (|| {
+            lender.require_auth();
+            if shares <= 0 {
+                return Err(ProtocolError::InvalidAmount);
+            }
+            let asset_info = AssetStorage::require_asset(&env, &asset)?;
 
-        let mut config = InterestRateStorage::get_config(&env);
-        config.rate_floor = floor;
-        config.rate_ceiling = ceiling;
-        config.last_update = env.ledger().timestamp();
-        InterestRateStorage::save_config(&env, &config);
+            // Redeeming shares the withdraw pause switch
+            let risk_config = RiskConfigStorage::for_asset(&env, &asset_info);
+            if risk_config.pause_withdraw {
+                return Err(ProtocolError::ProtocolPaused);
+            }
+            if FrozenAccounts::is_frozen(&env, &lender) {
+                return Err(ProtocolError::Unauthorized);
+            }
+            require_kyc(&env, &lender)?;
+            require_not_blacklisted(&env, &lender)?;
 
-        // Update current rates
-        InterestRateStorage::update_state(&env);
+            let token = AssetStorage::get_token_address(&env, &asset)?;
+            let stoken = AssetStorage::get_stoken_address(&env, &asset)?;
+            let mut position = StateHelper::get_position(&env, &lender, &asset)
+                .ok_or(ProtocolError::PositionNotFound)?;
 
-        Ok(())
-    }
+            // Accrue interest before updating position
+            let state = InterestRateStorage::update_asset_state(&env, &asset);
+            InterestRateManager::accrue_interest_for_position(
+                &env,
+                &mut position,
+                state.borrow_index,
+                state.supply_index,
+            );
 
-    /// Emergency rate adjustment (admin only)
-    pub fn emergency_rate_adjustment(
-        env: Env,
-        caller: String,
-        new_rate: i128,
-    ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+            if position.supply_shares < shares {
+                return Err(ProtocolError::InvalidAmount);
+            }
+            let amount = InterestRateManager::shares_to_supply(shares, state.supply_index);
+            check_aml(&env, &lender, amount, "redeem")?;
 
-        let mut state = InterestRateStorage::get_state(&env);
-        state.current_borrow_rate = new_rate;
-        state.last_accrual_time = env.ledger().timestamp();
-        InterestRateStorage::save_state(&env, &state);
+            // Lent-out liquidity cannot be redeemed until it is repaid
+            if amount > state.total_supplied - state.total_borrowed {
+                return Err(ProtocolError::InsufficientLiquidity);
+            }
 
-        Ok(())
-    }
+            position.supply_shares -= shares;
+            StateHelper::save_position(&env, &position);
 
-    /// Get current interest rates
-    pub fn get_current_rates(env: Env) -> Result<(i128, i128), ProtocolError> {
-        let state = InterestRateStorage::update_state(&env);
-        Ok((state.current_borrow_rate, state.current_supply_rate))
-    }
+            // Update total supplied amount
+            let mut ir_state = InterestRateStorage::get_asset_state(&env, &asset);
+            ir_state.total_supplied -= amount;
+            InterestRateStorage::save_asset_state(&env, &asset, &ir_state);
 
-    /// Get utilization metrics
-    pub fn get_utilization_metrics(env: Env) -> Result<(i128, i128, i128), ProtocolError> {
-        let state = InterestRateStorage::update_state(&env);
-        Ok((
-            state.utilization_rate,
-            state.total_borrowed,
-            state.total_supplied,
-        ))
+            TokenHelper::burn_shares(&env, &stoken, &lender, shares);
+            TokenHelper::transfer_out(&env, &token, &lender, amount);
+
+            ProtocolEvent::Redeem {
+                user: lender.to_string(),
+                amount,
+                asset: asset.clone(),
+            }
+            .emit(&env);
+            Ok(())
+        })();
+        ReentrancyGuard::exit(&env);
+        result
     }
 
-    /// Get user's accrued interest
-    pub fn get_user_accrued_interest(
+    /// Lend `amount` of an asset's available liquidity to `receiver` for the duration of
+    /// one invocation. The receiver's `on_flash_loan` callback must return the principal
+    /// plus the flash loan fee before it finishes; the fee goes to protocol reserves.
+    pub fn flash_loan(
         env: Env,
-        user: String,
-    ) -> Result<(i128, i128), ProtocolError> {
-        let user_addr = Address::from_string(&user);
-        let mut position =
-            StateHelper::get_position(&env, &user_addr).unwrap_or(Position::new(user_addr, 0, 0));
+        receiver: Address,
+        asset: String,
+        amount: i128,
+        params: Bytes,
+    ) -> Result<(), ProtocolError> {
+        ReentrancyGuard::enter(&env)?;
+        let result = (|| {
+            receiver.require_auth();
+            if amount <= 0 {
+                return Err(ProtocolError::InvalidAmount);
+            }
+            let asset_info = AssetStorage::require_asset(&env, &asset)?;
+            if !asset_info.borrow_enabled {
+                return Err(ProtocolError::AssetDisabled);
+            }
 
-        // Accrue interest for the position
-        let state = InterestRateStorage::update_state(&env);
-        InterestRateManager::accrue_interest_for_position(
-            &env,
-            &mut position,
-            state.current_borrow_rate,
-            state.current_supply_rate,
-        );
+            // Flash loans share the borrow pause switch
+            let risk_config = RiskConfigStorage::for_asset(&env, &asset_info);
+            if risk_config.pause_borrow {
+                return Err(ProtocolError::ProtocolPaused);
+            }
+            if FrozenAccounts::is_frozen(&env, &receiver) {
+                return Err(ProtocolError::Unauthorized);
+            }
+            require_not_blacklisted(&env, &receiver)?;
 
-        Ok((position.borrow_interest, position.supply_interest))
-    }
+            let state = InterestRateStorage::update_asset_state(&env, &asset);
+            if amount > state.total_supplied - state.total_borrowed {
+                return Err(ProtocolError::InsufficientLiquidity);
+            }
+            let fee = ReserveStorage::flash_loan_fee_for(&env, amount);
+
+            let token = AssetStorage::get_token_address(&env, &asset)?;
+            let token_client = token::Client::new(&env, &token);
+            let pool = env.current_contract_address();
+            let balance_before = token_client.balance(&pool);
+            TokenHelper::transfer_out(&env, &token, &receiver, amount);
+            let accepted = FlashLoanReceiverClient::new(&env, &receiver)
+                .on_flash_loan(&pool, &asset, &token, &amount, &fee, &params);
+            if !accepted || token_client.balance(&pool) < balance_before + fee {
+                return Err(ProtocolError::FlashLoanNotRepaid);
+            }
 
-    /// Manually accrue interest (anyone can call)
-    pub fn accrue_interest(env: Env) -> Result<(), ProtocolError> {
-        InterestRateStorage::update_state(&env);
-        Ok(())
+            ProtocolEvent::FlashLoan {
+                receiver: receiver.to_string(),
+                asset: asset.clone(),
+                amount,
+                fee,
+            }
+            .emit(&env);
+            // The fee stays in the pool as reserves of the asset
+            ReserveStorage::collect_flash_loan_fee(&env, &asset, fee);
+            Ok(())
+        })();
+        ReentrancyGuard::exit(&env);
+        result
     }
 
-    /// Get interest rate configuration
-    pub fn get_interest_rate_config(
+    /// Swap `amount` of the user's `from_asset` collateral in a sub-account for `to_asset`
+    /// collateral through a swap adapter, receiving at least `min_out`. The sub-account only
+    /// has to be within its borrow limit once the swap completes. Returns the collateral
+    /// received.
+    pub fn swap_collateral(
         env: Env,
-    ) -> Result<(i128, i128, i128, i128, i128, i128, u64), ProtocolError> {
-        let config = InterestRateStorage::get_config(&env);
-        Ok((
-            config.base_rate,
-            config.kink_utilization,
-            config.multiplier,
-            config.reserve_factor,
-            config.rate_floor,
-            config.rate_ceiling,
-            config.last_update,
-        ))
-    }
-
-    /// Minimum collateral ratio required (e.g., 150%)
-    const MIN_COLLATERAL_RATIO: i128 = 150;
-
-    // --- Core Protocol Function Placeholders ---
-/// Deposit collateral into the protocol
-pub fn deposit_collateral(env: Env, depositor: String, amount: i128) -> Result<(), ProtocolError> {
-    ReentrancyGuard::enter(&env)?;
-    let result = (|| {
-        // Input validation with enhanced error logging
-        if depositor.is_empty() {
-            let error = ProtocolError::InvalidAddress;
-            ErrorLogger::log_error(&env, &error, None, "deposit_collateral", "Empty depositor address provided");
-            return Err(error);
-        }
-        if amount <= 0 {
-            let error = ProtocolError::InvalidAmount;
-            let context = format!("Invalid deposit amount: {}", amount);
-            ErrorLogger::log_error(&env, &error, None, "deposit_collateral", &context);
-            return Err(error);
-        }
+        user: Address,
+        sub_account: u32,
+        from_asset: String,
+        to_asset: String,
+        amount: i128,
+        min_out: i128,
+        adapter: Address,
+    ) -> Result<i128, ProtocolError> {
+        ReentrancyGuard::enter(&env)?;
+        let result = (|| {
+            user.require_auth();
+            if amount <= 0 {
+                return Err(ProtocolError::InvalidAmount);
+            }
+            if from_asset == to_asset {
+                return Err(ProtocolError::InvalidInput);
+            }
+            SubAccounts::validate(sub_account)?;
+            let from_info = AssetStorage::require_asset(&env, &from_asset)?;
+            let to_info = AssetStorage::require_asset(&env, &to_asset)?;
+            if !to_info.deposit_enabled {
+                return Err(ProtocolError::AssetDisabled);
+            }
 
-        // Check if deposit is paused
-        let risk_config = RiskConfigStorage::get(&env);
-        if risk_config.pause_deposit {
-            let error = ProtocolError::ProtocolPaused;
-            ErrorLogger::log_error(&env, &error, None, "deposit_collateral", "Deposit operations are paused");
-            return Err(error);
-        }
+            // The swap withdraws one collateral and deposits the other
+            if RiskConfigStorage::for_asset(&env, &from_info).pause_withdraw
+                || RiskConfigStorage::for_asset(&env, &to_info).pause_deposit
+            {
+                return Err(ProtocolError::ProtocolPaused);
+            }
+            if FrozenAccounts::is_frozen(&env, &user) {
+                SecurityMonitor::record_suspicious(&env, &user, "swap while frozen");
+                return Err(ProtocolError::Unauthorized);
+            }
+            require_kyc(&env, &user)?;
+            require_not_blacklisted(&env, &user)?;
+
+            let from_state = InterestRateStorage::update_asset_state(&env, &from_asset);
+            let to_state = InterestRateStorage::update_asset_state(&env, &to_asset);
+            let mut from_position =
+                StateHelper::accrued_position(&env, &user, sub_account, &from_asset, &from_state);
+            if from_position.collateral < amount {
+                return Err(ProtocolError::InsufficientCollateral);
+            }
+            IsolationMode::record_collateral_added(&env, &user, sub_account, &to_info)?;
+
+            let from_token = AssetStorage::get_token_address(&env, &from_asset)?;
+            let to_token = AssetStorage::get_token_address(&env, &to_asset)?;
+            let received = TokenHelper::swap(&env, &adapter, &from_token, &to_token, amount, min_out)?;
+            to_info.check_supply_cap(&to_state, received)?;
+
+            from_position.collateral -= amount;
+            StateHelper::save_position(&env, &from_position);
+            let mut to_position =
+                StateHelper::accrued_position(&env, &user, sub_account, &to_asset, &to_state);
+            to_position.collateral += received;
+            StateHelper::save_position(&env, &to_position);
+
+            // Only the final state has to respect the borrow limit
+            if !StateHelper::sub_account_health(&env, &user, sub_account)?.within_borrow_limit() {
+                return Err(ProtocolError::InsufficientCollateralRatio);
+            }
 
-        let depositor_addr = Address::from_string(&depositor);
-        
-        // Enhanced security checks with error logging
-        if FrozenAccounts::is_frozen(&env, &depositor_addr) {
-            SecurityMonitor::record_suspicious(&env, &depositor_addr, "deposit while frozen");
-            let error = ProtocolError::Unauthorized;
-            ErrorLogger::log_error(&env, &error, Some(depositor_addr.clone()), "deposit_collateral", "Account is frozen");
-            return Err(error);
-        }
-        
-        // Compliance checks with error logging
-        if let Err(error) = require_kyc(&env, &depositor_addr) {
-            ErrorLogger::log_error(&env, &error, Some(depositor_addr.clone()), "deposit_collateral", "KYC requirement not met");
-            return Err(error);
-        }
-        if let Err(error) = require_not_blacklisted(&env, &depositor_addr) {
-            ErrorLogger::log_error(&env, &error, Some(depositor_addr.clone()), "deposit_collateral", "Address is blacklisted");
-            return Err(error);
-        }
-        if let Err(error) = check_aml(&env, &depositor_addr, amount, "deposit") {
-            ErrorLogger::log_error(&env, &error, Some(depositor_addr.clone()), "deposit_collateral", "AML check failed");
-            return Err(error);
-        }
-        // Load user position with error handling
-        let mut position = match StateHelper::get_position(&env, &depositor_addr) {
-            Some(pos) => pos,
-            None => Position::new(depositor_addr.clone(), 0, 0),
-        };
+            let mut ir_state = InterestRateStorage::get_asset_state(&env, &from_asset);
+            ir_state.total_collateral -= amount;
+            InterestRateStorage::save_asset_state(&env, &from_asset, &ir_state);
+            let mut ir_state = InterestRateStorage::get_asset_state(&env, &to_asset);
+            ir_state.total_collateral += received;
+            InterestRateStorage::save_asset_state(&env, &to_asset, &ir_state);
+
+            ProtocolEvent::CollateralSwapped {
+                user: user.clone(),
+                from_asset: from_asset.clone(),
+                to_asset: to_asset.clone(),
+                amount_in: amount,
+                amount_out: received,
+            }
+            .emit(&env);
+            Ok(received)
+        })();
+        ReentrancyGuard::exit(&env);
+        result
+    }
 
-        // Accrue interest before updating position with error handling
-        let state = match InterestRateStorage::update_state(&env) {
-            state => state,
-        };
-        
-        InterestRateManager::accrue_interest_for_position(
-            &env,
-            &mut position,
-            state.current_borrow_rate,
-            state.current_supply_rate,
-        );
+    /// Raise a sub-account's debt-to-collateral ratio toward `target_ltv` (basis points) by
+    /// borrowing `debt_asset`, swapping it through `adapter` and depositing the proceeds
+    /// as `collateral_asset`. Each swap may return up to `max_slippage` basis points less
+    /// than the oracle price. With `one_shot` the whole amount is flash-borrowed, swapped
+    /// and deposited first, then borrowed against the new collateral together with the
+    /// flash loan fee. Otherwise rounds repeat within the borrow limit. The borrow limit is
+    /// checked once at the end. Returns the resulting account health.
+    pub fn leverage(
+        env: Env,
+        user: Address,
+        sub_account: u32,
+        collateral_asset: String,
+        debt_asset: String,
+        target_ltv: i128,
+        max_slippage: i128,
+        adapter: Address,
+        one_shot: bool,
+    ) -> Result<AccountHealth, ProtocolError> {
+        ReentrancyGuard::enter(&env)?;
+        let result = (|| {
+            user.require_auth();
+            let (collateral_info, debt_info) = Self::leverage_assets(
+                &env,
+                &user,
+                sub_account,
+                &collateral_asset,
+                &debt_asset,
+                target_ltv,
+                max_slippage,
+            )?;
+            if !debt_info.borrow_enabled || !collateral_info.deposit_enabled {
+                return Err(ProtocolError::AssetDisabled);
+            }
+            if RiskConfigStorage::for_asset(&env, &debt_info).pause_borrow
+                || RiskConfigStorage::for_asset(&env, &collateral_info).pause_deposit
+            {
+                return Err(ProtocolError::ProtocolPaused);
+            }
 
-        // Update position with error recovery
-        position.collateral += amount;
-        
-        // Attempt to save position with error recovery
-        let save_result = || -> Result<(), ProtocolError> {
-            StateHelper::save_position(&env, &position);
-            Ok(())
-        };
+            // Debt value at which debt / collateral reaches the target, with swaps at oracle
+            // prices: every unit borrowed adds its value to both sides
+            let health = StateHelper::sub_account_health(&env, &user, sub_account)?;
+            let target_debt = health.debt_value
+                + (target_ltv * health.collateral_value - health.debt_value * BPS_ONE)
+                    / (BPS_ONE - target_ltv);
+            if target_debt <= health.debt_value {
+                return Err(ProtocolError::InvalidOperation);
+            }
 
-        if let Err(error) = save_result() {
-            let storage_error = ProtocolError::StorageError;
-            let context = ErrorLogger::log_error(
-                &env, 
-                &storage_error, 
-                Some(depositor_addr.clone()), 
-                "deposit_collateral", 
-                "Failed to save user position"
-            );
-            
-            // Attempt recovery
-            match ErrorRecovery::attempt_recovery(&env, &storage_error, context) {
-                Ok(()) => {
-                    // Retry the save operation
-                    StateHelper::save_position(&env, &position);
+            let (mut borrowed, mut deposited) = (0, 0);
+            for _ in 0..MAX_LEVERAGE_STEPS {
+                let health = StateHelper::sub_account_health(&env, &user, sub_account)?;
+                let mut step = target_debt - health.debt_value;
+                if !one_shot {
+                    step = step.min(health.borrow_limit - health.debt_value);
                 }
-                Err(_) => return Err(storage_error),
+                let amount = StateHelper::value_to_amount(&env, &debt_info, step)?;
+                if amount <= 0 {
+                    break;
+                }
+                if one_shot {
+                    let (received, debt) = Leverage::flash_and_deposit(
+                        &env,
+                        &user,
+                        sub_account,
+                        &adapter,
+                        &debt_info,
+                        &collateral_info,
+                        amount,
+                        max_slippage,
+                    )?;
+                    deposited += received;
+                    borrowed += debt;
+                    break;
+                }
+                deposited += Leverage::borrow_and_deposit(
+                    &env,
+                    &user,
+                    sub_account,
+                    &adapter,
+                    &debt_info,
+                    &collateral_info,
+                    amount,
+                    max_slippage,
+                )?;
+                borrowed += amount;
             }
-        }
-
-        // Update total supplied amount with error recovery
-        let mut ir_state = InterestRateStorage::get_state(&env);
-        ir_state.total_supplied += amount;
-        
-        let save_ir_result = || -> Result<(), ProtocolError> {
-            InterestRateStorage::save_state(&env, &ir_state);
-            Ok(())
-        };
 
-        if let Err(_) = save_ir_result() {
-            let storage_error = ProtocolError::StorageError;
-            let context = ErrorLogger::log_error(
-                &env, 
-                &storage_error, 
-                Some(depositor_addr.clone()), 
-                "deposit_collateral", 
-                "Failed to update interest rate state"
-            );
-            
-            // Attempt recovery
-            if let Err(_) = ErrorRecovery::attempt_recovery(&env, &storage_error, context) {
-                return Err(storage_error);
+            let health = StateHelper::sub_account_health(&env, &user, sub_account)?;
+            if borrowed == 0 || !health.within_borrow_limit() {
+                return Err(ProtocolError::InsufficientCollateralRatio);
             }
-        }
+            ProtocolEvent::LeverageAdjusted {
+                user: user.clone(),
+                collateral_asset: collateral_asset.clone(),
+                debt_asset: debt_asset.clone(),
+                collateral_change: deposited,
+                debt_change: borrowed,
+            }
+            .emit(&env);
+            Ok(health)
+        })();
+        ReentrancyGuard::exit(&env);
+        result
+    }
 
-        // Collect any accrued supply interest as protocol fees
-        if position.supply_interest > 0 {
-            let config = InterestRateStorage::get_config(&env);
-            let (_, supply_fee) = InterestRateManager::collect_fees_from_interest(
+    /// Lower a sub-account's debt-to-collateral ratio toward `target_ltv` (basis points) by
+    /// withdrawing `collateral_asset`, swapping it through `adapter` and repaying
+    /// `debt_asset`; proceeds beyond the debt go to the user. With `one_shot` the
+    /// collateral is released in a single round against the debt it repays, without a flash
+    /// loan. Otherwise each round only takes what the borrow limit spares, or, while the
+    /// account is over its limit, what closes the shortfall. The account must end within
+    /// its borrow limit or at least healthier than it started. Returns the resulting
+    /// account health.
+    pub fn deleverage(
+        env: Env,
+        user: Address,
+        sub_account: u32,
+        collateral_asset: String,
+        debt_asset: String,
+        target_ltv: i128,
+        max_slippage: i128,
+        adapter: Address,
+        one_shot: bool,
+    ) -> Result<AccountHealth, ProtocolError> {
+        ReentrancyGuard::enter(&env)?;
+        let result = (|| {
+            user.require_auth();
+            let (collateral_info, debt_info) = Self::leverage_assets(
                 &env,
-                0,
-                position.supply_interest,
-                config.reserve_factor,
-            );
-            if supply_fee > 0 {
-                let mut reserve_data = ReserveStorage::get_reserve_data(&env);
-                reserve_data.total_fees_collected += supply_fee;
-                reserve_data.current_reserves += supply_fee;
-                ReserveStorage::save_reserve_data(&env, &reserve_data);
-
-                // Update revenue metrics
-                let mut metrics = ReserveStorage::get_revenue_metrics(&env);
-                metrics.total_supply_fees += supply_fee;
-                ReserveStorage::save_revenue_metrics(&env, &metrics);
-
-                ProtocolEvent::FeesCollected {
-                    amount: supply_fee,
-                    source: String::from_str(&env, "supply"),
+                &user,
+                sub_account,
+                &collateral_asset,
+                &debt_asset,
+                target_ltv,
+                max_slippage,
+            )?;
+            if RiskConfigStorage::for_asset(&env, &collateral_info).pause_withdraw {
+                return Err(ProtocolError::ProtocolPaused);
+            }
+
+            // Collateral value to sell for debt / collateral to fall to the target, with
+            // swaps at oracle prices: every unit sold takes its value off both sides
+            let start = StateHelper::sub_account_health(&env, &user, sub_account)?;
+            if start.debt_value == 0 {
+                return Err(ProtocolError::PositionNotFound);
+            }
+            let target_sold = (start.debt_value * BPS_ONE - target_ltv * start.collateral_value)
+                / (BPS_ONE - target_ltv);
+            if target_sold <= 0 {
+                return Err(ProtocolError::InvalidOperation);
+            }
+
+            let (mut sold, mut withdrawn, mut repaid) = (0, 0, 0);
+            for _ in 0..MAX_LEVERAGE_STEPS {
+                let health = StateHelper::sub_account_health(&env, &user, sub_account)?;
+                if health.debt_value == 0 {
+                    break;
+                }
+                let mut step = target_sold - sold;
+                if !one_shot {
+                    let ltv = EModeStorage::get_category(&env, health.emode_category)
+                        .map_or(collateral_info.ltv, |category| category.ltv)
+                        .max(1);
+                    let headroom = health.borrow_limit - health.debt_value;
+                    // Each unit sold lowers the debt by its value and the limit by its value
+                    // times the LTV, so an account over its limit closes the gap by selling
+                    // the shortfall over one minus the LTV
+                    step = step.min(if headroom >= 0 {
+                        headroom * BPS_ONE / ltv
+                    } else {
+                        -headroom * BPS_ONE / (BPS_ONE - ltv)
+                    });
+                }
+                let collateral =
+                    StateHelper::get_sub_position(&env, &user, sub_account, &collateral_asset)
+                        .map_or(0, |position| position.collateral);
+                let amount = StateHelper::value_to_amount(&env, &collateral_info, step)?.min(collateral);
+                if amount <= 0 {
+                    break;
+                }
+                repaid += Leverage::withdraw_and_repay(
+                    &env,
+                    &user,
+                    sub_account,
+                    &adapter,
+                    &collateral_info,
+                    &debt_info,
+                    amount,
+                    max_slippage,
+                )?;
+                withdrawn += amount;
+                sold += StateHelper::asset_value(&env, &collateral_info, amount)?;
+                if one_shot {
+                    break;
                 }
-                .emit(&env);
             }
-        }
 
-        ProtocolEvent::Deposit {
-            user: depositor,
-            amount,
-            asset: String::from_str(&env, "XLM"),
-        }
-        .emit(&env);
+            let health = StateHelper::sub_account_health(&env, &user, sub_account)?;
+            if withdrawn == 0 {
+                return Err(ProtocolError::InvalidOperation);
+            }
+            if !health.within_borrow_limit() && health.health_factor < start.health_factor {
+                return Err(ProtocolError::InsufficientCollateralRatio);
+            }
+            ProtocolEvent::LeverageAdjusted {
+                user: user.clone(),
+                collateral_asset: collateral_asset.clone(),
+                debt_asset: debt_asset.clone(),
+                collateral_change: -withdrawn,
+                debt_change: -repaid,
+            }
+            .emit(&env);
+            Ok(health)
+        })();
+        ReentrancyGuard::exit(&env);
+        result
+    }
 
-        Ok(())
-    })();
-    ReentrancyGuard::exit(&env);
-    result
-}
-/// Borrow assets from the protocol with dynamic risk check
-pub fn borrow(env: Env, borrower: String, amount: i128) -> Result<(), ProtocolError> {
-    ReentrancyGuard::enter(&env)?;
-    let result = (|| {
-        if borrower.is_empty() {
-            return Err(ProtocolError::InvalidAddress);
+    /// Validate the inputs and account shared by `leverage` and `deleverage`
+    fn leverage_assets(
+        env: &Env,
+        user: &Address,
+        sub_account: u32,
+        collateral_asset: &String,
+        debt_asset: &String,
+        target_ltv: i128,
+        max_slippage: i128,
+    ) -> Result<(AssetInfo, AssetInfo), ProtocolError> {
+        if collateral_asset == debt_asset
+            || !(0..BPS_ONE).contains(&target_ltv)
+            || !(0..BPS_ONE).contains(&max_slippage)
+        {
+            return Err(ProtocolError::InvalidInput);
         }
-        if amount <= 0 {
-            return Err(ProtocolError::InvalidAmount);
+        SubAccounts::validate(sub_account)?;
+        let collateral_info = AssetStorage::require_asset(env, collateral_asset)?;
+        let debt_info = AssetStorage::require_asset(env, debt_asset)?;
+        if FrozenAccounts::is_frozen(env, user) {
+            SecurityMonitor::record_suspicious(env, user, "leverage while frozen");
+            return Err(ProtocolError::Unauthorized);
         }
+        require_kyc(env, user)?;
+        require_not_blacklisted(env, user)?;
+        Ok((collateral_info, debt_info))
+    }
 
-        // Check if borrow is paused
-        let risk_config = RiskConfigStorage::get(&env);
-        if risk_config.pause_borrow {
-            return Err(ProtocolError::ProtocolPaused);
+    /// Move lender shares along with an sToken transfer (called by the asset's sToken contract).
+    /// Fails, reverting the transfer, if the sender is frozen or holds fewer shares.
+    pub fn on_stoken_transfer(
+        env: Env,
+        asset: String,
+        from: Address,
+        to: Address,
+        amount: i128,
+    ) -> Result<(), ProtocolError> {
+        let stoken = AssetStorage::get_stoken_address(&env, &asset)?;
+        stoken.require_auth();
+        if amount < 0 {
+            return Err(ProtocolError::InvalidAmount);
         }
-
-        let borrower_addr = Address::from_string(&borrower);
-        if FrozenAccounts::is_frozen(&env, &borrower_addr) {
-            SecurityMonitor::record_suspicious(&env, &borrower_addr, "borrow while frozen");
+        if amount == 0 || from == to {
+            return Ok(());
+        }
+        if FrozenAccounts::is_frozen(&env, &from) {
             return Err(ProtocolError::Unauthorized);
         }
-        require_kyc(&env, &borrower_addr)?;
-        require_not_blacklisted(&env, &borrower_addr)?;
-        check_aml(&env, &borrower_addr, amount, "borrow")?;
-        let mut position = StateHelper::get_position(&env, &borrower_addr)
-            .unwrap_or(Position::new(borrower_addr.clone(), 0, 0));
 
-        // Accrue interest before updating position
-        let state = InterestRateStorage::update_state(&env);
+        let mut sender = StateHelper::get_position(&env, &from, &asset)
+            .ok_or(ProtocolError::PositionNotFound)?;
+        let mut receiver = StateHelper::get_position(&env, &to, &asset)
+            .unwrap_or(AssetPosition::new(to.clone(), asset.clone(), 0, 0));
+
+        // Accrue interest before moving shares
+        let state = InterestRateStorage::update_asset_state(&env, &asset);
         InterestRateManager::accrue_interest_for_position(
             &env,
-            &mut position,
-            state.current_borrow_rate,
-            state.current_supply_rate,
+            &mut sender,
+            state.borrow_index,
+            state.supply_index,
+        );
+        InterestRateManager::accrue_interest_for_position(
+            &env,
+            &mut receiver,
+            state.borrow_index,
+            state.supply_index,
         );
 
-        let new_debt = position.debt + amount;
-        let mut new_position = position.clone();
-        new_position.debt = new_debt;
-
-        let min_ratio = ProtocolConfig::get_min_collateral_ratio(&env);
-        let ratio = StateHelper::dynamic_collateral_ratio::<RealPriceOracle>(&env, &new_position);
-        if ratio < min_ratio {
-            SecurityMonitor::record_suspicious(&env, &borrower_addr, "borrow below collateral ratio");
-            return Err(ProtocolError::InsufficientCollateralRatio);
-        }
-
-        position.debt = new_debt;
-        StateHelper::save_position(&env, &position);
-
-        // Update total borrowed amount
-        let mut ir_state = InterestRateStorage::get_state(&env);
-        ir_state.total_borrowed += amount;
-        InterestRateStorage::save_state(&env, &ir_state);
-
-        // Collect any accrued borrow interest as protocol fees
-        if position.borrow_interest > 0 {
-            let config = InterestRateStorage::get_config(&env);
-            let (borrow_fee, _) = InterestRateManager::collect_fees_from_interest(
-                &env,
-                position.borrow_interest,
-                0,
-                config.reserve_factor,
-            );
-            if borrow_fee > 0 {
-                let mut reserve_data = ReserveStorage::get_reserve_data(&env);
-                reserve_data.total_fees_collected += borrow_fee;
-                reserve_data.current_reserves += borrow_fee;
-                ReserveStorage::save_reserve_data(&env, &reserve_data);
-
-                // Update revenue metrics
-                let mut metrics = ReserveStorage::get_revenue_metrics(&env);
-                metrics.total_borrow_fees += borrow_fee;
-                ReserveStorage::save_revenue_metrics(&env, &metrics);
-
-                ProtocolEvent::FeesCollected {
-                    amount: borrow_fee,
-                    source: String::from_str(&env, "borrow"),
-                }
-                .emit(&env);
-            }
-        }
-
-        ProtocolEvent::Borrow {
-            user: borrower,
-            amount,
-            asset: String::from_str(&env, "XLM"),
+        if sender.supply_shares < amount {
+            return Err(ProtocolError::InvalidAmount);
         }
-        .emit(&env);
+        sender.supply_shares -= amount;
+        receiver.supply_shares += amount;
 
+        StateHelper::save_position(&env, &sender);
+        StateHelper::save_position(&env, &receiver);
         Ok(())
-    })();
-    ReentrancyGuard::exit(&env);
-    result
-}
+    }
 
-/// Repay borrowed assets
-pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolError> {
-    ReentrancyGuard::enter(&env)?;
-    let result = (|| {
-        if repayer.is_empty() {
-            return Err(ProtocolError::InvalidAddress);
-        }
-        if amount <= 0 {
-            return Err(ProtocolError::InvalidAmount);
-        }
+    pub fn hello(env: Env, to: String) -> Vec<String> {
+        vec![&env, String::from_str(&env, "Hello"), to]
+    }
 
-        let repayer_addr = Address::from_string(&repayer);
-        if FrozenAccounts::is_frozen(&env, &repayer_addr) {
-            SecurityMonitor::record_suspicious(&env, &repayer_addr, "repay while frozen");
-            return Err(ProtocolError::Unauthorized);
-        }
-        require_kyc(&env, &repayer_addr)?;
-        require_not_blacklisted(&env, &repayer_addr)?;
-        check_aml(&env, &repayer_addr, amount, "repay")?;
-        let mut position = StateHelper::get_position(&env, &repayer_addr).unwrap_or(Position::new(
-            repayer_addr.clone(),
-            0,
-            0,
-        ));
+    /// Query a user's position in an asset (collateral, debt, collateral ratio in that asset)
+    pub fn get_position(
+        env: Env,
+        user: Address,
+        asset: String,
+    ) -> Result<(i128, i128, i128), ProtocolError> {
+        AssetStorage::require_asset(&env, &asset)?;
+        let position = StateHelper::get_position(&env, &user, &asset)
+            .unwrap_or(AssetPosition::new(user, asset, 0, 0));
+        let debt = StateHelper::current_debt(&env, &position);
+        let ratio = StateHelper::collateral_ratio(&env, &position);
+        Ok((position.collateral, debt, ratio))
+    }
+
+    /// Move collateral between two of the user's sub-accounts (the main account is
+    /// `MAIN_ACCOUNT`); both must stay within their borrow limits. Collateral enters and
+    /// leaves sub-accounts this way, through the main account's deposits and withdrawals.
+    pub fn move_collateral(
+        env: Env,
+        user: Address,
+        from_sub_account: u32,
+        to_sub_account: u32,
+        asset: String,
+        amount: i128,
+    ) -> Result<(), ProtocolError> {
+        ReentrancyGuard::enter(&env)?;
+        let result = (|| {
+            user.require_auth();
+            if amount <= 0 {
+                return Err(ProtocolError::InvalidAmount);
+            }
+            if from_sub_account == to_sub_account {
+                return Err(ProtocolError::InvalidInput);
+            }
+            SubAccounts::validate(from_sub_account)?;
+            SubAccounts::validate(to_sub_account)?;
+            let asset_info = AssetStorage::require_asset(&env, &asset)?;
 
-        let mut position = StateHelper::get_position(&env, &repayer_addr)
-            .unwrap_or(Position::new(repayer_addr.clone(), 0, 0));
+            // The move withdraws from one sub-account and deposits into the other
+            let risk_config = RiskConfigStorage::for_asset(&env, &asset_info);
+            if risk_config.pause_withdraw || risk_config.pause_deposit {
+                return Err(ProtocolError::ProtocolPaused);
+            }
+            if FrozenAccounts::is_frozen(&env, &user) {
+                SecurityMonitor::record_suspicious(&env, &user, "move while frozen");
+                return Err(ProtocolError::Unauthorized);
+            }
+            require_kyc(&env, &user)?;
+            require_not_blacklisted(&env, &user)?;
+
+            let state = InterestRateStorage::update_asset_state(&env, &asset);
+            let (from, from_health) =
+                StateHelper::plan_withdraw(&env, &user, from_sub_account, &asset, &state, amount)?;
+            let mut to = StateHelper::accrued_position(&env, &user, to_sub_account, &asset, &state);
+            to.collateral += amount;
+            // Added collateral can still take the destination out of its efficiency mode
+            let to_health = StateHelper::account_health_with(&env, &user, Some(&to))?;
+            if !from_health.within_borrow_limit() || !to_health.within_borrow_limit() {
+                return Err(ProtocolError::InsufficientCollateralRatio);
+            }
+            // Debt left behind stops counting toward the ceiling of isolated collateral
+            // that is moved out entirely; the destination's debt is charged instead
+            StateHelper::save_position(&env, &from);
+            IsolationMode::record_collateral_removed(&env, &user, from_sub_account, &asset_info);
+            let asset_info = AssetStorage::require_asset(&env, &asset)?;
+            IsolationMode::record_collateral_added(&env, &user, to_sub_account, &asset_info)?;
+            StateHelper::save_position(&env, &to);
+
+            ProtocolEvent::CollateralMoved {
+                user,
+                asset,
+                from_sub_account,
+                to_sub_account,
+                amount,
+            }
+            .emit(&env);
+            Ok(())
+        })();
+        ReentrancyGuard::exit(&env);
+        result
+    }
 
-        // Accrue interest before updating position
-        let state = InterestRateStorage::update_state(&env);
-        InterestRateManager::accrue_interest_for_position(
-            &env,
-            &mut position,
-            state.current_borrow_rate,
-            state.current_supply_rate,
-        );
+    /// Query a user's collateral and debt (with interest to now) in an asset within a sub-account
+    pub fn get_sub_account_position(
+        env: Env,
+        user: Address,
+        sub_account: u32,
+        asset: String,
+    ) -> Result<(i128, i128), ProtocolError> {
+        AssetStorage::require_asset(&env, &asset)?;
+        let position = StateHelper::get_sub_position(&env, &user, sub_account, &asset)
+            .unwrap_or(AssetPosition::new(user, asset, 0, 0));
+        Ok((position.collateral, StateHelper::current_debt(&env, &position)))
+    }
 
-        let old_debt = position.debt;
-        position.debt = (position.debt - amount).max(0);
-        StateHelper::save_position(&env, &position);
+    /// Query the health of one of a user's sub-accounts
+    pub fn get_sub_account_health(
+        env: Env,
+        user: Address,
+        sub_account: u32,
+    ) -> Result<AccountHealth, ProtocolError> {
+        StateHelper::sub_account_health(&env, &user, sub_account)
+    }
 
-        // Update total borrowed amount
-        let mut ir_state = InterestRateStorage::get_state(&env);
-        ir_state.total_borrowed -= (old_debt - position.debt);
-        InterestRateStorage::save_state(&env, &ir_state);
+    /// Query the sub-accounts a user has opened besides their main account
+    pub fn get_sub_accounts(env: Env, user: Address) -> Vec<u32> {
+        SubAccounts::list(&env, &user)
+    }
 
-        ProtocolEvent::Repay {
-            user: repayer,
-            amount,
-            asset: String::from_str(&env, "XLM"),
+    /// Query a user's stable debt in an asset with interest to now, and its locked rate
+    pub fn get_stable_debt(
+        env: Env,
+        user: Address,
+        asset: String,
+    ) -> Result<(i128, i128), ProtocolError> {
+        AssetStorage::require_asset(&env, &asset)?;
+        let state = InterestRateStorage::projected_asset_state(&env, &asset);
+        let position = StateHelper::accrued_position(&env, &user, MAIN_ACCOUNT, &asset, &state);
+        if position.stable_debt == 0 {
+            return Ok((0, 0));
         }
-        .emit(&env);
+        Ok((position.stable_debt, position.stable_rate))
+    }
 
-        Ok(())
-    })();
-    ReentrancyGuard::exit(&env);
-    result
-}
+    /// Query an asset's stable borrowing: (total stable debt, average stable rate, rate
+    /// offered to new stable borrows), with interest accrued to now
+    pub fn get_stable_rate_info(env: Env, asset: String) -> Result<(i128, i128, i128), ProtocolError> {
+        let asset_info = AssetStorage::require_asset(&env, &asset)?;
+        let state = InterestRateStorage::projected_asset_state(&env, &asset);
+        Ok((
+            state.total_stable_debt,
+            state.average_stable_rate,
+            InterestRateManager::stable_borrow_rate(&state, &asset_info.interest_config, 0),
+        ))
+    }
 
+    /// Query an account's health across all assets (collateral value, borrow limit,
+    /// debt value, health factor scaled by 1e8)
+    pub fn get_account_health(
+        env: Env,
+        user: Address,
+    ) -> Result<(i128, i128, i128, i128), ProtocolError> {
+        let health = StateHelper::account_health(&env, &user)?;
+        Ok((
+            health.collateral_value,
+            health.borrow_limit,
+            health.debt_value,
+            health.health_factor,
+        ))
+    }
 
-    /// Withdraw collateral with dynamic risk check
-    pub fn withdraw(env: Env, withdrawer: String, amount: i128) -> Result<(), ProtocolError> {
-        if withdrawer.is_empty() {
-            return Err(ProtocolError::InvalidAddress);
-        }
-        if amount <= 0 {
-            return Err(ProtocolError::InvalidAmount);
+    /// Largest amount of an asset the user could borrow into a sub-account now, within its
+    /// borrow limit, the asset's liquidity and borrow cap, and any isolation debt ceiling
+    pub fn max_borrowable(
+        env: Env,
+        user: Address,
+        sub_account: u32,
+        asset: String,
+    ) -> Result<i128, ProtocolError> {
+        let asset_info = AssetStorage::require_asset(&env, &asset)?;
+        if !asset_info.borrow_enabled {
+            return Ok(0);
         }
+        let state = InterestRateStorage::projected_asset_state(&env, &asset);
+        let health = StateHelper::sub_account_health(&env, &user, sub_account)?;
 
-        // Check if withdraw is paused
-        let risk_config = RiskConfigStorage::get(&env);
-        if risk_config.pause_withdraw {
-            return Err(ProtocolError::ProtocolPaused);
+        let headroom = (health.borrow_limit - health.debt_value).max(0);
+        let mut max = StateHelper::value_to_amount(&env, &asset_info, headroom)?
+            .min(state.total_supplied - state.total_borrowed);
+        if asset_info.borrow_cap > 0 {
+            max = max.min(asset_info.borrow_cap - state.total_borrowed);
         }
-        let withdrawer_addr = Address::from_string(&withdrawer);
-        if FrozenAccounts::is_frozen(&env, &withdrawer_addr) {
-            return Err(ProtocolError::Unauthorized);
+        if let Some(isolated) = IsolationMode::borrow_headroom(&env, &user, sub_account, &asset_info)? {
+            max = max.min(isolated);
         }
-        require_kyc(&env, &withdrawer_addr)?;
-        require_not_blacklisted(&env, &withdrawer_addr)?;
-        check_aml(&env, &withdrawer_addr, amount, "withdraw")?;
-        let mut position = StateHelper::get_position(&env, &withdrawer_addr)
-            .unwrap_or(Position::new(withdrawer_addr.clone(), 0, 0));
+        Ok(max.max(0))
+    }
 
-        // Accrue interest before updating position
-        let state = InterestRateStorage::update_state(&env);
-        InterestRateManager::accrue_interest_for_position(
-            &env,
-            &mut position,
-            state.current_borrow_rate,
-            state.current_supply_rate,
-        );
+    /// Largest amount of a sub-account's collateral in an asset the user could withdraw now
+    /// without exceeding its borrow limit
+    pub fn max_withdrawable(
+        env: Env,
+        user: Address,
+        sub_account: u32,
+        asset: String,
+    ) -> Result<i128, ProtocolError> {
+        let asset_info = AssetStorage::require_asset(&env, &asset)?;
+        let collateral = match StateHelper::get_sub_position(&env, &user, sub_account, &asset) {
+            Some(position) => position.collateral,
+            None => return Ok(0),
+        };
+        let health = StateHelper::sub_account_health(&env, &user, sub_account)?;
+        let ltv = match EModeStorage::get_category(&env, health.emode_category) {
+            Some(category) => category.ltv,
+            None => asset_info.ltv,
+        };
+        if health.debt_value == 0 || ltv == 0 {
+            return Ok(collateral);
+        }
+
+        // Each unit withdrawn lowers the borrow limit by its value times the LTV
+        let headroom = (health.borrow_limit - health.debt_value).max(0);
+        let max = StateHelper::value_to_amount(&env, &asset_info, headroom * BPS_ONE / ltv)?;
+        Ok(max.min(collateral))
+    }
+
+    /// Price (scaled by 1e8) of each collateral asset at which the account would become
+    /// liquidatable, holding other prices constant. Assets whose fall cannot trigger
+    /// liquidation are left out, and an account without debt has none.
+    pub fn liquidation_price(env: Env, user: Address) -> Result<Map<String, i128>, ProtocolError> {
+        let mut prices = Map::new(&env);
+        let health = StateHelper::account_health(&env, &user)?;
+        if health.debt_value == 0 {
+            return Ok(prices);
+        }
+        let emode = EModeStorage::get_category(&env, health.emode_category);
+        let surplus = health.liquidation_limit - health.debt_value;
+        for asset in AssetStorage::get_registry(&env).supported_assets.iter() {
+            let (position, info) = match (
+                StateHelper::get_position(&env, &user, &asset),
+                AssetStorage::get_asset_info(&env, &asset),
+            ) {
+                (Some(position), Some(info)) if position.collateral > 0 => (position, info),
+                _ => continue,
+            };
+            let threshold = emode
+                .as_ref()
+                .map_or(info.liquidation_threshold, |category| category.liquidation_threshold);
+
+            // A price move scales this asset's threshold-weighted collateral and its own
+            // debt together; liquidation comes once it eats through the surplus
+            let exposure = StateHelper::asset_value(&env, &info, position.collateral)? * threshold
+                / BPS_ONE
+                - StateHelper::asset_value(&env, &info, StateHelper::current_debt(&env, &position))?;
+            if exposure <= 0 {
+                continue;
+            }
+            let price = StateHelper::asset_price(&env, &info)?;
+            prices.set(asset, (price - surplus * price / exposure).max(0));
+        }
+        Ok(prices)
+    }
 
-        if position.collateral < amount {
-            return Err(ProtocolError::InsufficientCollateral);
+    /// Preview a borrow into a sub-account with interest accrued to now; fails as `borrow`
+    /// would
+    pub fn preview_borrow(
+        env: Env,
+        user: Address,
+        sub_account: u32,
+        asset: String,
+        amount: i128,
+    ) -> Result<PositionPreview, ProtocolError> {
+        if amount <= 0 {
+            return Err(ProtocolError::InvalidAmount);
         }
-        let mut new_position = position.clone();
-        new_position.collateral -= amount;
-        let min_ratio = ProtocolConfig::get_min_collateral_ratio(&env);
-        let ratio = StateHelper::dynamic_collateral_ratio::<RealPriceOracle>(&env, &new_position);
-        if position.debt > 0 && ratio < min_ratio {
-            return Err(ProtocolError::InsufficientCollateralRatio);
+        let asset_info = AssetStorage::require_asset(&env, &asset)?;
+        if !asset_info.borrow_enabled {
+            return Err(ProtocolError::AssetDisabled);
+        }
+        if RiskConfigStorage::for_asset(&env, &asset_info).pause_borrow {
+            return Err(ProtocolError::ProtocolPaused);
         }
-        position.collateral = new_position.collateral;
-        StateHelper::save_position(&env, &position);
-
-        // Update total supplied amount
-        let mut ir_state = InterestRateStorage::get_state(&env);
-        ir_state.total_supplied -= amount;
-        InterestRateStorage::save_state(&env, &ir_state);
+        // Account checks as in `borrow`; AML screening only publishes events, so a
+        // preview leaves it out
+        if FrozenAccounts::is_frozen(&env, &user) {
+            return Err(ProtocolError::Unauthorized);
+        }
+        require_kyc(&env, &user)?;
+        require_not_blacklisted(&env, &user)?;
 
-        ProtocolEvent::Withdraw {
-            user: withdrawer,
-            amount,
-            asset: String::from_str(&env, "XLM"),
+        let mut state = InterestRateStorage::projected_asset_state(&env, &asset);
+        let (position, health) =
+            StateHelper::plan_borrow(&env, &user, sub_account, &asset_info, &state, amount, None)?;
+        if !health.within_borrow_limit() {
+            return Err(ProtocolError::InsufficientCollateralRatio);
         }
-        .emit(&env);
-        Ok(())
+        state.total_borrowed += amount;
+        Ok(PositionPreview::new(&env, &position, &health, state))
     }
 
-    /// Liquidate undercollateralized positions using dynamic risk check
-    pub fn liquidate(
+    /// Preview a collateral withdrawal from a sub-account with interest accrued to now;
+    /// fails as `withdraw` would
+    pub fn preview_withdraw(
         env: Env,
-        liquidator: String,
-        target: String,
+        user: Address,
+        sub_account: u32,
+        asset: String,
         amount: i128,
-    ) -> Result<(), ProtocolError> {
-        if liquidator.is_empty() || target.is_empty() {
-            return Err(ProtocolError::InvalidAddress);
-        }
+    ) -> Result<PositionPreview, ProtocolError> {
         if amount <= 0 {
             return Err(ProtocolError::InvalidAmount);
         }
-
-        // Check if liquidation is paused
-        let risk_config = RiskConfigStorage::get(&env);
-        if risk_config.pause_liquidate {
+        let asset_info = AssetStorage::require_asset(&env, &asset)?;
+        if RiskConfigStorage::for_asset(&env, &asset_info).pause_withdraw {
             return Err(ProtocolError::ProtocolPaused);
         }
-
-        let target_addr = Address::from_string(&target);
-        if FrozenAccounts::is_frozen(&env, &target_addr) {
+        if FrozenAccounts::is_frozen(&env, &user) {
             return Err(ProtocolError::Unauthorized);
         }
+        require_kyc(&env, &user)?;
+        require_not_blacklisted(&env, &user)?;
 
-        let mut position = match StateHelper::get_position(&env, &target_addr) {
-            Some(pos) => pos,
-            None => return Err(ProtocolError::PositionNotFound),
-        };
-
-        // Accrue interest before liquidation
-        let state = InterestRateStorage::update_state(&env);
-        InterestRateManager::accrue_interest_for_position(
-            &env,
-            &mut position,
-            state.current_borrow_rate,
-            state.current_supply_rate,
-        );
-
-        let min_ratio = ProtocolConfig::get_min_collateral_ratio(&env);
-        let ratio = StateHelper::dynamic_collateral_ratio::<RealPriceOracle>(&env, &position);
-        if ratio >= min_ratio {
-            return Err(ProtocolError::NotEligibleForLiquidation);
+        let mut state = InterestRateStorage::projected_asset_state(&env, &asset);
+        let (position, health) =
+            StateHelper::plan_withdraw(&env, &user, sub_account, &asset, &state, amount)?;
+        if !health.within_borrow_limit() {
+            return Err(ProtocolError::InsufficientCollateralRatio);
         }
+        state.total_collateral -= amount;
+        Ok(PositionPreview::new(&env, &position, &health, state))
+    }
 
-        // Apply close factor to limit liquidation amount
-        let max_repay_amount = (position.debt * risk_config.close_factor) / 100_000_000;
-        let repay_amount = amount.min(position.debt).min(max_repay_amount);
-
-        if repay_amount == 0 {
+    /// Preview a repayment in a sub-account with interest accrued to now; amounts above the
+    /// debt only repay what is owed, as in `repay`
+    pub fn preview_repay(
+        env: Env,
+        user: Address,
+        sub_account: u32,
+        asset: String,
+        amount: i128,
+    ) -> Result<PositionPreview, ProtocolError> {
+        if amount <= 0 {
             return Err(ProtocolError::InvalidAmount);
         }
+        AssetStorage::require_asset(&env, &asset)?;
 
-        // Calculate liquidation incentive
-        let incentive_amount = (repay_amount * risk_config.liquidation_incentive) / 100_000_000;
-        let total_collateral_seized = repay_amount + incentive_amount;
-
-        // Ensure we don't seize more collateral than available
-        let actual_collateral_seized = total_collateral_seized.min(position.collateral);
-
-        // Update position
-        position.debt -= repay_amount;
-        position.collateral -= actual_collateral_seized;
-        StateHelper::save_position(&env, &position);
-
-        // Update total borrowed amount
-        let mut ir_state = InterestRateStorage::get_state(&env);
-        ir_state.total_borrowed -= repay_amount;
-        InterestRateStorage::save_state(&env, &ir_state);
-
-        ProtocolEvent::Liquidate {
-            user: target,
-            amount: repay_amount,
-            asset: String::from_str(&env, "XLM"),
-        }
-        .emit(&env);
-        Ok(())
+        let mut state = InterestRateStorage::projected_asset_state(&env, &asset);
+        let (position, repaid, stable_repaid) =
+            StateHelper::plan_repay(&env, &user, sub_account, &asset, &state, amount);
+        let health = StateHelper::account_health_with(&env, &user, Some(&position))?;
+        state.total_borrowed -= repaid;
+        InterestRateManager::decrease_stable_total(&mut state, stable_repaid, position.stable_rate);
+        Ok(PositionPreview::new(&env, &position, &health, state))
     }
 
-    pub fn hello(env: Env, to: String) -> Vec<String> {
-        vec![&env, String::from_str(&env, "Hello"), to]
+    /// Extend the storage TTL of a user's positions and account data so they are not
+    /// archived; anyone may call it. Returns the number of entries bumped.
+    pub fn bump_user(env: Env, user: Address) -> u32 {
+        StateHelper::bump_user(&env, &user)
     }
 
-    /// Query a user's position (collateral, debt, dynamic ratio)
-    pub fn get_position(env: Env, user: String) -> Result<(i128, i128, i128), ProtocolError> {
-        let user_addr = Address::from_string(&user);
-        let position =
-            StateHelper::get_position(&env, &user_addr).unwrap_or(Position::new(user_addr, 0, 0));
-        let ratio = StateHelper::dynamic_collateral_ratio::<RealPriceOracle>(&env, &position);
-        Ok((position.collateral, position.debt, ratio))
+    /// Query a lender's supply position in an asset (sToken shares, liquidity they redeem for)
+    pub fn get_supply_position(
+        env: Env,
+        user: Address,
+        asset: String,
+    ) -> Result<(i128, i128), ProtocolError> {
+        AssetStorage::require_asset(&env, &asset)?;
+        let position = StateHelper::get_position(&env, &user, &asset)
+            .unwrap_or(AssetPosition::new(user, asset, 0, 0));
+        let supplied = StateHelper::current_supply(&env, &position);
+        Ok((position.supply_shares, supplied))
     }
 
     /// Query protocol parameters (admin, oracle, min collateral ratio)
@@ -2939,9 +6853,21 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
         Ok((admin, oracle, min_ratio))
     }
 
-    /// Query system-wide stats (total collateral, total debt)
-    pub fn get_system_stats(_env: Env) -> Result<(i128, i128), ProtocolError> {
-        Ok((0, 0))
+    /// Query live protocol-wide totals, per asset and in aggregate quote-currency value
+    pub fn get_system_stats(env: Env) -> Result<SystemStats, ProtocolError> {
+        SystemStats::collect(&env)
+    }
+
+    /// Query the cumulative bad debt written off in an asset
+    pub fn get_bad_debt(env: Env, asset: String) -> Result<BadDebtStats, ProtocolError> {
+        AssetStorage::require_asset(&env, &asset)?;
+        Ok(BadDebtManager::get_stats(&env, &asset))
+    }
+
+    /// Query the live totals of one asset market
+    pub fn get_asset_stats(env: Env, asset: String) -> Result<AssetStats, ProtocolError> {
+        AssetStorage::require_asset(&env, &asset)?;
+        Ok(SystemStats::for_asset(&env, &asset))
     }
 
     /// Query event logs for a given user and event type (stub for off-chain indexer)
@@ -2988,10 +6914,9 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
     /// # Example
     /// ```
     /// // Off-chain indexer would listen for events like:
-    /// // env.events().publish((Symbol::short("deposit"), Symbol::short("user")), (Symbol::short("user"), amount));
+    /// // env.events().publish((symbol_short!("deposit"), symbol_short!("user")), (symbol_short!("user"), amount));
     /// // and store them in a database for querying.
     /// ```
-
     pub fn event_indexer_example_doc() -> Result<(), ProtocolError> {
         Ok(())
     }
@@ -2999,12 +6924,11 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
     /// Set risk parameters (admin only)
     pub fn set_risk_params(
         env: Env,
-        caller: String,
+        caller: Address,
         close_factor: i128,
         liquidation_incentive: i128,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+        ProtocolConfig::require_admin(&env, &caller)?;
         let mut config = RiskConfigStorage::get(&env);
         config.close_factor = close_factor;
         config.liquidation_incentive = liquidation_incentive;
@@ -3013,73 +6937,432 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
         Ok(())
     }
 
-    /// Set protocol pause switches (admin only)
-    pub fn set_pause_switches(
+    /// Set an asset's stable rate premium over the variable rate and the utilization at
+    /// which stable rates can be rebalanced up (admin only, both scaled by 1e8)
+    pub fn set_stable_rate_params(
         env: Env,
-        caller: String,
-        pause_borrow: bool,
-        pause_deposit: bool,
-        pause_withdraw: bool,
-        pause_liquidate: bool,
+        caller: Address,
+        asset: String,
+        premium: i128,
+        rebalance_threshold: i128,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
-        let mut config = RiskConfigStorage::get(&env);
+        ProtocolConfig::require_admin(&env, &caller)?;
+        if premium < 0 || !(0..=100_000_000).contains(&rebalance_threshold) {
+            return Err(ProtocolError::InvalidInput);
+        }
+        AssetStorage::require_asset(&env, &asset)?;
 
-    /// Withdraw collateral from the protocol
-    pub fn withdraw(env: Env, withdrawer: String, amount: i128) -> Result<(), ProtocolError> {
+        let mut config = InterestRateStorage::get_asset_config(&env, &asset);
+        config.stable_rate_premium = premium;
+        config.stable_rebalance_threshold = rebalance_threshold;
+        config.last_update = env.ledger().timestamp();
+        InterestRateStorage::save_asset_config(&env, &asset, &config);
+        Ok(())
+    }
+
+    /// Reset a user's stable rate in an asset to the rate offered to new stable borrows;
+    /// anyone may call it. A rate is rebalanced up when the asset's utilization is at or
+    /// above its rebalance threshold and the rate has fallen below what suppliers earn, and
+    /// down when it sits more than `STABLE_REBALANCE_DOWN_MARGIN` above the offer. Returns
+    /// the new rate.
+    pub fn rebalance_stable_rate(env: Env, user: Address, asset: String) -> Result<i128, ProtocolError> {
+        AssetStorage::require_asset(&env, &asset)?;
+        let mut state = InterestRateStorage::update_asset_state(&env, &asset);
+        let config = InterestRateStorage::get_asset_config(&env, &asset);
+        let mut position = StateHelper::accrued_position(&env, &user, MAIN_ACCOUNT, &asset, &state);
+        if position.stable_debt == 0 {
+            return Err(ProtocolError::PositionNotFound);
+        }
+
+        let rate = InterestRateManager::stable_borrow_rate(&state, &config, 0);
+        let up = state.utilization_rate >= config.stable_rebalance_threshold
+            && position.stable_rate < state.current_supply_rate;
+        let down = position.stable_rate > rate + STABLE_REBALANCE_DOWN_MARGIN;
+        if !up && !down {
+            return Err(ProtocolError::InvalidOperation);
+        }
+        InterestRateManager::decrease_stable_total(&mut state, position.stable_debt, position.stable_rate);
+        InterestRateManager::increase_stable_total(&mut state, position.stable_debt, rate);
+        InterestRateStorage::save_asset_state(&env, &asset, &state);
+        position.stable_rate = rate;
+        StateHelper::save_position(&env, &position);
+        Ok(rate)
+    }
+
+    /// Let `delegatee` borrow up to `amount` of an asset against the delegator's collateral
+    /// with `borrow_on_behalf`; the debt stays the delegator's. Replaces any previous allowance.
+    pub fn delegate_borrow_allowance(
+        env: Env,
+        delegator: Address,
+        delegatee: Address,
+        asset: String,
+        amount: i128,
+    ) -> Result<(), ProtocolError> {
+        delegator.require_auth();
+        if amount < 0 {
+            return Err(ProtocolError::InvalidAmount);
+        }
+        if delegatee == delegator {
+            return Err(ProtocolError::InvalidAddress);
+        }
+        AssetStorage::require_asset(&env, &asset)?;
+        CreditDelegation::set_allowance(&env, &delegator, &delegatee, &asset, amount);
+        ProtocolEvent::BorrowAllowanceSet {
+            delegator,
+            delegatee,
+            asset,
+            amount,
+        }
+        .emit(&env);
+        Ok(())
+    }
+
+    /// Revoke a delegatee's remaining borrow allowance in an asset
+    pub fn revoke_borrow_allowance(
+        env: Env,
+        delegator: Address,
+        delegatee: Address,
+        asset: String,
+    ) -> Result<(), ProtocolError> {
+        Self::delegate_borrow_allowance(env, delegator, delegatee, asset, 0)
+    }
+
+    /// Query how much of an asset a delegatee may still borrow against the delegator's collateral
+    pub fn get_borrow_allowance(env: Env, delegator: Address, delegatee: Address, asset: String) -> i128 {
+        CreditDelegation::get_allowance(&env, &delegator, &delegatee, &asset)
+    }
+
+    /// Take out a fixed-term loan of `amount` for `term` seconds at the asset's current
+    /// stable rate, which stays fixed until the loan is repaid. Fails if that rate exceeds
+    /// `max_rate`. Returns the loan id.
+    pub fn originate_loan(
+        env: Env,
+        borrower: Address,
+        asset: String,
+        amount: i128,
+        term: u64,
+        max_rate: i128,
+    ) -> Result<u32, ProtocolError> {
         ReentrancyGuard::enter(&env)?;
         let result = (|| {
-            if withdrawer.is_empty() {
-                return Err(ProtocolError::InvalidAddress);
-            }
+            borrower.require_auth();
             if amount <= 0 {
                 return Err(ProtocolError::InvalidAmount);
             }
-            // Check if withdraw is paused
-            let risk_config = RiskConfigStorage::get(&env);
-            if risk_config.pause_withdraw {
+            if term == 0 {
+                return Err(ProtocolError::InvalidInput);
+            }
+            let asset_info = AssetStorage::require_asset(&env, &asset)?;
+            if !asset_info.borrow_enabled {
+                return Err(ProtocolError::AssetDisabled);
+            }
+            let risk_config = RiskConfigStorage::for_asset(&env, &asset_info);
+            if risk_config.pause_borrow {
                 return Err(ProtocolError::ProtocolPaused);
             }
-            let withdrawer_addr = Address::from_string(&withdrawer);
-            if FrozenAccounts::is_frozen(&env, &withdrawer_addr) {
+            if FrozenAccounts::is_frozen(&env, &borrower) {
+                SecurityMonitor::record_suspicious(&env, &borrower, "loan while frozen");
                 return Err(ProtocolError::Unauthorized);
             }
-            // Load user position
-            let mut position = StateHelper::get_position(&env, &withdrawer_addr)
-                .ok_or(ProtocolError::PositionNotFound)?;
-            // Check sufficient collateral
-            if position.collateral < amount {
-                SecurityMonitor::record_suspicious(&env, &withdrawer_addr, "Insufficient collateral for withdrawal");
-                return Err(ProtocolError::InsufficientCollateral);
+            require_kyc(&env, &borrower)?;
+            require_not_blacklisted(&env, &borrower)?;
+            check_aml(&env, &borrower, amount, "borrow")?;
+
+            let mut state = InterestRateStorage::update_asset_state(&env, &asset);
+            let rate = InterestRateManager::stable_borrow_rate(&state, &asset_info.interest_config, amount);
+            if rate > max_rate {
+                return Err(ProtocolError::SlippageExceeded);
             }
-            // Simulate withdrawal and check collateral ratio
-            position.collateral -= amount;
-            let min_ratio = ProtocolConfig::get_min_collateral_ratio(&env);
-            let ratio = StateHelper::collateral_ratio(&position);
-            if ratio < min_ratio {
-                SecurityMonitor::record_suspicious(&env, &withdrawer_addr, "Collateral ratio too low after withdrawal");
+            if amount > state.total_supplied - state.total_borrowed {
+                return Err(ProtocolError::InsufficientLiquidity);
+            }
+            asset_info.check_borrow_cap(&state, amount)?;
+
+            let now = env.ledger().timestamp();
+            let mut loan = FixedTermLoan {
+                id: 0,
+                borrower: borrower.clone(),
+                asset: asset.clone(),
+                principal: amount,
+                rate,
+                originated_at: now,
+                maturity: now.saturating_add(term),
+                debt: amount,
+                last_accrual_time: now,
+                status: LoanStatus::Active,
+            };
+            let health = StateHelper::account_health_with_loan(&env, &borrower, &loan)?;
+            if !health.within_borrow_limit() {
+                SecurityMonitor::record_suspicious(&env, &borrower, "loan below collateral ratio");
                 return Err(ProtocolError::InsufficientCollateralRatio);
             }
-            // Save updated position
-            StateHelper::save_position(&env, &position);
-            // Track user activity
-            let mut activity = ActivityStorage::get_user_activity(&env, &withdrawer_addr)
-                .unwrap_or(UserActivity::new());
-            activity.record_withdrawal(amount, env.ledger().timestamp());
-            ActivityStorage::save_user_activity(&env, &withdrawer_addr, &activity);
-            // Emit event
-            ProtocolEvent::Withdraw {
-                user: withdrawer.clone(),
-                amount,
-                asset: String::from_str(&env, "XLM"),
+            IsolationMode::record_borrow(&env, &borrower, MAIN_ACCOUNT, &asset_info, amount)?;
+
+            loan.id = LoanStorage::next_id(&env);
+            LoanStorage::save_loan(&env, &loan);
+            LoanStorage::add_user_loan(&env, &borrower, loan.id);
+
+            // Fixed-rate debt accrues in the pool's totals like stable debt
+            state.total_borrowed += amount;
+            InterestRateManager::increase_stable_total(&mut state, amount, rate);
+            InterestRateStorage::save_asset_state(&env, &asset, &state);
+
+            let token = AssetStorage::get_token_address(&env, &asset)?;
+            TokenHelper::transfer_out(&env, &token, &borrower, amount);
+
+            ProtocolEvent::LoanOriginated {
+                loan_id: loan.id,
+                borrower: borrower.clone(),
+                asset: asset.clone(),
+                principal: amount,
+                rate,
+                maturity: loan.maturity,
+            }
+            .emit(&env);
+            Ok(loan.id)
+        })();
+        ReentrancyGuard::exit(&env);
+        result
+    }
+
+    /// Repay up to `amount` of a fixed-term loan; overpayments stay with the borrower
+    pub fn repay_loan(
+        env: Env,
+        borrower: Address,
+        loan_id: u32,
+        amount: i128,
+    ) -> Result<(), ProtocolError> {
+        ReentrancyGuard::enter(&env)?;
+        let result = (|| {
+            borrower.require_auth();
+            if amount <= 0 {
+                return Err(ProtocolError::InvalidAmount);
+            }
+            let mut loan = LoanStorage::get_loan(&env, loan_id).ok_or(ProtocolError::NotFound)?;
+            if loan.borrower != borrower {
+                return Err(ProtocolError::Unauthorized);
+            }
+            if !loan.is_open() {
+                return Err(ProtocolError::InvalidOperation);
+            }
+            if FrozenAccounts::is_frozen(&env, &borrower) {
+                SecurityMonitor::record_suspicious(&env, &borrower, "repay while frozen");
+                return Err(ProtocolError::Unauthorized);
+            }
+            require_kyc(&env, &borrower)?;
+            require_not_blacklisted(&env, &borrower)?;
+            check_aml(&env, &borrower, amount, "repay")?;
+            let asset_info = AssetStorage::require_asset(&env, &loan.asset)?;
+
+            let mut state = InterestRateStorage::update_asset_state(&env, &loan.asset);
+            LoanStorage::sync_status(&env, &mut loan);
+            loan.accrue(env.ledger().timestamp());
+            let repaid = amount.min(loan.debt);
+            let token = AssetStorage::get_token_address(&env, &loan.asset)?;
+            TokenHelper::transfer_in(&env, &token, &borrower, repaid);
+
+            loan.debt -= repaid;
+            if loan.debt == 0 {
+                loan.status = LoanStatus::Repaid;
+            }
+            LoanStorage::save_loan(&env, &loan);
+
+            state.total_borrowed -= repaid;
+            InterestRateManager::decrease_stable_total(&mut state, repaid, loan.rate);
+            InterestRateStorage::save_asset_state(&env, &loan.asset, &state);
+            IsolationMode::record_repay(&env, &borrower, MAIN_ACCOUNT, &asset_info, repaid);
+
+            ProtocolEvent::Repay {
+                user: borrower.to_string(),
+                amount: repaid,
+                asset: loan.asset.clone(),
+            }
+            .emit(&env);
+            Ok(())
+        })();
+        ReentrancyGuard::exit(&env);
+        result
+    }
+
+    /// Advance a fixed-term loan past maturity or into default once its time has come;
+    /// anyone may call it. Returns the loan's status.
+    pub fn process_loan(env: Env, loan_id: u32) -> Result<LoanStatus, ProtocolError> {
+        let mut loan = LoanStorage::get_loan(&env, loan_id).ok_or(ProtocolError::NotFound)?;
+        let status = loan.status;
+        LoanStorage::sync_status(&env, &mut loan);
+        if loan.status != status {
+            LoanStorage::save_loan(&env, &loan);
+        }
+        Ok(loan.status)
+    }
+
+    /// Liquidate a fixed-term loan: the liquidator repays the loan and seizes the
+    /// borrower's collateral in `collateral_asset`. A defaulted loan can be repaid in full
+    /// whatever the account's health; otherwise the account must be unhealthy and the
+    /// close factor applies.
+    pub fn liquidate_loan(
+        env: Env,
+        liquidator: Address,
+        loan_id: u32,
+        amount: i128,
+        collateral_asset: String,
+    ) -> Result<(), ProtocolError> {
+        ReentrancyGuard::enter(&env)?;
+        let result = (|| {
+            liquidator.require_auth();
+            if amount <= 0 {
+                return Err(ProtocolError::InvalidAmount);
+            }
+            let mut loan = LoanStorage::get_loan(&env, loan_id).ok_or(ProtocolError::NotFound)?;
+            if !loan.is_open() {
+                return Err(ProtocolError::InvalidOperation);
+            }
+            let target = loan.borrower.clone();
+            let debt_info = AssetStorage::require_asset(&env, &loan.asset)?;
+            let collateral_info = AssetStorage::require_asset(&env, &collateral_asset)?;
+            let debt_risk = RiskConfigStorage::for_asset(&env, &debt_info);
+            let collateral_risk = RiskConfigStorage::for_asset(&env, &collateral_info);
+            if debt_risk.pause_liquidate || collateral_risk.pause_liquidate {
+                return Err(ProtocolError::ProtocolPaused);
+            }
+            if FrozenAccounts::is_frozen(&env, &target) {
+                return Err(ProtocolError::Unauthorized);
+            }
+
+            // Accrue interest before liquidation
+            let mut debt_state = InterestRateStorage::update_asset_state(&env, &loan.asset);
+            LoanStorage::sync_status(&env, &mut loan);
+            loan.accrue(env.ledger().timestamp());
+            let collateral_state = InterestRateStorage::update_asset_state(&env, &collateral_asset);
+            let mut collateral_position =
+                StateHelper::accrued_position(&env, &target, MAIN_ACCOUNT, &collateral_asset, &collateral_state);
+
+            let health = StateHelper::account_health(&env, &target)?;
+            let max_repay_amount = if loan.status == LoanStatus::Defaulted {
+                loan.debt
+            } else if health.health_factor < HEALTH_FACTOR_ONE {
+                (loan.debt * debt_risk.close_factor) / 100_000_000
+            } else {
+                return Err(ProtocolError::NotEligibleForLiquidation);
+            };
+            let repay_amount = amount.min(loan.debt).min(max_repay_amount);
+            if repay_amount == 0 {
+                return Err(ProtocolError::InvalidAmount);
+            }
+            let (repay_amount, seized, protocol_fee, liquidator_amount) = StateHelper::liquidation_terms(
+                &env,
+                &debt_info,
+                &collateral_info,
+                &collateral_risk,
+                health.emode_category,
+                repay_amount,
+                collateral_position.collateral,
+            )?;
+
+            // Liquidator pays down the loan before any collateral leaves the protocol
+            let debt_token = AssetStorage::get_token_address(&env, &loan.asset)?;
+            let collateral_token = AssetStorage::get_token_address(&env, &collateral_asset)?;
+            TokenHelper::transfer_in(&env, &debt_token, &liquidator, repay_amount);
+            IsolationMode::record_repay(&env, &target, MAIN_ACCOUNT, &debt_info, repay_amount);
+
+            loan.debt -= repay_amount;
+            if loan.debt == 0 {
+                loan.status = LoanStatus::Repaid;
+            }
+            LoanStorage::save_loan(&env, &loan);
+            collateral_position.collateral -= seized;
+            StateHelper::save_position(&env, &collateral_position);
+
+            debt_state.total_borrowed -= repay_amount;
+            InterestRateManager::decrease_stable_total(&mut debt_state, repay_amount, loan.rate);
+            InterestRateStorage::save_asset_state(&env, &loan.asset, &debt_state);
+            let mut ir_state = InterestRateStorage::get_asset_state(&env, &collateral_asset);
+            ir_state.total_collateral -= seized;
+            ir_state.total_reserves += protocol_fee;
+            InterestRateStorage::save_asset_state(&env, &collateral_asset, &ir_state);
+
+            TokenHelper::transfer_out(&env, &collateral_token, &liquidator, liquidator_amount);
+
+            ProtocolEvent::Liquidate {
+                user: target.to_string(),
+                amount: repay_amount,
+                asset: loan.asset.clone(),
+                liquidator: liquidator.clone(),
+                collateral_asset: collateral_asset.clone(),
+                collateral_seized: seized,
+                liquidator_amount,
+                protocol_fee,
             }
             .emit(&env);
+
+            BadDebtManager::realize_if_insolvent(&env, &target, MAIN_ACCOUNT);
             Ok(())
         })();
         ReentrancyGuard::exit(&env);
         result
     }
+
+    /// Set how long after maturity an unpaid fixed-term loan defaults (admin only, seconds)
+    pub fn set_loan_grace_period(env: Env, caller: Address, seconds: u64) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+        LoanStorage::set_grace_period(&env, seconds);
+        Ok(())
+    }
+
+    /// Query a fixed-term loan with interest and status as of now
+    pub fn get_loan(env: Env, loan_id: u32) -> Result<FixedTermLoan, ProtocolError> {
+        LoanStorage::projected_loan(&env, loan_id).ok_or(ProtocolError::NotFound)
+    }
+
+    /// Query every fixed-term loan a user has taken, with interest and status as of now
+    pub fn get_loans_by_user(env: Env, user: Address) -> Vec<FixedTermLoan> {
+        let mut loans = Vec::new(&env);
+        for id in LoanStorage::get_user_loan_ids(&env, &user).iter() {
+            if let Some(loan) = LoanStorage::projected_loan(&env, id) {
+                loans.push_back(loan);
+            }
+        }
+        loans
+    }
+
+    /// Set the flash loan fee as a share of the principal (admin only, scaled by 1e8)
+    pub fn set_flash_loan_fee(env: Env, caller: Address, fee: i128) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+        if !(0..=100_000_000).contains(&fee) {
+            return Err(ProtocolError::InvalidInput);
+        }
+        ReserveStorage::save_flash_loan_fee(&env, fee);
+        Ok(())
+    }
+
+    /// Set the share of the liquidation bonus kept by the protocol (admin only, scaled by 1e8)
+    pub fn set_liquidation_protocol_fee(
+        env: Env,
+        caller: Address,
+        fee: i128,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+        if !(0..=100_000_000).contains(&fee) {
+            return Err(ProtocolError::InvalidInput);
+        }
+        let mut config = RiskConfigStorage::get(&env);
+        config.liquidation_protocol_fee = fee;
+        config.last_update = env.ledger().timestamp();
+        RiskConfigStorage::save(&env, &config);
+        Ok(())
+    }
+
+    /// Set protocol pause switches (admin only)
+    pub fn set_pause_switches(
+        env: Env,
+        caller: Address,
+        pause_borrow: bool,
+        pause_deposit: bool,
+        pause_withdraw: bool,
+        pause_liquidate: bool,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+        let mut config = RiskConfigStorage::get(&env);
         config.pause_borrow = pause_borrow;
         config.pause_deposit = pause_deposit;
         config.pause_withdraw = pause_withdraw;
@@ -3108,21 +7391,19 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
     /// Set treasury address (admin only)
     pub fn set_treasury_address(
         env: Env,
-        caller: String,
-        treasury: String,
+        caller: Address,
+        treasury: Address,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+        ProtocolConfig::require_admin(&env, &caller)?;
 
-        let treasury_addr = Address::from_string(&treasury);
         let mut reserve_data = ReserveStorage::get_reserve_data(&env);
         let old_address = reserve_data.treasury_address.to_string();
-        reserve_data.treasury_address = treasury_addr;
+        reserve_data.treasury_address = treasury.clone();
         ReserveStorage::save_reserve_data(&env, &reserve_data);
 
         ProtocolEvent::TreasuryUpdated {
             old_address,
-            new_address: treasury,
+            new_address: treasury.to_string(),
         }
         .emit(&env);
 
@@ -3132,12 +7413,11 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
     /// Collect protocol fees from interest payments
     pub fn collect_protocol_fees(
         env: Env,
-        caller: String,
+        caller: Address,
         amount: i128,
         source: String,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+        ProtocolConfig::require_admin(&env, &caller)?;
 
         if amount <= 0 {
             return Err(ProtocolError::InvalidAmount);
@@ -3154,6 +7434,8 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
             metrics.total_borrow_fees += amount;
         } else if source == String::from_str(&env, "supply") {
             metrics.total_supply_fees += amount;
+        } else if source == String::from_str(&env, "flash_loan") {
+            metrics.total_flash_loan_fees += amount;
         }
         ReserveStorage::save_revenue_metrics(&env, &metrics);
 
@@ -3170,11 +7452,10 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
     /// Distribute fees to treasury
     pub fn distribute_fees_to_treasury(
         env: Env,
-        caller: String,
+        caller: Address,
         amount: i128,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+        ProtocolConfig::require_admin(&env, &caller)?;
 
         if amount <= 0 {
             return Err(ProtocolError::InvalidAmount);
@@ -3204,11 +7485,10 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
     /// Emergency withdrawal of fees (admin only)
     pub fn emergency_withdraw_fees(
         env: Env,
-        caller: String,
+        caller: Address,
         amount: i128,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+        ProtocolConfig::require_admin(&env, &caller)?;
 
         if amount <= 0 {
             return Err(ProtocolError::InvalidAmount);
@@ -3245,7 +7525,7 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
     }
 
     /// Get revenue metrics
-    pub fn get_revenue_metrics(env: Env) -> (i128, i128, i128, i128, i128) {
+    pub fn get_revenue_metrics(env: Env) -> (i128, i128, i128, i128, i128, i128) {
         let metrics = ReserveStorage::get_revenue_metrics(&env);
         (
             metrics.daily_fees,
@@ -3253,17 +7533,17 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
             metrics.monthly_fees,
             metrics.total_borrow_fees,
             metrics.total_supply_fees,
+            metrics.total_flash_loan_fees,
         )
     }
 
     /// Set distribution frequency (admin only)
     pub fn set_distribution_frequency(
         env: Env,
-        caller: String,
+        caller: Address,
         frequency: u64,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+        ProtocolConfig::require_admin(&env, &caller)?;
 
         let mut reserve_data = ReserveStorage::get_reserve_data(&env);
         reserve_data.distribution_frequency = frequency;
@@ -3272,95 +7552,168 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
         Ok(())
     }
 
-    // --- Multi-Asset Support Functions ---
-
-    /// Add a new asset to the protocol (admin only)
-    pub fn add_asset(
+    // --- Multi-Asset Support Functions ---
+
+    /// Add a new asset to the protocol (admin only)
+    pub fn add_asset(
+        env: Env,
+        caller: Address,
+        symbol: String,
+        decimals: u32,
+        oracle_address: Address,
+        token_address: Address,
+        min_collateral_ratio: i128,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+        AssetStorage::list_asset(
+            &env,
+            symbol,
+            decimals,
+            oracle_address,
+            token_address,
+            min_collateral_ratio,
+        )
+    }
+
+    /// Set asset parameters (admin only)
+    pub fn set_asset_params(
+        env: Env,
+        caller: Address,
+        asset: String,
+        min_collateral_ratio: i128,
+        close_factor: i128,
+        liquidation_incentive: i128,
+        base_rate: i128,
+        reserve_factor: i128,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+
+        let mut asset_info =
+            AssetStorage::get_asset_info(&env, &asset).ok_or(ProtocolError::AssetNotSupported)?;
+
+        // Update parameters
+        asset_info = asset_info.with_collateral_ratio(min_collateral_ratio);
+        asset_info.risk_config.close_factor = close_factor;
+        asset_info.risk_config.liquidation_incentive = liquidation_incentive;
+        asset_info.interest_config.base_rate = base_rate;
+        asset_info.interest_config.reserve_factor = reserve_factor;
+        asset_info.last_update = env.ledger().timestamp();
+
+        AssetStorage::save_asset_info(&env, &asset, &asset_info);
+
+        ProtocolEvent::AssetUpdated {
+            asset: asset.clone(),
+            parameter: String::from_str(&env, "min_collateral_ratio"),
+            old_value: String::from_str(&env, "old_ratio"),
+            new_value: String::from_str(&env, "new_ratio"),
+        }
+        .emit(&env);
+
+        Ok(())
+    }
+
+    /// Set an asset's LTV and liquidation threshold in basis points (admin only); the
+    /// threshold may not be below the LTV
+    pub fn set_asset_ltv(
         env: Env,
-        caller: String,
-        symbol: String,
-        decimals: u32,
-        oracle_address: String,
-        min_collateral_ratio: i128,
+        caller: Address,
+        asset: String,
+        ltv: i128,
+        liquidation_threshold: i128,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
-
-        if symbol.is_empty() {
-            return Err(ProtocolError::InvalidAsset);
+        ProtocolConfig::require_admin(&env, &caller)?;
+        if ltv < 0 || ltv > liquidation_threshold || liquidation_threshold >= BPS_ONE {
+            return Err(ProtocolError::InvalidInput);
         }
 
-        if decimals == 0 {
-            return Err(ProtocolError::InvalidAmount);
+        let mut asset_info = AssetStorage::require_asset(&env, &asset)?;
+        asset_info.ltv = ltv;
+        asset_info.liquidation_threshold = liquidation_threshold;
+        asset_info.last_update = env.ledger().timestamp();
+        AssetStorage::save_asset_info(&env, &asset, &asset_info);
+
+        ProtocolEvent::AssetUpdated {
+            asset: asset.clone(),
+            parameter: String::from_str(&env, "ltv"),
+            old_value: String::from_str(&env, ""),
+            new_value: String::from_str(&env, ""),
         }
+        .emit(&env);
 
-        let oracle_addr = Address::from_string(&oracle_address);
+        Ok(())
+    }
 
-        // Check if asset already exists
-        if AssetStorage::get_asset_info(&env, &symbol).is_some() {
-            return Err(ProtocolError::AlreadyInitialized);
-        }
+    /// Query an asset's LTV and liquidation threshold in basis points
+    pub fn get_asset_ltv(env: Env, asset: String) -> Result<(i128, i128), ProtocolError> {
+        let asset_info = AssetStorage::require_asset(&env, &asset)?;
+        Ok((asset_info.ltv, asset_info.liquidation_threshold))
+    }
 
-        // Create new asset info
-        let asset_info =
-            AssetInfo::new(symbol.clone(), decimals, oracle_addr, min_collateral_ratio);
-        AssetStorage::save_asset_info(&env, &symbol, &asset_info);
+    /// Set the SEP-41 token contract backing an asset (admin only)
+    pub fn set_asset_token_address(
+        env: Env,
+        caller: Address,
+        asset: String,
+        token_address: Address,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
 
-        // Update registry
-        let mut registry = AssetStorage::get_registry(&env);
-        registry.supported_assets.push_back(symbol.clone());
-        registry.last_update = env.ledger().timestamp();
-        AssetStorage::save_registry(&env, &registry);
+        let mut asset_info =
+            AssetStorage::get_asset_info(&env, &asset).ok_or(ProtocolError::AssetNotSupported)?;
 
-        ProtocolEvent::AssetAdded {
-            asset: symbol.clone(),
-            symbol: asset_info.symbol,
-            decimals: asset_info.decimals,
+        asset_info.token_address = Some(token_address.clone());
+        asset_info.last_update = env.ledger().timestamp();
+        AssetStorage::save_asset_info(&env, &asset, &asset_info);
+
+        ProtocolEvent::AssetUpdated {
+            asset: asset.clone(),
+            parameter: String::from_str(&env, "token_address"),
+            old_value: String::from_str(&env, ""),
+            new_value: token_address.to_string(),
         }
         .emit(&env);
 
         Ok(())
     }
 
-    /// Set asset parameters (admin only)
-    pub fn set_asset_params(
+    /// Get the token contract backing an asset
+    pub fn get_asset_token_address(env: Env, asset: String) -> Result<Address, ProtocolError> {
+        AssetStorage::get_token_address(&env, &asset)
+    }
+
+    /// Set the sToken contract minting lender shares for an asset (admin only).
+    /// The sToken must be initialized with this contract as its pool.
+    pub fn set_asset_stoken_address(
         env: Env,
-        caller: String,
+        caller: Address,
         asset: String,
-        min_collateral_ratio: i128,
-        close_factor: i128,
-        liquidation_incentive: i128,
-        base_rate: i128,
-        reserve_factor: i128,
+        stoken_address: Address,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+        ProtocolConfig::require_admin(&env, &caller)?;
 
         let mut asset_info =
             AssetStorage::get_asset_info(&env, &asset).ok_or(ProtocolError::AssetNotSupported)?;
 
-        // Update parameters
-        let old_ratio = asset_info.min_collateral_ratio;
-        asset_info.min_collateral_ratio = min_collateral_ratio;
-        asset_info.risk_config.close_factor = close_factor;
-        asset_info.risk_config.liquidation_incentive = liquidation_incentive;
-        asset_info.interest_config.base_rate = base_rate;
-        asset_info.interest_config.reserve_factor = reserve_factor;
+        asset_info.stoken_address = Some(stoken_address.clone());
         asset_info.last_update = env.ledger().timestamp();
-
         AssetStorage::save_asset_info(&env, &asset, &asset_info);
 
         ProtocolEvent::AssetUpdated {
             asset: asset.clone(),
-            parameter: String::from_str(&env, "min_collateral_ratio"),
-            old_value: String::from_str(&env, "old_ratio"),
-            new_value: String::from_str(&env, "new_ratio"),
+            parameter: String::from_str(&env, "stoken_address"),
+            old_value: String::from_str(&env, ""),
+            new_value: stoken_address.to_string(),
         }
         .emit(&env);
 
         Ok(())
     }
 
+    /// Get the sToken contract minting lender shares for an asset
+    pub fn get_asset_stoken_address(env: Env, asset: String) -> Result<Address, ProtocolError> {
+        AssetStorage::get_stoken_address(&env, &asset)
+    }
+
     /// Get asset information
     pub fn get_asset_info(
         env: Env,
@@ -3385,15 +7738,201 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
         registry.supported_assets
     }
 
+    /// Set an asset's supply and borrow caps in its base units (admin only, 0 = uncapped)
+    pub fn set_asset_caps(
+        env: Env,
+        caller: Address,
+        asset: String,
+        supply_cap: i128,
+        borrow_cap: i128,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+        if supply_cap < 0 || borrow_cap < 0 {
+            return Err(ProtocolError::InvalidInput);
+        }
+
+        let mut asset_info = AssetStorage::require_asset(&env, &asset)?;
+        asset_info.supply_cap = supply_cap;
+        asset_info.borrow_cap = borrow_cap;
+        asset_info.last_update = env.ledger().timestamp();
+        AssetStorage::save_asset_info(&env, &asset, &asset_info);
+
+        ProtocolEvent::AssetUpdated {
+            asset: asset.clone(),
+            parameter: String::from_str(&env, "caps"),
+            old_value: String::from_str(&env, ""),
+            new_value: String::from_str(&env, ""),
+        }
+        .emit(&env);
+
+        Ok(())
+    }
+
+    /// Query an asset's cap usage: (collateral plus lender supply, supply cap, total debt, borrow cap)
+    pub fn get_asset_caps(env: Env, asset: String) -> Result<(i128, i128, i128, i128), ProtocolError> {
+        let asset_info = AssetStorage::require_asset(&env, &asset)?;
+        let mut state = InterestRateStorage::get_asset_state(&env, &asset);
+        InterestRateManager::accrue_indexes(&mut state, env.ledger().timestamp());
+        Ok((
+            state.total_collateral + state.total_supplied,
+            asset_info.supply_cap,
+            state.total_borrowed,
+            asset_info.borrow_cap,
+        ))
+    }
+
+    /// Put an asset's collateral in isolation mode with a debt ceiling, or take it out
+    /// (admin only; the ceiling is a quote-currency value scaled by 1e8)
+    pub fn set_asset_isolation(
+        env: Env,
+        caller: Address,
+        asset: String,
+        isolated: bool,
+        debt_ceiling: i128,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+        if debt_ceiling < 0 {
+            return Err(ProtocolError::InvalidInput);
+        }
+
+        let mut asset_info = AssetStorage::require_asset(&env, &asset)?;
+        asset_info.isolated = isolated;
+        asset_info.debt_ceiling = debt_ceiling;
+        asset_info.last_update = env.ledger().timestamp();
+        AssetStorage::save_asset_info(&env, &asset, &asset_info);
+
+        ProtocolEvent::AssetUpdated {
+            asset: asset.clone(),
+            parameter: String::from_str(&env, "isolation"),
+            old_value: String::from_str(&env, ""),
+            new_value: String::from_str(&env, if isolated { "isolated" } else { "" }),
+        }
+        .emit(&env);
+
+        Ok(())
+    }
+
+    /// Allow or disallow borrowing an asset against isolated collateral (admin only)
+    pub fn set_borrowable_in_isolation(
+        env: Env,
+        caller: Address,
+        asset: String,
+        borrowable: bool,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+        let mut asset_info = AssetStorage::require_asset(&env, &asset)?;
+        asset_info.borrowable_in_isolation = borrowable;
+        asset_info.last_update = env.ledger().timestamp();
+        AssetStorage::save_asset_info(&env, &asset, &asset_info);
+        Ok(())
+    }
+
+    /// Query an asset's isolation settings: (isolated, debt ceiling, debt backed in
+    /// isolation, borrowable in isolation)
+    pub fn get_asset_isolation(env: Env, asset: String) -> Result<(bool, i128, i128, bool), ProtocolError> {
+        let asset_info = AssetStorage::require_asset(&env, &asset)?;
+        Ok((
+            asset_info.isolated,
+            asset_info.debt_ceiling,
+            asset_info.isolation_debt,
+            asset_info.borrowable_in_isolation,
+        ))
+    }
+
+    /// Create or update an efficiency-mode category (admin only). The LTV, liquidation
+    /// threshold and bonus are in basis points; the bonus on a position at the threshold
+    /// must stay within its collateral.
+    pub fn set_emode_category(
+        env: Env,
+        caller: Address,
+        id: u32,
+        label: String,
+        ltv: i128,
+        liquidation_threshold: i128,
+        liquidation_bonus: i128,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+        if id == 0
+            || ltv <= 0
+            || ltv > liquidation_threshold
+            || liquidation_threshold >= BPS_ONE
+            || liquidation_bonus < 0
+            || liquidation_threshold * (BPS_ONE + liquidation_bonus) > BPS_ONE * BPS_ONE
+        {
+            return Err(ProtocolError::InvalidInput);
+        }
+        EModeStorage::save_category(
+            &env,
+            &EModeCategory {
+                id,
+                label,
+                ltv,
+                liquidation_threshold,
+                liquidation_bonus,
+            },
+        );
+        Ok(())
+    }
+
+    /// Place an asset in an efficiency-mode category, or remove it with 0 (admin only)
+    pub fn set_asset_emode_category(
+        env: Env,
+        caller: Address,
+        asset: String,
+        category: u32,
+    ) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+        if category != 0 && EModeStorage::get_category(&env, category).is_none() {
+            return Err(ProtocolError::NotFound);
+        }
+        let mut asset_info = AssetStorage::require_asset(&env, &asset)?;
+        asset_info.emode_category = category;
+        asset_info.last_update = env.ledger().timestamp();
+        AssetStorage::save_asset_info(&env, &asset, &asset_info);
+        Ok(())
+    }
+
+    /// Query an efficiency-mode category
+    pub fn get_emode_category(env: Env, id: u32) -> Result<EModeCategory, ProtocolError> {
+        EModeStorage::get_category(&env, id).ok_or(ProtocolError::NotFound)
+    }
+
+    /// Opt into an efficiency-mode category, or out of it with 0. The category applies to
+    /// every sub-account, and each must stay healthy under the new parameters.
+    pub fn set_user_emode(env: Env, user: Address, category: u32) -> Result<(), ProtocolError> {
+        user.require_auth();
+        let emode = match category {
+            0 => None,
+            id => Some(EModeStorage::get_category(&env, id).ok_or(ProtocolError::NotFound)?),
+        };
+        let mut accounts = SubAccounts::list(&env, &user);
+        accounts.push_front(MAIN_ACCOUNT);
+        for sub_account in accounts.iter() {
+            let health = StateHelper::account_health_in(&env, &user, sub_account, None, None, emode.clone())?;
+            if health.health_factor < HEALTH_FACTOR_ONE {
+                return Err(ProtocolError::InsufficientCollateralRatio);
+            }
+        }
+
+        EModeStorage::set_user_category(&env, &user, category);
+        UserStorage::bump_instance(&env);
+        ProtocolEvent::UserEModeSet { user, category }.emit(&env);
+        Ok(())
+    }
+
+    /// Efficiency-mode category the user opted into (0 = none)
+    pub fn get_user_emode(env: Env, user: Address) -> u32 {
+        EModeStorage::get_user_category(&env, &user)
+    }
+
     /// Enable/disable asset for deposits (admin only)
     pub fn set_asset_deposit_enabled(
         env: Env,
-        caller: String,
+        caller: Address,
         asset: String,
         enabled: bool,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+        ProtocolConfig::require_admin(&env, &caller)?;
 
         let mut asset_info =
             AssetStorage::get_asset_info(&env, &asset).ok_or(ProtocolError::AssetNotSupported)?;
@@ -3415,12 +7954,11 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
     /// Enable/disable asset for borrowing (admin only)
     pub fn set_asset_borrow_enabled(
         env: Env,
-        caller: String,
+        caller: Address,
         asset: String,
         enabled: bool,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+        ProtocolConfig::require_admin(&env, &caller)?;
 
         let mut asset_info =
             AssetStorage::get_asset_info(&env, &asset).ok_or(ProtocolError::AssetNotSupported)?;
@@ -3444,15 +7982,15 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
     /// Track user activity for analytics
     pub fn track_user_activity(
         env: Env,
-        user: String,
+        user: Address,
         action: String,
         amount: i128,
     ) -> Result<(), ProtocolError> {
-        let user_addr = Address::from_string(&user);
+        user.require_auth();
         let timestamp = env.ledger().timestamp();
 
         let mut activity =
-            ActivityStorage::get_user_activity(&env, &user_addr).unwrap_or_else(UserActivity::new);
+            ActivityStorage::get_user_activity(&env, &user).unwrap_or_default();
 
         if action == String::from_str(&env, "deposit") {
             activity.record_deposit(amount, timestamp);
@@ -3466,10 +8004,10 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
             return Err(ProtocolError::Unknown);
         }
 
-        ActivityStorage::save_user_activity(&env, &user_addr, &activity);
+        ActivityStorage::save_user_activity(&env, &user, &activity);
 
         ProtocolEvent::UserActivityTracked {
-            user: user.clone(),
+            user: user.to_string(),
             action,
             amount,
             timestamp,
@@ -3482,12 +8020,10 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
     /// Get user activity metrics
     pub fn get_user_activity(
         env: Env,
-        user: String,
+        user: Address,
     ) -> Result<(i128, i128, i128, i128, u64, u32), ProtocolError> {
-        let user_addr = Address::from_string(&user);
-
         let activity =
-            ActivityStorage::get_user_activity(&env, &user_addr).unwrap_or_else(UserActivity::new);
+            ActivityStorage::get_user_activity(&env, &user).unwrap_or_default();
 
         Ok((
             activity.total_deposits,
@@ -3515,14 +8051,13 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
     /// Update protocol activity statistics (admin only)
     pub fn update_protocol_stats(
         env: Env,
-        caller: String,
+        caller: Address,
         total_users: u32,
         active_users_24h: u32,
         active_users_7d: u32,
         total_transactions: u32,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+        ProtocolConfig::require_admin(&env, &caller)?;
 
         let mut activity = ActivityStorage::get_protocol_activity(&env);
         let timestamp = env.ledger().timestamp();
@@ -3549,34 +8084,26 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
     /// Get recent user activities (simplified version)
     pub fn get_recent_activity(
         env: Env,
-        user: String,
+        user: Address,
     ) -> Result<(String, i128, u64), ProtocolError> {
-        let user_addr = Address::from_string(&user);
-
         let activity =
-            ActivityStorage::get_user_activity(&env, &user_addr).unwrap_or_else(UserActivity::new);
+            ActivityStorage::get_user_activity(&env, &user).unwrap_or_default();
 
         if activity.activity_count == 0 {
             return Err(ProtocolError::PositionNotFound);
         }
 
         // Return the most recent activity info
-        let last_action = if activity.total_repayments > 0 {
-            "repayment"
+        let (last_action, last_amount) = if activity.total_repayments > 0 {
+            ("repayment", activity.total_repayments)
         } else if activity.total_borrows > 0 {
-            "borrow"
+            ("borrow", activity.total_borrows)
         } else if activity.total_withdrawals > 0 {
-            "withdrawal"
+            ("withdrawal", activity.total_withdrawals)
         } else {
-            "deposit"
+            ("deposit", activity.total_deposits)
         };
 
-        let last_amount = activity
-            .total_repayments
-            .max(activity.total_borrows)
-            .max(activity.total_withdrawals)
-            .max(activity.total_deposits);
-
         Ok((
             String::from_str(&env, last_action),
             last_amount,
@@ -3585,29 +8112,24 @@ pub fn repay(env: Env, repayer: String, amount: i128) -> Result<(), ProtocolErro
     }
 
     /// Freeze a user account (admin only)
-    pub fn freeze_account(env: Env, caller: String, user: String) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
-        let user_addr = Address::from_string(&user);
-        FrozenAccounts::freeze(&env, &user_addr);
-        ProtocolEvent::AccountFrozen { user }.emit(&env);
+    pub fn freeze_account(env: Env, caller: Address, user: Address) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+        FrozenAccounts::freeze(&env, &user);
+        ProtocolEvent::AccountFrozen { user: user.to_string() }.emit(&env);
         Ok(())
     }
 
     /// Unfreeze a user account (admin only)
-    pub fn unfreeze_account(env: Env, caller: String, user: String) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
-        let user_addr = Address::from_string(&user);
-        FrozenAccounts::unfreeze(&env, &user_addr);
-        ProtocolEvent::AccountUnfrozen { user }.emit(&env);
+    pub fn unfreeze_account(env: Env, caller: Address, user: Address) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
+        FrozenAccounts::unfreeze(&env, &user);
+        ProtocolEvent::AccountUnfrozen { user: user.to_string() }.emit(&env);
         Ok(())
     }
 
     /// Query if a user is frozen
-    pub fn is_account_frozen(env: Env, user: String) -> bool {
-        let user_addr = Address::from_string(&user);
-        FrozenAccounts::is_frozen(&env, &user_addr)
+    pub fn is_account_frozen(env: Env, user: Address) -> bool {
+        FrozenAccounts::is_frozen(&env, &user)
     }
 
     // --- Compliance Reporting ---
@@ -3660,30 +8182,22 @@ mod test;
 pub struct FrozenAccounts;
 
 impl FrozenAccounts {
-    fn key(user: &Address) -> Symbol {
-        let env = Env::default();
-        let user_str = user.to_string();
-        // Use a fixed key for simplicity - in production you'd want a more sophisticated approach
-        Symbol::new(&env, &user_str.to_string())
+    fn key(user: &Address) -> DataKey {
+        DataKey::Frozen(user.clone())
     }
     pub fn freeze(env: &Env, user: &Address) {
-        env.storage().instance().set(&Self::key(user), &true);
+        UserStorage::set(env, &Self::key(user), &true);
     }
     pub fn unfreeze(env: &Env, user: &Address) {
-        env.storage().instance().set(&Self::key(user), &false);
+        UserStorage::remove(env, &Self::key(user));
     }
     pub fn is_frozen(env: &Env, user: &Address) -> bool {
-        env.storage()
-            .instance()
-            .get::<Symbol, bool>(&Self::key(user))
-            .unwrap_or(false)
+        UserStorage::get::<bool>(env, &Self::key(user)).unwrap_or(false)
     }
 }
 
 // --- Governance: Multi-Admin Support ---
 
-// Storage key for admin set
-const ADMIN_SET_KEY: &str = "admin_set";
 
 // Event types for admin changes
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -3699,11 +8213,11 @@ pub enum GovernanceEvent {
 fn get_admin_set(e: &Env) -> Vec<Address> {
     e.storage()
         .instance()
-        .get(&ADMIN_SET_KEY)
+        .get(&DataKey::AdminSet)
         .unwrap_or_else(|| {
             let mut set = Vec::new(e);
             // Fallback: add legacy single admin if present
-            if let Some(admin) = e.storage().instance().get::<_, Address>(&"admin") {
+            if let Some(admin) = e.storage().instance().get::<_, Address>(&DataKey::Admin) {
                 set.push_back(admin.clone());
             }
             set
@@ -3712,7 +8226,7 @@ fn get_admin_set(e: &Env) -> Vec<Address> {
 
 // Helper: save admin set
 fn save_admin_set(e: &Env, set: &Vec<Address>) {
-    e.storage().instance().set(&ADMIN_SET_KEY, set);
+    e.storage().instance().set(&DataKey::AdminSet, set);
 }
 
 // Helper: is admin
@@ -3726,6 +8240,7 @@ pub fn add_admin(e: Env, admin: Address, new_admin: Address) -> Result<(), Proto
     if !is_admin(&e, &admin) {
         return Err(ProtocolError::Unauthorized);
     }
+    admin.require_auth();
     let mut admins = get_admin_set(&e);
     if admins.contains(&new_admin) {
         return Err(ProtocolError::AlreadyExists);
@@ -3741,6 +8256,7 @@ pub fn remove_admin(e: Env, admin: Address, remove_admin: Address) -> Result<(),
     if !is_admin(&e, &admin) {
         return Err(ProtocolError::Unauthorized);
     }
+    admin.require_auth();
     let mut admins = get_admin_set(&e);
     if !admins.contains(&remove_admin) {
         return Err(ProtocolError::NotFound);
@@ -3765,6 +8281,7 @@ pub fn transfer_admin(e: Env, admin: Address, new_admin: Address) -> Result<(),
     if !is_admin(&e, &admin) {
         return Err(ProtocolError::Unauthorized);
     }
+    admin.require_auth();
     let mut admins = get_admin_set(&e);
     if !admins.contains(&admin) {
         return Err(ProtocolError::NotFound);
@@ -3784,7 +8301,7 @@ pub fn transfer_admin(e: Env, admin: Address, new_admin: Address) -> Result<(),
 
 // Query: get admin list
 pub fn get_admins(e: Env) -> Vec<Address> {
-    get_admin_set(&e).into()
+    get_admin_set(&e)
 }
 
 // Query: is address admin
@@ -3794,10 +8311,6 @@ pub fn is_address_admin(e: Env, addr: Address) -> bool {
 
 // --- Permissionless Market Listing ---
 
-// Storage keys for proposals
-const PROPOSAL_COUNTER_KEY: &str = "proposal_counter";
-const PROPOSAL_PREFIX: &str = "proposal_";
-
 // Proposal status enum
 #[soroban_sdk::contracttype]
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -3817,6 +8330,7 @@ pub struct AssetProposal {
     pub symbol: String,
     pub name: String,
     pub oracle_address: Address,
+    pub token_address: Address,
     pub collateral_factor: u32,
     pub borrow_factor: u32,
     pub status: ProposalStatus,
@@ -3838,35 +8352,28 @@ fn get_next_proposal_id(e: &Env) -> u32 {
     let current = e
         .storage()
         .instance()
-        .get(&PROPOSAL_COUNTER_KEY)
+        .get(&DataKey::ProposalCounter)
         .unwrap_or(0u32);
     let next = current + 1;
-    e.storage().instance().set(&PROPOSAL_COUNTER_KEY, &next);
+    e.storage().instance().set(&DataKey::ProposalCounter, &next);
     next
 }
 
 // Helper: get proposal storage key
-fn get_proposal_key(proposal_id: u32) -> String {
-    let env = Env::default();
-    let mut key = String::from_str(&env, PROPOSAL_PREFIX);
-    let id_str = proposal_id.to_string();
-    let rust_key = key.to_string();
-    let rust_id = id_str.to_string();
-    let combined = format!("{}{}", rust_key, rust_id);
-    key = String::from_str(&env, &combined);
-    key
+fn get_proposal_key(proposal_id: u32) -> DataKey {
+    DataKey::Proposal(proposal_id)
 }
 
 // Helper: save proposal
 fn save_proposal(e: &Env, proposal: &AssetProposal) {
     let key = get_proposal_key(proposal.id);
-    e.storage().instance().set(&key, proposal);
+    UserStorage::set(e, &key, proposal);
 }
 
 // Helper: get proposal
 fn get_proposal(e: &Env, proposal_id: u32) -> Option<AssetProposal> {
     let key = get_proposal_key(proposal_id);
-    e.storage().instance().get(&key)
+    UserStorage::get(e, &key)
 }
 
 // Propose new asset (anyone can propose)
@@ -3876,9 +8383,12 @@ pub fn propose_asset(
     symbol: String,
     name: String,
     oracle_address: Address,
+    token_address: Address,
     collateral_factor: u32,
     borrow_factor: u32,
 ) -> Result<u32, ProtocolError> {
+    proposer.require_auth();
+
     // Validate inputs
     if symbol.len() > 10 || name.len() > 50 {
         return Err(ProtocolError::InvalidInput);
@@ -3894,6 +8404,7 @@ pub fn propose_asset(
         symbol: symbol.clone(),
         name: name.clone(),
         oracle_address,
+        token_address,
         collateral_factor,
         borrow_factor,
         status: ProposalStatus::Pending,
@@ -3910,19 +8421,24 @@ pub fn approve_proposal(e: Env, admin: Address, proposal_id: u32) -> Result<(),
     if !is_admin(&e, &admin) {
         return Err(ProtocolError::Unauthorized);
     }
+    admin.require_auth();
     let mut proposal = get_proposal(&e, proposal_id).ok_or(ProtocolError::NotFound)?;
     if proposal.status != ProposalStatus::Pending {
         return Err(ProtocolError::InvalidOperation);
     }
     // Create the asset (hardcode decimals to 7 for now)
-    Contract::add_asset(
-        e.clone(),
-        admin.to_string(),
+    AssetStorage::list_asset(
+        &e,
         proposal.symbol.clone(),
         7, // default decimals
-        proposal.oracle_address.to_string(),
+        proposal.oracle_address.clone(),
+        proposal.token_address.clone(),
         proposal.collateral_factor as i128,
     )?;
+    // Community-listed collateral starts isolated with no debt ceiling until governance raises it
+    let mut asset_info = AssetStorage::require_asset(&e, &proposal.symbol)?;
+    asset_info.isolated = true;
+    AssetStorage::save_asset_info(&e, &proposal.symbol, &asset_info);
     // Update proposal status
     proposal.status = ProposalStatus::Approved;
     save_proposal(&e, &proposal);
@@ -3935,6 +8451,7 @@ pub fn reject_proposal(e: Env, admin: Address, proposal_id: u32) -> Result<(), P
     if !is_admin(&e, &admin) {
         return Err(ProtocolError::Unauthorized);
     }
+    admin.require_auth();
 
     let mut proposal = get_proposal(&e, proposal_id).ok_or(ProtocolError::NotFound)?;
 
@@ -3956,6 +8473,7 @@ pub fn cancel_proposal(e: Env, proposer: Address, proposal_id: u32) -> Result<()
     if proposal.proposer != proposer {
         return Err(ProtocolError::Unauthorized);
     }
+    proposer.require_auth();
 
     if proposal.status != ProposalStatus::Pending {
         return Err(ProtocolError::InvalidOperation);
@@ -3979,7 +8497,7 @@ pub fn get_all_proposals(e: Env) -> Vec<AssetProposal> {
     let counter = e
         .storage()
         .instance()
-        .get(&PROPOSAL_COUNTER_KEY)
+        .get(&DataKey::ProposalCounter)
         .unwrap_or(0u32);
 
     for i in 1..=counter {
@@ -4008,9 +8526,8 @@ pub fn get_all_proposals(e: Env) -> Vec<AssetProposal> {
     // --- Error Analytics and Management Functions ---
 
     /// Get error analytics summary (admin only)
-    pub fn get_error_analytics(env: Env, caller: String) -> Result<(u32, u32, u32, u32, u32, u64), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+    pub fn get_error_analytics(env: Env, caller: Address) -> Result<(u32, u32, u32, u32, u32, u64), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
 
         let analytics = ErrorLogger::get_analytics(&env);
         Ok((
@@ -4024,18 +8541,16 @@ pub fn get_all_proposals(e: Env) -> Vec<AssetProposal> {
     }
 
     /// Get recent error logs (admin only)
-    pub fn get_recent_error_logs(env: Env, caller: String, limit: u32) -> Result<Vec<ErrorContext>, ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+    pub fn get_recent_error_logs(env: Env, caller: Address, limit: u32) -> Result<Vec<ErrorContext>, ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
 
         let recent_errors = ErrorLogger::get_recent_errors(&env, limit);
         Ok(recent_errors)
     }
 
     /// Get error statistics by type (admin only)
-    pub fn get_error_statistics(env: Env, caller: String) -> Result<Vec<(u32, u32)>, ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+    pub fn get_error_statistics(env: Env, caller: Address) -> Result<Vec<(u32, u32)>, ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
 
         let analytics = ErrorLogger::get_analytics(&env);
         Ok(analytics.error_counts)
@@ -4044,12 +8559,11 @@ pub fn get_all_proposals(e: Env) -> Vec<AssetProposal> {
     /// Manually trigger error recovery for a specific error type (admin only)
     pub fn trigger_error_recovery(
         env: Env,
-        caller: String,
+        caller: Address,
         error_code: u32,
         context_data: String,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+        ProtocolConfig::require_admin(&env, &caller)?;
 
         // Convert error code back to ProtocolError
         let error = match error_code {
@@ -4063,7 +8577,7 @@ pub fn get_all_proposals(e: Env) -> Vec<AssetProposal> {
         let context = ErrorContext::new(
             &env,
             &error,
-            Some(caller_addr),
+            Some(caller),
             "trigger_error_recovery",
             &context_data.to_string(),
         );
@@ -4072,18 +8586,17 @@ pub fn get_all_proposals(e: Env) -> Vec<AssetProposal> {
     }
 
     /// Clear error analytics (admin only) - for testing or maintenance
-    pub fn clear_error_analytics(env: Env, caller: String) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&caller);
-        ProtocolConfig::require_admin(&env, &caller_addr)?;
+    pub fn clear_error_analytics(env: Env, caller: Address) -> Result<(), ProtocolError> {
+        ProtocolConfig::require_admin(&env, &caller)?;
 
-        let fresh_analytics = ErrorAnalytics::new();
+        let fresh_analytics = ErrorAnalytics::new(&env);
         ErrorLogger::save_analytics(&env, &fresh_analytics);
 
         Ok(())
     }
 
     /// Check if a specific error type is recoverable
-    pub fn is_error_recoverable(env: Env, error_code: u32) -> bool {
+    pub fn is_error_recoverable(_env: Env, error_code: u32) -> bool {
         let error = match error_code {
             1 => ProtocolError::Unauthorized,
             2 => ProtocolError::InsufficientCollateral,
@@ -4160,7 +8673,7 @@ pub fn get_all_proposals(e: Env) -> Vec<AssetProposal> {
         String::from_str(&env, error.get_recovery_suggestion())
     }
 
-fn require_kyc(env: &Env, user: &Address) -> Result<(), ProtocolError> {
+fn require_kyc(_env: &Env, _user: &Address) -> Result<(), ProtocolError> {
     // Replace this with your actual KYC logic
     // For now, we'll just assume everyone is KYC-verified
     Ok(())
@@ -4178,7 +8691,7 @@ const AML_LARGE_TX_THRESHOLD: i128 = 100_000_000; // Example: 100M units
 fn check_aml(env: &Env, user: &Address, amount: i128, action: &str) -> Result<(), ProtocolError> {
     if amount >= AML_LARGE_TX_THRESHOLD {
         env.events().publish(
-            (Symbol::short("SuspiciousActivity"), user.clone()),
+            (symbol_short!("suspect"), user.clone()),
             (action, amount, env.ledger().timestamp()),
         );
     }
@@ -4199,23 +8712,16 @@ pub enum KYCStatus {
 pub struct KYCStorage;
 
 impl KYCStorage {
-    fn key(user: &Address) -> Symbol {
-        let env = Env::default();
-        let user_str = user.to_string();
-        let prefix = "kyc_";
-        let combined = prefix.to_string() + &user_str.to_string();
-        Symbol::new(&env, &combined)
+    fn key(user: &Address) -> DataKey {
+        DataKey::Kyc(user.clone())
     }
 
     pub fn set(env: &Env, user: &Address, status: KYCStatus) {
-        env.storage().instance().set(&Self::key(user), &status);
+        UserStorage::set(env, &Self::key(user), &status);
     }
 
     pub fn get(env: &Env, user: &Address) -> KYCStatus {
-        env.storage()
-            .instance()
-            .get::<Symbol, KYCStatus>(&Self::key(user))
-            .unwrap_or(KYCStatus::Unverified)
+        UserStorage::get::<KYCStatus>(env, &Self::key(user)).unwrap_or(KYCStatus::Unverified)
     }
 }
 
@@ -4223,20 +8729,14 @@ impl KYCStorage {
 pub struct BlacklistStorage;
 
 impl BlacklistStorage {
-    fn key(user: &Address) -> Symbol {
-        let env = Env::default();
-        let user_str = user.to_string();
-        // Use a fixed key for simplicity - in production you'd want a more sophisticated approach
-        Symbol::new(&env, &user_str.to_string())
+    fn key(user: &Address) -> DataKey {
+        DataKey::Blacklisted(user.clone())
     }
     pub fn set(env: &Env, user: &Address, value: bool) {
-        env.storage().instance().set(&Self::key(user), &value);
+        UserStorage::set(env, &Self::key(user), &value);
     }
     pub fn is_blacklisted(env: &Env, user: &Address) -> bool {
-        env.storage()
-            .instance()
-            .get::<Symbol, bool>(&Self::key(user))
-            .unwrap_or(false)
+        UserStorage::get::<bool>(env, &Self::key(user)).unwrap_or(false)
     }
 }
 
@@ -4269,6 +8769,8 @@ pub struct BatchOperation {
     pub amount: i128,
     /// Optional target for liquidations
     pub target: Option<Address>,
+    /// Collateral asset seized by liquidations; `asset` is the debt repaid
+    pub collateral_asset: Option<String>,
     /// Gas optimization flag
     pub optimize_gas: bool,
 }
@@ -4360,7 +8862,7 @@ pub struct UXConfig {
 }
 
 impl UXConfig {
-    pub fn default() -> Self {
+    pub fn default(env: &Env) -> Self {
         Self {
             detailed_errors: true,
             gas_suggestions: true,
@@ -4368,7 +8870,7 @@ impl UXConfig {
             enable_batch_ops: true,
             max_batch_size: 10,
             default_gas_strategy: GasOptimizationStrategy::BatchSimilar,
-            error_language: String::from_str(&Env::default(), "en"),
+            error_language: String::from_str(env, "en"),
         }
     }
 }
@@ -4383,43 +8885,39 @@ impl AdvancedUXManager {
         operations: Vec<BatchOperation>,
         gas_strategy: GasOptimizationStrategy,
     ) -> Result<BatchOperationResponse, ProtocolError> {
-        let _guard = ReentrancyGuard::enter(env)?;
-        
-        let mut results = Vec::new();
+        // Each operation takes the reentrancy guard itself
+        let mut results = Vec::new(env);
         let mut total_gas_used = 0;
         let mut successful_operations = 0;
         
         // Validate batch size
         let config = UXConfigStorage::get(env);
-        if operations.len() > config.max_batch_size as usize {
+        if operations.len() > config.max_batch_size {
             return Err(ProtocolError::InvalidInput);
         }
         
         // Group operations by type for optimization
-        let grouped_ops = Self::group_operations_by_type(&operations);
+        let grouped_ops = Self::group_operations_by_type(env, &operations);
         
-        for (op_type, ops) in grouped_ops {
+        for (_, ops) in grouped_ops.iter() {
             let group_results = Self::execute_operation_group(env, &ops, &gas_strategy)?;
             
-            for result in group_results {
+            for result in group_results.iter() {
                 total_gas_used += result.gas_used;
                 if result.success {
                     successful_operations += 1;
                 }
-                results.push(result);
+                results.push_back(result);
             }
         }
         
         // Calculate gas savings
         let estimated_individual_gas = operations.len() as u64 * 1000; // Base estimate
-        let gas_savings = if total_gas_used < estimated_individual_gas {
-            estimated_individual_gas - total_gas_used
-        } else {
-            0
-        };
+        let gas_savings = estimated_individual_gas.saturating_sub(total_gas_used);
         
-        let overall_success = successful_operations == operations.len() as u32;
-        let summary = Self::generate_batch_summary(&operations, successful_operations, gas_savings);
+        let overall_success = successful_operations == operations.len();
+        let summary =
+            Self::generate_batch_summary(env, &operations, successful_operations, gas_savings);
         
         Ok(BatchOperationResponse {
             overall_success,
@@ -4432,22 +8930,19 @@ impl AdvancedUXManager {
     
     /// Group operations by type for optimization
     fn group_operations_by_type(
-        operations: &[BatchOperation],
+        env: &Env,
+        operations: &Vec<BatchOperation>,
     ) -> Vec<(BatchOperationType, Vec<BatchOperation>)> {
-        let mut groups: Vec<(BatchOperationType, Vec<BatchOperation>)> = Vec::new();
+        let mut groups: Vec<(BatchOperationType, Vec<BatchOperation>)> = Vec::new(env);
         
-        for op in operations {
-            let mut found = false;
-            for (op_type, ops) in &mut groups {
-                if *op_type == op.operation_type {
-                    ops.push(op.clone());
-                    found = true;
-                    break;
+        for op in operations.iter() {
+            match groups.iter().position(|(op_type, _)| op_type == op.operation_type) {
+                Some(index) => {
+                    let (op_type, mut ops) = groups.get_unchecked(index as u32);
+                    ops.push_back(op);
+                    groups.set(index as u32, (op_type, ops));
                 }
-            }
-            
-            if !found {
-                groups.push((op.operation_type.clone(), vec![op.clone()]));
+                None => groups.push_back((op.operation_type.clone(), vec![env, op])),
             }
         }
         
@@ -4457,14 +8952,13 @@ impl AdvancedUXManager {
     /// Execute a group of similar operations
     fn execute_operation_group(
         env: &Env,
-        operations: &[BatchOperation],
-        gas_strategy: &GasOptimizationStrategy,
+        operations: &Vec<BatchOperation>,
+        _gas_strategy: &GasOptimizationStrategy,
     ) -> Result<Vec<BatchOperationResult>, ProtocolError> {
-        let mut results = Vec::new();
+        let mut results = Vec::new(env);
         
         for (index, operation) in operations.iter().enumerate() {
-            let start_gas = env.ledger().gas_consumed();
-            
+            let operation = &operation;
             let result = match operation.operation_type {
                 BatchOperationType::Deposit => {
                     Self::execute_deposit_operation(env, operation)
@@ -4483,8 +8977,8 @@ impl AdvancedUXManager {
                 }
             };
             
-            let end_gas = env.ledger().gas_consumed();
-            let gas_used = end_gas - start_gas;
+            // The host does not expose metered usage, so report the estimate
+            let gas_used = Self::estimate_gas_usage(&operation.operation_type, operation.amount);
             
             let operation_result = match result {
                 Ok(_) => BatchOperationResult {
@@ -4499,24 +8993,26 @@ impl AdvancedUXManager {
                     success: false,
                     error_code: Some(error as u32),
                     gas_used,
-                    error_message: Some(Self::get_user_friendly_error_message(error)),
+                    error_message: Some(Self::get_user_friendly_error_message(env, error)),
                 },
             };
             
-            results.push(operation_result);
+            results.push_back(operation_result);
         }
         
         Ok(results)
     }
     
-    /// Execute individual deposit operation
+    /// Execute individual deposit operation; the batch caller is `operation.user` and has
+    /// already authorized it
     fn execute_deposit_operation(
         env: &Env,
         operation: &BatchOperation,
     ) -> Result<(), ProtocolError> {
-        deposit_collateral(
+        Contract::deposit_for(
             env.clone(),
-            operation.user.to_string(),
+            operation.asset.clone(),
+            operation.user.clone(),
             operation.amount,
         )
     }
@@ -4526,9 +9022,11 @@ impl AdvancedUXManager {
         env: &Env,
         operation: &BatchOperation,
     ) -> Result<(), ProtocolError> {
-        Contract::withdraw(
+        Contract::withdraw_for(
             env.clone(),
-            operation.user.to_string(),
+            operation.asset.clone(),
+            operation.user.clone(),
+            MAIN_ACCOUNT,
             operation.amount,
         )
     }
@@ -4538,10 +9036,14 @@ impl AdvancedUXManager {
         env: &Env,
         operation: &BatchOperation,
     ) -> Result<(), ProtocolError> {
-        borrow(
+        Contract::borrow_in_mode(
             env.clone(),
-            operation.user.to_string(),
+            operation.asset.clone(),
+            operation.user.clone(),
+            operation.user.clone(),
+            MAIN_ACCOUNT,
             operation.amount,
+            false,
         )
     }
     
@@ -4550,9 +9052,12 @@ impl AdvancedUXManager {
         env: &Env,
         operation: &BatchOperation,
     ) -> Result<(), ProtocolError> {
-        repay(
+        Contract::repay_for(
             env.clone(),
-            operation.user.to_string(),
+            operation.asset.clone(),
+            operation.user.clone(),
+            operation.user.clone(),
+            MAIN_ACCOUNT,
             operation.amount,
         )
     }
@@ -4562,15 +9067,17 @@ impl AdvancedUXManager {
         env: &Env,
         operation: &BatchOperation,
     ) -> Result<(), ProtocolError> {
-        if let Some(target) = &operation.target {
-            Contract::liquidate(
+        match (&operation.target, &operation.collateral_asset) {
+            (Some(target), Some(collateral_asset)) => Contract::liquidate_in(
                 env.clone(),
-                operation.user.to_string(),
-                target.to_string(),
+                operation.user.clone(),
+                target.clone(),
+                MAIN_ACCOUNT,
                 operation.amount,
-            )
-        } else {
-            Err(ProtocolError::InvalidInput)
+                operation.asset.clone(),
+                collateral_asset.clone(),
+            ),
+            _ => Err(ProtocolError::InvalidInput),
         }
     }
     
@@ -4585,9 +9092,6 @@ impl AdvancedUXManager {
             return Err(ProtocolError::ConfigurationError);
         }
         
-        // Create a simulation environment
-        let simulation_env = env.clone();
-        
         // Estimate gas usage
         let estimated_gas = Self::estimate_gas_usage(&operation.operation_type, operation.amount);
         
@@ -4596,7 +9100,7 @@ impl AdvancedUXManager {
         
         let (error_code, error_message) = if !would_succeed {
             let error = Self::identify_potential_error(env, operation);
-            (Some(error as u32), Some(Self::get_user_friendly_error_message(error)))
+            (Some(error as u32), Some(Self::get_user_friendly_error_message(env, error)))
         } else {
             (None, None)
         };
@@ -4605,11 +9109,11 @@ impl AdvancedUXManager {
         let optimization_suggestions = if config.gas_suggestions {
             Self::generate_optimization_suggestions(env, operation, estimated_gas)
         } else {
-            Vec::new()
+            Vec::new(env)
         };
         
         // Suggest gas limit (add 20% buffer)
-        let suggested_gas_limit = (estimated_gas as f64 * 1.2) as u64;
+        let suggested_gas_limit = estimated_gas * 12 / 10;
         
         Ok(TransactionSimulation {
             would_succeed,
@@ -4652,25 +9156,21 @@ impl AdvancedUXManager {
             }
             BatchOperationType::Withdraw => {
                 // Check if user has sufficient collateral
-                if let Some(position) = StateHelper::get_position(env, &operation.user) {
+                if let Some(position) = StateHelper::get_position(env, &operation.user, &operation.asset) {
                     position.collateral >= operation.amount
                 } else {
                     false
                 }
             }
             BatchOperationType::Borrow => {
-                // Check if user has sufficient collateral ratio
-                if let Some(position) = StateHelper::get_position(env, &operation.user) {
-                    let ratio = StateHelper::collateral_ratio(&position);
-                    ratio >= ProtocolConfig::get_min_collateral_ratio(env)
-                } else {
-                    false
-                }
+                // Check if the account is within its borrow limit
+                StateHelper::account_health(env, &operation.user)
+                    .is_ok_and(|health| health.within_borrow_limit())
             }
             BatchOperationType::Repay => {
                 // Check if user has debt to repay
-                if let Some(position) = StateHelper::get_position(env, &operation.user) {
-                    position.debt >= operation.amount
+                if let Some(position) = StateHelper::get_position(env, &operation.user, &operation.asset) {
+                    StateHelper::current_debt(env, &position) >= operation.amount
                 } else {
                     false
                 }
@@ -4678,12 +9178,8 @@ impl AdvancedUXManager {
             BatchOperationType::Liquidate => {
                 // Check if target is eligible for liquidation
                 if let Some(target) = &operation.target {
-                    if let Some(position) = StateHelper::get_position(env, target) {
-                        let ratio = StateHelper::collateral_ratio(&position);
-                        ratio < ProtocolConfig::get_min_collateral_ratio(env)
-                    } else {
-                        false
-                    }
+                    StateHelper::account_health(env, target)
+                        .is_ok_and(|health| health.health_factor < HEALTH_FACTOR_ONE)
                 } else {
                     false
                 }
@@ -4702,7 +9198,7 @@ impl AdvancedUXManager {
                 }
             }
             BatchOperationType::Withdraw => {
-                if let Some(position) = StateHelper::get_position(env, &operation.user) {
+                if let Some(position) = StateHelper::get_position(env, &operation.user, &operation.asset) {
                     if position.collateral < operation.amount {
                         ProtocolError::InsufficientCollateral
                     } else {
@@ -4713,20 +9209,21 @@ impl AdvancedUXManager {
                 }
             }
             BatchOperationType::Borrow => {
-                if let Some(position) = StateHelper::get_position(env, &operation.user) {
-                    let ratio = StateHelper::collateral_ratio(&position);
-                    if ratio < ProtocolConfig::get_min_collateral_ratio(env) {
-                        ProtocolError::InsufficientCollateralRatio
-                    } else {
-                        ProtocolError::InsufficientCollateral
-                    }
-                } else {
+                if StateHelper::get_position(env, &operation.user, &operation.asset).is_none() {
                     ProtocolError::PositionNotFound
+                } else {
+                    match StateHelper::account_health(env, &operation.user) {
+                        Err(error) => error,
+                        Ok(health) if !health.within_borrow_limit() => {
+                            ProtocolError::InsufficientCollateralRatio
+                        }
+                        Ok(_) => ProtocolError::InsufficientCollateral,
+                    }
                 }
             }
             BatchOperationType::Repay => {
-                if let Some(position) = StateHelper::get_position(env, &operation.user) {
-                    if position.debt < operation.amount {
+                if let Some(position) = StateHelper::get_position(env, &operation.user, &operation.asset) {
+                    if StateHelper::current_debt(env, &position) < operation.amount {
                         ProtocolError::InvalidAmount
                     } else {
                         ProtocolError::InsufficientCollateral
@@ -4751,29 +9248,29 @@ impl AdvancedUXManager {
         operation: &BatchOperation,
         estimated_gas: u64,
     ) -> Vec<String> {
-        let mut suggestions = Vec::new();
+        let mut suggestions = Vec::new(env);
         
         // Add suggestions based on gas usage
         if estimated_gas > 1000 {
-            suggestions.push(String::from_str(env, "Consider batching similar operations to reduce gas costs"));
+            suggestions.push_back(String::from_str(env, "Consider batching similar operations to reduce gas costs"));
         }
         
         // Add suggestions based on operation type
         match operation.operation_type {
             BatchOperationType::Deposit => {
-                suggestions.push(String::from_str(env, "Deposits are gas-efficient, consider larger amounts"));
+                suggestions.push_back(String::from_str(env, "Deposits are gas-efficient, consider larger amounts"));
             }
             BatchOperationType::Withdraw => {
-                suggestions.push(String::from_str(env, "Withdrawals can be optimized by batching multiple small amounts"));
+                suggestions.push_back(String::from_str(env, "Withdrawals can be optimized by batching multiple small amounts"));
             }
             BatchOperationType::Borrow => {
-                suggestions.push(String::from_str(env, "Borrow operations are gas-intensive, plan your borrowing strategy"));
+                suggestions.push_back(String::from_str(env, "Borrow operations are gas-intensive, plan your borrowing strategy"));
             }
             BatchOperationType::Repay => {
-                suggestions.push(String::from_str(env, "Consider repaying larger amounts to reduce frequency"));
+                suggestions.push_back(String::from_str(env, "Consider repaying larger amounts to reduce frequency"));
             }
             BatchOperationType::Liquidate => {
-                suggestions.push(String::from_str(env, "Liquidation operations are complex, ensure sufficient gas"));
+                suggestions.push_back(String::from_str(env, "Liquidation operations are complex, ensure sufficient gas"));
             }
         }
         
@@ -4782,55 +9279,40 @@ impl AdvancedUXManager {
     
     /// Generate batch operation summary
     fn generate_batch_summary(
-        operations: &[BatchOperation],
+        env: &Env,
+        operations: &Vec<BatchOperation>,
         successful_operations: u32,
         gas_savings: u64,
     ) -> String {
-        let total_operations = operations.len() as u32;
-        let success_rate = (successful_operations as f64 / total_operations as f64 * 100.0) as u32;
+        let total_operations = operations.len();
+        let success_rate = successful_operations * 100 / total_operations.max(1);
         
-        format!(
+        String::from_str(env, &format!(
             "Batch completed: {}/{} operations successful ({}% success rate). Gas savings: {} units.",
             successful_operations,
             total_operations,
             success_rate,
             gas_savings
-        )
+        ))
     }
     
     /// Get user-friendly error message
-    pub fn get_user_friendly_error_message(error: ProtocolError) -> String {
+    pub fn get_user_friendly_error_message(env: &Env, error: ProtocolError) -> String {
+        String::from_str(env, Self::error_message_text(error))
+    }
+    
+    fn error_message_text(error: ProtocolError) -> &'static str {
         match error {
-            ProtocolError::InsufficientCollateral => {
-                String::from_str(&Env::default(), "Insufficient collateral. Please add more collateral to your position.")
-            }
-            ProtocolError::InsufficientCollateralRatio => {
-                String::from_str(&Env::default(), "Collateral ratio too low. Please add more collateral or reduce your debt.")
-            }
-            ProtocolError::InvalidAmount => {
-                String::from_str(&Env::default(), "Invalid amount. Please enter a positive number.")
-            }
-            ProtocolError::PositionNotFound => {
-                String::from_str(&Env::default(), "No position found. Please create a position first.")
-            }
-            ProtocolError::NotEligibleForLiquidation => {
-                String::from_str(&Env::default(), "Position is not eligible for liquidation.")
-            }
-            ProtocolError::ProtocolPaused => {
-                String::from_str(&Env::default(), "Protocol is currently paused. Please try again later.")
-            }
-            ProtocolError::AssetNotSupported => {
-                String::from_str(&Env::default(), "Asset not supported. Please use a supported asset.")
-            }
-            ProtocolError::OracleFailure => {
-                String::from_str(&Env::default(), "Price feed error. Please try again later.")
-            }
-            ProtocolError::ReentrancyDetected => {
-                String::from_str(&Env::default(), "Security check failed. Please try again.")
-            }
-            _ => {
-                String::from_str(&Env::default(), "An unexpected error occurred. Please try again or contact support.")
-            }
+            ProtocolError::InsufficientCollateral => "Insufficient collateral. Please add more collateral to your position.",
+            ProtocolError::InsufficientCollateralRatio => "Collateral ratio too low. Please add more collateral or reduce your debt.",
+            ProtocolError::InvalidAmount => "Invalid amount. Please enter a positive number.",
+            ProtocolError::PositionNotFound => "No position found. Please create a position first.",
+            ProtocolError::NotEligibleForLiquidation => "Position is not eligible for liquidation.",
+            ProtocolError::ProtocolPaused => "Protocol is currently paused. Please try again later.",
+            ProtocolError::AssetNotSupported => "Asset not supported. Please use a supported asset.",
+            ProtocolError::OracleFailure => "Price feed error. Please try again later.",
+            ProtocolError::ReentrancyDetected => "Security check failed. Please try again.",
+            _ => "An unexpected error occurred. Please try again or contact support.",
         }
     }
     
@@ -4838,33 +9320,33 @@ impl AdvancedUXManager {
     pub fn get_detailed_error_info(
         env: &Env,
         error: ProtocolError,
-        user: &Address,
+        _user: &Address,
         operation: &str,
     ) -> String {
         let config = UXConfigStorage::get(env);
         
         if !config.detailed_errors {
-            return Self::get_user_friendly_error_message(error);
+            return Self::get_user_friendly_error_message(env, error);
         }
         
-        let base_message = Self::get_user_friendly_error_message(error);
+        let base_message = Self::error_message_text(error);
         let error_code = error as u32;
         let timestamp = env.ledger().timestamp();
         
-        format!(
-            "Error {}: {}. User: {}, Operation: {}, Time: {}. For support, reference error code {}.",
+        let message = format!(
+            "Error {}: {} Operation: {}, Time: {}. For support, reference error code {}.",
             error_code,
             base_message,
-            user.to_string(),
             operation,
             timestamp,
             error_code
-        )
+        );
+        String::from_str(env, &message)
     }
     
     /// Optimize gas usage for a specific operation
     pub fn optimize_gas_usage(
-        env: &Env,
+        _env: &Env,
         operation: &BatchOperation,
         strategy: GasOptimizationStrategy,
     ) -> Result<BatchOperation, ProtocolError> {
@@ -4896,19 +9378,19 @@ impl AdvancedUXManager {
     }
     
     /// Get gas usage statistics
-    pub fn get_gas_statistics(env: &Env) -> (u64, u64, u64) {
+    pub fn get_gas_statistics(_env: &Env) -> (u64, u64, u64) {
         // This would typically track historical gas usage
         // For now, return placeholder values
         (1000, 2000, 1500) // (min, max, average)
     }
     
     /// Validate batch operation parameters
-    pub fn validate_batch_operations(operations: &[BatchOperation]) -> Result<(), ProtocolError> {
+    pub fn validate_batch_operations(operations: &Vec<BatchOperation>) -> Result<(), ProtocolError> {
         if operations.is_empty() {
             return Err(ProtocolError::InvalidInput);
         }
         
-        for operation in operations {
+        for operation in operations.iter() {
             if operation.amount <= 0 {
                 return Err(ProtocolError::InvalidAmount);
             }
@@ -4926,8 +9408,8 @@ impl AdvancedUXManager {
 pub struct UXConfigStorage;
 
 impl UXConfigStorage {
-    fn config_key() -> Symbol {
-        symbol_short!("ux_config")
+    fn config_key() -> DataKey {
+        DataKey::UxConfig
     }
     
     pub fn save(env: &Env, config: &UXConfig) {
@@ -4935,7 +9417,7 @@ impl UXConfigStorage {
     }
     
     pub fn get(env: &Env) -> UXConfig {
-        env.storage().instance().get(&Self::config_key()).unwrap_or_else(UXConfig::default)
+        env.storage().instance().get(&Self::config_key()).unwrap_or_else(|| UXConfig::default(env))
     }
     
     pub fn update_config(
@@ -4969,8 +9451,6 @@ impl UXErrorHandler {
         operation: &str,
         context: &str,
     ) -> Result<(), ProtocolError> {
-        let config = UXConfigStorage::get(env);
-        
         // Log error with enhanced context
         let error_context = ErrorLogger::log_error(
             env,
@@ -5000,13 +9480,13 @@ impl UXErrorHandler {
         user: &Address,
         operation: &str,
     ) {
-        let error_message = AdvancedUXManager::get_user_friendly_error_message(*error);
+        let error_message = AdvancedUXManager::get_user_friendly_error_message(env, *error);
         
         env.events().publish(
             (symbol_short!("ux_error"), symbol_short!("user")),
             (
-                symbol_short!("error_code"), error as u32,
-                symbol_short!("error_message"), error_message,
+                symbol_short!("code"), *error as u32,
+                symbol_short!("message"), error_message,
                 symbol_short!("operation"), String::from_str(env, operation),
                 symbol_short!("user"), user.clone(),
             )
@@ -5014,31 +9494,30 @@ impl UXErrorHandler {
     }
     
     /// Get error recovery suggestions
-    pub fn get_error_recovery_suggestions(error: ProtocolError) -> Vec<String> {
-        let env = Env::default();
-        let mut suggestions = Vec::new();
+    pub fn get_error_recovery_suggestions(env: &Env, error: ProtocolError) -> Vec<String> {
+        let mut suggestions = Vec::new(env);
         
         match error {
             ProtocolError::InsufficientCollateral => {
-                suggestions.push(String::from_str(&env, "Add more collateral to your position"));
-                suggestions.push(String::from_str(&env, "Check your current collateral balance"));
+                suggestions.push_back(String::from_str(env, "Add more collateral to your position"));
+                suggestions.push_back(String::from_str(env, "Check your current collateral balance"));
             }
             ProtocolError::InsufficientCollateralRatio => {
-                suggestions.push(String::from_str(&env, "Add more collateral"));
-                suggestions.push(String::from_str(&env, "Repay some of your debt"));
-                suggestions.push(String::from_str(&env, "Check current market prices"));
+                suggestions.push_back(String::from_str(env, "Add more collateral"));
+                suggestions.push_back(String::from_str(env, "Repay some of your debt"));
+                suggestions.push_back(String::from_str(env, "Check current market prices"));
             }
             ProtocolError::OracleFailure => {
-                suggestions.push(String::from_str(&env, "Try again in a few minutes"));
-                suggestions.push(String::from_str(&env, "Check if price feeds are working"));
+                suggestions.push_back(String::from_str(env, "Try again in a few minutes"));
+                suggestions.push_back(String::from_str(env, "Check if price feeds are working"));
             }
             ProtocolError::ProtocolPaused => {
-                suggestions.push(String::from_str(&env, "Wait for protocol to resume"));
-                suggestions.push(String::from_str(&env, "Check protocol status"));
+                suggestions.push_back(String::from_str(env, "Wait for protocol to resume"));
+                suggestions.push_back(String::from_str(env, "Check protocol status"));
             }
             _ => {
-                suggestions.push(String::from_str(&env, "Try again later"));
-                suggestions.push(String::from_str(&env, "Contact support if problem persists"));
+                suggestions.push_back(String::from_str(env, "Try again later"));
+                suggestions.push_back(String::from_str(env, "Contact support if problem persists"));
             }
         }
         
@@ -5055,11 +9534,11 @@ impl TransactionSimulator {
         env: &Env,
         operations: Vec<BatchOperation>,
     ) -> Result<Vec<TransactionSimulation>, ProtocolError> {
-        let mut simulations = Vec::new();
+        let mut simulations = Vec::new(env);
         
-        for operation in operations {
+        for operation in operations.iter() {
             let simulation = AdvancedUXManager::simulate_transaction(env, &operation)?;
-            simulations.push(simulation);
+            simulations.push_back(simulation);
         }
         
         Ok(simulations)
@@ -5068,31 +9547,28 @@ impl TransactionSimulator {
     /// Get simulation summary
     pub fn get_simulation_summary(
         env: &Env,
-        simulations: &[TransactionSimulation],
+        simulations: &Vec<TransactionSimulation>,
     ) -> String {
         let total_operations = simulations.len();
-        let successful_operations = simulations.iter().filter(|s| s.would_succeed).count();
+        let successful_operations = simulations.iter().filter(|s| s.would_succeed).count() as u32;
         let total_estimated_gas: u64 = simulations.iter().map(|s| s.estimated_gas).sum();
         
-        let success_rate = if total_operations > 0 {
-            (successful_operations as f64 / total_operations as f64 * 100.0) as u32
-        } else {
-            0
-        };
+        let success_rate = successful_operations * 100 / total_operations.max(1);
         
-        format!(
+        let summary = format!(
             "Simulation complete: {}/{} operations would succeed ({}% success rate). Total estimated gas: {} units.",
             successful_operations,
             total_operations,
             success_rate,
             total_estimated_gas
-        )
+        );
+        String::from_str(env, &summary)
     }
     
     /// Validate simulation parameters
     pub fn validate_simulation_parameters(
         env: &Env,
-        operations: &[BatchOperation],
+        operations: &Vec<BatchOperation>,
     ) -> Result<(), ProtocolError> {
         let config = UXConfigStorage::get(env);
         
@@ -5100,7 +9576,7 @@ impl TransactionSimulator {
             return Err(ProtocolError::ConfigurationError);
         }
         
-        if operations.len() > config.max_batch_size as usize {
+        if operations.len() > config.max_batch_size {
             return Err(ProtocolError::InvalidInput);
         }
         
@@ -5116,14 +9592,18 @@ impl Contract {
     /// Execute batch operations with advanced UX features
     pub fn execute_batch_operations_ux(
         env: Env,
-        caller: String,
+        caller: Address,
         operations: Vec<BatchOperation>,
         gas_strategy: GasOptimizationStrategy,
     ) -> Result<BatchOperationResponse, ProtocolError> {
-        let caller_addr = Address::from_string(&String::from_str(&env, &caller));
-        
-        // Validate operations
+        caller.require_auth();
+        // Validate operations; a batch may only act for its caller
         AdvancedUXManager::validate_batch_operations(&operations)?;
+        for operation in operations.iter() {
+            if operation.user != caller {
+                return Err(ProtocolError::Unauthorized);
+            }
+        }
         
         // Check UX configuration
         let config = UXConfigStorage::get(&env);
@@ -5136,13 +9616,13 @@ impl Contract {
         
         // Emit batch operation event
         env.events().publish(
-            (symbol_short!("batch_executed"), symbol_short!("caller")),
+            (symbol_short!("batch_run"), symbol_short!("caller")),
             (
-                symbol_short!("caller"), caller_addr,
-                symbol_short!("total_operations"), response.results.len() as u32,
-                symbol_short!("successful_operations"), response.results.iter().filter(|r| r.success).count() as u32,
-                symbol_short!("total_gas"), response.total_gas_used,
-                symbol_short!("gas_savings"), response.gas_savings,
+                symbol_short!("caller"), caller,
+                symbol_short!("ops"), response.results.len(),
+                symbol_short!("succeeded"), response.results.iter().filter(|r| r.success).count() as u32,
+                symbol_short!("gas"), response.total_gas_used,
+                symbol_short!("savings"), response.gas_savings,
             )
         );
         
@@ -5152,25 +9632,24 @@ impl Contract {
     /// Simulate transaction with UX features
     pub fn simulate_transaction_ux(
         env: Env,
-        caller: String,
+        caller: Address,
         operation: BatchOperation,
     ) -> Result<TransactionSimulation, ProtocolError> {
-        let caller_addr = Address::from_string(&String::from_str(&env, &caller));
-        
+        caller.require_auth();
         // Validate simulation parameters
-        TransactionSimulator::validate_simulation_parameters(&env, &[operation.clone()])?;
+        TransactionSimulator::validate_simulation_parameters(&env, &vec![&env, operation.clone()])?;
         
         // Perform simulation
         let simulation = AdvancedUXManager::simulate_transaction(&env, &operation)?;
         
         // Emit simulation event
         env.events().publish(
-            (symbol_short!("transaction_simulated"), symbol_short!("caller")),
+            (symbol_short!("tx_sim"), symbol_short!("caller")),
             (
-                symbol_short!("caller"), caller_addr,
-                symbol_short!("operation_type"), format!("{:?}", operation.operation_type),
-                symbol_short!("would_succeed"), simulation.would_succeed,
-                symbol_short!("estimated_gas"), simulation.estimated_gas,
+                symbol_short!("caller"), caller,
+                symbol_short!("op_type"), operation.operation_type.clone(),
+                symbol_short!("succeeds"), simulation.would_succeed,
+                symbol_short!("gas"), simulation.estimated_gas,
             )
         );
         
@@ -5180,11 +9659,10 @@ impl Contract {
     /// Simulate batch operations
     pub fn simulate_batch_operations_ux(
         env: Env,
-        caller: String,
+        caller: Address,
         operations: Vec<BatchOperation>,
     ) -> Result<Vec<TransactionSimulation>, ProtocolError> {
-        let caller_addr = Address::from_string(&String::from_str(&env, &caller));
-        
+        caller.require_auth();
         // Validate simulation parameters
         TransactionSimulator::validate_simulation_parameters(&env, &operations)?;
         
@@ -5196,11 +9674,11 @@ impl Contract {
         
         // Emit batch simulation event
         env.events().publish(
-            (symbol_short!("batch_simulated"), symbol_short!("caller")),
+            (symbol_short!("batch_sim"), symbol_short!("caller")),
             (
-                symbol_short!("caller"), caller_addr,
-                symbol_short!("total_operations"), simulations.len() as u32,
-                symbol_short!("summary"), String::from_str(&env, &summary),
+                symbol_short!("caller"), caller,
+                symbol_short!("ops"), simulations.len(),
+                symbol_short!("summary"), summary,
             )
         );
         
@@ -5210,12 +9688,10 @@ impl Contract {
     /// Update UX configuration
     pub fn update_ux_config(
         env: Env,
-        caller: String,
+        caller: Address,
         config: UXConfig,
     ) -> Result<(), ProtocolError> {
-        let caller_addr = Address::from_string(&String::from_str(&env, &caller));
-        
-        UXConfigStorage::update_config(&env, &caller_addr, config)?;
+        UXConfigStorage::update_config(&env, &caller, config)?;
         
         Ok(())
     }
@@ -5236,9 +9712,9 @@ impl Contract {
         error_code: u32,
     ) -> Vec<String> {
         if let Some(error) = Self::error_code_to_protocol_error(error_code) {
-            UXErrorHandler::get_error_recovery_suggestions(error)
+            UXErrorHandler::get_error_recovery_suggestions(&env, error)
         } else {
-            vec![String::from_str(&env, "Unknown error. Please contact support.")]
+            vec![&env, String::from_str(&env, "Unknown error. Please contact support.")]
         }
     }
     
@@ -5282,20 +9758,21 @@ impl Contract {
     /// Enhanced deposit with UX features
     pub fn deposit_collateral_ux(
         env: Env,
-        depositor: String,
+        asset: String,
+        depositor: Address,
         amount: i128,
     ) -> Result<(), ProtocolError> {
-        let depositor_addr = Address::from_string(&String::from_str(&env, &depositor));
-        
+        depositor.require_auth();
         // Simulate transaction first if enabled
         let config = UXConfigStorage::get(&env);
         if config.enable_simulation {
             let operation = BatchOperation {
                 operation_type: BatchOperationType::Deposit,
-                user: depositor_addr.clone(),
-                asset: String::from_str(&env, "XLM"), // Default asset
+                user: depositor.clone(),
+                asset: asset.clone(),
                 amount,
                 target: None,
+                collateral_asset: None,
                 optimize_gas: false,
             };
             
@@ -5306,13 +9783,13 @@ impl Contract {
         }
         
         // Execute deposit with enhanced error handling
-        match deposit_collateral(env.clone(), depositor.clone(), amount) {
+        match Contract::deposit_for(env.clone(), asset, depositor.clone(), amount) {
             Ok(()) => {
                 // Emit success event with UX details
                 env.events().publish(
-                    (symbol_short!("deposit_success"), symbol_short!("user")),
+                    (symbol_short!("deposited"), symbol_short!("user")),
                     (
-                        symbol_short!("user"), depositor_addr,
+                        symbol_short!("user"), depositor,
                         symbol_short!("amount"), amount,
                         symbol_short!("message"), String::from_str(&env, "Deposit successful! Your collateral has been added to your position."),
                     )
@@ -5324,7 +9801,7 @@ impl Contract {
                 UXErrorHandler::handle_error(
                     &env,
                     error,
-                    &depositor_addr,
+                    &depositor,
                     "deposit_collateral",
                     &format!("amount: {}", amount),
                 )
@@ -5335,20 +9812,21 @@ impl Contract {
     /// Enhanced borrow with UX features
     pub fn borrow_ux(
         env: Env,
-        borrower: String,
+        asset: String,
+        borrower: Address,
         amount: i128,
     ) -> Result<(), ProtocolError> {
-        let borrower_addr = Address::from_string(&String::from_str(&env, &borrower));
-        
+        borrower.require_auth();
         // Simulate transaction first if enabled
         let config = UXConfigStorage::get(&env);
         if config.enable_simulation {
             let operation = BatchOperation {
                 operation_type: BatchOperationType::Borrow,
-                user: borrower_addr.clone(),
-                asset: String::from_str(&env, "XLM"), // Default asset
+                user: borrower.clone(),
+                asset: asset.clone(),
                 amount,
                 target: None,
+                collateral_asset: None,
                 optimize_gas: false,
             };
             
@@ -5359,13 +9837,21 @@ impl Contract {
         }
         
         // Execute borrow with enhanced error handling
-        match borrow(env.clone(), borrower.clone(), amount) {
+        match Contract::borrow_in_mode(
+            env.clone(),
+            asset,
+            borrower.clone(),
+            borrower.clone(),
+            MAIN_ACCOUNT,
+            amount,
+            false,
+        ) {
             Ok(()) => {
                 // Emit success event with UX details
                 env.events().publish(
-                    (symbol_short!("borrow_success"), symbol_short!("user")),
+                    (symbol_short!("borrowed"), symbol_short!("user")),
                     (
-                        symbol_short!("user"), borrower_addr,
+                        symbol_short!("user"), borrower,
                         symbol_short!("amount"), amount,
                         symbol_short!("message"), String::from_str(&env, "Borrow successful! Funds have been transferred to your account."),
                     )
@@ -5377,7 +9863,7 @@ impl Contract {
                 UXErrorHandler::handle_error(
                     &env,
                     error,
-                    &borrower_addr,
+                    &borrower,
                     "borrow",
                     &format!("amount: {}", amount),
                 )
@@ -5444,6 +9930,7 @@ pub struct RecoveryRequest {
 
 impl RecoveryRequest {
     pub fn new(
+        env: &Env,
         id: u32,
         user: Address,
         new_address: Address,
@@ -5459,7 +9946,7 @@ impl RecoveryRequest {
             created_at: 0,
             time_delay,
             executed: false,
-            approvals: vec![],
+            approvals: Vec::new(env),
         }
     }
 
@@ -5470,8 +9957,8 @@ impl RecoveryRequest {
     }
 
     pub fn add_approval(&mut self, guardian: Address, timestamp: u64) {
-        if !self.approvals.iter().any(|(addr, _)| *addr == guardian) {
-            self.approvals.push((guardian, timestamp));
+        if !self.approvals.iter().any(|(addr, _)| addr == guardian) {
+            self.approvals.push_back((guardian, timestamp));
             self.current_approvals += 1;
         }
     }
@@ -5509,6 +9996,7 @@ pub struct MultiSigProposal {
 
 impl MultiSigProposal {
     pub fn new(
+        env: &Env,
         id: u32,
         creator: Address,
         description: String,
@@ -5528,7 +10016,7 @@ impl MultiSigProposal {
             created_at: 0,
             time_delay,
             executed: false,
-            approvals: vec![],
+            approvals: Vec::new(env),
             status: ProposalStatus::Pending,
         }
     }
@@ -5541,8 +10029,8 @@ impl MultiSigProposal {
     }
 
     pub fn add_approval(&mut self, guardian: Address, timestamp: u64) {
-        if !self.approvals.iter().any(|(addr, _)| *addr == guardian) {
-            self.approvals.push((guardian, timestamp));
+        if !self.approvals.iter().any(|(addr, _)| addr == guardian) {
+            self.approvals.push_back((guardian, timestamp));
             self.current_approvals += 1;
             
             if self.current_approvals >= self.required_approvals {
@@ -5571,10 +10059,10 @@ pub struct SocialRecoveryConfig {
 }
 
 impl SocialRecoveryConfig {
-    pub fn new(user: Address, min_guardians: u32, recovery_time_delay: u64) -> Self {
+    pub fn new(env: &Env, user: Address, min_guardians: u32, recovery_time_delay: u64) -> Self {
         Self {
             user,
-            guardians: vec![],
+            guardians: Vec::new(env),
             min_guardians,
             recovery_time_delay,
             enabled: false,
@@ -5584,20 +10072,26 @@ impl SocialRecoveryConfig {
 
     pub fn add_guardian(&mut self, guardian: Guardian) {
         if !self.guardians.iter().any(|g| g.address == guardian.address) {
-            self.guardians.push(guardian);
+            self.guardians.push_back(guardian);
         }
     }
 
     pub fn remove_guardian(&mut self, guardian_address: &Address) {
-        self.guardians.retain(|g| g.address != *guardian_address);
+        if let Some(index) = self.guardians.iter().position(|g| g.address == *guardian_address) {
+            self.guardians.remove(index as u32);
+        }
     }
 
-    pub fn get_active_guardians(&self) -> Vec<&Guardian> {
-        self.guardians.iter().filter(|g| g.is_active).collect()
+    pub fn get_active_guardians(&self) -> Vec<Guardian> {
+        let mut active = Vec::new(self.guardians.env());
+        for guardian in self.guardians.iter().filter(|g| g.is_active) {
+            active.push_back(guardian);
+        }
+        active
     }
 
     pub fn is_valid_recovery_setup(&self) -> bool {
-        self.enabled && self.guardians.len() >= self.min_guardians as usize
+        self.enabled && self.guardians.len() >= self.min_guardians
     }
 }
 
@@ -5618,9 +10112,9 @@ pub struct MultiSigConfig {
 }
 
 impl MultiSigConfig {
-    pub fn new(min_signatures: u32, execution_delay: u64) -> Self {
+    pub fn new(env: &Env, min_signatures: u32, execution_delay: u64) -> Self {
         Self {
-            signers: vec![],
+            signers: Vec::new(env),
             min_signatures,
             execution_delay,
             enabled: false,
@@ -5630,16 +10124,18 @@ impl MultiSigConfig {
 
     pub fn add_signer(&mut self, signer: Address) {
         if !self.signers.contains(&signer) {
-            self.signers.push(signer);
+            self.signers.push_back(signer);
         }
     }
 
     pub fn remove_signer(&mut self, signer: &Address) {
-        self.signers.retain(|s| s != signer);
+        if let Some(index) = self.signers.first_index_of(signer) {
+            self.signers.remove(index);
+        }
     }
 
     pub fn is_valid_setup(&self) -> bool {
-        self.enabled && self.signers.len() >= self.min_signatures as usize
+        self.enabled && self.signers.len() >= self.min_signatures
     }
 }
 
@@ -5647,54 +10143,48 @@ impl MultiSigConfig {
 pub struct RecoveryStorage;
 
 impl RecoveryStorage {
-    fn recovery_config_key(user: &Address) -> Symbol {
-        let env = Env::default();
-        Symbol::new(&env, &format!("recovery_cfg_{}", user.to_string()))
+    fn recovery_config_key(user: &Address) -> DataKey {
+        DataKey::RecoveryConfig(user.clone())
     }
 
-    fn recovery_request_key(request_id: u32) -> Symbol {
-        let env = Env::default();
-        Symbol::new(&env, &format!("recovery_req_{}", request_id))
+    fn recovery_request_key(request_id: u32) -> DataKey {
+        DataKey::RecoveryRequest(request_id)
     }
 
-    fn multisig_config_key() -> Symbol {
-        let env = Env::default();
-        Symbol::new(&env, "multisig_cfg")
+    fn multisig_config_key() -> DataKey {
+        DataKey::MultisigConfig
     }
 
-    fn multisig_proposal_key(proposal_id: u32) -> Symbol {
-        let env = Env::default();
-        Symbol::new(&env, &format!("multisig_prop_{}", proposal_id))
+    fn multisig_proposal_key(proposal_id: u32) -> DataKey {
+        DataKey::MultisigProposal(proposal_id)
     }
 
-    fn next_recovery_id_key() -> Symbol {
-        let env = Env::default();
-        Symbol::new(&env, "next_recovery_id")
+    fn next_recovery_id_key() -> DataKey {
+        DataKey::NextRecoveryId
     }
 
-    fn next_proposal_id_key() -> Symbol {
-        let env = Env::default();
-        Symbol::new(&env, "next_proposal_id")
+    fn next_proposal_id_key() -> DataKey {
+        DataKey::NextMultisigProposalId
     }
 
     pub fn save_recovery_config(env: &Env, config: &SocialRecoveryConfig) {
         let key = Self::recovery_config_key(&config.user);
-        env.storage().instance().set(&key, config);
+        UserStorage::set(env, &key, config);
     }
 
     pub fn get_recovery_config(env: &Env, user: &Address) -> Option<SocialRecoveryConfig> {
         let key = Self::recovery_config_key(user);
-        env.storage().instance().get::<Symbol, SocialRecoveryConfig>(&key)
+        UserStorage::get::<SocialRecoveryConfig>(env, &key)
     }
 
     pub fn save_recovery_request(env: &Env, request: &RecoveryRequest) {
         let key = Self::recovery_request_key(request.id);
-        env.storage().instance().set(&key, request);
+        UserStorage::set(env, &key, request);
     }
 
     pub fn get_recovery_request(env: &Env, request_id: u32) -> Option<RecoveryRequest> {
         let key = Self::recovery_request_key(request_id);
-        env.storage().instance().get::<Symbol, RecoveryRequest>(&key)
+        UserStorage::get::<RecoveryRequest>(env, &key)
     }
 
     pub fn save_multisig_config(env: &Env, config: &MultiSigConfig) {
@@ -5704,22 +10194,22 @@ impl RecoveryStorage {
 
     pub fn get_multisig_config(env: &Env) -> Option<MultiSigConfig> {
         let key = Self::multisig_config_key();
-        env.storage().instance().get::<Symbol, MultiSigConfig>(&key)
+        env.storage().instance().get::<DataKey, MultiSigConfig>(&key)
     }
 
     pub fn save_multisig_proposal(env: &Env, proposal: &MultiSigProposal) {
         let key = Self::multisig_proposal_key(proposal.id);
-        env.storage().instance().set(&key, proposal);
+        UserStorage::set(env, &key, proposal);
     }
 
     pub fn get_multisig_proposal(env: &Env, proposal_id: u32) -> Option<MultiSigProposal> {
         let key = Self::multisig_proposal_key(proposal_id);
-        env.storage().instance().get::<Symbol, MultiSigProposal>(&key)
+        UserStorage::get::<MultiSigProposal>(env, &key)
     }
 
     pub fn get_next_recovery_id(env: &Env) -> u32 {
         let key = Self::next_recovery_id_key();
-        env.storage().instance().get::<Symbol, u32>(&key).unwrap_or(0)
+        env.storage().instance().get::<DataKey, u32>(&key).unwrap_or(0)
     }
 
     pub fn increment_recovery_id(env: &Env) -> u32 {
@@ -5731,7 +10221,7 @@ impl RecoveryStorage {
 
     pub fn get_next_proposal_id(env: &Env) -> u32 {
         let key = Self::next_proposal_id_key();
-        env.storage().instance().get::<Symbol, u32>(&key).unwrap_or(0)
+        env.storage().instance().get::<DataKey, u32>(&key).unwrap_or(0)
     }
 
     pub fn increment_proposal_id(env: &Env) -> u32 {
@@ -5747,14 +10237,13 @@ impl RecoveryStorage {
 /// Set up social recovery for a user
 pub fn setup_social_recovery(
     env: Env,
-    user: String,
+    user: Address,
     min_guardians: u32,
     recovery_time_delay: u64,
 ) -> Result<(), ProtocolError> {
-    let user_addr = Address::from_string(&String::from_str(&env, &user));
-    
+    user.require_auth();
     // Check if recovery is already set up
-    if RecoveryStorage::get_recovery_config(&env, &user_addr).is_some() {
+    if RecoveryStorage::get_recovery_config(&env, &user).is_some() {
         return Err(ProtocolError::AlreadyExists);
     }
 
@@ -5763,7 +10252,7 @@ pub fn setup_social_recovery(
         return Err(ProtocolError::InvalidInput);
     }
 
-    let mut config = SocialRecoveryConfig::new(user_addr, min_guardians, recovery_time_delay);
+    let mut config = SocialRecoveryConfig::new(&env, user, min_guardians, recovery_time_delay);
     config.enabled = true;
     config.last_updated = env.ledger().timestamp();
 
@@ -5775,28 +10264,26 @@ pub fn setup_social_recovery(
 /// Add a guardian to a user's social recovery setup
 pub fn add_guardian(
     env: Env,
-    user: String,
-    guardian: String,
+    user: Address,
+    guardian: Address,
     name: String,
     weight: u32,
 ) -> Result<(), ProtocolError> {
-    let user_addr = Address::from_string(&String::from_str(&env, &user));
-    let guardian_addr = Address::from_string(&String::from_str(&env, &guardian));
-
+    user.require_auth();
     // Validate guardian address
-    if guardian_addr == user_addr {
+    if guardian == user {
         return Err(ProtocolError::InvalidGuardianAddress);
     }
 
-    let mut config = RecoveryStorage::get_recovery_config(&env, &user_addr)
+    let mut config = RecoveryStorage::get_recovery_config(&env, &user)
         .ok_or(ProtocolError::NotFound)?;
 
     // Check if guardian already exists
-    if config.guardians.iter().any(|g| g.address == guardian_addr) {
+    if config.guardians.iter().any(|g| g.address == guardian) {
         return Err(ProtocolError::GuardianAlreadyExists);
     }
 
-    let mut new_guardian = Guardian::new(guardian_addr, name, weight);
+    let mut new_guardian = Guardian::new(guardian.clone(), name.clone(), weight);
     new_guardian.added_at = env.ledger().timestamp();
     config.add_guardian(new_guardian);
     config.last_updated = env.ledger().timestamp();
@@ -5805,8 +10292,8 @@ pub fn add_guardian(
 
     // Emit event
     ProtocolEvent::GuardianAdded {
-        user: user.clone(),
-        guardian: guardian.clone(),
+        user: user.to_string(),
+        guardian: guardian.to_string(),
         name: name.clone(),
         weight,
     }.emit(&env);
@@ -5817,29 +10304,27 @@ pub fn add_guardian(
 /// Remove a guardian from a user's social recovery setup
 pub fn remove_guardian(
     env: Env,
-    user: String,
-    guardian: String,
+    user: Address,
+    guardian: Address,
 ) -> Result<(), ProtocolError> {
-    let user_addr = Address::from_string(&String::from_str(&env, &user));
-    let guardian_addr = Address::from_string(&String::from_str(&env, &guardian));
-
-    let mut config = RecoveryStorage::get_recovery_config(&env, &user_addr)
+    user.require_auth();
+    let mut config = RecoveryStorage::get_recovery_config(&env, &user)
         .ok_or(ProtocolError::NotFound)?;
 
     // Check if guardian exists
-    if !config.guardians.iter().any(|g| g.address == guardian_addr) {
+    if !config.guardians.iter().any(|g| g.address == guardian) {
         return Err(ProtocolError::GuardianNotFound);
     }
 
-    config.remove_guardian(&guardian_addr);
+    config.remove_guardian(&guardian);
     config.last_updated = env.ledger().timestamp();
 
     RecoveryStorage::save_recovery_config(&env, &config);
 
     // Emit event
     ProtocolEvent::GuardianRemoved {
-        user: user.clone(),
-        guardian: guardian.clone(),
+        user: user.to_string(),
+        guardian: guardian.to_string(),
     }.emit(&env);
 
     Ok(())
@@ -5848,18 +10333,17 @@ pub fn remove_guardian(
 /// Create a recovery request
 pub fn create_recovery_request(
     env: Env,
-    user: String,
-    new_address: String,
+    user: Address,
+    new_address: Address,
 ) -> Result<u32, ProtocolError> {
-    let user_addr = Address::from_string(&String::from_str(&env, &user));
-    let new_addr = Address::from_string(&String::from_str(&env, &new_address));
+    user.require_auth();
 
     // Validate new address
-    if new_addr == user_addr {
+    if new_address == user {
         return Err(ProtocolError::InvalidRecoveryAddress);
     }
 
-    let config = RecoveryStorage::get_recovery_config(&env, &user_addr)
+    let config = RecoveryStorage::get_recovery_config(&env, &user)
         .ok_or(ProtocolError::NotFound)?;
 
     if !config.is_valid_recovery_setup() {
@@ -5868,9 +10352,10 @@ pub fn create_recovery_request(
 
     let request_id = RecoveryStorage::increment_recovery_id(&env);
     let mut request = RecoveryRequest::new(
+        &env,
         request_id,
-        user_addr,
-        new_addr,
+        user.clone(),
+        new_address.clone(),
         config.min_guardians,
         config.recovery_time_delay,
     );
@@ -5881,8 +10366,8 @@ pub fn create_recovery_request(
     // Emit event
     ProtocolEvent::RecoveryRequestCreated {
         request_id,
-        user: user.clone(),
-        new_address: new_address.clone(),
+        user: user.to_string(),
+        new_address: new_address.to_string(),
         required_approvals: config.min_guardians,
         time_delay: config.recovery_time_delay,
     }.emit(&env);
@@ -5893,11 +10378,10 @@ pub fn create_recovery_request(
 /// Approve a recovery request
 pub fn approve_recovery_request(
     env: Env,
-    guardian: String,
+    guardian: Address,
     request_id: u32,
 ) -> Result<(), ProtocolError> {
-    let guardian_addr = Address::from_string(&String::from_str(&env, &guardian));
-
+    guardian.require_auth();
     let mut request = RecoveryStorage::get_recovery_request(&env, request_id)
         .ok_or(ProtocolError::RecoveryRequestNotFound)?;
 
@@ -5909,22 +10393,22 @@ pub fn approve_recovery_request(
         .ok_or(ProtocolError::NotFound)?;
 
     // Check if guardian is valid
-    if !config.guardians.iter().any(|g| g.address == guardian_addr && g.is_active) {
+    if !config.guardians.iter().any(|g| g.address == guardian && g.is_active) {
         return Err(ProtocolError::GuardianNotFound);
     }
 
     // Check if guardian already approved
-    if request.approvals.iter().any(|(addr, _)| *addr == guardian_addr) {
+    if request.approvals.iter().any(|(addr, _)| addr == guardian) {
         return Err(ProtocolError::InvalidOperation);
     }
 
-    request.add_approval(guardian_addr, env.ledger().timestamp());
+    request.add_approval(guardian.clone(), env.ledger().timestamp());
     RecoveryStorage::save_recovery_request(&env, &request);
 
     // Emit event
     ProtocolEvent::RecoveryRequestApproved {
         request_id,
-        guardian: guardian.clone(),
+        guardian: guardian.to_string(),
         current_approvals: request.current_approvals,
     }.emit(&env);
 
@@ -5947,14 +10431,16 @@ pub fn execute_recovery_request(
         return Err(ProtocolError::RecoveryNotReady);
     }
 
-    // Execute the recovery by transferring positions
+    // Execute the recovery by transferring positions in every listed asset
     // This is a simplified implementation - in practice, you'd transfer all user data
-    if let Some(position) = StateHelper::get_position(&env, &request.user) {
-        StateHelper::save_position(&env, &Position {
-            user: request.new_address.clone(),
-            ..position
-        });
-        StateHelper::remove_position(&env, &request.user);
+    for asset in AssetStorage::get_registry(&env).supported_assets.iter() {
+        if let Some(position) = StateHelper::get_position(&env, &request.user, &asset) {
+            StateHelper::save_position(&env, &AssetPosition {
+                user: request.new_address.clone(),
+                ..position
+            });
+            StateHelper::remove_position(&env, &request.user, &asset);
+        }
     }
 
     request.executed = true;
@@ -5973,15 +10459,14 @@ pub fn execute_recovery_request(
 /// Cancel a recovery request
 pub fn cancel_recovery_request(
     env: Env,
-    user: String,
+    user: Address,
     request_id: u32,
 ) -> Result<(), ProtocolError> {
-    let user_addr = Address::from_string(&String::from_str(&env, &user));
-
+    user.require_auth();
     let mut request = RecoveryStorage::get_recovery_request(&env, request_id)
         .ok_or(ProtocolError::RecoveryRequestNotFound)?;
 
-    if request.user != user_addr {
+    if request.user != user {
         return Err(ProtocolError::Unauthorized);
     }
 
@@ -5995,21 +10480,19 @@ pub fn cancel_recovery_request(
     // Emit event
     ProtocolEvent::RecoveryRequestCancelled {
         request_id,
-        user: user.clone(),
+        user: user.to_string(),
     }.emit(&env);
 
     Ok(())
 }
 
 /// Get recovery configuration for a user
-pub fn get_recovery_config(env: Env, user: String) -> Result<(u32, u32, bool), ProtocolError> {
-    let user_addr = Address::from_string(&String::from_str(&env, &user));
-
-    let config = RecoveryStorage::get_recovery_config(&env, &user_addr)
+pub fn get_recovery_config(env: Env, user: Address) -> Result<(u32, u32, bool), ProtocolError> {
+    let config = RecoveryStorage::get_recovery_config(&env, &user)
         .ok_or(ProtocolError::NotFound)?;
 
     Ok((
-        config.guardians.len() as u32,
+        config.guardians.len(),
         config.min_guardians,
         config.enabled,
     ))
@@ -6034,14 +10517,12 @@ pub fn get_recovery_request(env: Env, request_id: u32) -> Result<(String, String
 /// Set up multi-signature for admin operations
 pub fn setup_multisig(
     env: Env,
-    caller: String,
+    caller: Address,
     min_signatures: u32,
     execution_delay: u64,
 ) -> Result<(), ProtocolError> {
-    let caller_addr = Address::from_string(&String::from_str(&env, &caller));
-    
     // Only admin can set up multi-signature
-    ProtocolConfig::require_admin(&env, &caller_addr)?;
+    ProtocolConfig::require_admin(&env, &caller)?;
 
     // Check if multi-signature is already set up
     if RecoveryStorage::get_multisig_config(&env).is_some() {
@@ -6053,7 +10534,7 @@ pub fn setup_multisig(
         return Err(ProtocolError::InvalidInput);
     }
 
-    let mut config = MultiSigConfig::new(min_signatures, execution_delay);
+    let mut config = MultiSigConfig::new(&env, min_signatures, execution_delay);
     config.enabled = true;
     config.last_updated = env.ledger().timestamp();
 
@@ -6065,32 +10546,29 @@ pub fn setup_multisig(
 /// Add a signer to multi-signature setup
 pub fn add_multisig_signer(
     env: Env,
-    caller: String,
-    signer: String,
+    caller: Address,
+    signer: Address,
 ) -> Result<(), ProtocolError> {
-    let caller_addr = Address::from_string(&String::from_str(&env, &caller));
-    let signer_addr = Address::from_string(&String::from_str(&env, &signer));
-
     // Only admin can add signers
-    ProtocolConfig::require_admin(&env, &caller_addr)?;
+    ProtocolConfig::require_admin(&env, &caller)?;
 
     let mut config = RecoveryStorage::get_multisig_config(&env)
         .ok_or(ProtocolError::NotFound)?;
 
     // Check if signer already exists
-    if config.signers.contains(&signer_addr) {
+    if config.signers.contains(&signer) {
         return Err(ProtocolError::AlreadyExists);
     }
 
-    config.add_signer(signer_addr);
+    config.add_signer(signer.clone());
     config.last_updated = env.ledger().timestamp();
 
     RecoveryStorage::save_multisig_config(&env, &config);
 
     // Emit event
     ProtocolEvent::MultiSigSignerAdded {
-        signer: signer.clone(),
-        added_by: caller.clone(),
+        signer: signer.to_string(),
+        added_by: caller.to_string(),
     }.emit(&env);
 
     Ok(())
@@ -6099,32 +10577,29 @@ pub fn add_multisig_signer(
 /// Remove a signer from multi-signature setup
 pub fn remove_multisig_signer(
     env: Env,
-    caller: String,
-    signer: String,
+    caller: Address,
+    signer: Address,
 ) -> Result<(), ProtocolError> {
-    let caller_addr = Address::from_string(&String::from_str(&env, &caller));
-    let signer_addr = Address::from_string(&String::from_str(&env, &signer));
-
     // Only admin can remove signers
-    ProtocolConfig::require_admin(&env, &caller_addr)?;
+    ProtocolConfig::require_admin(&env, &caller)?;
 
     let mut config = RecoveryStorage::get_multisig_config(&env)
         .ok_or(ProtocolError::NotFound)?;
 
     // Check if signer exists
-    if !config.signers.contains(&signer_addr) {
+    if !config.signers.contains(&signer) {
         return Err(ProtocolError::NotFound);
     }
 
-    config.remove_signer(&signer_addr);
+    config.remove_signer(&signer);
     config.last_updated = env.ledger().timestamp();
 
     RecoveryStorage::save_multisig_config(&env, &config);
 
     // Emit event
     ProtocolEvent::MultiSigSignerRemoved {
-        signer: signer.clone(),
-        removed_by: caller.clone(),
+        signer: signer.to_string(),
+        removed_by: caller.to_string(),
     }.emit(&env);
 
     Ok(())
@@ -6133,13 +10608,12 @@ pub fn remove_multisig_signer(
 /// Create a multi-signature proposal
 pub fn create_multisig_proposal(
     env: Env,
-    creator: String,
+    creator: Address,
     description: String,
     target_function: String,
     parameters: Vec<String>,
 ) -> Result<u32, ProtocolError> {
-    let creator_addr = Address::from_string(&String::from_str(&env, &creator));
-
+    creator.require_auth();
     let config = RecoveryStorage::get_multisig_config(&env)
         .ok_or(ProtocolError::NotFound)?;
 
@@ -6148,16 +10622,17 @@ pub fn create_multisig_proposal(
     }
 
     // Check if creator is a signer
-    if !config.signers.contains(&creator_addr) {
+    if !config.signers.contains(&creator) {
         return Err(ProtocolError::Unauthorized);
     }
 
     let proposal_id = RecoveryStorage::increment_proposal_id(&env);
     let mut proposal = MultiSigProposal::new(
+        &env,
         proposal_id,
-        creator_addr,
-        description,
-        target_function,
+        creator.clone(),
+        description.clone(),
+        target_function.clone(),
         parameters,
         config.min_signatures,
         config.execution_delay,
@@ -6169,7 +10644,7 @@ pub fn create_multisig_proposal(
     // Emit event
     ProtocolEvent::MultiSigProposalCreated {
         proposal_id,
-        creator: creator.clone(),
+        creator: creator.to_string(),
         description: description.clone(),
         target_function: target_function.clone(),
         required_approvals: config.min_signatures,
@@ -6182,11 +10657,10 @@ pub fn create_multisig_proposal(
 /// Approve a multi-signature proposal
 pub fn approve_multisig_proposal(
     env: Env,
-    signer: String,
+    signer: Address,
     proposal_id: u32,
 ) -> Result<(), ProtocolError> {
-    let signer_addr = Address::from_string(&String::from_str(&env, &signer));
-
+    signer.require_auth();
     let mut proposal = RecoveryStorage::get_multisig_proposal(&env, proposal_id)
         .ok_or(ProtocolError::MultiSigProposalNotFound)?;
 
@@ -6198,22 +10672,22 @@ pub fn approve_multisig_proposal(
         .ok_or(ProtocolError::NotFound)?;
 
     // Check if signer is valid
-    if !config.signers.contains(&signer_addr) {
+    if !config.signers.contains(&signer) {
         return Err(ProtocolError::Unauthorized);
     }
 
     // Check if signer already approved
-    if proposal.approvals.iter().any(|(addr, _)| *addr == signer_addr) {
+    if proposal.approvals.iter().any(|(addr, _)| addr == signer) {
         return Err(ProtocolError::InvalidOperation);
     }
 
-    proposal.add_approval(signer_addr, env.ledger().timestamp());
+    proposal.add_approval(signer.clone(), env.ledger().timestamp());
     RecoveryStorage::save_multisig_proposal(&env, &proposal);
 
     // Emit event
     ProtocolEvent::MultiSigProposalApproved {
         proposal_id,
-        signer: signer.clone(),
+        signer: signer.to_string(),
         current_approvals: proposal.current_approvals,
     }.emit(&env);
 
@@ -6253,15 +10727,14 @@ pub fn execute_multisig_proposal(
 /// Cancel a multi-signature proposal
 pub fn cancel_multisig_proposal(
     env: Env,
-    creator: String,
+    creator: Address,
     proposal_id: u32,
 ) -> Result<(), ProtocolError> {
-    let creator_addr = Address::from_string(&String::from_str(&env, &creator));
-
+    creator.require_auth();
     let mut proposal = RecoveryStorage::get_multisig_proposal(&env, proposal_id)
         .ok_or(ProtocolError::MultiSigProposalNotFound)?;
 
-    if proposal.creator != creator_addr {
+    if proposal.creator != creator {
         return Err(ProtocolError::Unauthorized);
     }
 
@@ -6275,7 +10748,7 @@ pub fn cancel_multisig_proposal(
     // Emit event
     ProtocolEvent::MultiSigProposalCancelled {
         proposal_id,
-        creator: creator.clone(),
+        creator: creator.to_string(),
     }.emit(&env);
 
     Ok(())
@@ -6287,7 +10760,7 @@ pub fn get_multisig_config(env: Env) -> Result<(u32, u32, bool), ProtocolError>
         .ok_or(ProtocolError::NotFound)?;
 
     Ok((
-        config.signers.len() as u32,
+        config.signers.len(),
         config.min_signatures,
         config.enabled,
     ))
@@ -0,0 +1,354 @@
+//! StellarLend sToken
+//
+//! SEP-41 share token minted by the lending pool against lender supply. Balances
+//! are shares of the pool's supply index, so each share is redeemable for a growing
+//! amount of the underlying asset. Only the pool can mint or burn; transfers are
+//! reported back to the pool so the supply position moves with the token.
+
+#![no_std]
+use soroban_sdk::{
+    contract, contractclient, contracterror, contractimpl, contracttype, panic_with_error,
+    token::TokenInterface, Address, Env, String, Symbol,
+};
+
+/// Ledgers per day at a ~5s close time
+const DAY_IN_LEDGERS: u32 = 17280;
+const INSTANCE_BUMP_AMOUNT: u32 = 7 * DAY_IN_LEDGERS;
+const INSTANCE_LIFETIME_THRESHOLD: u32 = INSTANCE_BUMP_AMOUNT - DAY_IN_LEDGERS;
+const BALANCE_BUMP_AMOUNT: u32 = 30 * DAY_IN_LEDGERS;
+const BALANCE_LIFETIME_THRESHOLD: u32 = BALANCE_BUMP_AMOUNT - DAY_IN_LEDGERS;
+
+/// Errors raised by the sToken contract
+#[contracterror]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum STokenError {
+    NotInitialized = 1,
+    AlreadyInitialized = 2,
+    NegativeAmount = 3,
+    InsufficientBalance = 4,
+    InsufficientAllowance = 5,
+    InvalidExpiration = 6,
+    /// Shares can only be burned by the pool when they are redeemed
+    BurnDisabled = 7,
+}
+
+/// Token metadata
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct TokenMetadata {
+    pub decimals: u32,
+    pub name: String,
+    pub symbol: String,
+}
+
+/// Allowance granted by `from` to a spender
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct AllowanceValue {
+    pub amount: i128,
+    pub expiration_ledger: u32,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct AllowanceDataKey {
+    pub from: Address,
+    pub spender: Address,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub enum DataKey {
+    /// Lending pool that administers this token
+    Pool,
+    /// Pool asset whose supply the shares represent
+    Asset,
+    Metadata,
+    Balance(Address),
+    Allowance(AllowanceDataKey),
+}
+
+/// Callback the lending pool exposes for share transfers
+#[contractclient(name = "PoolClient")]
+pub trait PoolInterface {
+    /// Move `amount` shares of the `asset` supply position from `from` to `to`; the pool may reject the move
+    fn on_stoken_transfer(env: Env, asset: String, from: Address, to: Address, amount: i128);
+}
+
+#[contract]
+pub struct SToken;
+
+fn check_nonnegative_amount(env: &Env, amount: i128) {
+    if amount < 0 {
+        panic_with_error!(env, STokenError::NegativeAmount);
+    }
+}
+
+fn bump_instance(env: &Env) {
+    env.storage()
+        .instance()
+        .extend_ttl(INSTANCE_LIFETIME_THRESHOLD, INSTANCE_BUMP_AMOUNT);
+}
+
+fn read_pool(env: &Env) -> Address {
+    env.storage()
+        .instance()
+        .get(&DataKey::Pool)
+        .unwrap_or_else(|| panic_with_error!(env, STokenError::NotInitialized))
+}
+
+fn read_asset(env: &Env) -> String {
+    env.storage()
+        .instance()
+        .get(&DataKey::Asset)
+        .unwrap_or_else(|| panic_with_error!(env, STokenError::NotInitialized))
+}
+
+fn read_metadata(env: &Env) -> TokenMetadata {
+    env.storage()
+        .instance()
+        .get(&DataKey::Metadata)
+        .unwrap_or_else(|| panic_with_error!(env, STokenError::NotInitialized))
+}
+
+fn read_balance(env: &Env, id: &Address) -> i128 {
+    let key = DataKey::Balance(id.clone());
+    match env.storage().persistent().get::<DataKey, i128>(&key) {
+        Some(balance) => {
+            env.storage().persistent().extend_ttl(
+                &key,
+                BALANCE_LIFETIME_THRESHOLD,
+                BALANCE_BUMP_AMOUNT,
+            );
+            balance
+        }
+        None => 0,
+    }
+}
+
+fn write_balance(env: &Env, id: &Address, amount: i128) {
+    let key = DataKey::Balance(id.clone());
+    env.storage().persistent().set(&key, &amount);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, BALANCE_LIFETIME_THRESHOLD, BALANCE_BUMP_AMOUNT);
+}
+
+fn receive_balance(env: &Env, id: &Address, amount: i128) {
+    let balance = read_balance(env, id);
+    write_balance(env, id, balance + amount);
+}
+
+fn spend_balance(env: &Env, id: &Address, amount: i128) {
+    let balance = read_balance(env, id);
+    if balance < amount {
+        panic_with_error!(env, STokenError::InsufficientBalance);
+    }
+    write_balance(env, id, balance - amount);
+}
+
+fn read_allowance(env: &Env, from: &Address, spender: &Address) -> AllowanceValue {
+    let key = DataKey::Allowance(AllowanceDataKey {
+        from: from.clone(),
+        spender: spender.clone(),
+    });
+    match env
+        .storage()
+        .temporary()
+        .get::<DataKey, AllowanceValue>(&key)
+    {
+        Some(allowance) if allowance.expiration_ledger >= env.ledger().sequence() => allowance,
+        Some(allowance) => AllowanceValue {
+            amount: 0,
+            expiration_ledger: allowance.expiration_ledger,
+        },
+        None => AllowanceValue {
+            amount: 0,
+            expiration_ledger: 0,
+        },
+    }
+}
+
+fn write_allowance(
+    env: &Env,
+    from: &Address,
+    spender: &Address,
+    amount: i128,
+    expiration_ledger: u32,
+) {
+    if amount > 0 && expiration_ledger < env.ledger().sequence() {
+        panic_with_error!(env, STokenError::InvalidExpiration);
+    }
+    let key = DataKey::Allowance(AllowanceDataKey {
+        from: from.clone(),
+        spender: spender.clone(),
+    });
+    let allowance = AllowanceValue {
+        amount,
+        expiration_ledger,
+    };
+    env.storage().temporary().set(&key, &allowance);
+    if amount > 0 {
+        let live_for = expiration_ledger - env.ledger().sequence();
+        env.storage()
+            .temporary()
+            .extend_ttl(&key, live_for, live_for);
+    }
+}
+
+fn spend_allowance(env: &Env, from: &Address, spender: &Address, amount: i128) {
+    let allowance = read_allowance(env, from, spender);
+    if allowance.amount < amount {
+        panic_with_error!(env, STokenError::InsufficientAllowance);
+    }
+    if amount > 0 {
+        write_allowance(
+            env,
+            from,
+            spender,
+            allowance.amount - amount,
+            allowance.expiration_ledger,
+        );
+    }
+}
+
+/// Move shares between holders and let the pool move the supply position they represent
+fn move_shares(env: &Env, from: &Address, to: &Address, amount: i128) {
+    spend_balance(env, from, amount);
+    receive_balance(env, to, amount);
+    PoolClient::new(env, &read_pool(env)).on_stoken_transfer(&read_asset(env), from, to, &amount);
+    env.events().publish(
+        (Symbol::new(env, "transfer"), from.clone(), to.clone()),
+        amount,
+    );
+}
+
+#[contractimpl]
+impl SToken {
+    /// Initialize the token; `pool` is the lending contract that mints and burns shares
+    /// of its `asset` market, and must authorize binding the token to itself
+    pub fn initialize(
+        env: Env,
+        pool: Address,
+        asset: String,
+        decimals: u32,
+        name: String,
+        symbol: String,
+    ) {
+        if env.storage().instance().has(&DataKey::Pool) {
+            panic_with_error!(&env, STokenError::AlreadyInitialized);
+        }
+        pool.require_auth();
+        env.storage().instance().set(&DataKey::Pool, &pool);
+        env.storage().instance().set(&DataKey::Asset, &asset);
+        env.storage().instance().set(
+            &DataKey::Metadata,
+            &TokenMetadata {
+                decimals,
+                name,
+                symbol,
+            },
+        );
+        bump_instance(&env);
+    }
+
+    /// Mint shares to `to` (pool only)
+    pub fn mint(env: Env, to: Address, amount: i128) {
+        check_nonnegative_amount(&env, amount);
+        let pool = read_pool(&env);
+        pool.require_auth();
+        bump_instance(&env);
+
+        receive_balance(&env, &to, amount);
+        env.events()
+            .publish((Symbol::new(&env, "mint"), pool, to), amount);
+    }
+
+    /// Burn shares held by `from` when the pool redeems them (pool only)
+    pub fn pool_burn(env: Env, from: Address, amount: i128) {
+        check_nonnegative_amount(&env, amount);
+        let pool = read_pool(&env);
+        pool.require_auth();
+        bump_instance(&env);
+
+        spend_balance(&env, &from, amount);
+        env.events()
+            .publish((Symbol::new(&env, "burn"), from), amount);
+    }
+
+    /// Lending pool that administers this token
+    pub fn pool(env: Env) -> Address {
+        read_pool(&env)
+    }
+
+    /// Pool asset whose supply the shares represent
+    pub fn asset(env: Env) -> String {
+        read_asset(&env)
+    }
+}
+
+#[contractimpl]
+impl TokenInterface for SToken {
+    fn allowance(env: Env, from: Address, spender: Address) -> i128 {
+        bump_instance(&env);
+        read_allowance(&env, &from, &spender).amount
+    }
+
+    fn approve(env: Env, from: Address, spender: Address, amount: i128, expiration_ledger: u32) {
+        from.require_auth();
+        check_nonnegative_amount(&env, amount);
+        bump_instance(&env);
+
+        write_allowance(&env, &from, &spender, amount, expiration_ledger);
+        env.events().publish(
+            (Symbol::new(&env, "approve"), from, spender),
+            (amount, expiration_ledger),
+        );
+    }
+
+    fn balance(env: Env, id: Address) -> i128 {
+        bump_instance(&env);
+        read_balance(&env, &id)
+    }
+
+    fn transfer(env: Env, from: Address, to: Address, amount: i128) {
+        from.require_auth();
+        check_nonnegative_amount(&env, amount);
+        bump_instance(&env);
+
+        move_shares(&env, &from, &to, amount);
+    }
+
+    fn transfer_from(env: Env, spender: Address, from: Address, to: Address, amount: i128) {
+        spender.require_auth();
+        check_nonnegative_amount(&env, amount);
+        bump_instance(&env);
+
+        spend_allowance(&env, &from, &spender, amount);
+        move_shares(&env, &from, &to, amount);
+    }
+
+    /// Holders cannot burn shares directly; redeem them through the pool instead
+    fn burn(env: Env, _from: Address, _amount: i128) {
+        panic_with_error!(&env, STokenError::BurnDisabled);
+    }
+
+    /// Holders cannot burn shares directly; redeem them through the pool instead
+    fn burn_from(env: Env, _spender: Address, _from: Address, _amount: i128) {
+        panic_with_error!(&env, STokenError::BurnDisabled);
+    }
+
+    fn decimals(env: Env) -> u32 {
+        read_metadata(&env).decimals
+    }
+
+    fn name(env: Env) -> String {
+        read_metadata(&env).name
+    }
+
+    fn symbol(env: Env) -> String {
+        read_metadata(&env).symbol
+    }
+}
+
+mod test;
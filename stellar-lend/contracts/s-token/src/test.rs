@@ -0,0 +1,185 @@
+#![cfg(test)]
+
+use super::*;
+use soroban_sdk::{
+    contract, contractimpl, testutils::Address as _, testutils::Ledger, Address, Env, String,
+};
+
+/// Pool stand-in that records transfers and rejects moves out of `locked`
+#[contract]
+pub struct MockPool;
+
+#[contractimpl]
+impl MockPool {
+    pub fn lock(env: Env, holder: Address) {
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "locked"), &holder);
+    }
+
+    pub fn on_stoken_transfer(env: Env, asset: String, from: Address, _to: Address, amount: i128) {
+        assert_eq!(asset, String::from_str(&env, "XLM"));
+        let locked: Option<Address> = env.storage().instance().get(&Symbol::new(&env, "locked"));
+        if locked == Some(from) {
+            panic!("holder is locked");
+        }
+        let moved: i128 = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(&env, "moved"))
+            .unwrap_or(0);
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "moved"), &(moved + amount));
+    }
+
+    pub fn moved(env: Env) -> i128 {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "moved"))
+            .unwrap_or(0)
+    }
+}
+
+fn setup(env: &Env) -> (Address, STokenClient<'_>) {
+    let pool = env.register(MockPool, ());
+    let stoken = STokenClient::new(env, &env.register(SToken, ()));
+    stoken.initialize(
+        &pool,
+        &String::from_str(env, "XLM"),
+        &7,
+        &String::from_str(env, "StellarLend XLM"),
+        &String::from_str(env, "sXLM"),
+    );
+    (pool, stoken)
+}
+
+#[test]
+fn test_initialize_and_metadata() {
+    let env = Env::default();
+    env.mock_all_auths();
+    let (pool, stoken) = setup(&env);
+    assert_eq!(env.auths()[0].0, pool);
+
+    assert_eq!(stoken.pool(), pool);
+    assert_eq!(stoken.asset(), String::from_str(&env, "XLM"));
+    assert_eq!(stoken.decimals(), 7);
+    assert_eq!(stoken.name(), String::from_str(&env, "StellarLend XLM"));
+    assert_eq!(stoken.symbol(), String::from_str(&env, "sXLM"));
+    assert_eq!(
+        stoken.try_initialize(
+            &pool,
+            &String::from_str(&env, "XLM"),
+            &7,
+            &String::from_str(&env, "x"),
+            &String::from_str(&env, "x")
+        ),
+        Err(Ok(STokenError::AlreadyInitialized.into()))
+    );
+}
+
+#[test]
+fn test_pool_mints_and_burns() {
+    let env = Env::default();
+    env.mock_all_auths();
+    let (pool, stoken) = setup(&env);
+    let user = Address::generate(&env);
+
+    stoken.mint(&user, &1000);
+    assert_eq!(env.auths()[0].0, pool);
+    assert_eq!(stoken.balance(&user), 1000);
+
+    stoken.pool_burn(&user, &400);
+    assert_eq!(env.auths()[0].0, pool);
+    assert_eq!(stoken.balance(&user), 600);
+
+    assert_eq!(
+        stoken.try_pool_burn(&user, &601),
+        Err(Ok(STokenError::InsufficientBalance.into()))
+    );
+    assert_eq!(
+        stoken.try_burn(&user, &1),
+        Err(Ok(STokenError::BurnDisabled.into()))
+    );
+}
+
+#[test]
+fn test_initialize_requires_pool_auth() {
+    let env = Env::default();
+    let pool = env.register(MockPool, ());
+    let stoken = STokenClient::new(&env, &env.register(SToken, ()));
+    assert!(stoken
+        .try_initialize(
+            &pool,
+            &String::from_str(&env, "XLM"),
+            &7,
+            &String::from_str(&env, "x"),
+            &String::from_str(&env, "x")
+        )
+        .is_err());
+}
+
+#[test]
+fn test_mint_requires_pool_auth() {
+    let env = Env::default();
+    env.mock_all_auths();
+    let (_pool, stoken) = setup(&env);
+    env.set_auths(&[]);
+    let user = Address::generate(&env);
+
+    assert!(stoken.try_mint(&user, &1000).is_err());
+    assert_eq!(stoken.balance(&user), 0);
+}
+
+#[test]
+fn test_transfer_notifies_pool() {
+    let env = Env::default();
+    env.mock_all_auths();
+    let (pool, stoken) = setup(&env);
+    let pool_client = MockPoolClient::new(&env, &pool);
+    let alice = Address::generate(&env);
+    let bob = Address::generate(&env);
+    stoken.mint(&alice, &1000);
+
+    stoken.transfer(&alice, &bob, &300);
+    assert_eq!(env.auths()[0].0, alice);
+    assert_eq!(stoken.balance(&alice), 700);
+    assert_eq!(stoken.balance(&bob), 300);
+    assert_eq!(pool_client.moved(), 300);
+
+    // The pool can veto a transfer, which reverts the balance change
+    pool_client.lock(&alice);
+    assert!(stoken.try_transfer(&alice, &bob, &100).is_err());
+    assert_eq!(stoken.balance(&alice), 700);
+    assert_eq!(pool_client.moved(), 300);
+}
+
+#[test]
+fn test_transfer_from_spends_allowance() {
+    let env = Env::default();
+    env.mock_all_auths();
+    let (pool, stoken) = setup(&env);
+    let alice = Address::generate(&env);
+    let bob = Address::generate(&env);
+    let spender = Address::generate(&env);
+    stoken.mint(&alice, &1000);
+
+    let expiration = env.ledger().sequence() + 100;
+    stoken.approve(&alice, &spender, &500, &expiration);
+    assert_eq!(stoken.allowance(&alice, &spender), 500);
+
+    stoken.transfer_from(&spender, &alice, &bob, &200);
+    assert_eq!(stoken.allowance(&alice, &spender), 300);
+    assert_eq!(stoken.balance(&bob), 200);
+    assert_eq!(MockPoolClient::new(&env, &pool).moved(), 200);
+
+    assert_eq!(
+        stoken.try_transfer_from(&spender, &alice, &bob, &400),
+        Err(Ok(STokenError::InsufficientAllowance.into()))
+    );
+
+    // Expired allowances read as zero
+    env.ledger()
+        .with_mut(|li| li.sequence_number = expiration + 1);
+    assert_eq!(stoken.allowance(&alice, &spender), 0);
+}